@@ -1,11 +1,12 @@
 use std::io::{self};
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::mpsc::{channel, Sender, Receiver};
 
 use log::{info, error};
 
-use zecwalletlitelib::{commands,
-    lightclient::{LightClient, LightClientConfig},
+use zecwalletlitelib::{commands, grpcconnector,
+    lightclient::{self, LightClient, LightClientConfig},
 };
 
 pub mod version;
@@ -23,13 +24,31 @@ macro_rules! configure_clapapp {
                 .long("nosync")
                 .short("n")
                 .takes_value(false))
+            .arg(Arg::with_name("offline")
+                .long("offline")
+                .help("Open the existing wallet without connecting to the server, eg. to check the balance or export keys. Commands that need the server fail, and 'info' shows what the server said the last time the wallet was online.")
+                .conflicts_with("seed")
+                .takes_value(false))
+            .arg(Arg::with_name("chain")
+                .long("chain")
+                .value_name("chain")
+                .help("The chain of the wallet to open with --offline: main, test or regtest. Defaults to main. Online, the chain is the server's.")
+                .possible_values(&["main", "test", "regtest"])
+                .requires("offline")
+                .takes_value(true))
+            .arg(Arg::with_name("read-only")
+                .long("read-only")
+                .help("Open the existing wallet without ever writing it: it isn't synced or saved, and can't send. Shows the wallet as it was last saved, eg. while another yecshell has it open.")
+                .conflicts_with("seed")
+                .conflicts_with("restore-backup")
+                .takes_value(false))
             .arg(Arg::with_name("recover")
                 .long("recover")
                 .help("Attempt to recover the seed from the wallet")
                 .takes_value(false))
             .arg(Arg::with_name("password")
                 .long("password")
-                .help("When recovering seed, specify a password for the encrypted wallet")
+                .help("When recovering seed, specify a password for the encrypted wallet. With --restore-backup, the backup's password")
                 .takes_value(true))
             .arg(Arg::with_name("seed")
                 .short("s")
@@ -37,6 +56,13 @@ macro_rules! configure_clapapp {
                 .value_name("seed_phrase")
                 .help("Create a new wallet with the given 24-word seed phrase. Will fail if wallet already exists")
                 .takes_value(true))
+            .arg(Arg::with_name("restore-backup")
+                .long("restore-backup")
+                .value_name("file")
+                .help("Create a new wallet from a backup written by the 'exportbackup' command, whose password is given with --password. The wallet is rescanned from the backup's birthday. Will fail if wallet already exists")
+                .conflicts_with("seed")
+                .requires("password")
+                .takes_value(true))
             .arg(Arg::with_name("birthday")
                 .long("birthday")
                 .value_name("birthday")
@@ -45,9 +71,74 @@ macro_rules! configure_clapapp {
             .arg(Arg::with_name("server")
                 .long("server")
                 .value_name("server")
-                .help("Lightwalletd server to connect to.")
+                .help("Lightwalletd server to connect to. Pass a comma separated list to fail over to the next server when one can't be reached.")
                 .takes_value(true)
+                .use_delimiter(true)
                 .default_value(lightclient::DEFAULT_SERVER))
+            .arg(Arg::with_name("server-ip")
+                .long("server-ip")
+                .value_name("ip")
+                .help("Connect to the (first) server at this IP address, instead of looking up its name. The name is still used to check the server's TLS certificate. Useful where DNS isn't available, or can't be trusted.")
+                .takes_value(true))
+            .arg(Arg::with_name("auto-select-server")
+                .long("auto-select-server")
+                .help("When several servers are given, ping all of them at startup (and periodically after that) and use the fastest one that is up to date, instead of the first one.")
+                .takes_value(false))
+            .arg(Arg::with_name("compress")
+                .long("compress")
+                .help("Ask the server to gzip its responses. This makes syncing download a lot less, which helps on slow or metered connections. Servers that don't support it answer uncompressed.")
+                .takes_value(false))
+            .arg(Arg::with_name("block-connections")
+                .long("block-connections")
+                .value_name("n")
+                .help("Fetch blocks over this many connections to the server at the same time while syncing (at most 4), which can make a long sync faster on links with high latency. Defaults to 1.")
+                .takes_value(true))
+            .arg(Arg::with_name("max-message-size")
+                .long("max-message-size")
+                .value_name("MB")
+                .help("The largest message to accept from the server, in megabytes. Calls where the server sends a larger one fail. Defaults to 32.")
+                .takes_value(true))
+            .arg(Arg::with_name("reuse-change-address")
+                .long("reuse-change-address")
+                .help("Send change back to the address of the spent notes, instead of to a new diversified address for every transaction. This makes the wallet's transactions easier to link.")
+                .takes_value(false))
+            .arg(Arg::with_name("donate-dust-to-fee")
+                .long("donate-dust-to-fee")
+                .help("Allow sends that leave change of no more than the fee, and add that change to the fee instead. Such change costs more to spend than it is worth, so by default these sends are refused.")
+                .takes_value(false))
+            .arg(Arg::with_name("min-fee-per-action")
+                .long("min-fee-per-action")
+                .value_name("zatoshis")
+                .help("The fee the network wants for each logical action (input or output) of a transaction. Sends pay that for every action, and never less than the default fee. Defaults to 0, where every send pays the default fee.")
+                .takes_value(true))
+            .arg(Arg::with_name("ipv4")
+                .long("ipv4")
+                .short("4")
+                .help("Only connect to the server over IPv4")
+                .conflicts_with("ipv6")
+                .takes_value(false))
+            .arg(Arg::with_name("ipv6")
+                .long("ipv6")
+                .short("6")
+                .help("Only connect to the server over IPv6")
+                .takes_value(false))
+            .arg(Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("proxy")
+                .help("SOCKS5 proxy to connect to the server through, eg. socks5://127.0.0.1:9050 for Tor. The server's name is resolved by the proxy.")
+                .takes_value(true))
+            .arg(Arg::with_name("client-cert")
+                .long("client-cert")
+                .value_name("client_cert")
+                .help("PEM certificate to authenticate to servers that require client certificates")
+                .requires("client-key")
+                .takes_value(true))
+            .arg(Arg::with_name("client-key")
+                .long("client-key")
+                .value_name("client_key")
+                .help("PEM private key of the --client-cert")
+                .requires("client-cert")
+                .takes_value(true))
             .arg(Arg::with_name("COMMAND")
                 .help("Command to execute. If a command is not specified, yecshell will start in interactive mode.")
                 .required(false)
@@ -81,16 +172,33 @@ pub fn report_permission_error() {
     }
 }
 
-pub fn startup(server: http::Uri, dangerous: bool, seed: Option<String>, birthday: u64, first_sync: bool, print_updates: bool)
+pub fn startup(servers: Vec<http::Uri>, server_ip: Option<IpAddr>, dangerous: bool, proxy: Option<String>, auto_select_server: bool, ip_version: grpcconnector::IpVersion, compress: bool, block_connections: usize, max_message_size: usize,
+               reuse_change_address: bool, donate_dust_to_fee: bool, min_fee_per_action: u64, offline_chain: Option<String>, read_only: bool, client_cert: Option<String>, client_key: Option<String>, seed: Option<String>, birthday: u64, restore_backup: Option<(Vec<u8>, String)>, first_sync: bool, print_updates: bool)
         -> io::Result<(Sender<(String, Vec<String>)>, Receiver<String>)> {
     // Try to get the configuration
-    let (config, latest_block_height) = LightClientConfig::create(server.clone(), dangerous)?;
+    let (mut config, latest_block_height) = match offline_chain {
+        Some(chain) => (LightClientConfig::create_offline(chain, None)?, 0),
+        None        => LightClientConfig::create_with_servers(servers, dangerous, proxy, client_cert, client_key, server_ip)?
+    };
+    config.auto_select_server = auto_select_server;
+    config.ip_version = ip_version;
+    config.compression = compress;
+    config.block_connections = block_connections;
+    config.max_message_size = max_message_size;
+    config.fresh_change_address = !reuse_change_address;
+    config.donate_dust_to_fee = donate_dust_to_fee;
+    config.min_fee_per_action = min_fee_per_action;
+    config.read_only = read_only;
 
-    let lightclient = match seed {
-        Some(phrase) => Arc::new(LightClient::new_from_phrase(phrase, &config, birthday, false)?),
-        None => {
+    let lightclient = match (seed, restore_backup) {
+        (Some(phrase), _) => Arc::new(LightClient::new_from_phrase(phrase, &config, birthday, false)?),
+        (None, Some((backup, password))) => Arc::new(LightClient::restore_from_backup(&backup, &password, &config)?),
+        (None, None) => {
             if config.wallet_exists() {
                 Arc::new(LightClient::read_from_disk(&config)?)
+            } else if config.read_only {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                    format!("There is no wallet at {} to open read-only", config.get_wallet_path().display())));
             } else {
                 println!("Creating a new wallet");
                 Arc::new(LightClient::new(&config, latest_block_height)?)
@@ -107,11 +215,15 @@ pub fn startup(server: http::Uri, dangerous: bool, seed: Option<String>, birthda
     info!("Light Client config {:?}", config);
 
     if print_updates {
-        println!("Lightclient connecting to {}", config.server);
+        if config.offline {
+            println!("Lightclient is offline, not connecting to {}", lightclient.get_server_uri());
+        } else {
+            println!("Lightclient connecting to {}", lightclient.get_server_uri());
+        }
     }
 
     // At startup, run a sync.
-    if first_sync {
+    if first_sync && !config.offline && !config.read_only {
         let update = lightclient.do_sync(true);
         if print_updates {
             match update {
@@ -149,7 +261,10 @@ pub fn start_interactive(command_tx: Sender<(String, Vec<String>)>, resp_rx: Rec
     };
 
     let info = &send_command("info".to_string(), vec![]);
-    let chain_name = json::parse(info).unwrap()["chain_name"].as_str().unwrap().to_string();
+    // Offline, there might not be any info about the server to get the chain from
+    let chain_name = json::parse(info).ok()
+                        .and_then(|j| j["chain_name"].as_str().map(|s| s.to_string()))
+                        .unwrap_or_else(|| "offline".to_string());
 
     loop {
         // Read the height first
@@ -225,6 +340,16 @@ pub fn command_loop(lightclient: Arc<LightClient>) -> (Sender<(String, Vec<Strin
                 },
                 Err(_) => {
                     // Timeout. Do a sync to keep the wallet up-to-date. False to whether to print updates on the console
+                    // A read-only wallet can't be synced, and a locked one waits until it's unlocked
+                    if lc.config.read_only || !lc.wallet.read().unwrap().is_unlocked_for_viewing() {
+                        continue;
+                    }
+
+                    if lc.config.auto_select_server {
+                        info!("Timeout, checking for a faster server");
+                        lc.do_select_server();
+                    }
+
                     info!("Timeout, doing a sync");
                     match lc.do_sync(false) {
                         Ok(_) => {},
@@ -241,13 +366,45 @@ pub fn command_loop(lightclient: Arc<LightClient>) -> (Sender<(String, Vec<Strin
 pub fn attempt_recover_seed(password: Option<String>) {
     // Create a Light Client Config in an attempt to recover the file.
     let config = LightClientConfig {
-        server: "0.0.0.0:0".parse().unwrap(),
+        servers: vec!["0.0.0.0:0".parse().unwrap()],
         chain_name: "main".to_string(),
         sapling_activation_height: 0,
         consensus_branch_id: "000000".to_string(),
         anchor_offset: 0,
         no_cert_verification: false,
         data_dir: None,
+        connect_timeout: grpcconnector::DEFAULT_CONNECT_TIMEOUT,
+        unary_timeout: grpcconnector::DEFAULT_UNARY_TIMEOUT,
+        stream_inactivity_timeout: grpcconnector::DEFAULT_STREAM_INACTIVITY_TIMEOUT,
+        max_attempts: grpcconnector::DEFAULT_MAX_ATTEMPTS,
+        proxy: None,
+        auto_save: false,
+        wallet_backups: lightclient::DEFAULT_WALLET_BACKUPS,
+        auto_select_server: false,
+        max_reorg_depth: lightclient::DEFAULT_MAX_REORG_DEPTH,
+        info_ttl: lightclient::DEFAULT_INFO_TTL,
+        ip_version: grpcconnector::IpVersion::Any,
+        keepalive_interval: Some(grpcconnector::DEFAULT_KEEPALIVE_INTERVAL),
+        keepalive_timeout: grpcconnector::DEFAULT_KEEPALIVE_TIMEOUT,
+        client_cert_path: None,
+        client_key_path: None,
+        server_ip_override: None,
+        client_name: None,
+        compression: false,
+        max_send_per_tx: None,
+        max_send_per_day: None,
+        min_fee_per_action: 0,
+        fresh_change_address: true,
+        donate_dust_to_fee: false,
+        offline: false,
+        block_connections: 1,
+        max_message_size: grpcconnector::DEFAULT_MAX_MESSAGE_SIZE,
+        wallet_name: None,
+        read_only: false,
+        force_unlock: false,
+        compress_wallet_file: true,
+        compress_wallet_buffer: false,
+        checkpoint_interval: lightclient::DEFAULT_CHECKPOINT_INTERVAL,
     };
 
     match LightClient::attempt_recover_seed(&config, password) {
@@ -1,4 +1,4 @@
-use zecwalletlitelib::lightclient::{self, LightClientConfig};
+use zecwalletlitelib::{grpcconnector, lightclient::{self, LightClientConfig}};
 use yecshell::{configure_clapapp,
                     report_permission_error,
                     startup,
@@ -23,7 +23,7 @@ pub fn main() {
     let command = matches.value_of("COMMAND");
     let params = matches.values_of("PARAMS").map(|v| v.collect()).or(Some(vec![])).unwrap();
 
-    let maybe_server   = matches.value_of("server").map(|s| s.to_string());
+    let maybe_servers  = matches.values_of("server").map(|v| v.map(|s| s.to_string()).collect::<Vec<_>>());
 
     let seed           = matches.value_of("seed").map(|s| s.to_string());
     let maybe_birthday = matches.value_of("birthday");
@@ -43,17 +43,95 @@ pub fn main() {
                         }
                     };
 
-    let server = LightClientConfig::get_server_or_default(maybe_server);
+    let servers = match maybe_servers {
+        Some(v) => v.into_iter().map(|s| LightClientConfig::get_server_or_default(Some(s))).collect::<Vec<_>>(),
+        None    => vec![LightClientConfig::get_server_or_default(None)]
+    };
 
-    // Test to make sure the server has all of scheme, host and port
-    if server.scheme_str().is_none() || server.host().is_none() || server.port_part().is_none() {
-        eprintln!("Please provide the --server parameter as [scheme]://[host]:[port].\nYou provided: {}", server);
-        return;
+    // Test to make sure the servers have all of scheme, host and port
+    for server in servers.iter() {
+        if server.scheme_str().is_none() || server.host().is_none() || server.port_part().is_none() {
+            eprintln!("Please provide the --server parameter as [scheme]://[host]:[port].\nYou provided: {}", server);
+            return;
+        }
     }
 
+    let server_ip = match matches.value_of("server-ip").map(|s| s.parse::<std::net::IpAddr>()) {
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(e)) => {
+            eprintln!("Couldn't parse --server-ip. This should be an IPv4 or IPv6 address. Error={}", e);
+            return;
+        },
+        None => None
+    };
+
     let dangerous = matches.is_present("dangerous");
+    if dangerous {
+        eprintln!("WARNING: --dangerous turns off TLS certificate verification. Only use it with a server that is your own.");
+    }
+    let proxy = matches.value_of("proxy").map(|s| s.to_string());
+    let auto_select_server = matches.is_present("auto-select-server");
+    let ip_version = if matches.is_present("ipv4") { 
+        grpcconnector::IpVersion::V4 
+    } else if matches.is_present("ipv6") { 
+        grpcconnector::IpVersion::V6 
+    } else { 
+        grpcconnector::IpVersion::Any 
+    };
+    let compress = matches.is_present("compress");
+    let block_connections = match matches.value_of("block-connections").unwrap_or("1").parse::<usize>() {
+        Ok(n) if (1..=grpcconnector::MAX_BLOCK_CONNECTIONS).contains(&n) => n,
+        Ok(n) => {
+            eprintln!("--block-connections should be between 1 and {}, not {}", grpcconnector::MAX_BLOCK_CONNECTIONS, n);
+            return;
+        },
+        Err(e) => {
+            eprintln!("Couldn't parse --block-connections. This should be a number. Error={}", e);
+            return;
+        }
+    };
+    let max_message_size = match matches.value_of("max-message-size").map(|s| s.parse::<usize>()) {
+        None => grpcconnector::DEFAULT_MAX_MESSAGE_SIZE,
+        Some(Ok(mb)) if (1..=4096).contains(&mb) => mb * 1024 * 1024,
+        Some(Ok(mb)) => {
+            eprintln!("--max-message-size should be between 1 and 4096 (megabytes), not {}", mb);
+            return;
+        },
+        Some(Err(e)) => {
+            eprintln!("Couldn't parse --max-message-size. This should be a number of megabytes. Error={}", e);
+            return;
+        }
+    };
+    let reuse_change_address = matches.is_present("reuse-change-address");
+    let donate_dust_to_fee = matches.is_present("donate-dust-to-fee");
+    let min_fee_per_action = match matches.value_of("min-fee-per-action").unwrap_or("0").parse::<u64>() {
+        Ok(fee) => fee,
+        Err(e) => {
+            eprintln!("Couldn't parse --min-fee-per-action. This should be a number of zatoshis. Error={}", e);
+            return;
+        }
+    };
+    let offline_chain = if matches.is_present("offline") {
+        Some(matches.value_of("chain").unwrap_or("main").to_string())
+    } else {
+        None
+    };
+    let read_only = matches.is_present("read-only");
+    let client_cert = matches.value_of("client-cert").map(|s| s.to_string());
+    let client_key = matches.value_of("client-key").map(|s| s.to_string());
     let nosync = matches.is_present("nosync");
-    let (command_tx, resp_rx) = match startup(server, dangerous, seed, birthday, !nosync, command.is_none()) {
+    let restore_backup = match matches.value_of("restore-backup") {
+        Some(file) => match std::fs::read(file) {
+            Ok(backup) => Some((backup, matches.value_of("password").unwrap_or("").to_string())),
+            Err(e) => {
+                eprintln!("Couldn't read the backup {}. Error={}", file, e);
+                return;
+            }
+        },
+        None => None
+    };
+    let (command_tx, resp_rx) = match startup(servers, server_ip, dangerous, proxy, auto_select_server, ip_version, compress, block_connections, max_message_size, reuse_change_address, donate_dust_to_fee, min_fee_per_action, offline_chain, read_only,
+                                              client_cert, client_key, seed, birthday, restore_backup, !nosync, command.is_none()) {
         Ok(c) => c,
         Err(e) => {
             let emsg = format!("Error during startup:{}\nIf you repeatedly run into this issue, you might have to restore your wallet from your seed phrase.", e);
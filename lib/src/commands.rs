@@ -1,8 +1,8 @@
 use std::collections::HashMap;
-use json::{object};
+use json::{object, JsonValue};
 
-use crate::lightclient::LightClient;
-use crate::lightwallet::LightWallet;
+use crate::lightclient::{LightClient, NoteFilter, TxSort};
+use crate::lightwallet::{LightWallet, SEND_MAX, DEFAULT_ACCOUNT};
 
 pub trait Command {
     fn help(&self) -> String;
@@ -12,6 +12,15 @@ pub trait Command {
     fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String;
 }
 
+/// Sync before showing the wallet, unless it was opened read-only. That can't be synced, so it's shown as it is.
+fn sync_unless_read_only(lightclient: &LightClient) -> Result<(), String> {
+    if lightclient.config.read_only {
+        return Ok(());
+    }
+
+    lightclient.do_sync(true).map(|_| ())
+}
+
 struct SyncCommand {}
 impl Command for SyncCommand {
     fn help(&self) -> String {
@@ -40,7 +49,7 @@ struct EncryptionStatusCommand {}
 impl Command for EncryptionStatusCommand {
     fn help(&self) -> String {
         let mut h = vec![];
-        h.push("Check if the wallet is encrypted and if it is locked");
+        h.push("Check if the wallet is encrypted and if it is locked, and how its key is derived from the password");
         h.push("Usage:");
         h.push("encryptionstatus");
         h.push("");
@@ -76,24 +85,86 @@ impl Command for SyncStatusCommand {
     fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
         let status = lightclient.do_scan_status();
         match status.is_syncing {
-            false => object!{ "syncing" => "false" },
+            false => object!{ "syncing" => "false",
+                              "server" => lightclient.get_server_uri().to_string(),
+                              "failovers" => status.failovers,
+                              "bytes_downloaded" => status.bytes_downloaded,
+                              "bytes_uncompressed" => status.bytes_uncompressed },
             true  => object!{ "syncing" => "true",
                               "synced_blocks" => status.synced_blocks,
-                              "total_blocks" => status.total_blocks } 
+                              "total_blocks" => status.total_blocks,
+                              "current_height" => status.current_height,
+                              "txns_found" => status.txns_found_this_sync,
+                              "seconds_remaining" => status.seconds_remaining,
+                              "server" => lightclient.get_server_uri().to_string(),
+                              "failovers" => status.failovers,
+                              "bytes_downloaded" => status.bytes_downloaded,
+                              "bytes_uncompressed" => status.bytes_uncompressed } 
         }.pretty(2)
     }
 }
 
+struct NetworkStatsCommand {}
+impl Command for NetworkStatsCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Show the calls made to the server for each RPC method: how many, how many failed, the bytes");
+        h.push("sent and received, and how long they took");
+        h.push("Usage:");
+        h.push("netstats [reset]");
+        h.push("");
+        h.push("With 'reset', counting starts over after showing them.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Show network statistics for the calls to the server".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        match args {
+            []        => lightclient.do_network_stats(false).pretty(2),
+            ["reset"] => lightclient.do_network_stats(true).pretty(2),
+            _         => format!("Didn't understand arguments\n{}", self.help())
+        }
+    }
+}
+
+struct CheckWalletCommand {}
+impl Command for CheckWalletCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Check the wallet's notes, witnesses and transactions for inconsistencies, eg. after a crash or a bad sync");
+        h.push("Usage:");
+        h.push("checkwallet");
+        h.push("");
+        h.push("If there are problems, a 'rescan' rebuilds the wallet's data from the blockchain.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Check the wallet for inconsistencies".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        lightclient.do_check_wallet().pretty(2)
+    }
+}
+
 struct RescanCommand {}
 impl Command for RescanCommand {
     fn help(&self) -> String {
         let mut h = vec![];
         h.push("Rescan the wallet, rescanning all blocks for new transactions");
         h.push("Usage:");
-        h.push("rescan");
+        h.push("rescan [from height]");
         h.push("");
         h.push("This command will download all blocks since the intial block again from the light client server");
         h.push("and attempt to scan each block for transactions belonging to the wallet.");
+        h.push("With a height, the rescan starts from there if that's before the wallet's birthday, eg. to find older");
+        h.push("transactions of a key imported without a birthday. Later rescans start there too.");
 
         h.join("\n")
     }
@@ -102,8 +173,17 @@ impl Command for RescanCommand {
         "Rescan the wallet, downloading and scanning all blocks and transactions".to_string()
     }
 
-    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
-        match lightclient.do_rescan() {
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let r = match args {
+            []       => lightclient.do_rescan(),
+            [height] => match height.parse::<u64>() {
+                Ok(h)  => lightclient.do_rescan_from(h),
+                Err(e) => return format!("Couldn't parse height: {}", e)
+            },
+            _        => return self.help()
+        };
+
+        match r {
             Ok(j) => j.pretty(2),
             Err(e) => e
         }
@@ -187,8 +267,9 @@ impl Command for InfoCommand {
         let mut h = vec![];
         h.push("Get info about the lightwalletd we're connected to");
         h.push("Usage:");
-        h.push("info");
+        h.push("info [refresh]");
         h.push("");
+        h.push("The info is cached for a minute. With 'refresh', the server is asked again right away.");
 
         h.join("\n")
     }
@@ -197,8 +278,44 @@ impl Command for InfoCommand {
         "Get the lightwalletd server's info".to_string()
     }
 
-    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {        
-        lightclient.do_info()
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {        
+        match args {
+            []          => lightclient.do_info(false),
+            ["refresh"] => lightclient.do_info(true),
+            _           => format!("Didn't understand arguments\n{}", self.help())
+        }
+    }
+}
+
+struct ChangeServerCommand {}
+impl Command for ChangeServerCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Switch to another lightwalletd server, without restarting");
+        h.push("Usage:");
+        h.push("changeserver <uri>");
+        h.push("");
+        h.push("The server has to be reachable and on the same chain as the wallet, otherwise the current one is kept.");
+        h.push("The server can't be changed while a sync is running.");
+        h.push("Example:");
+        h.push("changeserver https://lightwalletd.ycash.xyz:443");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Switch to another lightwalletd server".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return format!("Didn't understand arguments\n{}", self.help());
+        }
+
+        match lightclient.do_change_server(args[0]) {
+            Ok(j)  => j.pretty(2),
+            Err(e) => object!{ "error" => e }.pretty(2)
+        }
     }
 }
 
@@ -208,9 +325,11 @@ impl Command for BalanceCommand {
         let mut h = vec![];
         h.push("Show the current YEC balance in the wallet");
         h.push("Usage:");
-        h.push("balance");
+        h.push("balance [nowatchonly]");
         h.push("");
         h.push("Transparent and Shielded balances, along with the addresses they belong to are displayed");
+        h.push("'spendable_only' is what can be spent now, without unconfirmed funds and the funds of watch-only addresses.");
+        h.push("With 'nowatchonly', the watch-only addresses of imported viewing keys are left out of the balances.");
 
         h.join("\n")
     }
@@ -219,9 +338,15 @@ impl Command for BalanceCommand {
         "Show the current YEC balance in the wallet".to_string()
     }
 
-    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
-        match lightclient.do_sync(true) {
-            Ok(_) => format!("{}", lightclient.do_balance().pretty(2)),
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        let include_watchonly = match args {
+            []              => true,
+            ["nowatchonly"] => false,
+            _               => return self.help()
+        };
+
+        match sync_unless_read_only(lightclient) {
+            Ok(_) => format!("{}", lightclient.do_balance_with_watchonly(include_watchonly).pretty(2)),
             Err(e) => e
         }
     }
@@ -283,6 +408,69 @@ impl Command for ExportCommand {
     }
 }
 
+struct ExportXprvCommand {}
+impl Command for ExportXprvCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Export the BIP32 extended private and public keys for the wallet's transparent account.");
+        h.push("External software can use these to derive the wallet's t-addresses at <derivation_path>/0/<index>");
+        h.push("Usage:");
+        h.push("exportxprv");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Export the transparent account's extended keys".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 0 {
+            return self.help();
+        }
+
+        match lightclient.do_export_transparent_xprv() {
+            Ok(j)  => j,
+            Err(e) => object!{ "error" => e }
+        }.pretty(2)
+    }
+}
+
+struct ExportBackupCommand {}
+impl Command for ExportBackupCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Write a password protected backup of the wallet to a file");
+        h.push("Usage:");
+        h.push("exportbackup <file> <password>");
+        h.push("");
+        h.push("The backup has the seed, the accounts and their addresses, the imported viewing keys and the birthday, but");
+        h.push("not the scanned blocks and transactions, so it is small enough to keep anywhere. Restore it by starting");
+        h.push("yecshell with --restore-backup <file> --password <password>, which rescans from the birthday.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Write a password protected backup of the wallet to a file".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 2 {
+            return format!("Didn't understand arguments\n{}", self.help());
+        }
+
+        let result = lightclient.do_export_backup(args[1])
+            .and_then(|backup| std::fs::write(args[0], &backup).map_err(|e| format!("Couldn't write {}: {}", args[0], e)));
+
+        match result {
+            Ok(_)  => object!{ "result" => "success", "file" => args[0] },
+            Err(e) => object!{ "result" => "error", "error" => e },
+        }.pretty(2)
+    }
+}
+
 struct EncryptCommand {}
 impl Command for EncryptCommand {
     fn help(&self) -> String {
@@ -413,12 +601,52 @@ impl Command for UnlockCommand {
 }
 
 
+struct UnlockViewingCommand {}
+impl Command for UnlockViewingCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Unlock the wallet's encryption for viewing only, allowing it to sync and show balances.");
+        h.push("Note 1: The spending keys stay encrypted. Use 'unlock' to send, export keys or show the seed.");
+        h.push("Note 2: The viewing keys are never encrypted, so locking for viewing only stops this wallet's");
+        h.push("        commands from showing its history. Anyone who can read the wallet file can still see it.");
+        h.push("Note 3: If you've forgotten the password, the only way to recover the wallet is to restore");
+        h.push("        from the seed phrase.");
+        h.push("Usage:");
+        h.push("unlockview password");
+        h.push("");
+        h.push("Example:");
+        h.push("unlockview my_strong_password");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Unlock wallet encryption for syncing and balances only".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        match lightclient.do_unlock_viewing(args[0].to_string()) {
+            Ok(_)  => object!{ "result" => "success" },
+            Err(e) => object!{
+                "result" => "error",
+                "error"  => e
+            }
+        }.pretty(2)
+    }
+}
+
+
 struct LockCommand {}
 impl Command for LockCommand {
     fn help(&self) -> String {
         let mut h = vec![];
         h.push("Lock a wallet that's been temporarily unlocked. You should already have encryption enabled.");
-        h.push("Note 1: This will remove all spending keys from memory. The wallet remains encrypted on disk");
+        h.push("Note 1: This will remove all spending keys from memory. The wallet remains encrypted on disk,");
+        h.push("        and can't sync until it's unlocked again, with 'unlock' or 'unlockview'.");
         h.push("Note 2: If you've forgotten the password, the only way to recover the wallet is to restore");
         h.push("        from the seed phrase.");
         h.push("Usage:");
@@ -460,10 +688,17 @@ impl Command for SendCommand {
         let mut h = vec![];
         h.push("Send YEC to a given address");
         h.push("Usage:");
-        h.push("send <address> <amount in zatoshis> \"optional_memo\"");
+        h.push("send [override] [rawtx|dryrun] <address> <amount in zatoshis> \"optional_memo\"");
         h.push("OR");
-        h.push("send '[{'address': <address>, 'amount': <amount in zatoshis>, 'memo': <optional memo>}, ...]'");
+        h.push("send [override] [rawtx|dryrun] '[{'address': <address>, 'amount': <amount in zatoshis>, 'memo': <optional memo>, 'split': <optional number of outputs>}, ...]'");
         h.push("");
+        h.push("If 'rawtx' is given, the result also contains the hex of the raw transaction that was broadcast.");
+        h.push("The transaction is broadcast to all the servers the wallet was started with, and 'servers' in the result shows what each of them said.");
+        h.push("If 'override' is given, the send goes through even if it is over the wallet's spend limits.");
+        h.push("If 'dryrun' is given, nothing is sent. Instead, it shows what the send would spend, warns about ways it would weaken privacy, and lists the addresses that were never sent to before.");
+        h.push("If 'split' is given, the amount is sent to the (shielded) address as that many roughly equal notes (at most 20).");
+        h.push("If the amount (of the last address) is 'max', it sends everything that is left after the other addresses and the fee.");
+        h.push("The address can also be a viewing key, in which case the send goes to the key's default address.");
         h.push("NOTE: The fee required to send this transaction (currently ZEC 0.0001) is additionally detected from your balance.");
         h.push("Example:");
         h.push("send ytestsapling1x65nq4dgp0qfywgxcwk9n0fvm4fysmapgr2q00p85ju252h6l7mmxu2jg9cqqhtvzd69jwhgv8d 200000 \"Hello from the command line\"");
@@ -479,7 +714,16 @@ impl Command for SendCommand {
     fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
         // Parse the args. There are two argument types.
         // 1 - A set of 2(+1 optional) arguments for a single address send representing address, value, memo?
-        // 2 - A single argument in the form of a JSON string that is "[{address: address, value: value, memo: memo},...]"
+        // 2 - A single argument in the form of a JSON string that is "[{address: address, value: value, memo: memo, split: split},...]"
+
+        let override_limits = args.first() == Some(&"override");
+        let args = if override_limits { &args[1..] } else { args };
+
+        let include_raw_tx = args.first() == Some(&"rawtx");
+        let args = if include_raw_tx { &args[1..] } else { args };
+
+        let dry_run = args.first() == Some(&"dryrun");
+        let args = if dry_run { &args[1..] } else { args };
 
         // 1 - Destination address. T or Z address
         if args.len() < 1 || args.len() > 3 {
@@ -506,20 +750,38 @@ impl Command for SendCommand {
                 if !j.has_key("address") || !j.has_key("amount") {
                     Err(format!("Need 'address' and 'amount'\n"))
                 } else {
-                    Ok((j["address"].as_str().unwrap().to_string().clone(), j["amount"].as_u64().unwrap(), j["memo"].as_str().map(|s| s.to_string().clone())))
+                    let amount = match (j["amount"].as_u64(), j["amount"].as_str()) {
+                        (Some(amt), _)     => amt,
+                        (_, Some("max"))   => SEND_MAX,
+                        _                  => return Err(format!("Couldn't parse amount: {}\n", j["amount"]))
+                    };
+                    let split = if j.has_key("split") {
+                        match j["split"].as_usize() {
+                            Some(n) => n,
+                            None    => return Err(format!("Couldn't parse split: {}\n", j["split"]))
+                        }
+                    } else {
+                        1
+                    };
+                    Ok((j["address"].as_str().unwrap().to_string().clone(), amount, j["memo"].as_str().map(|s| s.to_string().clone()),
+                        split))
                 }
-            }).collect::<Result<Vec<(String, u64, Option<String>)>, String>>();
+            }).collect::<Result<Vec<(String, u64, Option<String>, usize)>, String>>();
 
             match maybe_send_args {
                 Ok(a) => a.clone(),
                 Err(s) => { return format!("Error: {}\n{}", s, self.help()); }
             }
         } else if args.len() == 2 || args.len() == 3 {
-            let address = args[0].to_string();
+            let address = match LightWallet::resolve_recipient(args[0], &lightclient.config) {
+                Ok(a)  => a,
+                Err(e) => return e
+            };
 
             // Make sure we can parse the amount
             let value = match args[1].parse::<u64>() {
                 Ok(amt) => amt,
+                Err(_) if args[1] == "max" => SEND_MAX,
                 Err(e)  => {
                     return format!("Couldn't parse amount: {}", e);
                 }
@@ -532,7 +794,7 @@ impl Command for SendCommand {
                 return format!("Can't send a memo to the non-shielded address {}", address);
             }
             
-            vec![(args[0].to_string(), value, memo)]
+            vec![(address, value, memo, 1)]
         } else {
             return self.help()
         };
@@ -540,9 +802,25 @@ impl Command for SendCommand {
         match lightclient.do_sync(true) {
             Ok(_) => {
                 // Convert to the right format. String -> &str.
-                let tos = send_args.iter().map(|(a, v, m)| (a.as_str(), *v, m.clone()) ).collect::<Vec<_>>();
-                match lightclient.do_send(tos) {
-                    Ok(txid) => { object!{ "txid" => txid } },
+                let tos = send_args.iter().map(|(a, v, m, n)| (a.as_str(), *v, m.clone(), *n) ).collect::<Vec<_>>();
+                if dry_run {
+                    return match lightclient.do_send_estimate(tos) {
+                        Ok(o)  => o,
+                        Err(e) => object!{ "error" => e }
+                    }.pretty(2);
+                }
+
+                match lightclient.do_send_with_raw_tx(tos, override_limits) {
+                    Ok((txid, rawtx, details)) => {
+                        let mut o = object!{ "txid" => txid };
+                        for (k, v) in details.entries() {
+                            o[k] = v.clone();
+                        }
+                        if include_raw_tx {
+                            o["rawtx"] = rawtx.into();
+                        }
+                        o
+                    },
                     Err(e)   => { object!{ "error" => e } }
                 }.pretty(2)
             },
@@ -551,6 +829,258 @@ impl Command for SendCommand {
     }
 }
 
+struct SendHistoryCommand {}
+impl Command for SendHistoryCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("List the addresses this wallet has sent to, most recently used first");
+        h.push("Usage:");
+        h.push("sendhistory");
+        h.push("");
+        h.push("Each address has when it was last sent to, and how many sends went to it.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "List the addresses sent to before".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        lightclient.do_send_history().pretty(2)
+    }
+}
+
+struct FeeRateCommand {}
+impl Command for FeeRateCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Show the fee a raw transaction pays, and what that is per logical action");
+        h.push("Usage:");
+        h.push("feerate <raw transaction hex>");
+        h.push("");
+        h.push("Logical actions are counted as in ZIP-317. 'min_fee' is the lowest fee the network takes for the transaction.");
+        h.push("The fee is only known for transactions whose transparent inputs are all the wallet's, eg. from 'send rawtx'.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Show a raw transaction's fee and fee rate".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        match lightclient.do_tx_fee_rate(args[0]) {
+            Ok(j)  => j,
+            Err(e) => object!{ "error" => e }
+        }.pretty(2)
+    }
+}
+
+struct BroadcastCommand {}
+impl Command for BroadcastCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Broadcast a raw transaction again, eg. one that 'send rawtx' returned but that didn't get mined");
+        h.push("Usage:");
+        h.push("broadcast <raw transaction hex>");
+        h.push("");
+        h.push("The transaction is sent to all the servers the wallet was started with, and the result shows what each of them said.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Broadcast a raw transaction again".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        match lightclient.do_broadcast(args[0]) {
+            Ok(j)  => j,
+            Err(e) => object!{ "error" => e }
+        }.pretty(2)
+    }
+}
+
+struct BumpFeeCommand {}
+impl Command for BumpFeeCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Rebuild a pending transaction this wallet sent with a higher fee, and broadcast it");
+        h.push("Usage:");
+        h.push("bumpfee <txid> <new fee in zatoshis>");
+        h.push("");
+        h.push("The new transaction pays the same recipients out of the same notes, and the higher fee comes out of the change.");
+        h.push("The servers don't replace a pending transaction with one that pays more, they refuse it while they still have the");
+        h.push("old one. So this only goes through once the old transaction has dropped out of their mempool, eg. when it expired.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Rebuild a pending transaction with a higher fee".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 2 {
+            return self.help();
+        }
+
+        let fee = match args[1].parse::<u64>() {
+            Ok(fee) => fee,
+            Err(e)  => return format!("Couldn't parse the fee: {}", e)
+        };
+
+        match lightclient.do_bump_fee(args[0], fee) {
+            Ok(txid) => object!{ "txid" => txid },
+            Err(e)   => object!{ "error" => e }
+        }.pretty(2)
+    }
+}
+
+struct RawTxCommand {}
+impl Command for RawTxCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Get the full raw transaction from the server");
+        h.push("Usage:");
+        h.push("rawtx <txid>");
+        h.push("");
+        h.push("The wallet only keeps what it decrypted from its transactions, this gets the whole transaction as hex, eg. to 'broadcast' it again.");
+        h.push("A server that has pruned the transaction can't send it.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Get the full raw transaction from the server".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        match lightclient.do_fetch_raw_transaction(args[0]) {
+            Ok(rawtx) => object!{ "txid" => args[0].trim(), "rawtx" => rawtx },
+            Err(e)    => object!{ "error" => e }
+        }.pretty(2)
+    }
+}
+
+struct QueueSendCommand {}
+impl Command for QueueSendCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Queue a payment, to be sent together with the other queued payments by 'flushsends'");
+        h.push("Usage:");
+        h.push("queuesend <address> <amount in zatoshis> \"optional_memo\"");
+        h.push("queuesend list");
+        h.push("");
+        h.push("Batching payments into one transaction saves fees. 'queuesend list' shows the queued payments.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Queue a payment to be sent in a batch".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() == 1 && args[0] == "list" {
+            return JsonValue::Array(lightclient.do_list_queued_sends().into_iter().map(|q| object!{
+                "id"      => q.id,
+                "address" => q.address,
+                "amount"  => q.amount,
+                "memo"    => q.memo,
+            }).collect()).pretty(2);
+        }
+
+        if args.len() < 2 || args.len() > 3 {
+            return self.help();
+        }
+
+        let amount = match args[1].parse::<u64>() {
+            Ok(amt) => amt,
+            Err(e)  => return format!("Couldn't parse amount: {}", e)
+        };
+        let memo = args.get(2).map(|m| m.to_string());
+
+        match lightclient.do_queue_send(args[0].to_string(), amount, memo) {
+            Ok(id) => object!{ "id" => id, "queued" => lightclient.do_list_queued_sends().len() },
+            Err(e) => object!{ "error" => e }
+        }.pretty(2)
+    }
+}
+
+struct FlushSendsCommand {}
+impl Command for FlushSendsCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Send all the payments queued with 'queuesend'");
+        h.push("Usage:");
+        h.push("flushsends");
+        h.push("");
+        h.push("The payments go out in as few transactions as possible. The result shows the txid each payment");
+        h.push("was sent in. Payments whose transaction failed stay queued.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Send the queued payments".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        match lightclient.do_sync(true) {
+            Ok(_)  => lightclient.do_flush_sends().pretty(2),
+            Err(e) => e
+        }
+    }
+}
+
+struct BackupsCommand {}
+impl Command for BackupsCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("List the previous versions of the wallet file, or go back to one of them");
+        h.push("Usage:");
+        h.push("backups");
+        h.push("backups restore <n>");
+        h.push("");
+        h.push("Every save keeps the wallet file it replaces as backup 1, and moves the older ones down.");
+        h.push("Restoring a backup keeps the current wallet as backup 1, and moves the others down. Rescan afterwards to catch up on the blocks since the backup.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "List or restore the wallet file's backups".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        match args {
+            [] => lightclient.do_list_backups().pretty(2),
+            ["restore", n] => match n.parse::<usize>() {
+                Ok(n) => match lightclient.do_restore_backup(n) {
+                    Ok(j)  => j,
+                    Err(e) => object!{ "error" => e }
+                }.pretty(2),
+                Err(e) => format!("Couldn't parse the backup number: {}", e)
+            },
+            _ => format!("Didn't understand arguments\n{}", self.help())
+        }
+    }
+}
+
 struct SaveCommand {}
 impl Command for SaveCommand {
     fn help(&self) -> String {
@@ -611,14 +1141,42 @@ impl Command for SeedCommand {
     }
 }
 
+struct NullifiersCommand {}
+impl Command for NullifiersCommand {
+    fn help(&self) -> String {
+        let mut h = vec![];
+        h.push("Show the nullifiers of the wallet's unspent notes");
+        h.push("Usage:");
+        h.push("nullifiers");
+        h.push("");
+        h.push("A note's nullifier shows up on chain when it is spent, so other tools can use these to watch for the");
+        h.push("wallet's notes being spent. Anyone who has them can tell when you spend, so don't share them carelessly");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Show the nullifiers of the unspent notes".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        match lightclient.do_nullifiers() {
+            Ok(j)  => j,
+            Err(e) => object!{ "error" => e }
+        }.pretty(2)
+    }
+}
+
 struct TransactionsCommand {}
 impl Command for TransactionsCommand {
     fn help(&self)  -> String {
         let mut h = vec![];
         h.push("List all incoming and outgoing transactions from this wallet");
         h.push("Usage:");
-        h.push("list");
+        h.push("list [height_asc | height_desc | amount_desc | date_desc] [memo preview length]");
         h.push("");
+        h.push("The transactions are sorted oldest first (height_asc) by default.");
+        h.push("With a memo preview length, each memo also has a 'memo_preview', shortened to at most that many characters.");
 
         h.join("\n")
     }
@@ -627,16 +1185,201 @@ impl Command for TransactionsCommand {
         "List all transactions in the wallet".to_string()
     }
 
-    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
-        match lightclient.do_sync(true) {
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        // A number at the end is the memo preview length
+        let (args, memo_preview) = match args.last().map(|a| a.parse::<usize>()) {
+            Some(Ok(n)) => (&args[..args.len() - 1], Some(n)),
+            _           => (args, None)
+        };
+
+        if args.len() > 1 {
+            return format!("Didn't understand arguments\n{}", self.help());
+        }
+
+        let sort = match args.get(0) {
+            None    => TxSort::HeightAsc,
+            Some(s) => match TxSort::from_str(s) {
+                Some(sort) => sort,
+                None       => return format!("Unknown sort order '{}'\n{}", s, self.help())
+            }
+        };
+
+        match sync_unless_read_only(lightclient) {
             Ok(_) => {
-                format!("{}", lightclient.do_list_transactions().pretty(2))
+                format!("{}", lightclient.do_list_transactions_with_previews(sort, memo_preview).pretty(2))
             },
             Err(e) => e
         }
     }
 }
 
+struct TxOutputsCommand {}
+impl Command for TxOutputsCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("List the outputs of a transaction this wallet sent: every recipient, and the change");
+        h.push("Usage:");
+        h.push("txoutputs <txid>");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "List the outputs of a sent transaction".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return format!("Didn't understand arguments\n{}", self.help());
+        }
+
+        match lightclient.do_tx_outputs(args[0]) {
+            Ok(j)  => j.pretty(2),
+            Err(e) => object!{ "error" => e }.pretty(2)
+        }
+    }
+}
+
+struct ChainHeightCommand {}
+impl Command for ChainHeightCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("Get the server's latest block, and how far behind it the wallet is");
+        h.push("Usage:");
+        h.push("chainheight");
+        h.push("");
+        h.push("This doesn't sync the wallet. Use 'sync' to catch up to the server.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Get the server's latest block height and the wallet's lag behind it".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        match lightclient.do_height() {
+            Ok(j)  => j.pretty(2),
+            Err(e) => e
+        }
+    }
+}
+
+struct PingCommand {}
+impl Command for PingCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("Check if the lightwalletd server is reachable, and measure its response time");
+        h.push("Usage:");
+        h.push("ping [samples]");
+        h.push("");
+        h.push("'samples' is the number of requests to time (default 5)");
+        h.push("If the server was selected automatically, the measurements of all the candidate servers are shown as well.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Check the server's availability and latency".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() > 1 {
+            return format!("Didn't understand arguments\n{}", self.help());
+        }
+
+        let samples = match args.get(0).map(|s| s.parse::<u32>()) {
+            None         => 5,
+            Some(Ok(n))  => n,
+            Some(Err(e)) => return format!("Couldn't parse samples: {}", e)
+        };
+
+        lightclient.do_ping(samples).pretty(2)
+    }
+}
+
+struct FetchUtxosCommand {}
+impl Command for FetchUtxosCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("Fetch the unspent outputs of the wallet's t addresses from the server");
+        h.push("Usage:");
+        h.push("fetchutxos");
+        h.push("");
+        h.push("This shows the transparent balance right away, without waiting for a sync. The outputs are");
+        h.push("reconciled with the transactions found when the blocks are scanned.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Fetch the t address UTXOs from the server".to_string()
+    }
+
+    fn exec(&self, _args: &[&str], lightclient: &LightClient) -> String {
+        match lightclient.do_fetch_utxos() {
+            Ok(j)  => j.pretty(2),
+            Err(e) => e
+        }
+    }
+}
+
+struct EncryptMessageCommand {}
+impl Command for EncryptMessageCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("Encrypt a message to a z address, without sending a transaction");
+        h.push("Usage:");
+        h.push("encryptmessage <z address> \"message\"");
+        h.push("");
+        h.push("The message has to fit in a memo (512 bytes). Pass the resulting hex on to the address' owner,");
+        h.push("who can read it with 'decryptmessage'.");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Encrypt a message to a z address, off-chain".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 2 {
+            return self.help();
+        }
+
+        lightclient.do_encrypt_message(args[0].to_string(), args[1].to_string())
+    }
+}
+
+struct DecryptMessageCommand {}
+impl Command for DecryptMessageCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("Decrypt a message that was encrypted to one of this wallet's z addresses");
+        h.push("Usage:");
+        h.push("decryptmessage <encrypted message hex>");
+        h.push("");
+
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Decrypt an off-chain message sent to this wallet".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() != 1 {
+            return self.help();
+        }
+
+        match lightclient.do_decrypt_message(args[0].to_string()) {
+            Ok(s)  => s,
+            Err(e) => object!{ "error" => e }.pretty(2)
+        }
+    }
+}
+
 struct HeightCommand {}
 impl Command for HeightCommand {
     fn help(&self)  -> String {
@@ -660,7 +1403,7 @@ impl Command for HeightCommand {
         }
 
         if args.len() == 0 || (args.len() == 1 && args[0].trim() == "true") {
-            match lightclient.do_sync(true) {
+            match sync_unless_read_only(lightclient) {
                 Ok(_) => format!("{}", object! { "height" => lightclient.last_scanned_height()}.pretty(2)),
                 Err(e) => e
             }
@@ -677,11 +1420,15 @@ impl Command for NewAddressCommand {
         let mut h = vec![];
         h.push("Create a new address in this wallet");
         h.push("Usage:");
-        h.push("new [z | t]");
+        h.push("new [z | t] [account]");
+        h.push("");
+        h.push("The address is in the default account, unless another account is given. See 'accounts'.");
         h.push("");
         h.push("Example:");
         h.push("To create a new z address:");
         h.push("new z");
+        h.push("To create a new t address in the savings account:");
+        h.push("new t savings");
         h.join("\n")
     }
 
@@ -690,11 +1437,88 @@ impl Command for NewAddressCommand {
     }
 
     fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
-        if args.len() != 1 {
+        if args.len() < 1 || args.len() > 2 {
             return format!("No address type specified\n{}", self.help());
         }
 
-        match lightclient.do_new_address(args[0]) {
+        let account = args.get(1).map_or(DEFAULT_ACCOUNT, |a| *a);
+        match lightclient.do_new_address_for_account(args[0], account) {
+            Ok(j)  => j,
+            Err(e) => object!{ "error" => e }
+        }.pretty(2)
+    }
+}
+
+struct AccountsCommand {}
+impl Command for AccountsCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("List the accounts in the wallet, add one, or show the addresses in one");
+        h.push("Usage:");
+        h.push("accounts");
+        h.push("accounts new <name>");
+        h.push("accounts <name>");
+        h.push("");
+        h.push("Accounts keep funds apart, eg. savings from spending, with their own addresses from the same seed.");
+        h.push("A new account starts out with a z and a t address, 'new [z | t] <name>' adds more.");
+        h.push("Restoring the seed brings back the default account. To get the others back, add them again in the");
+        h.push("same order, with as many addresses as before, and rescan.");
+        h.push("");
+        h.push("Example:");
+        h.push("accounts new savings");
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "List, add or show the accounts in the wallet".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        match args {
+            []             => lightclient.do_balance()["accounts"].pretty(2),
+            ["new", name]  => match lightclient.do_new_account(name) {
+                Ok(j)  => j,
+                Err(e) => object!{ "error" => e }
+            }.pretty(2),
+            [name]         => match lightclient.do_get_address_for_account(name) {
+                Ok(j)  => j,
+                Err(e) => object!{ "error" => e }
+            }.pretty(2),
+            _              => format!("Didn't understand arguments\n{}", self.help())
+        }
+    }
+}
+
+struct ImportCommand {}
+impl Command for ImportCommand {
+    fn help(&self)  -> String {
+        let mut h = vec![];
+        h.push("Import a viewing key, to watch its z address");
+        h.push("Usage:");
+        h.push("import <viewing key> [birthday]");
+        h.push("");
+        h.push("The address's notes show up in the balance, but can't be spent. The wallet is rescanned afterwards to find them.");
+        h.push("The rescan starts from the key's birthday, the height of its first transaction, if it's before the wallet's.");
+        h.push("Without a birthday, only recent transactions are found. Use 'rescan <height>' to find older ones.");
+        h.join("\n")
+    }
+
+    fn short_help(&self) -> String {
+        "Import a viewing key to watch its z address".to_string()
+    }
+
+    fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
+        if args.len() < 1 || args.len() > 2 {
+            return self.help();
+        }
+
+        let birthday = match args.get(1).map(|b| b.parse::<u64>()) {
+            None         => None,
+            Some(Ok(b))  => Some(b),
+            Some(Err(e)) => return format!("Couldn't parse birthday: {}", e)
+        };
+
+        match lightclient.do_import_key(args[0], birthday) {
             Ok(j)  => j,
             Err(e) => object!{ "error" => e }
         }.pretty(2)
@@ -707,9 +1531,10 @@ impl Command for NotesCommand {
         let mut h = vec![];
         h.push("Show all sapling notes and utxos in this wallet");
         h.push("Usage:");
-        h.push("notes [all]");
+        h.push("notes [all] [min_value=<zatoshis>] [max_value=<zatoshis>] [min_confirmations=<n>]");
         h.push("");
         h.push("If you supply the \"all\" parameter, all previously spent sapling notes and spent utxos are also included");
+        h.push("The other parameters only list the notes and utxos with that value or number of confirmations");
 
         h.join("\n")
     }
@@ -720,23 +1545,35 @@ impl Command for NotesCommand {
 
     fn exec(&self, args: &[&str], lightclient: &LightClient) -> String {
         // Parse the args. 
-        if args.len() > 1 {
+        if args.len() > 4 {
             return self.short_help();
         }
 
-        // Make sure we can parse the amount
-        let all_notes = if args.len() == 1 {
-            match args[0] {
-                "all" => true,
-                a     => return format!("Invalid argument \"{}\". Specify 'all' to include unspent notes", a)
+        let mut all_notes = false;
+        let mut filter = NoteFilter::default();
+        for arg in args {
+            if *arg == "all" {
+                all_notes = true;
+                continue;
             }
-        } else {
-            false
-        };
 
-        match lightclient.do_sync(true) {
+            let kv = arg.splitn(2, '=').collect::<Vec<_>>();
+            let value = match kv.get(1).map(|v| v.parse::<u64>()) {
+                Some(Ok(v)) => v,
+                _           => return format!("Invalid argument \"{}\"\n{}", arg, self.help())
+            };
+
+            match kv[0] {
+                "min_value"         => filter.min_value = Some(value),
+                "max_value"         => filter.max_value = Some(value),
+                "min_confirmations" => filter.min_confirmations = Some(value),
+                _                   => return format!("Invalid argument \"{}\"\n{}", arg, self.help())
+            }
+        }
+
+        match sync_unless_read_only(lightclient) {
             Ok(_) => {
-                format!("{}", lightclient.do_list_notes(all_notes).pretty(2))
+                format!("{}", lightclient.do_list_notes_filtered(all_notes, &filter).pretty(2))
             },
             Err(e) => e
         }
@@ -773,25 +1610,48 @@ pub fn get_commands() -> Box<HashMap<String, Box<dyn Command>>> {
 
     map.insert("sync".to_string(),              Box::new(SyncCommand{}));
     map.insert("syncstatus".to_string(),        Box::new(SyncStatusCommand{}));
+    map.insert("netstats".to_string(),          Box::new(NetworkStatsCommand{}));
     map.insert("encryptionstatus".to_string(),  Box::new(EncryptionStatusCommand{}));
     map.insert("rescan".to_string(),            Box::new(RescanCommand{}));
+    map.insert("checkwallet".to_string(),       Box::new(CheckWalletCommand{}));
     map.insert("clear".to_string(),             Box::new(ClearCommand{}));
     map.insert("help".to_string(),              Box::new(HelpCommand{}));
     map.insert("balance".to_string(),           Box::new(BalanceCommand{}));
     map.insert("addresses".to_string(),         Box::new(AddressCommand{}));
     map.insert("height".to_string(),            Box::new(HeightCommand{}));
+    map.insert("chainheight".to_string(),       Box::new(ChainHeightCommand{}));
+    map.insert("fetchutxos".to_string(),        Box::new(FetchUtxosCommand{}));
+    map.insert("encryptmessage".to_string(),    Box::new(EncryptMessageCommand{}));
+    map.insert("decryptmessage".to_string(),    Box::new(DecryptMessageCommand{}));
+    map.insert("ping".to_string(),              Box::new(PingCommand{}));
     map.insert("export".to_string(),            Box::new(ExportCommand{}));
+    map.insert("exportxprv".to_string(),        Box::new(ExportXprvCommand{}));
+    map.insert("exportbackup".to_string(),      Box::new(ExportBackupCommand{}));
     map.insert("info".to_string(),              Box::new(InfoCommand{}));
+    map.insert("changeserver".to_string(),      Box::new(ChangeServerCommand{}));
     map.insert("send".to_string(),              Box::new(SendCommand{}));
+    map.insert("broadcast".to_string(),         Box::new(BroadcastCommand{}));
+    map.insert("bumpfee".to_string(),           Box::new(BumpFeeCommand{}));
+    map.insert("rawtx".to_string(),             Box::new(RawTxCommand{}));
+    map.insert("feerate".to_string(),           Box::new(FeeRateCommand{}));
+    map.insert("sendhistory".to_string(),       Box::new(SendHistoryCommand{}));
+    map.insert("queuesend".to_string(),         Box::new(QueueSendCommand{}));
+    map.insert("flushsends".to_string(),        Box::new(FlushSendsCommand{}));
     map.insert("save".to_string(),              Box::new(SaveCommand{}));
+    map.insert("backups".to_string(),           Box::new(BackupsCommand{}));
     map.insert("quit".to_string(),              Box::new(QuitCommand{}));
     map.insert("list".to_string(),              Box::new(TransactionsCommand{}));
     map.insert("notes".to_string(),             Box::new(NotesCommand{}));
+    map.insert("nullifiers".to_string(),        Box::new(NullifiersCommand{}));
+    map.insert("txoutputs".to_string(),         Box::new(TxOutputsCommand{}));
     map.insert("new".to_string(),               Box::new(NewAddressCommand{}));
+    map.insert("accounts".to_string(),          Box::new(AccountsCommand{}));
+    map.insert("import".to_string(),            Box::new(ImportCommand{}));
     map.insert("seed".to_string(),              Box::new(SeedCommand{}));
     map.insert("encrypt".to_string(),           Box::new(EncryptCommand{}));
     map.insert("decrypt".to_string(),           Box::new(DecryptCommand{}));
     map.insert("unlock".to_string(),            Box::new(UnlockCommand{}));
+    map.insert("unlockview".to_string(),        Box::new(UnlockViewingCommand{}));
     map.insert("lock".to_string(),              Box::new(LockCommand{}));
 
     Box::new(map)
@@ -810,8 +1670,9 @@ pub fn do_user_command(cmd: &str, args: &Vec<&str>, lightclient: &LightClient) -
 #[cfg(test)]
 pub mod tests {
     use lazy_static::lazy_static;
+    use tempdir::TempDir;
     use super::do_user_command;
-    use crate::lightclient::{LightClient};
+    use crate::lightclient::{LightClient, LightClientConfig};
 
     lazy_static!{
         static ref TEST_SEED: String = "youth strong sweet gorilla hammer unhappy congress stamp left stereo riot salute road tag clean toilet artefact fork certain leopard entire civil degree wonder".to_string();
@@ -827,6 +1688,33 @@ pub mod tests {
                    do_user_command("Addresses", &vec![], &lc));
     }
 
+    #[test]
+    pub fn test_send_bad_split() {
+        let lc = LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let addr = lc.do_address()["z_addresses"][0].as_str().unwrap().to_string();
+
+        for split in &["-1", "\"two\""] {
+            let arg = format!("[{{\"address\": \"{}\", \"amount\": 1000, \"split\": {}}}]", addr, split);
+            let r = do_user_command("send", &vec![arg.as_str()], &lc);
+            assert!(r.starts_with("Error: Couldn't parse split"), "{}", r);
+        }
+    }
+
+    #[test]
+    pub fn test_read_only_commands() {
+        let tmp = TempDir::new("cmdtest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+
+        // A read-only wallet can't be synced, so it is shown without syncing
+        let lc = LightClient::read_from_disk(&LightClientConfig { read_only: true, ..config }).unwrap();
+        assert!(json::parse(&do_user_command("balance", &vec![], &lc)).unwrap()["zbalance"].is_number());
+        assert!(json::parse(&do_user_command("list", &vec![], &lc)).unwrap().is_array());
+        assert!(json::parse(&do_user_command("notes", &vec![], &lc)).unwrap()["unspent_notes"].is_array());
+        assert!(json::parse(&do_user_command("height", &vec![], &lc)).unwrap()["height"].is_number());
+    }
+
     #[test]
     pub fn test_nosync_commands() {
         // The following commands should run 
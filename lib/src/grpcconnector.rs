@@ -1,28 +1,43 @@
-use log::{error};
+use log::{info, warn, error};
 
-use std::sync::{Arc};
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::net::ToSocketAddrs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
 
-use futures::{Future};
+use futures::{Future, try_ready};
 use futures::stream::Stream;
+use futures::sync::oneshot;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use byteorder::{BigEndian, ByteOrder};
 
 use tower_h2;
 use tower_util::MakeService;
-use tower_grpc::Request;
+use tower_grpc::{Request, BoxBody};
 
 use tokio_rustls::client::TlsStream;
-use tokio_rustls::{rustls::ClientConfig, TlsConnector};
+use tokio_rustls::{rustls::{ClientConfig, Certificate, PrivateKey}, TlsConnector};
 
 use tokio::executor::DefaultExecutor;
 use tokio::net::tcp::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Runtime;
+use tokio::timer::timeout;
+use tokio::util::{FutureExt, StreamExt};
 
+use json::{object, JsonValue};
 use zcash_primitives::transaction::{TxId};
 
 use crate::grpc_client::{ChainSpec, BlockId, BlockRange, RawTransaction, 
-                         TransparentAddressBlockFilter, TxFilter, Empty, LightdInfo};
+                         TransparentAddressBlockFilter, TxFilter, Empty, LightdInfo,
+                         GetAddressUtxosArg, GetAddressUtxosReply, SendResponse};
 use crate::grpc_client::client::CompactTxStreamer;
 
+#[cfg(feature = "dangerous-tls")]
 mod danger {
     use rustls;
     use webpki;
@@ -40,32 +55,234 @@ mod danger {
     }
 }
 
-/// A Secure (https) grpc destination.
+/// The connection to the server. This is a TLS stream for https servers, and a plain
+/// TCP stream (h2c) for http servers, like a local lightwalletd used for development.
+pub enum MaybeTlsStream {
+    Tls(TlsStream<TcpStream>),
+    Plain(TcpStream),
+}
+
+impl Read for MaybeTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MaybeTlsStream::Tls(s)   => s.read(buf),
+            MaybeTlsStream::Plain(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MaybeTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MaybeTlsStream::Tls(s)   => s.write(buf),
+            MaybeTlsStream::Plain(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MaybeTlsStream::Tls(s)   => s.flush(),
+            MaybeTlsStream::Plain(s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn shutdown(&mut self) -> futures::Poll<(), io::Error> {
+        match self {
+            MaybeTlsStream::Tls(s)   => AsyncWrite::shutdown(s),
+            MaybeTlsStream::Plain(s) => AsyncWrite::shutdown(s),
+        }
+    }
+}
+
+/// Parse a proxy like `socks5://127.0.0.1:9050` (or `socks5h://`, or just `127.0.0.1:9050`) 
+/// into the address of the proxy. Only SOCKS5 proxies are supported.
+pub fn parse_proxy(proxy: &str) -> Result<SocketAddr, String> {
+    let hostport = match proxy.find("://") {
+        Some(i) => {
+            let scheme = &proxy[..i];
+            if scheme != "socks5" && scheme != "socks5h" {
+                return Err(format!("Unsupported proxy {}, only socks5:// proxies are supported", proxy));
+            }
+            &proxy[i+3..]
+        },
+        None    => proxy
+    };
+
+    hostport.to_socket_addrs()
+        .map_err(|e| format!("Couldn't resolve proxy {}: {}", proxy, e))?
+        .next()
+        .ok_or(format!("Couldn't resolve proxy {}", proxy))
+}
+
+fn socks_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("SOCKS5 proxy error: {}", msg))
+}
+
+/// Which IP versions to connect to the server over
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpVersion {
+    Any,
+    V4,
+    V6,
+}
+
+/// Order the addresses so that IPv6 and IPv4 alternate, starting with the family of the first one,
+/// so that if one family is broken, we don't wait on all of its addresses before trying the other.
+fn interleave_addrs(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().map_or(false, |a| a.is_ipv6());
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    first.reverse();
+    second.reverse();
+
+    let mut ordered = vec![];
+    while !first.is_empty() || !second.is_empty() {
+        ordered.extend(first.pop());
+        ordered.extend(second.pop());
+    }
+
+    ordered
+}
+
+/// Try each address in turn, giving each one `addr_timeout` to connect, until one of them does.
+/// If none do, the error lists what went wrong with each of them.
+fn connect_any(addrs: Vec<SocketAddr>, addr_timeout: Duration) -> Box<dyn Future<Item = TcpStream, Error = io::Error> + Send> {
+    use futures::future::{loop_fn, Loop};
+    type State = (std::vec::IntoIter<SocketAddr>, Vec<String>); // The addresses left, and the errors so far
+    type Attempt = Box<dyn Future<Item = Loop<TcpStream, State>, Error = io::Error> + Send>;
+
+    Box::new(loop_fn((addrs.into_iter(), vec![]), move |(mut remaining, mut errors): State| -> Attempt {
+        let addr = match remaining.next() {
+            Some(addr) => addr,
+            None       => {
+                let e = if errors.is_empty() { "No address to connect to".to_string() } 
+                        else { format!("Couldn't connect to any address. {}", errors.join("; ")) };
+                return Box::new(futures::future::err(io::Error::new(io::ErrorKind::Other, e)));
+            }
+        };
+
+        Box::new(TcpStream::connect(&addr).timeout(addr_timeout).then(move |r| match r {
+            Ok(sock) => Ok(Loop::Break(sock)),
+            Err(e)   => {
+                let e = if e.is_elapsed() { format!("timed out after {}ms", addr_timeout.as_millis()) } 
+                        else { e.into_inner().map(|e| e.to_string()).unwrap_or("timer error".to_string()) };
+                warn!("Couldn't connect to {}: {}", addr, e);
+                errors.push(format!("{}: {}", addr, e));
+
+                Ok(Loop::Continue((remaining, errors)))
+            }
+        }))
+    }))
+}
+
+/// Open a TCP stream to host:port through the SOCKS5 proxy. The host name is passed to the proxy
+/// as-is (socks5h), so DNS resolution happens at the proxy and doesn't leak.
+fn socks5_connect(proxy: SocketAddr, host: String, port: u16) -> Box<dyn Future<Item = TcpStream, Error = io::Error> + Send> {
+    if host.len() > 255 {
+        return Box::new(futures::future::err(socks_error(format!("host name {} is too long", host))));
+    }
+
+    // CONNECT to a domain name
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+
+    let stream = TcpStream::connect(&proxy)
+        // Greeting, offering only "no authentication"
+        .and_then(|sock| tokio::io::write_all(sock, [0x05u8, 0x01, 0x00]))
+        .and_then(|(sock, _)| tokio::io::read_exact(sock, [0u8; 2]))
+        .and_then(|(sock, reply)| {
+            if reply != [0x05, 0x00] {
+                return Err(socks_error("proxy needs authentication, or is not a SOCKS5 proxy".to_string()));
+            }
+            Ok(sock)
+        })
+        .and_then(move |sock| tokio::io::write_all(sock, request))
+        .and_then(|(sock, _)| tokio::io::read_exact(sock, [0u8; 4]))
+        .and_then(|(sock, reply)| -> Box<dyn Future<Item = (TcpStream, usize), Error = io::Error> + Send> {
+            if reply[1] != 0x00 {
+                return Box::new(futures::future::err(socks_error(format!("connect failed with reply code {}", reply[1]))));
+            }
+
+            // Length of the bound address that follows
+            match reply[3] {
+                0x01 => Box::new(futures::future::ok((sock, 4))),
+                0x04 => Box::new(futures::future::ok((sock, 16))),
+                0x03 => Box::new(tokio::io::read_exact(sock, [0u8; 1]).map(|(sock, len)| (sock, len[0] as usize))),
+                t    => Box::new(futures::future::err(socks_error(format!("unknown address type {}", t)))),
+            }
+        })
+        // Skip the bound address and port
+        .and_then(|(sock, len)| tokio::io::read_exact(sock, vec![0u8; len + 2]))
+        .map(|(sock, _)| sock);
+
+    Box::new(stream)
+}
+
+/// A grpc destination. Uses TLS (https) unless `tls` is false. If there is a `proxy`, the 
+/// connection goes through it, and `addrs` are not used.
 struct Dst {
-    addr:        SocketAddr, 
+    addrs:       Vec<SocketAddr>,   // Tried in order until one connects. Empty when using a proxy
+    addr_timeout: Duration,         // How long to wait on each address before moving on
+    keepalive:   Option<Duration>,  // TCP keepalive interval, so NATs and load balancers don't drop idle connections
     host:        String,
+    port:        u16,
+    proxy:       Option<SocketAddr>,
+    tls:         bool,
     no_cert:     bool,
+    client_cert: Option<(Vec<Certificate>, PrivateKey)>, // Presented to servers that require client authentication
+}
+
+impl Dst {
+    fn tcp_connect(&self) -> Box<dyn Future<Item = TcpStream, Error = io::Error> + Send> {
+        let stream: Box<dyn Future<Item = TcpStream, Error = io::Error> + Send> = match self.proxy {
+            Some(proxy) => socks5_connect(proxy, self.host.clone(), self.port),
+            None        => connect_any(self.addrs.clone(), self.addr_timeout),
+        };
+
+        let keepalive = self.keepalive;
+        Box::new(stream.and_then(move |sock| {
+            sock.set_nodelay(true)?;
+            sock.set_keepalive(keepalive)?;
+            Ok(sock)
+        }))
+    }
 }
 
 impl tower_service::Service<()> for Dst {
-    type Response = TlsStream<TcpStream>;
+    type Response = MaybeTlsStream;
     type Error = ::std::io::Error;
-    type Future = Box<dyn Future<Item = TlsStream<TcpStream>, Error = ::std::io::Error> + Send>;
+    type Future = Box<dyn Future<Item = MaybeTlsStream, Error = ::std::io::Error> + Send>;
 
     fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
         Ok(().into())
     }
 
     fn call(&mut self, _: ()) -> Self::Future {
+        if !self.tls {
+            // Plaintext, so we speak HTTP/2 directly over the TCP stream
+            return Box::new(self.tcp_connect().map(MaybeTlsStream::Plain));
+        }
+
         let mut config = ClientConfig::new();
 
 
         config.alpn_protocols.push(b"h2".to_vec());
         config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
         
-        if self.no_cert {
-            config.dangerous()
-                .set_certificate_verifier(Arc::new(danger::NoCertificateVerification {}));
+        if let Some((certs, key)) = &self.client_cert {
+            config.set_single_client_cert(certs.clone(), key.clone());
+        }
+        
+        #[cfg(feature = "dangerous-tls")]
+        {
+            if self.no_cert {
+                config.dangerous()
+                    .set_certificate_verifier(Arc::new(danger::NoCertificateVerification {}));
+            }
         }
 
         let config = Arc::new(config);
@@ -73,246 +290,3168 @@ impl tower_service::Service<()> for Dst {
 
         let addr_string_local = self.host.clone();
 
+        // .onion addresses don't have publicly verifiable certificates, so they use the localhost 
+        // fallback, same as IP addresses
         let domain = match webpki::DNSNameRef::try_from_ascii_str(&addr_string_local) {
-            Ok(d)  => d,
-            Err(_) => webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap()
+            Ok(d) if !addr_string_local.ends_with(".onion") => d,
+            _  => webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap()
         };
         let domain_local = domain.to_owned();
 
-        let stream = TcpStream::connect(&self.addr).and_then(move |sock| {
-            sock.set_nodelay(true).unwrap();
+        let client_auth = self.client_cert.is_some();
+        let stream = self.tcp_connect().and_then(move |sock| {
             tls_connector.connect(domain_local.as_ref(), sock)
+                .map_err(move |e| if client_auth {
+                    io::Error::new(e.kind(), format!("TLS handshake failed: {}. {}", e, CLIENT_CERT_HINT))
+                } else {
+                    e
+                })
         })
-            .map(move |tcp| tcp);
+            .map(move |tls| MaybeTlsStream::Tls(tls));
 
         Box::new(stream)
     }
 }
 
-// Same implementation but without TLS. Should make it straightforward to run without TLS
-// when testing on local machine
-//
-// impl tower_service::Service<()> for Dst {
-//     type Response = TcpStream;
-//     type Error = ::std::io::Error;
-//     type Future = Box<dyn Future<Item = TcpStream, Error = ::std::io::Error> + Send>;
-//
-//     fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
-//         Ok(().into())
-//     }
-//
-//     fn call(&mut self, _: ()) -> Self::Future {
-//         let mut config = ClientConfig::new();
-//         config.alpn_protocols.push(b"h2".to_vec());
-//         config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-//
-//         let stream = TcpStream::connect(&self.addr)
-//             .and_then(move |sock| {
-//                 sock.set_nodelay(true).unwrap();
-//                 Ok(sock)
-//             });
-//         Box::new(stream)
-//     }
-// }
-
-
-macro_rules! make_grpc_client {
-    ($protocol:expr, $host:expr, $port:expr, $nocert:expr) => {{
-        let uri: http::Uri = format!("{}://{}", $protocol, $host).parse().unwrap();
-
-        let addr = format!("{}:{}", $host, $port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap();
+const CLIENT_CERT_HINT: &str = "If the server rejected the client certificate, check that it trusts the certificate's issuer, and that the key belongs to the certificate";
 
-        let h2_settings = Default::default();
-        let mut make_client = tower_h2::client::Connect::new(Dst {addr, host: $host.to_string(), no_cert: $nocert}, h2_settings, DefaultExecutor::current());
+/// Load the client certificate and key, if there are any. Both have to be given, or neither.
+pub fn load_client_cert(cert_path: &Option<String>, key_path: &Option<String>) -> Result<Option<(Vec<Certificate>, PrivateKey)>, String> {
+    match (cert_path, key_path) {
+        (Some(cert), Some(key)) => read_client_cert(cert, key).map(Some),
+        (None, None)            => Ok(None),
+        _                       => Err("A client certificate and its key have to be given together".to_string())
+    }
+}
 
-        make_client
-            .make_service(())
-            .map_err(|e| { format!("HTTP/2 connection failed; err={:?}.\nIf you're connecting to a local server, please pass --dangerous to trust the server without checking its TLS certificate", e) })
-            .and_then(move |conn| {
-                let conn = tower_request_modifier::Builder::new()
-                    .set_origin(uri)
-                    .build(conn)
-                    .unwrap();
+/// Read a PEM client certificate chain and its PEM private key (PKCS8 or RSA), which has to be the key of
+/// the first certificate
+fn read_client_cert(cert_path: &str, key_path: &str) -> Result<(Vec<Certificate>, PrivateKey), String> {
+    use tokio_rustls::rustls::{internal::pemfile, sign};
 
-                CompactTxStreamer::new(conn)
-                    // Wait until the client is ready...
-                    .ready()
-                    .map_err(|e| { format!("client closed: {:?}", e) })
-            })
-    }};
+    let cert_pem = std::fs::read(cert_path)
+        .map_err(|e| format!("Couldn't read client certificate {}: {}", cert_path, e))?;
+    let certs = pemfile::certs(&mut &cert_pem[..])
+        .map_err(|_| format!("Client certificate {} is not a valid PEM file", cert_path))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path));
+    }
+
+    let key_pem = std::fs::read(key_path)
+        .map_err(|e| format!("Couldn't read client key {}: {}", key_path, e))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut &key_pem[..])
+        .map_err(|_| format!("Client key {} is not a valid PEM file", key_path))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &key_pem[..])
+            .map_err(|_| format!("Client key {} is not a valid PEM file", key_path))?;
+    }
+    let key = keys.into_iter().next()
+        .ok_or(format!("No PKCS8 or RSA private key found in {}", key_path))?;
+
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| format!("The private key in {} is not supported", key_path))?;
+    check_key_pair(&certs[0], &*signing_key)
+        .map_err(|e| format!("The key in {} doesn't belong to the certificate in {}: {}", key_path, cert_path, e))?;
+
+    Ok((certs, key))
 }
 
+/// Check that `key` is the private key of `cert`, by signing with it and verifying the signature with the
+/// certificate's public key
+fn check_key_pair(cert: &Certificate, key: &dyn tokio_rustls::rustls::sign::SigningKey) -> Result<(), String> {
+    use tokio_rustls::rustls::SignatureScheme;
 
-// ==============
-// GRPC code
-// ==============
+    let schemes: [(SignatureScheme, &webpki::SignatureAlgorithm); 3] = [
+        (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+        (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+        (SignatureScheme::RSA_PKCS1_SHA256,      &webpki::RSA_PKCS1_2048_8192_SHA256),
+    ];
+    let signer = key.choose_scheme(&schemes.iter().map(|(scheme, _)| *scheme).collect::<Vec<_>>())
+        .ok_or("the key can't sign with any scheme a client certificate is checked with".to_string())?;
+    let alg = schemes.iter().find(|(scheme, _)| *scheme == signer.get_scheme()).map(|(_, alg)| *alg).unwrap();
 
-pub fn get_info(uri: http::Uri, no_cert: bool) -> Result<LightdInfo, String> {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
-        .and_then(move |mut client| {
-            client.get_lightd_info(Request::new(Empty{}))
-                .map_err(|e| {
-                    format!("ERR = {:?}", e)
-                })
-                .and_then(move |response| {
-                    Ok(response.into_inner())
-                })
-                .map_err(|e| {
-                    format!("ERR = {:?}", e)
-                })
-        });
+    let message = b"yecshell client certificate check";
+    let signature = signer.sign(message).map_err(|e| format!("couldn't sign with the key: {:?}", e))?;
 
-    tokio::runtime::current_thread::Runtime::new().unwrap().block_on(runner)
+    let cert = webpki::EndEntityCert::from(untrusted::Input::from(&cert.0))
+        .map_err(|e| format!("couldn't read the certificate: {:?}", e))?;
+    cert.verify_signature(alg, untrusted::Input::from(message), untrusted::Input::from(&signature))
+        .map_err(|_| "the signature doesn't verify".to_string())
 }
 
+/// Puts the server uri's path in front of every request's path, so that servers behind a reverse proxy
+/// at something like https://example.com/lightwalletd/ can be reached. Does nothing for an empty prefix.
+#[derive(Clone)]
+struct PathPrefix<S> {
+    prefix: String,
+    inner:  S,
+}
 
-pub fn fetch_blocks<F : 'static + std::marker::Send>(uri: &http::Uri, start_height: u64, end_height: u64, no_cert: bool, mut c: F)
-    where F : FnMut(&[u8], u64) {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
-        .and_then(move |mut client| {
-            let bs = BlockId{ height: start_height, hash: vec!()};
-            let be = BlockId{ height: end_height,   hash: vec!()};
+impl<S, B> tower_service::Service<http::Request<B>> for PathPrefix<S> 
+    where S: tower_service::Service<http::Request<B>> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
 
-            let br = Request::new(BlockRange{ start: Some(bs), end: Some(be)});
-            client
-                .get_block_range(br)
-                .map_err(|e| {
-                    format!("RouteChat request failed; err={:?}", e)
-                })
-                .and_then(move |response| {
-                    let inbound = response.into_inner();
-                    inbound.for_each(move |b| {
-                        use prost::Message;
-                        let mut encoded_buf = vec![];
+    fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
 
-                        b.encode(&mut encoded_buf).unwrap();
-                        c(&encoded_buf, b.height);
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        if !self.prefix.is_empty() {
+            let mut parts = req.uri().clone().into_parts();
+            let path = parts.path_and_query.as_ref().map(|pq| pq.as_str()).unwrap_or("/");
 
-                        Ok(())
-                    })
-                    .map_err(|e| format!("gRPC inbound stream error: {:?}", e))
-                })
-        });
+            if let Ok(pq) = format!("{}{}", self.prefix, path).parse() {
+                parts.path_and_query = Some(pq);
+                if let Ok(uri) = http::Uri::from_parts(parts) {
+                    *req.uri_mut() = uri;
+                }
+            }
+        }
+
+        self.inner.call(req)
+    }
+}
+
+/// Tells the server which client is calling, with an `x-client` header on every request, so that
+/// operators can see which versions are still in use.
+#[derive(Clone)]
+struct ClientHeader<S> {
+    value: http::header::HeaderValue,
+    inner: S,
+}
+
+impl<S, B> tower_service::Service<http::Request<B>> for ClientHeader<S> 
+    where S: tower_service::Service<http::Request<B>> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        req.headers_mut().insert(CLIENT_HEADER, self.value.clone());
+        self.inner.call(req)
+    }
+}
+
+pub const CLIENT_HEADER: &str = "x-client";
+
+pub const GZIP: &str = "gzip";
 
-    match tokio::runtime::current_thread::Runtime::new().unwrap().block_on(runner) {
-        Ok(_)  => {}, // The result is processed in callbacks, so nothing to do here
-        Err(e) => {
-            error!("Error while executing fetch_blocks: {}", e);
-            eprintln!("{}", e);
+/// Upper bounds of the latency histogram's buckets, in milliseconds. Slower calls go in one more bucket after these.
+const LATENCY_BUCKETS_MS: [u64; 15] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 30_000, 60_000];
+
+/// Counters for the calls to one RPC method. They are all atomics, so that keeping count is cheap
+/// enough to always be on.
+#[derive(Debug, Default)]
+pub struct MethodStats {
+    calls:              AtomicU64,
+    errors:             AtomicU64,
+    bytes_sent:         AtomicU64,
+    bytes_received:     AtomicU64, // As it came over the wire, so compressed if the server compressed it
+    bytes_uncompressed: AtomicU64,
+    latency_total_ms:   AtomicU64, // Of the calls that finished
+    latency_buckets:    [AtomicU64; 16],
+}
+
+impl MethodStats {
+    /// Count a call that finished, successfully or not, after `elapsed`
+    fn finish(&self, ok: bool, elapsed: Duration) {
+        if !ok {
+            self.errors.fetch_add(1, Ordering::SeqCst);
         }
-    };
+
+        let ms = elapsed.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|b| ms <= *b).unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_total_ms.fetch_add(ms, Ordering::SeqCst);
+        self.latency_buckets[bucket].fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn finished(&self) -> u64 {
+        self.latency_buckets.iter().map(|c| c.load(Ordering::SeqCst)).sum()
+    }
+
+    /// Average latency of the finished calls, in milliseconds
+    pub fn average_latency_ms(&self) -> Option<u64> {
+        match self.finished() {
+            0 => None,
+            n => Some(self.latency_total_ms.load(Ordering::SeqCst) / n)
+        }
+    }
+
+    /// The latency that a fraction `q` of the finished calls took at most, rounded up to the bucket it falls in.
+    /// None if no calls finished yet, or if it is slower than the last bucket.
+    pub fn latency_percentile_ms(&self, q: f64) -> Option<u64> {
+        let finished = self.finished();
+        if finished == 0 {
+            return None;
+        }
+
+        let wanted = std::cmp::max((q * finished as f64).ceil() as u64, 1);
+        let mut seen = 0;
+        for (i, bucket) in self.latency_buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::SeqCst);
+            if seen >= wanted {
+                return LATENCY_BUCKETS_MS.get(i).cloned();
+            }
+        }
+
+        None
+    }
+
+    fn to_json(&self) -> JsonValue {
+        object!{
+            "calls"              => self.calls.load(Ordering::SeqCst),
+            "errors"             => self.errors.load(Ordering::SeqCst),
+            "bytes_sent"         => self.bytes_sent.load(Ordering::SeqCst),
+            "bytes_received"     => self.bytes_received.load(Ordering::SeqCst),
+            "bytes_uncompressed" => self.bytes_uncompressed.load(Ordering::SeqCst),
+            "latency_avg_ms"     => self.average_latency_ms(),
+            "latency_p50_ms"     => self.latency_percentile_ms(0.5),
+            "latency_p90_ms"     => self.latency_percentile_ms(0.9),
+            "latency_p99_ms"     => self.latency_percentile_ms(0.99),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in &[&self.calls, &self.errors, &self.bytes_sent, &self.bytes_received, &self.bytes_uncompressed, &self.latency_total_ms] {
+            counter.store(0, Ordering::SeqCst);
+        }
+        for bucket in self.latency_buckets.iter() {
+            bucket.store(0, Ordering::SeqCst);
+        }
+    }
 }
 
-pub fn fetch_transparent_txids<F : 'static + std::marker::Send>(uri: &http::Uri, address: String, 
-    start_height: u64, end_height: u64, no_cert: bool, c: F)
-        where F : Fn(&[u8], u64) {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
-        .and_then(move |mut client| {
-            let start = Some(BlockId{ height: start_height, hash: vec!()});
-            let end   = Some(BlockId{ height: end_height,   hash: vec!()});
+/// Network counters for each RPC method, eg. `GetBlockRange`, across all the connections they are shared by
+#[derive(Debug, Default)]
+pub struct NetworkStats {
+    methods: RwLock<HashMap<String, Arc<MethodStats>>>,
+}
 
-            let br = Request::new(TransparentAddressBlockFilter{ address, range: Some(BlockRange{start, end}) });
+impl NetworkStats {
+    pub fn new() -> Self {
+        NetworkStats::default()
+    }
 
-            client
-                .get_address_txids(br)
-                .map_err(|e| {
-                    format!("RouteChat request failed; err={:?}", e)
-                })
-                .and_then(move |response| {
-                    let inbound = response.into_inner();
-                    inbound.for_each(move |tx| {
-                        //let tx = Transaction::read(&tx.into_inner().data[..]).unwrap();
-                        c(&tx.data, tx.height);
+    /// The counters for the method that a request to `path` calls
+    fn method(&self, path: &str) -> Arc<MethodStats> {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        if let Some(m) = self.methods.read().unwrap().get(name) {
+            return m.clone();
+        }
 
-                        Ok(())
-                    })
-                    .map_err(|e| format!("gRPC inbound stream error: {:?}", e))
-                })
-        });
+        self.methods.write().unwrap().entry(name.to_string()).or_default().clone()
+    }
 
-    match tokio::runtime::current_thread::Runtime::new().unwrap().block_on(runner) {
-        Ok(_)  => {}, // The result is processed in callbacks, so nothing to do here
-        Err(e) => {
-            error!("Error while executing fetch_transparent_txids: {}", e);
-            eprintln!("{}", e);
+    /// The counters for `method`, if it was called at all
+    pub fn get(&self, method: &str) -> Option<Arc<MethodStats>> {
+        self.methods.read().unwrap().get(method).cloned()
+    }
+
+    /// Total bytes received from the servers over the wire
+    pub fn bytes_received(&self) -> u64 {
+        self.methods.read().unwrap().values().map(|m| m.bytes_received.load(Ordering::SeqCst)).sum()
+    }
+
+    /// Total bytes received from the servers, once decompressed
+    pub fn bytes_uncompressed(&self) -> u64 {
+        self.methods.read().unwrap().values().map(|m| m.bytes_uncompressed.load(Ordering::SeqCst)).sum()
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let methods = self.methods.read().unwrap();
+        let mut names = methods.keys().collect::<Vec<_>>();
+        names.sort();
+
+        let mut j = JsonValue::new_object();
+        for name in names {
+            j[name.as_str()] = methods[name].to_json();
         }
-    };
+        j
+    }
+
+    /// Start counting from zero again
+    pub fn reset(&self) {
+        for m in self.methods.read().unwrap().values() {
+            m.reset();
+        }
+    }
 }
 
-pub fn fetch_full_tx<F : 'static + std::marker::Send>(uri: &http::Uri, txid: TxId, no_cert: bool, c: F)
-        where F : Fn(&[u8]) {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
-        .and_then(move |mut client| {
-            let txfilter = TxFilter { block: None, index: 0, hash: txid.0.to_vec() };
-            client.get_transaction(Request::new(txfilter))
-                    .map_err(|e| {
-                    format!("RouteChat request failed; err={:?}", e)
-                })
-                .and_then(move |response| {
-                    c(&response.into_inner().data);
+/// Counts every call that goes through it in `stats`, under its RPC method
+#[derive(Clone)]
+struct Instrument<S> {
+    stats: Arc<NetworkStats>,
+    inner: S,
+}
 
-                    Ok(())
-                })
-                .map_err(|e| { format!("ERR = {:?}", e) })
-        });
+impl<S, B, R> tower_service::Service<http::Request<B>> for Instrument<S> 
+    where S: tower_service::Service<http::Request<CountedBody<B>>, Response = http::Response<R>> {
+    type Response = http::Response<InstrumentedBody<R>>;
+    type Error = S::Error;
+    type Future = InstrumentedResponse<S::Future>;
 
-    match tokio::runtime::current_thread::Runtime::new().unwrap().block_on(runner) {
-        Ok(_)  => {}, // The result is processed in callbacks, so nothing to do here
-        Err(e) => {
-            error!("Error while executing fetch_full_tx: {}", e);
-            eprintln!("{}", e);
+    fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let method = self.stats.method(req.uri().path());
+        method.calls.fetch_add(1, Ordering::SeqCst);
+
+        let req = req.map(|inner| CountedBody { inner, method: method.clone() });
+        InstrumentedResponse { inner: self.inner.call(req), method, started: Instant::now(), done: false }
+    }
+}
+
+/// A request body that counts the bytes sent
+struct CountedBody<B> {
+    inner:  B,
+    method: Arc<MethodStats>,
+}
+
+impl<B> http_body::Body for CountedBody<B> where B: http_body::Body {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn poll_data(&mut self) -> futures::Poll<Option<Self::Data>, Self::Error> {
+        let data = try_ready!(self.inner.poll_data());
+        if let Some(d) = &data {
+            self.method.bytes_sent.fetch_add(d.remaining() as u64, Ordering::SeqCst);
         }
-    };
+        Ok(data.into())
+    }
+
+    fn poll_trailers(&mut self) -> futures::Poll<Option<http::HeaderMap>, Self::Error> {
+        self.inner.poll_trailers()
+    }
 }
 
-pub fn broadcast_raw_tx(uri: &http::Uri, no_cert: bool, tx_bytes: Box<[u8]>) -> Result<String, String> {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
-        .and_then(move |mut client| {
-            client.send_transaction(Request::new(RawTransaction {data: tx_bytes.to_vec(), height: 0}))
-                .map_err(|e| {
-                    format!("ERR = {:?}", e)
-                })
-                .and_then(move |response| {
-                    let sendresponse = response.into_inner();
-                    if sendresponse.error_code == 0 {
-                        let mut txid = sendresponse.error_message;
-                        if txid.starts_with("\"") && txid.ends_with("\"") {
-                            txid = txid[1..txid.len()-1].to_string();
-                        }
+/// Whether a grpc-status header says the call succeeded
+fn grpc_ok(headers: Option<&http::HeaderMap>) -> bool {
+    headers.and_then(|h| h.get("grpc-status")).map_or(false, |s| s == "0")
+}
 
-                        Ok(txid)
-                    } else {
-                        Err(format!("Error: {:?}", sendresponse))
-                    }
-                })
-                .map_err(|e| { format!("ERR = {:?}", e) })
-        });
+struct InstrumentedResponse<F> {
+    inner:   F,
+    method:  Arc<MethodStats>,
+    started: Instant,
+    done:    bool,
+}
+
+impl<F, R> Future for InstrumentedResponse<F> where F: Future<Item = http::Response<R>> {
+    type Item = http::Response<InstrumentedBody<R>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        let response = match self.inner.poll() {
+            Ok(futures::Async::Ready(r)) => r,
+            Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+            Err(e) => {
+                self.done = true;
+                self.method.finish(false, self.started.elapsed());
+                return Err(e);
+            }
+        };
+        self.done = true;
+
+        // Without a body, the call's status comes with the headers instead of the trailers
+        let done = response.headers().contains_key("grpc-status");
+        if done {
+            self.method.finish(grpc_ok(Some(response.headers())), self.started.elapsed());
+        }
 
-    tokio::runtime::current_thread::Runtime::new().unwrap().block_on(runner)
+        let (method, started) = (self.method.clone(), self.started);
+        Ok(response.map(|inner| InstrumentedBody { inner, method, started, done }).into())
+    }
 }
 
-pub fn fetch_latest_block<F : 'static + std::marker::Send>(uri: &http::Uri, no_cert: bool, mut c : F) 
-    where F : FnMut(BlockId) {
-    let runner = make_grpc_client!(uri.scheme_str().unwrap(), uri.host().unwrap(), uri.port_part().unwrap(), no_cert)
-        .and_then(|mut client| {
-            client.get_latest_block(Request::new(ChainSpec {}))
-            .map_err(|e| { format!("ERR = {:?}", e) })
-            .and_then(move |response| {
-                c(response.into_inner());
-                Ok(())
-            })
-            .map_err(|e| { format!("ERR = {:?}", e) })
-        });
+impl<F> Drop for InstrumentedResponse<F> {
+    fn drop(&mut self) {
+        // Abandoned, eg. because it timed out
+        if !self.done {
+            self.method.finish(false, self.started.elapsed());
+        }
+    }
+}
+
+/// A response body that counts the bytes received, and the call as finished once the trailers with its status arrive
+struct InstrumentedBody<B> {
+    inner:   B,
+    method:  Arc<MethodStats>,
+    started: Instant,
+    done:    bool,
+}
+
+impl<B> InstrumentedBody<B> {
+    fn finish(&mut self, ok: bool) {
+        if !self.done {
+            self.done = true;
+            self.method.finish(ok, self.started.elapsed());
+        }
+    }
+}
+
+impl<B> http_body::Body for InstrumentedBody<B> where B: http_body::Body {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
 
-    match tokio::runtime::current_thread::Runtime::new().unwrap().block_on(runner) {
-        Ok(_)  => {}, // The result is processed in callbacks, so nothing to do here
-        Err(e) => {
-            error!("Error while executing fetch_latest_block: {}", e);
-            eprintln!("{}", e);
+    fn poll_data(&mut self) -> futures::Poll<Option<Self::Data>, Self::Error> {
+        match self.inner.poll_data() {
+            Ok(futures::Async::Ready(Some(data))) => {
+                self.method.bytes_received.fetch_add(data.remaining() as u64, Ordering::SeqCst);
+                Ok(Some(data).into())
+            },
+            Err(e) => {
+                self.finish(false);
+                Err(e)
+            },
+            r => r
         }
+    }
+
+    fn poll_trailers(&mut self) -> futures::Poll<Option<http::HeaderMap>, Self::Error> {
+        match self.inner.poll_trailers() {
+            Ok(futures::Async::Ready(trailers)) => {
+                self.finish(grpc_ok(trailers.as_ref()));
+                Ok(trailers.into())
+            },
+            Err(e) => {
+                self.finish(false);
+                Err(e)
+            },
+            r => r
+        }
+    }
+}
+
+impl<B> Drop for InstrumentedBody<B> {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
+
+/// Asks the server to gzip its messages if `enabled`, and decompresses the ones that come back 
+/// compressed, because tower-grpc can't decode compressed messages itself. A server without gzip 
+/// support either just answers uncompressed, or refuses the call, in which case `refused` is set 
+/// and the following calls to that server don't ask for compression any more.
+#[derive(Clone)]
+struct Gzip<S> {
+    enabled: bool,
+    refused: Arc<AtomicBool>,
+    stats:   Arc<NetworkStats>,
+    max_message_size: usize,
+    inner:   S,
+}
+
+impl<S, B, R> tower_service::Service<http::Request<B>> for Gzip<S> 
+    where S: tower_service::Service<http::Request<B>, Response = http::Response<R>> {
+    type Response = http::Response<GunzipBody<R>>;
+    type Error = S::Error;
+    type Future = GzipResponse<S::Future>;
+
+    fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        let asked = self.enabled && !self.refused.load(Ordering::SeqCst);
+        if asked {
+            // The messages we send aren't compressed, they just say so in their flag. Servers answer
+            // in the request's encoding, so this is what gets them to compress.
+            let gzip = http::header::HeaderValue::from_static(GZIP);
+            req.headers_mut().insert("grpc-encoding", gzip.clone());
+            req.headers_mut().insert("grpc-accept-encoding", gzip);
+        }
+
+        let method = self.stats.method(req.uri().path());
+        GzipResponse { inner: self.inner.call(req), asked, refused: self.refused.clone(), method, max_message_size: self.max_message_size }
+    }
+}
+
+struct GzipResponse<F> {
+    inner:   F,
+    asked:   bool,
+    refused: Arc<AtomicBool>,
+    method:  Arc<MethodStats>,
+    max_message_size: usize,
+}
+
+impl<F, R> Future for GzipResponse<F> where F: Future<Item = http::Response<R>> {
+    type Item = http::Response<GunzipBody<R>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        let response = try_ready!(self.inner.poll());
+        let header = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+
+        // UNIMPLEMENTED, because of the encoding rather than the method
+        let message = header("grpc-message").unwrap_or_default();
+        if self.asked && header("grpc-status").as_ref().map(|s| s.as_str()) == Some("12") 
+                && (message.contains("grpc-encoding") || message.contains("ompressor")) {
+            warn!("Server doesn't support gzip, continuing without compression. {}", message);
+            self.refused.store(true, Ordering::SeqCst);
+        }
+
+        let encoding = header("grpc-encoding");
+        let (method, max_message_size) = (self.method.clone(), self.max_message_size);
+        Ok(response.map(|inner| GunzipBody { inner, encoding, buf: BytesMut::new(), method, max_message_size }).into())
+    }
+}
+
+/// The error for a message larger than `max_message_size`, with its size if it's known
+fn message_too_large(size: Option<usize>, max_message_size: usize) -> String {
+    let message = match size {
+        Some(size) => format!("a message of {} bytes", size),
+        None       => "a gzipped message that is larger than that once decompressed".to_string(),
     };
+
+    format!("{}: the server sent {}, but the limit is {} bytes. If the server is right, start with a larger \
+             --max-message-size to accept it", MESSAGE_TOO_LARGE, message, max_message_size)
+}
+
+/// An error from the body, without the quotes and whatever else it was wrapped in on the way out of tower-grpc
+fn without_wrapping(e: &str) -> String {
+    e.split(&['"', '\\'][..]).next().unwrap_or(e).trim().to_string()
+}
+
+/// A gRPC response body, with the messages passed on uncompressed
+struct GunzipBody<B> {
+    inner:    B,
+    encoding: Option<String>,   // What the server compressed the messages with
+    buf:      BytesMut,         // Received bytes that don't make up a whole message yet
+    method:   Arc<MethodStats>, // Where the bytes are counted once decompressed
+    max_message_size: usize,    // Larger messages fail the call, compressed or not
+}
+
+impl<B> GunzipBody<B> {
+    /// Take the next message off the buffer if all of it has arrived, and decompress it if it was compressed
+    fn next_message(&mut self) -> Result<Option<Bytes>, String> {
+        // Every message is a compressed flag and a 4 byte length, followed by the message itself
+        if self.buf.len() < 5 {
+            return Ok(None);
+        }
+        let len = BigEndian::read_u32(&self.buf[1..5]) as usize;
+        if len > self.max_message_size {
+            return Err(message_too_large(Some(len), self.max_message_size));
+        }
+        if self.buf.len() < 5 + len {
+            return Ok(None);
+        }
+
+        let frame = self.buf.split_to(5 + len).freeze();
+        if frame[0] == 0 {
+            self.method.bytes_uncompressed.fetch_add(frame.len() as u64, Ordering::SeqCst);
+            return Ok(Some(frame));
+        }
+
+        let message = match (frame[0], self.encoding.as_ref().map(|e| e.as_str())) {
+            (1, Some(GZIP)) => {
+                // Stop decompressing as soon as it's too large, instead of after running out of memory
+                let mut message = vec![];
+                flate2::read::GzDecoder::new(&frame[5..]).take(self.max_message_size as u64 + 1).read_to_end(&mut message)
+                    .map_err(|e| format!("Couldn't decompress gzipped message: {}", e))?;
+                if message.len() > self.max_message_size {
+                    return Err(message_too_large(None, self.max_message_size));
+                }
+                message
+            },
+            (1, encoding)   => return Err(format!("Message compressed with unsupported encoding {:?}", encoding)),
+            (flag, _)       => return Err(format!("Invalid message flag {}", flag)),
+        };
+        let mut out = BytesMut::with_capacity(5 + message.len());
+        out.put_u8(0);
+        out.put_u32_be(message.len() as u32);
+        out.put_slice(&message);
+        self.method.bytes_uncompressed.fetch_add(out.len() as u64, Ordering::SeqCst);
+
+        Ok(Some(out.freeze()))
+    }
+}
+
+impl<B> http_body::Body for GunzipBody<B> 
+    where B: http_body::Body, B::Error: Into<Box<dyn std::error::Error + Send + Sync>> {
+    type Data = io::Cursor<Bytes>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn is_end_stream(&self) -> bool {
+        self.buf.is_empty() && self.inner.is_end_stream()
+    }
+
+    fn poll_data(&mut self) -> futures::Poll<Option<Self::Data>, Self::Error> {
+        loop {
+            if let Some(message) = self.next_message()? {
+                return Ok(Some(io::Cursor::new(message)).into());
+            }
+
+            match try_ready!(self.inner.poll_data().map_err(Into::into)) {
+                Some(data) => {
+                    self.buf.reserve(data.remaining());
+                    self.buf.put(data);
+                },
+                None if self.buf.is_empty() => return Ok(None.into()),
+                None => return Err(format!("Response ended in the middle of a message ({} bytes left over)", self.buf.len()).into()),
+            }
+        }
+    }
+
+    fn poll_trailers(&mut self) -> futures::Poll<Option<http::HeaderMap>, Self::Error> {
+        self.inner.poll_trailers().map_err(Into::into)
+    }
+}
+
+/// Split `start_height..=end_height` into at most `n` contiguous chunks of about the same length, in order.
+/// Ranges too short to be worth splitting stay in one chunk.
+pub fn split_block_range(start_height: u64, end_height: u64, n: usize) -> Vec<(u64, u64)> {
+    if end_height < start_height {
+        return vec![];
+    }
+
+    let total = end_height - start_height + 1;
+    let n = std::cmp::max(1, std::cmp::min(n as u64, total / MIN_BLOCK_CHUNK));
+    let (len, extra) = (total / n, total % n);
+
+    let mut start = start_height;
+    (0..n).map(|i| {
+        let end = start + len + (i < extra) as u64 - 1;
+        let chunk = (start, end);
+        start = end + 1;
+        chunk
+    }).collect()
+}
+
+/// The `x-client` header, eg. `yecshell/1.0.0`, followed by the application's own name if it gave one
+pub fn client_header_value(suffix: &Option<String>) -> String {
+    let base = format!("yecshell/{}", env!("CARGO_PKG_VERSION"));
+    match suffix {
+        Some(s) if !s.trim().is_empty() => format!("{} {}", base, s.trim()),
+        _                               => base
+    }
+}
+
+/// Default port for a server uri without one: 443 for https, and lightwalletd's
+/// conventional 9067 for plaintext http
+pub fn default_port(scheme: Option<&str>) -> u16 {
+    match scheme {
+        Some("http") => 9067,
+        _            => 443,
+    }
+}
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_UNARY_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_STREAM_INACTIVITY_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest gRPC message accepted from a server, after decompressing it. Far more than any block or
+/// transaction needs, but it keeps a broken or hostile server from using up all the memory.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+/// How long to wait on each of the server's addresses before trying the next one
+const ADDRESS_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How many full transactions get_transaction keeps around
+const TX_CACHE_SIZE: usize = 64;
+
+// When selecting the fastest server, how far behind the highest tip a server may be, and how many
+// requests to time on each one
+pub const MAX_SELECT_LAG: u64 = 3;
+const SELECT_PING_SAMPLES: u32 = 3;
+
+/// The start of the error for a message that is larger than the connection's `max_message_size`
+pub const MESSAGE_TOO_LARGE: &str = "Message too large";
+
+/// What every call returns on a connection that was opened offline
+pub const OFFLINE_ERROR: &str = "Offline, this needs a connection to the server";
+
+/// What opening a connection, or changing its servers, to an empty list of servers returns
+pub const NO_SERVERS_ERROR: &str = "Need at least one server";
+
+// How many times a block stream that broke off part way is reopened where it stopped
+pub const MAX_STREAM_RESUMES: u32 = 3;
+// The most connections a sync may fetch blocks over at the same time, so it doesn't hog a public server
+pub const MAX_BLOCK_CONNECTIONS: usize = 4;
+// A range is only split into chunks at least this long, shorter ones aren't worth another connection
+const MIN_BLOCK_CHUNK: u64 = 100;
+
+/// Parse a txid as displayed by block explorers and the full node, which is the reverse of the
+/// byte order used inside transactions (and by lightwalletd's GetTransaction).
+pub fn txid_from_hex(s: &str) -> Result<TxId, String> {
+    let mut bytes = hex::decode(s.trim()).map_err(|e| format!("Invalid txid {}: {}", s, e))?;
+    if bytes.len() != 32 {
+        return Err(format!("Invalid txid {}: should be 32 bytes", s));
+    }
+    bytes.reverse();
+
+    let mut txid = [0u8; 32];
+    txid.copy_from_slice(&bytes);
+    Ok(TxId{0: txid})
+}
+
+/// Interpret lightwalletd's GetTransaction response for `txid`. A server that has pruned the transaction,
+/// or whose full node doesn't index all transactions, answers with an error from the full node, or on some
+/// versions with an empty transaction, so both get the same error. The bytes are checked to really be `txid`.
+pub fn raw_transaction_data(txid: TxId, response: Result<RawTransaction, String>) -> Result<Vec<u8>, String> {
+    let not_found = || format!("The server doesn't have transaction {}. It may have pruned it, try a server that keeps all transactions", txid);
+
+    let rtx = match response {
+        Ok(rtx) => rtx,
+        Err(e)  => {
+            if e.contains("NotFound") || e.contains("No such mempool or blockchain transaction")
+                    || e.contains("No information available about transaction") {
+                return Err(not_found());
+            }
+            return Err(format!("Couldn't get transaction {}: {}", txid, e));
+        }
+    };
+
+    if rtx.data.is_empty() {
+        return Err(not_found());
+    }
+    if txid_of(&rtx.data) != txid.to_string() {
+        return Err(format!("The server sent transaction {} instead of {}", txid_of(&rtx.data), txid));
+    }
+
+    Ok(rtx.data)
+}
+
+/// A transaction that the server accepted
+#[derive(Clone, Debug, PartialEq)]
+pub struct BroadcastResult {
+    pub txid:           String, // Computed from the transaction itself, in the usual (reversed) hex
+    pub server_message: String, // Whatever the server said, usually the txid
+}
+
+/// Why a transaction couldn't be broadcast
+#[derive(Clone, Debug, PartialEq)]
+pub enum BroadcastError {
+    AlreadyKnown(String),       // Already in the mempool or in the chain
+    Expired(String),            // The expiry height has passed, or is too close
+    InsufficientFee(String),
+    Rejected(i32, String),      // Any other error from the server, with its error code
+    Connection(String),         // Couldn't get the transaction to the server at all
+}
+
+impl std::fmt::Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BroadcastError::AlreadyKnown(m)    => write!(f, "The transaction was already sent: {}", m),
+            BroadcastError::Expired(m)         => write!(f, "The transaction has expired, please sync and send it again: {}", m),
+            BroadcastError::InsufficientFee(m) => write!(f, "The fee is too low for the server to accept the transaction: {}", m),
+            BroadcastError::Rejected(code, m)  => write!(f, "The server rejected the transaction (error {}): {}", code, m),
+            BroadcastError::Connection(e)      => write!(f, "Couldn't send the transaction to the server: {}", e),
+        }
+    }
+}
+
+/// The txid of the raw transaction, as hex, the same way the full node displays it
+pub fn txid_of(tx_bytes: &[u8]) -> String {
+    let mut txid = crate::lightwallet::double_sha256(tx_bytes);
+    txid.reverse();
+
+    hex::encode(txid)
+}
+
+/// Interpret lightwalletd's SendTransaction response. On success, the error message usually has 
+/// the txid (possibly quoted), which is checked against the one computed from `tx_bytes`. Errors
+/// are passed on from the full node, so they are recognized by zcashd's rejection reasons.
+pub fn parse_send_response(tx_bytes: &[u8], response: SendResponse) -> Result<BroadcastResult, BroadcastError> {
+    let message = response.error_message.trim().to_string();
+
+    if response.error_code == 0 {
+        let txid = txid_of(tx_bytes);
+
+        let echoed = message.trim_matches('"');
+        if echoed.len() == 64 && hex::decode(echoed).is_ok() && !echoed.eq_ignore_ascii_case(&txid) {
+            warn!("Server reported txid {} for transaction {}", echoed, txid);
+        }
+
+        return Ok(BroadcastResult{ txid, server_message: message });
+    }
+
+    let lower = message.to_lowercase();
+    if lower.contains("already in mempool") || lower.contains("txn-already-in-mempool") || 
+       lower.contains("txn-already-known") || lower.contains("already in block chain") {
+        Err(BroadcastError::AlreadyKnown(message))
+    } else if lower.contains("expired") || lower.contains("expiring-soon") {
+        Err(BroadcastError::Expired(message))
+    } else if lower.contains("insufficient fee") || lower.contains("insufficient priority") || 
+              lower.contains("min relay fee not met") {
+        Err(BroadcastError::InsufficientFee(message))
+    } else {
+        Err(BroadcastError::Rejected(response.error_code, message))
+    }
+}
+
+/// Whether a broadcast to several servers succeeded, given each server's outcome with the wallet's own
+/// server first. It did if any server accepted the transaction. The other servers often only say they
+/// already have it, because it got to them from another server first, so that counts too. Otherwise,
+/// the wallet's server's error is returned.
+pub fn broadcast_outcome(tx_bytes: &[u8], results: &[(http::Uri, Result<BroadcastResult, BroadcastError>)])
+        -> Result<BroadcastResult, BroadcastError> {
+    if let Some(r) = results.iter().find_map(|(_, r)| r.as_ref().ok()) {
+        return Ok(r.clone());
+    }
+
+    let known = results.iter().skip(1).find_map(|(_, r)| match r {
+        Err(BroadcastError::AlreadyKnown(m)) => Some(m),
+        _ => None
+    });
+    if let Some(m) = known {
+        return Ok(BroadcastResult{ txid: txid_of(tx_bytes), server_message: m.clone() });
+    }
+
+    match results.first() {
+        Some((_, Err(e))) => Err(e.clone()),
+        _                 => Err(BroadcastError::Connection("No servers to broadcast to".to_string()))
+    }
+}
+
+/// Turn a timeout error into a readable message. If the inner future failed on its own, its
+/// error is passed through unchanged. `limit` is the name of the setting that was hit.
+fn timeout_error(e: timeout::Error<String>, limit: &str, after: Duration, uri: &http::Uri) -> String {
+    if e.is_elapsed() {
+        format!("timed out after {:?} ({}) contacting {}", after, limit, uri)
+    } else {
+        e.into_inner().unwrap_or(format!("timer error while contacting {}", uri))
+    }
+}
+
+type Connection = tower_h2::client::Connection<MaybeTlsStream, DefaultExecutor, CountedBody<BoxBody>>;
+type Client = CompactTxStreamer<tower_request_modifier::RequestModifier<ClientHeader<Gzip<Instrument<PathPrefix<Connection>>>>, BoxBody>>;
+
+/// A long-lived gRPC channel to a lightwalletd server. The HTTP/2 connection is established
+/// lazily on the first call and then shared by all subsequent calls. If the connection breaks,
+/// it is dropped and transparently re-established on the next call.
+pub struct GrpcConnection {
+    servers:            RwLock<Vec<http::Uri>>, // Tried in order, moving on to the next one if a server can't be reached
+    active:             AtomicUsize,
+    expected_chain:     Option<(String, u64)>, // (chain_name, sapling_activation_height) a server has to match
+    failovers:          AtomicU64,
+    ip_version:         IpVersion,
+    ip_override:        Option<(String, IpAddr)>, // (host, ip) to connect to instead of resolving that host
+    keepalive_interval: Option<Duration>, // None turns keepalive off
+    keepalive_timeout:  Duration,
+    no_cert:            bool,
+    connect_timeout:    Duration,
+    unary_timeout:      Duration, // Deadline for a whole one-shot call
+    stream_timeout:     Duration, // For streaming calls, the max time between two messages
+    max_attempts:       u32,
+    proxy:              Option<String>,
+    client_cert:        (Option<String>, Option<String>), // (certificate, key) PEM files, for servers that require client authentication
+    client_header:      String,
+    compression:        bool, // Ask servers to gzip their responses
+    max_message_size:   usize, // Bytes, after decompressing
+    offline:            bool, // Never connect, failing every call right away
+    gzip_refused:       RwLock<Vec<Arc<AtomicBool>>>, // For each server, whether it refused a call because of that
+    stats:              Arc<NetworkStats>,
+
+    // The runtime that drives the HTTP/2 connection in the background. It has to outlive
+    // individual calls, otherwise the connection would be torn down after every call.
+    runtime:    Runtime,
+    client:     Mutex<Option<Client>>,
+    last_used:  Mutex<Option<Instant>>, // When the connection last got a response
+
+    // Recently fetched full transactions, most recently used first
+    tx_cache:   Mutex<VecDeque<(TxId, RawTransaction)>>,
+
+    // What each server looked like the last time the fastest one was selected
+    measurements: Mutex<Vec<ServerMeasurement>>,
+}
+
+/// The result of pinging one of the candidate servers in `select_fastest`
+#[derive(Clone, Debug)]
+pub struct ServerMeasurement {
+    pub uri:        http::Uri,
+    pub latency:    Option<Duration>,   // Average round trip time, if the server responded
+    pub tip:        Option<u64>,
+    pub error:      Option<String>,     // Why the server couldn't be used
+    pub selected:   bool,
+}
+
+impl GrpcConnection {
+    pub fn new(uri: http::Uri, no_cert: bool, connect_timeout: Duration, unary_timeout: Duration, max_attempts: u32, 
+               proxy: Option<String>) -> Self {
+        GrpcConnection::from_servers(vec![uri], no_cert, connect_timeout, unary_timeout, max_attempts, proxy)
+    }
+
+    /// A connection that fails over to the next server in the list when the current one can't be reached.
+    /// Fails if the list is empty.
+    pub fn with_servers(servers: Vec<http::Uri>, no_cert: bool, connect_timeout: Duration, unary_timeout: Duration,
+                        max_attempts: u32, proxy: Option<String>) -> Result<Self, String> {
+        if servers.is_empty() {
+            return Err(NO_SERVERS_ERROR.to_string());
+        }
+
+        Ok(GrpcConnection::from_servers(servers, no_cert, connect_timeout, unary_timeout, max_attempts, proxy))
+    }
+
+    fn from_servers(servers: Vec<http::Uri>, no_cert: bool, connect_timeout: Duration, unary_timeout: Duration,
+                    max_attempts: u32, proxy: Option<String>) -> Self {
+        let gzip_refused = servers.iter().map(|_| Arc::new(AtomicBool::new(false))).collect::<Vec<_>>();
+
+        GrpcConnection {
+            servers: RwLock::new(servers),
+            active: AtomicUsize::new(0),
+            expected_chain: None,
+            failovers: AtomicU64::new(0),
+            ip_version: IpVersion::Any,
+            ip_override: None,
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            keepalive_timeout: DEFAULT_KEEPALIVE_TIMEOUT,
+            no_cert,
+            connect_timeout,
+            unary_timeout,
+            stream_timeout: DEFAULT_STREAM_INACTIVITY_TIMEOUT,
+            max_attempts: std::cmp::max(max_attempts, 1),
+            proxy,
+            client_cert: (None, None),
+            client_header: client_header_value(&None),
+            compression: false,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            offline: false,
+            gzip_refused: RwLock::new(gzip_refused),
+            stats: Arc::new(NetworkStats::new()),
+            runtime: Runtime::new().expect("Couldn't start the gRPC runtime"),
+            client: Mutex::new(None),
+            last_used: Mutex::new(None),
+            tx_cache: Mutex::new(VecDeque::with_capacity(TX_CACHE_SIZE)),
+            measurements: Mutex::new(vec![]),
+        }
+    }
+
+    /// Only fail over to servers that are on this chain, and agree on the sapling activation height
+    pub fn expecting_chain(mut self, chain_name: String, sapling_activation_height: u64) -> Self {
+        self.expected_chain = Some((chain_name, sapling_activation_height));
+        self
+    }
+
+    /// Only connect to the server over this IP version
+    pub fn with_ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.ip_version = ip_version;
+        self
+    }
+
+    /// Connect to `ip` instead of resolving the server's host name, when the server is on that host. The host
+    /// name is still used for TLS (SNI and checking the certificate), and in the requests. Not used with a 
+    /// proxy, which resolves the name itself.
+    pub fn with_ip_override(mut self, ip_override: Option<(String, IpAddr)>) -> Self {
+        self.ip_override = ip_override;
+        self
+    }
+
+    /// Keep the connection alive with TCP keepalives every `interval`, and check that a connection
+    /// that has been idle for longer than that still works before using it, giving the server
+    /// `timeout` to answer.
+    pub fn with_keepalive(mut self, interval: Option<Duration>, timeout: Duration) -> Self {
+        self.keepalive_interval = interval;
+        self.keepalive_timeout = timeout;
+        self
+    }
+
+    /// Authenticate to the server with this PEM certificate and private key. Both have to be given,
+    /// or neither, otherwise connecting fails.
+    pub fn with_client_cert(mut self, cert_path: Option<String>, key_path: Option<String>) -> Self {
+        self.client_cert = (cert_path, key_path);
+        self
+    }
+
+    /// Add the application's name, eg. `MyWallet/2.1`, to the `x-client` header sent to the server
+    pub fn with_client_name(mut self, suffix: Option<String>) -> Self {
+        self.client_header = client_header_value(&suffix);
+        self
+    }
+
+    /// Ask the servers to gzip their responses, which mostly helps with downloading blocks. Servers that
+    /// don't support it answer uncompressed.
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Fail calls where the server sends a message larger than `max_message_size` bytes, uncompressed
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// How long a streaming call may go without receiving anything before it is abandoned
+    pub fn with_stream_inactivity_timeout(mut self, timeout: Duration) -> Self {
+        self.stream_timeout = timeout;
+        self
+    }
+
+    /// Don't connect to the servers at all, eg. to open a wallet without a network. Every call fails
+    /// with `OFFLINE_ERROR`.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// The server currently in use
+    pub fn uri(&self) -> http::Uri {
+        self.servers.read().unwrap()[self.active.load(Ordering::SeqCst)].clone()
+    }
+
+    /// Replace the list of servers, and start over on the first of the new ones. The current 
+    /// connection is dropped, so the next call connects to the new server. An empty list is refused,
+    /// and the servers are left as they were.
+    pub fn change_servers(&self, servers: Vec<http::Uri>) -> Result<(), String> {
+        if servers.is_empty() {
+            return Err(NO_SERVERS_ERROR.to_string());
+        }
+
+        let mut current = self.servers.write().unwrap();
+        info!("Changing servers from {:?} to {:?}", *current, servers);
+
+        // Go back to the first server before the lists change, so the active one is always in them
+        self.active.store(0, Ordering::SeqCst);
+        *self.gzip_refused.write().unwrap() = servers.iter().map(|_| Arc::new(AtomicBool::new(false))).collect();
+        *current = servers;
+        self.measurements.lock().unwrap().clear();
+        self.reset();
+
+        Ok(())
+    }
+
+    /// How many times the connection has moved on to another server
+    pub fn failovers(&self) -> u64 {
+        self.failovers.load(Ordering::SeqCst)
+    }
+
+    /// Count the calls to the servers in `stats`
+    pub fn with_network_stats(mut self, stats: Arc<NetworkStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// The measurements from the last `select_fastest`, empty if it never ran
+    pub fn measurements(&self) -> Vec<ServerMeasurement> {
+        self.measurements.lock().unwrap().clone()
+    }
+
+    /// Ping all the servers at the same time, and switch to the fastest one that is on the expected 
+    /// chain. Servers more than `MAX_SELECT_LAG` blocks behind the highest tip are skipped, so that
+    /// a fast server that has fallen behind isn't picked. If none of them respond, nothing changes.
+    pub fn select_fastest(&self) -> Vec<ServerMeasurement> {
+        let servers = self.servers.read().unwrap().clone();
+        let pings = servers.iter().map(|uri| {
+            let conn = self.probe_connection(uri.clone());
+            std::thread::spawn(move || conn.time_calls(SELECT_PING_SAMPLES))
+        }).collect::<Vec<_>>();
+
+        let mut measurements = servers.iter().zip(pings).map(|(uri, p)| {
+            let r = p.join().unwrap_or(Err("Ping thread panicked".to_string()));
+            let r = r.and_then(|(info, tip, times)| match &self.expected_chain {
+                Some((chain_name, height)) if *chain_name != info.chain_name || *height != info.sapling_activation_height => 
+                    Err(format!("Server is on chain {} (sapling at {})", info.chain_name, info.sapling_activation_height)),
+                _ => Ok((tip, times.iter().sum::<Duration>() / times.len() as u32))
+            });
+
+            match r {
+                Ok((tip, latency)) => ServerMeasurement{ uri: uri.clone(), latency: Some(latency), tip: Some(tip), error: None, selected: false },
+                Err(e)             => ServerMeasurement{ uri: uri.clone(), latency: None, tip: None, error: Some(e), selected: false },
+            }
+        }).collect::<Vec<_>>();
+
+        let best_tip = measurements.iter().filter_map(|m| m.tip).max().unwrap_or(0);
+        for m in measurements.iter_mut() {
+            if m.tip.map_or(false, |t| t + MAX_SELECT_LAG < best_tip) {
+                m.error = Some(format!("Server is more than {} blocks behind", MAX_SELECT_LAG));
+            }
+        }
+
+        let fastest = measurements.iter().enumerate()
+                        .filter(|(_, m)| m.error.is_none())
+                        .min_by_key(|(_, m)| m.latency)
+                        .map(|(i, _)| i);
+
+        match fastest {
+            Some(i) => {
+                measurements[i].selected = true;
+                if self.active.swap(i, Ordering::SeqCst) != i {
+                    self.reset();
+                }
+                info!("Selected server {} ({}ms)", servers[i], measurements[i].latency.unwrap().as_millis());
+            },
+            None => warn!("None of the servers could be selected, staying on {}", self.uri())
+        }
+
+        *self.measurements.lock().unwrap() = measurements.clone();
+        measurements
+    }
+
+    /// Check that the server at `uri` is up, and time `samples` GetLatestBlock calls to it, through a
+    /// `probe_connection`. Returns the server's info, its chain tip, and the round trip times.
+    pub fn ping(&self, uri: http::Uri, samples: u32) -> Result<(LightdInfo, u64, Vec<Duration>), String> {
+        self.probe_connection(uri).time_calls(samples)
+    }
+
+    /// A connection to `uri` that is set up like this one, with the same TLS settings, client certificate,
+    /// proxy, IP settings and client header, so that it gets into the same servers. It has PING_TIMEOUT for
+    /// each step and no retries, so that it doesn't hang on a dead server.
+    fn probe_connection(&self, uri: http::Uri) -> GrpcConnection {
+        let mut conn = GrpcConnection::new(uri, self.no_cert, PING_TIMEOUT, PING_TIMEOUT, 1, self.proxy.clone())
+            .with_ip_version(self.ip_version)
+            .with_ip_override(self.ip_override.clone())
+            .with_client_cert(self.client_cert.0.clone(), self.client_cert.1.clone())
+            .with_offline(self.offline);
+        conn.client_header = self.client_header.clone();
+        conn
+    }
+
+    /// Get the server's info, which also sets up the connection, and then time `samples` GetLatestBlock
+    /// calls. Returns the info, the chain tip, and the round trip times.
+    fn time_calls(&self, samples: u32) -> Result<(LightdInfo, u64, Vec<Duration>), String> {
+        let info = self.get_info()?;
+
+        let mut tip = info.block_height;
+        let mut times = vec![];
+        for _ in 0..std::cmp::max(samples, 1) {
+            let start = Instant::now();
+            tip = self.get_latest_block()?.height;
+            times.push(start.elapsed());
+        }
+
+        Ok((info, tip, times))
+    }
+
+    /// Run the future on the connection's runtime and wait for it to finish.
+    fn block_on<F>(&self, f: F) -> Result<F::Item, String>
+        where F: Future<Error = String> + Send + 'static, F::Item: Send + 'static {
+        let (tx, rx) = oneshot::channel();
+        self.runtime.executor().spawn(f.then(move |r| {
+            let _ = tx.send(r);
+            Ok(())
+        }));
+
+        rx.wait().map_err(|_| format!("gRPC call to {} was cancelled", self.uri()))?
+    }
+
+    /// Dial the server: DNS resolution, TCP connect, TLS handshake and HTTP/2 setup. With a proxy,
+    /// the server's name is not resolved locally, and is instead passed on to the proxy.
+    fn connect(&self) -> Result<Client, String> {
+        let uri = self.uri();
+        let host = uri.host()
+            .ok_or(format!("No host in server uri {}", uri))?
+            .to_string();
+        let tls = uri.scheme_str() != Some("http");
+        if tls && self.no_cert {
+            if !cfg!(feature = "dangerous-tls") {
+                return Err(format!("Not connecting to {}: this build doesn't allow turning off TLS certificate verification", uri));
+            }
+            warn!("TLS certificate verification is DISABLED for {}. Anyone between you and the server can impersonate it!", uri);
+        }
+
+        let port = uri.port_part().map(|p| p.as_u16()).unwrap_or(default_port(uri.scheme_str()));
+
+        let proxy = match &self.proxy {
+            Some(p) => Some(parse_proxy(p)?),
+            None    => None
+        };
+
+        let client_cert = load_client_cert(&self.client_cert.0, &self.client_cert.1)?;
+        if client_cert.is_some() && !tls {
+            warn!("Not using the client certificate for the plaintext connection to {}", uri);
+        }
+
+        let ip_override = self.ip_override.as_ref()
+                            .filter(|(h, _)| h.eq_ignore_ascii_case(&host))
+                            .map(|(_, ip)| *ip);
+
+        let addrs = match (proxy, ip_override) {
+            (Some(_), _)     => vec![],
+            (None, Some(ip)) => vec![SocketAddr::new(ip, port)],
+            (None, None)     => {
+                let ip_version = self.ip_version;
+                let addrs = format!("{}:{}", host, port)
+                        .to_socket_addrs()
+                        .map_err(|e| format!("Couldn't resolve server {}: {}", host, e))?
+                        .filter(|a| match ip_version {
+                            IpVersion::Any => true,
+                            IpVersion::V4  => a.is_ipv4(),
+                            IpVersion::V6  => a.is_ipv6(),
+                        })
+                        .collect::<Vec<_>>();
+                if addrs.is_empty() {
+                    return Err(format!("Couldn't resolve server {} to any {:?} address", host, ip_version));
+                }
+
+                interleave_addrs(addrs)
+            }
+        };
+
+        let origin: http::Uri = format!("{}://{}", uri.scheme_str().unwrap_or("https"), host).parse()
+            .map_err(|e| format!("Invalid server uri {}: {}", uri, e))?;
+        let prefix = uri.path().trim_end_matches('/').to_string();
+        let client_header = http::header::HeaderValue::from_str(&self.client_header)
+            .map_err(|_| format!("Invalid client name in {}", self.client_header))?;
+        let gzip_refused = {
+            // change_servers replaces the list while holding the servers lock, so hold it too
+            let _servers = self.servers.read().unwrap();
+            self.gzip_refused.read().unwrap()[self.active.load(Ordering::SeqCst)].clone()
+        };
+        let (compression, stats, max_message_size) = (self.compression, self.stats.clone(), self.max_message_size);
+
+        match proxy {
+            Some(p) => info!("Opening {} gRPC connection to {} via proxy {}", if tls {"TLS"} else {"plaintext"}, uri, p),
+            None    => info!("Opening {} gRPC connection to {} ({:?})", if tls {"TLS"} else {"plaintext"}, uri, addrs),
+        }
+
+        let h2_settings = Default::default();
+        // Give every address a chance before the whole connection attempt times out
+        let addr_timeout = std::cmp::min(self.connect_timeout, ADDRESS_CONNECT_TIMEOUT);
+        let extra_addrs = addrs.len().saturating_sub(1) as u32;
+        let client_auth = tls && client_cert.is_some();
+        let dst = Dst {addrs, addr_timeout, keepalive: self.keepalive_interval, host, port, proxy, tls, no_cert: self.no_cert, client_cert};
+        let mut make_client = tower_h2::client::Connect::new(dst, h2_settings, DefaultExecutor::current());
+
+        let connecting = make_client
+            .make_service(())
+            .map_err(move |e| {
+                if client_auth {
+                    format!("HTTP/2 connection failed; err={:?}.\n{}", e, CLIENT_CERT_HINT)
+                } else if tls {
+                    format!("HTTP/2 connection failed; err={:?}.\nIf you're connecting to a local server, please pass --dangerous to trust the server without checking its TLS certificate", e)
+                } else {
+                    format!("HTTP/2 connection failed; err={:?}.\nIf the server uses TLS, please connect to it with an https:// uri", e)
+                }
+            })
+            .and_then(move |conn| {
+                let conn = tower_request_modifier::Builder::new()
+                    .set_origin(origin)
+                    .build(ClientHeader { 
+                        value: client_header, 
+                        inner: Gzip { 
+                            enabled: compression, 
+                            refused: gzip_refused, 
+                            stats:   stats.clone(), 
+                            max_message_size,
+                            inner:   Instrument { stats, inner: PathPrefix { prefix, inner: conn } } 
+                        } 
+                    })
+                    .unwrap();
+
+                CompactTxStreamer::new(conn)
+                    // Wait until the client is ready...
+                    .ready()
+                    .map_err(|e| { format!("client closed: {:?}", e) })
+            });
+
+        let t = self.connect_timeout + addr_timeout * extra_addrs;
+        self.block_on(connecting.timeout(t).map_err(move |e| timeout_error(e, "connect_timeout", t, &uri)))
+    }
+
+    /// Get a ready client, reusing the existing connection if it is still alive, and
+    /// reconnecting if it is not.
+    fn get_client(&self) -> Result<Client, String> {
+        let cached = self.client.lock().unwrap().clone();
+
+        if let Some(client) = cached {
+            let r = if self.is_idle() {
+                // The probe uses up the client's readiness, so wait for it to be ready again after
+                self.block_on(self.ready(client.clone()))
+                    .and_then(|c| self.probe(c))
+                    .and_then(|_| self.block_on(self.ready(client)))
+            } else {
+                self.block_on(self.ready(client))
+            };
+
+            match r {
+                Ok(client) => return Ok(client),
+                Err(e)     => warn!("gRPC connection to {} is broken, reconnecting. {}", self.uri(), e)
+            }
+        }
+
+        let client = self.connect()?;
+        *self.client.lock().unwrap() = Some(client.clone());
+        self.touch();
+
+        Ok(client)
+    }
+
+    fn ready(&self, client: Client) -> impl Future<Item = Client, Error = String> + Send + 'static {
+        let (t, uri) = (self.connect_timeout, self.uri());
+        client.ready()
+            .map_err(|e| format!("client closed: {:?}", e))
+            .timeout(t)
+            .map_err(move |e| timeout_error(e, "connect_timeout", t, &uri))
+    }
+
+    /// Remember that the connection just worked
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// A connection that sat idle for a while may have been silently dropped by a NAT or load balancer
+    /// along the way, which would only show up as a confusing stream error in the middle of the next 
+    /// call. Such a connection gets checked with `probe` before it is used.
+    fn is_idle(&self) -> bool {
+        match self.keepalive_interval {
+            Some(interval) => self.last_used.lock().unwrap().map_or(false, |t| t.elapsed() > interval),
+            None           => false
+        }
+    }
+
+    /// Check that the connection still works with a cheap request
+    fn probe(&self, mut client: Client) -> Result<(), String> {
+        let (t, uri) = (self.keepalive_timeout, self.uri());
+        let probe = client.get_latest_block(Request::new(ChainSpec {}))
+            .map_err(|e| format!("keepalive check failed: {:?}", e))
+            .timeout(t)
+            .map_err(move |e| timeout_error(e, "keepalive_timeout", t, &uri));
+
+        self.block_on(probe)?;
+        self.touch();
+
+        Ok(())
+    }
+
+    /// Drop the cached connection, so that the next call dials the server again.
+    fn reset(&self) {
+        *self.client.lock().unwrap() = None;
+    }
+
+    /// Close the connection to the server now, instead of when it is dropped. A later call connects again.
+    pub fn disconnect(&self) {
+        self.reset();
+        *self.last_used.lock().unwrap() = None;
+    }
+
+    /// Run a single RPC against a ready client, with the configured unary timeout as the deadline
+    /// for the whole call. See `call_without_deadline` for how failures are retried.
+    fn call<F, R>(&self, name: &str, can_retry_rpc: &dyn Fn() -> bool, f: F) -> Result<R::Item, String>
+        where F: Fn(Client) -> R, R: Future<Error = String> + Send + 'static, R::Item: Send + 'static {
+        let t = self.unary_timeout;
+        self.call_without_deadline(name, can_retry_rpc, move |client| {
+            let uri = self.uri();
+            f(client).timeout(t).map_err(move |e| timeout_error(e, "unary_timeout", t, &uri))
+        })
+    }
+
+    /// Same as `call`, but without an overall deadline. Streaming RPCs use this, and apply
+    /// the stream inactivity timeout between messages instead, so that long syncs don't get killed.
+    ///
+    /// Failures to connect happen before anything was sent to the server, so they are always
+    /// retried, with exponential backoff, up to `max_attempts`. A failed RPC is only retried if
+    /// `can_retry_rpc` says it is safe to repeat it. If the RPC fails, the connection is dropped
+    /// because we can't tell if it is still usable.
+    ///
+    /// If the server still can't be reached after all the attempts, the call moves on to the next
+    /// compatible server in the list, if there is one.
+    fn call_without_deadline<F, R>(&self, name: &str, can_retry_rpc: &dyn Fn() -> bool, f: F) -> Result<R::Item, String>
+        where F: Fn(Client) -> R, R: Future<Error = String> + Send + 'static, R::Item: Send + 'static {
+        if self.offline {
+            return Err(OFFLINE_ERROR.to_string());
+        }
+
+        let mut backoff = Duration::from_millis(500);
+        let mut attempt = 1;
+        let first_server = self.active.load(Ordering::SeqCst);
+        let mut servers_tried = 1;
+
+        loop {
+            let mut connect_failed = false;
+            let (e, retryable) = match self.get_client() {
+                Err(e) => {
+                    connect_failed = true;
+                    (e, true)
+                },
+                Ok(client) => match self.block_on(f(client)) {
+                    Ok(r) => {
+                        self.touch();
+                        if attempt > 1 {
+                            info!("{} succeeded after {} attempts", name, attempt);
+                        }
+                        return Ok(r);
+                    },
+                    Err(e) => {
+                        self.reset();
+
+                        // A message that is too large would be too large again
+                        match e.find(MESSAGE_TOO_LARGE) {
+                            Some(i) => (without_wrapping(&e[i..]), false),
+                            None    => (e, can_retry_rpc())
+                        }
+                    }
+                }
+            };
+
+            if !retryable {
+                // The server rejected the request (or we can't safely repeat it), so report the
+                // error as-is.
+                error!("{} failed on attempt {}: {}", name, attempt, e);
+                return Err(e);
+            }
+
+            if attempt >= self.max_attempts {
+                if connect_failed {
+                    if let Some(tried) = self.failover(first_server, servers_tried) {
+                        servers_tried = tried;
+                        backoff = Duration::from_millis(500);
+                        attempt = 1;
+                        continue;
+                    }
+                }
+
+                let e = format!("{} failed after {} attempts: {}", name, attempt, e);
+                error!("{}", e);
+                return Err(e);
+            }
+
+            warn!("{} attempt {}/{} failed, retrying in {}ms. {}", name, attempt, self.max_attempts, backoff.as_millis(), e);
+            std::thread::sleep(backoff);
+
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Move on to the next server that is reachable and on the expected chain. The first
+    /// `servers_tried` servers, counting from `first_server`, have already failed. Returns how many
+    /// servers have been tried in total, or None if none of the remaining ones can be used.
+    fn failover(&self, first_server: usize, servers_tried: usize) -> Option<usize> {
+        let from = self.active.load(Ordering::SeqCst);
+        let servers = self.servers.read().unwrap().clone();
+
+        for i in servers_tried..servers.len() {
+            self.active.store((first_server + i) % servers.len(), Ordering::SeqCst);
+            self.reset();
+
+            match self.check_server() {
+                Ok(_) => {
+                    let n = self.failovers.fetch_add(1, Ordering::SeqCst) + 1;
+                    warn!("Failed over from {} to {} (failover #{})", servers[from], self.uri(), n);
+                    return Some(i + 1);
+                },
+                Err(e) => warn!("Not failing over to {}: {}", self.uri(), e)
+            }
+        }
+
+        // Nothing usable, so stay on the server we were on
+        self.active.store(from, Ordering::SeqCst);
+        self.reset();
+        None
+    }
+
+    /// Connect to the active server, and make sure it is on the same chain before trusting it
+    fn check_server(&self) -> Result<(), String> {
+        let mut client = self.get_client()?;
+
+        let (t, uri) = (self.unary_timeout, self.uri());
+        let info = self.block_on(client.get_lightd_info(Request::new(Empty{}))
+                .map_err(|e| format!("ERR = {:?}", e))
+                .map(|response| response.into_inner())
+                .timeout(t)
+                .map_err(move |e| timeout_error(e, "unary_timeout", t, &uri)))
+            .map_err(|e| { self.reset(); e })?;
+
+        match &self.expected_chain {
+            Some((chain_name, height)) if *chain_name != info.chain_name || *height != info.sapling_activation_height => {
+                self.reset();
+                Err(format!("Server is on chain {} (sapling at {}), expected {} (sapling at {})",
+                    info.chain_name, info.sapling_activation_height, chain_name, height))
+            },
+            _ => Ok(())
+        }
+    }
+
+    // ==============
+    // GRPC code
+    // ==============
+
+    pub fn get_info(&self) -> Result<LightdInfo, String> {
+        self.call("get_info", &|| true, |mut client| {
+            client.get_lightd_info(Request::new(Empty{}))
+                .map_err(|e| {
+                    format!("ERR = {:?}", e)
+                })
+                .and_then(move |response| {
+                    Ok(response.into_inner())
+                })
+        })
+    }
+
+    pub fn fetch_blocks<F : 'static + std::marker::Send>(&self, start_height: u64, end_height: u64, c: F)
+        where F : FnMut(&[u8], u64) {
+        // Blocks have to be delivered in order exactly once. A stream that fails before the first block
+        // came in is simply retried. One that dies part way, or ends without error but short of `end_height`,
+        // is reopened after the last block it delivered, skipping any block an earlier stream already passed on.
+        let c = Arc::new(Mutex::new(c));
+        let delivered = Arc::new(Mutex::new(HashSet::new()));
+        let mut from = start_height;
+        let mut resumes = 0;
+
+        let r = loop {
+            let received = Arc::new(AtomicBool::new(false));
+            let earlier = Arc::new(delivered.lock().unwrap().clone());
+
+            let t = self.stream_timeout;
+            let received_inner = received.clone();
+            let r = self.call_without_deadline("fetch_blocks", &|| !received.load(Ordering::SeqCst), |mut client| {
+                let (c, uri, received) = (c.clone(), self.uri(), received_inner.clone());
+                let (earlier, delivered) = (earlier.clone(), delivered.clone());
+
+                let bs = BlockId{ height: from, hash: vec!()};
+                let be = BlockId{ height: end_height,   hash: vec!()};
+
+                let br = Request::new(BlockRange{ start: Some(bs), end: Some(be)});
+                client
+                    .get_block_range(br)
+                    .map_err(|e| {
+                        format!("RouteChat request failed; err={:?}", e)
+                    })
+                    .and_then(move |response| {
+                        let inbound = response.into_inner();
+                        inbound
+                            .map_err(|e| format!("gRPC inbound stream error: {:?}", e))
+                            .timeout(t)
+                            .map_err(move |e| timeout_error(e, "stream_inactivity_timeout", t, &uri))
+                            .for_each(move |b| {
+                                use prost::Message;
+                                received.store(true, Ordering::SeqCst);
+
+                                // The block where the last stream broke off can come again
+                                if earlier.contains(&b.height) {
+                                    return Ok(());
+                                }
+
+                                let mut encoded_buf = vec![];
+                                b.encode(&mut encoded_buf).unwrap();
+                                delivered.lock().unwrap().insert(b.height);
+                                (c.lock().unwrap())(&encoded_buf, b.height);
+
+                                Ok(())
+                            })
+                    })
+            });
+
+            let e = match r {
+                Ok(_)  => match (from..=end_height).find(|h| !delivered.lock().unwrap().contains(h)) {
+                    None         => break Ok(()),
+                    Some(height) => format!("The block stream ended before block {}", height)
+                },
+                Err(e) => e
+            };
+            if !received.load(Ordering::SeqCst) || e.starts_with(MESSAGE_TOO_LARGE) {
+                break Err(e);
+            }
+
+            match (from..=end_height).find(|h| !delivered.lock().unwrap().contains(h)) {
+                None => break Ok(()),
+                Some(_) if resumes >= MAX_STREAM_RESUMES => 
+                    break Err(format!("{} (gave up after resuming the stream {} times)", e, resumes)),
+                Some(next) => {
+                    resumes += 1;
+                    warn!("Block stream broke off before {}, resuming ({}/{}). {}", next, resumes, MAX_STREAM_RESUMES, e);
+                    from = next;
+                }
+            }
+        };
+
+        match r {
+            Ok(_)  => {}, // The result is processed in callbacks, so nothing to do here
+            Err(e) => {
+                error!("Error while executing fetch_blocks: {}", e);
+                eprintln!("{}", e);
+            }
+        };
+    }
+
+    /// Like `fetch_blocks`, but `start_height..=end_height` is split into a chunk per connection in `conns`,
+    /// which are all downloaded at the same time. Blocks are passed to `c` as they come in, so they are
+    /// out of order across chunks, but every height is passed at most once. When a chunk's stream fails,
+    /// the rest of the chunk is fetched again on the next connection, until each one has been tried.
+    pub fn fetch_blocks_concurrently<F : 'static + std::marker::Send>(conns: &[Arc<GrpcConnection>], start_height: u64, end_height: u64, c: F)
+        where F : FnMut(&[u8], u64) {
+        let c = Arc::new(Mutex::new(c));
+
+        let threads = split_block_range(start_height, end_height, conns.len()).into_iter().enumerate()
+            .map(|(i, (start, end))| {
+                let (conns, c) = (conns.to_vec(), c.clone());
+
+                std::thread::spawn(move || {
+                    let received = Arc::new(Mutex::new(HashSet::new()));
+
+                    for attempt in 0..conns.len() {
+                        let from = match (start..=end).find(|h| !received.lock().unwrap().contains(h)) {
+                            Some(h) => h,
+                            None    => return,
+                        };
+
+                        let conn = &conns[(i + attempt) % conns.len()];
+                        if attempt > 0 {
+                            warn!("Fetching blocks {}-{} again from {}", from, end, conn.uri());
+                        }
+
+                        let (c, received) = (c.clone(), received.clone());
+                        conn.fetch_blocks(from, end, move |encoded_block: &[u8], height: u64| {
+                            if received.lock().unwrap().insert(height) {
+                                (c.lock().unwrap())(encoded_block, height);
+                            }
+                        });
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for t in threads {
+            if t.join().is_err() {
+                error!("A thread fetching blocks panicked");
+            }
+        }
+    }
+
+    /// The unspent outputs of the transparent addresses, as currently known to the server
+    pub fn get_address_utxos(&self, addresses: Vec<String>) -> Result<Vec<GetAddressUtxosReply>, String> {
+        self.call("get_address_utxos", &|| true, move |mut client| {
+            let arg = Request::new(GetAddressUtxosArg{ addresses: addresses.clone(), start_height: 0, max_entries: 0 });
+
+            client.get_address_utxos(arg)
+                .map_err(|e| format!("ERR = {:?}", e))
+                .and_then(move |response| {
+                    Ok(response.into_inner().address_utxos)
+                })
+        })
+    }
+
+    /// All the transactions involving the transparent address between the two heights, with their
+    /// full raw transaction bytes, in block order.
+    pub fn get_address_txids(&self, address: String, start_height: u64, end_height: u64) -> Result<Vec<RawTransaction>, String> {
+        // A retried stream starts over, so leave out what the failed one already sent
+        let txns = Arc::new(Mutex::new((vec![], HashSet::new())));
+        let txns_inner = txns.clone();
+        self.fetch_transparent_txids(address, start_height, end_height, move |tx_bytes: &[u8], height: u64| {
+            let (txns, seen) = &mut *txns_inner.lock().unwrap();
+            if seen.insert(txid_of(tx_bytes)) {
+                txns.push(RawTransaction { data: tx_bytes.to_vec(), height });
+            }
+        })?;
+
+        let txns = txns.lock().unwrap().0.clone();
+        Ok(txns)
+    }
+
+    /// Stream the transactions involving the transparent address between the two heights to `c`, with
+    /// their heights. If the stream breaks and is retried, `c` gets the transactions again.
+    pub fn fetch_transparent_txids<F : 'static + std::marker::Send + std::marker::Sync>(&self, address: String,
+        start_height: u64, end_height: u64, c: F) -> Result<(), String>
+            where F : Fn(&[u8], u64) {
+        // Scanning the same tx twice is harmless, so this can always be retried
+        let c = Arc::new(c);
+
+        let t = self.stream_timeout;
+        self.call_without_deadline("fetch_transparent_txids", &|| true, move |mut client| {
+            let (c, uri) = (c.clone(), self.uri());
+
+            let start = Some(BlockId{ height: start_height, hash: vec!()});
+            let end   = Some(BlockId{ height: end_height,   hash: vec!()});
+
+            let br = Request::new(TransparentAddressBlockFilter{ address: address.clone(), range: Some(BlockRange{start, end}) });
+
+            client
+                .get_address_txids(br)
+                .map_err(|e| {
+                    format!("RouteChat request failed; err={:?}", e)
+                })
+                .and_then(move |response| {
+                    let inbound = response.into_inner();
+                    inbound
+                        .map_err(|e| format!("gRPC inbound stream error: {:?}", e))
+                        .timeout(t)
+                        .map_err(move |e| timeout_error(e, "stream_inactivity_timeout", t, &uri))
+                        .for_each(move |tx| {
+                            //let tx = Transaction::read(&tx.into_inner().data[..]).unwrap();
+                            c(&tx.data, tx.height);
+
+                            Ok(())
+                        })
+                })
+        })
+    }
+
+    /// Get a full transaction by its txid. Recently fetched transactions are served from a small cache.
+    pub fn get_transaction(&self, txid: TxId) -> Result<RawTransaction, String> {
+        {
+            let mut cache = self.tx_cache.lock().unwrap();
+            if let Some(pos) = cache.iter().position(|(id, _)| *id == txid) {
+                let entry = cache.remove(pos).unwrap();
+                let rtx = entry.1.clone();
+                cache.push_front(entry);
+                return Ok(rtx);
+            }
+        }
+
+        let rtx = self.call("get_transaction", &|| true, move |mut client| {
+            // The hash is in the tx's internal byte order, which is the reverse of how txids are displayed
+            let txfilter = TxFilter { block: None, index: 0, hash: txid.0.to_vec() };
+            client.get_transaction(Request::new(txfilter))
+                .map_err(|e| format!("ERR = {:?}", e))
+                .and_then(move |response| Ok(response.into_inner()))
+        })?;
+
+        let mut cache = self.tx_cache.lock().unwrap();
+        cache.push_front((txid, rtx.clone()));
+        cache.truncate(TX_CACHE_SIZE);
+
+        Ok(rtx)
+    }
+
+    /// The raw bytes of a transaction, see `raw_transaction_data`
+    pub fn fetch_transaction(&self, txid: TxId) -> Result<Vec<u8>, String> {
+        raw_transaction_data(txid, self.get_transaction(txid))
+    }
+
+    pub fn fetch_full_tx<F : 'static + std::marker::Send + std::marker::Sync>(&self, txid: TxId, c: F)
+            where F : Fn(&[u8]) {
+        let c = Arc::new(c);
+
+        let r = self.call("fetch_full_tx", &|| true, move |mut client| {
+            let c = c.clone();
+
+            let txfilter = TxFilter { block: None, index: 0, hash: txid.0.to_vec() };
+            client.get_transaction(Request::new(txfilter))
+                .map_err(|e| {
+                    format!("RouteChat request failed; err={:?}", e)
+                })
+                .and_then(move |response| {
+                    c(&response.into_inner().data);
+
+                    Ok(())
+                })
+        });
+
+        match r {
+            Ok(_)  => {}, // The result is processed in callbacks, so nothing to do here
+            Err(e) => {
+                error!("Error while executing fetch_full_tx: {}", e);
+                eprintln!("{}", e);
+            }
+        };
+    }
+
+    pub fn broadcast_raw_tx(&self, tx_bytes: Box<[u8]>) -> Result<BroadcastResult, BroadcastError> {
+        // Once the transaction reached the server, we can't tell if it was accepted, so only
+        // retry if we couldn't connect at all.
+        let data = tx_bytes.to_vec();
+        let response = self.call("broadcast_raw_tx", &|| false, move |mut client| {
+            client.send_transaction(Request::new(RawTransaction {data: data.clone(), height: 0}))
+                .map_err(|e| {
+                    format!("ERR = {:?}", e)
+                })
+                .and_then(move |response| {
+                    Ok(response.into_inner())
+                })
+        }).map_err(BroadcastError::Connection)?;
+
+        parse_send_response(&tx_bytes, response)
+    }
+
+    /// Broadcast the transaction to this server and to all of `others` at the same time, so that one
+    /// server dropping it doesn't keep it from the network. Returns each server's outcome, this one first.
+    pub fn broadcast_raw_tx_everywhere(&self, others: &[Arc<GrpcConnection>], tx_bytes: Box<[u8]>)
+            -> Vec<(http::Uri, Result<BroadcastResult, BroadcastError>)> {
+        let threads = others.iter()
+            .map(|conn| {
+                let (conn, tx_bytes) = (conn.clone(), tx_bytes.clone());
+                std::thread::spawn(move || (conn.uri(), conn.broadcast_raw_tx(tx_bytes)))
+            })
+            .collect::<Vec<_>>();
+
+        let mut results = vec![(self.uri(), self.broadcast_raw_tx(tx_bytes))];
+        results.extend(threads.into_iter().zip(others.iter()).map(|(t, conn)| {
+            t.join().unwrap_or_else(|_| (conn.uri(), Err(BroadcastError::Connection("The broadcast panicked".to_string()))))
+        }));
+
+        results
+    }
+
+    /// The server's current chain tip
+    pub fn get_latest_block(&self) -> Result<BlockId, String> {
+        self.call("get_latest_block", &|| true, |mut client| {
+            client.get_latest_block(Request::new(ChainSpec {}))
+                .map_err(|e| { format!("ERR = {:?}", e) })
+                .and_then(move |response| {
+                    Ok(response.into_inner())
+                })
+        })
+    }
+
+    pub fn fetch_latest_block<F : 'static + std::marker::Send>(&self, c : F)
+        where F : FnMut(BlockId) {
+        let c = Arc::new(Mutex::new(c));
+
+        let r = self.call("fetch_latest_block", &|| true, move |mut client| {
+            let c = c.clone();
+
+            client.get_latest_block(Request::new(ChainSpec {}))
+                .map_err(|e| { format!("ERR = {:?}", e) })
+                .and_then(move |response| {
+                    (c.lock().unwrap())(response.into_inner());
+                    Ok(())
+                })
+        });
+
+        match r {
+            Ok(_)  => {}, // The result is processed in callbacks, so nothing to do here
+            Err(e) => {
+                error!("Error while executing fetch_latest_block: {}", e);
+                eprintln!("{}", e);
+            }
+        };
+    }
+}
+
+/// One-shot getinfo, used before a `LightClient` (and its connection) exists.
+pub fn get_info(uri: http::Uri, no_cert: bool, proxy: Option<String>) -> Result<LightdInfo, String> {
+    GrpcConnection::new(uri, no_cert, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, DEFAULT_MAX_ATTEMPTS, proxy).get_info()
+}
+
+/// Timeout for each step of a ping, so that a dead server is reported quickly
+pub const PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[cfg(test)]
+pub mod tests {
+    use super::{GrpcConnection, split_block_range, parse_proxy, txid_from_hex, txid_of, parse_send_response, raw_transaction_data, broadcast_outcome, BroadcastResult, BroadcastError, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, DEFAULT_MAX_ATTEMPTS};
+
+    #[test]
+    pub fn test_connection_is_lazy() {
+        // Creating a connection doesn't dial the server, so this should not fail
+        // even though nothing is listening.
+        let conn = GrpcConnection::new("https://127.0.0.1:1".parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None);
+        assert!(conn.client.lock().unwrap().is_none());
+
+        // Calls against a server that isn't there return an error instead of panicking,
+        // and don't leave a broken connection behind.
+        assert!(conn.get_info().is_err());
+        assert!(conn.client.lock().unwrap().is_none());
+    }
+
+    #[test]
+    pub fn test_keepalive_idle() {
+        use std::time::Duration;
+
+        // A connection that hasn't been used yet is not idle, it's just new
+        let conn = GrpcConnection::new("https://127.0.0.1:1".parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None)
+                    .with_keepalive(Some(Duration::from_millis(10)), Duration::from_secs(1));
+        assert!(!conn.is_idle());
+
+        conn.touch();
+        assert!(!conn.is_idle());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(conn.is_idle());
+
+        // With keepalive off, connections are used as they are
+        let conn = conn.with_keepalive(None, Duration::from_secs(1));
+        assert!(!conn.is_idle());
+    }
+
+    #[test]
+    pub fn test_connect_timeout() {
+        use std::net::TcpListener;
+        use std::time::{Duration, Instant};
+
+        // A listener that never accepts. The connection only gets as far as its backlog, so nothing
+        // ever answers, and the connection has to time out instead of hanging.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = GrpcConnection::new(format!("https://{}", listener.local_addr().unwrap()).parse().unwrap(), false,
+                                       Duration::from_millis(500), DEFAULT_UNARY_TIMEOUT, 1, None);
+
+        let start = Instant::now();
+        let e = conn.get_info().unwrap_err();
+        assert!(e.contains("timed out after 500ms"), "{}", e);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        drop(listener);
+    }
+
+    /// A server that accepts one connection and then stalls. If `http2` is set, it completes the HTTP/2
+    /// handshake in plaintext first, and if `headers` is also set, it answers the first request with
+    /// response headers, but never sends a message or ends the response.
+    fn slow_server(http2: bool, headers: bool) -> std::net::SocketAddr {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::time::Duration;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+
+            if http2 {
+                let mut preface = [0u8; 24];
+                sock.read_exact(&mut preface).unwrap();
+                sock.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+
+                // Acknowledge the client's settings, and wait for the request to come in
+                loop {
+                    let mut frame = [0u8; 9];
+                    if sock.read_exact(&mut frame).is_err() {
+                        return;
+                    }
+                    let len = ((frame[0] as usize) << 16) | ((frame[1] as usize) << 8) | frame[2] as usize;
+                    let mut payload = vec![0u8; len];
+                    sock.read_exact(&mut payload).unwrap();
+
+                    match (frame[3], frame[4] & 0x1) {
+                        (4, 0) => sock.write_all(&[0, 0, 0, 4, 1, 0, 0, 0, 0]).unwrap(),
+                        (1, _) => {
+                            if headers {
+                                // :status 200, content-type: application/grpc
+                                let mut block = vec![0x88, 0x0f, 0x10, 16];
+                                block.extend_from_slice(b"application/grpc");
+
+                                let mut response = vec![0, 0, block.len() as u8, 1, 0x4];
+                                response.extend_from_slice(&frame[5..9]);
+                                response.extend_from_slice(&block);
+                                sock.write_all(&response).unwrap();
+                            }
+                            break;
+                        },
+                        _ => {}
+                    }
+                }
+            }
+
+            // Hold the connection open without saying anything else
+            std::thread::sleep(Duration::from_secs(30));
+            drop(sock);
+        });
+
+        addr
+    }
+
+    /// A plaintext HTTP/2 server that answers each GetBlockRange on a new connection. The n-th stream
+    /// breaks off, by dropping the connection, right after the block at `breaks[n]`, or with `clean`, ends
+    /// there as if it had sent everything. Resumed streams start one block early, like a server that sends
+    /// the block it broke off at again. Returns the ranges it was asked for.
+    fn block_server(breaks: Vec<u64>, clean: bool) -> (std::net::SocketAddr, std::sync::Arc<std::sync::Mutex<Vec<(u64, u64)>>>) {
+        paced_block_server(breaks, clean, std::time::Duration::from_millis(0))
+    }
+
+    /// A `block_server` that waits `per_block` before sending each block, like a server far away, whose
+    /// streams are each slower than the line. Its connections are served side by side.
+    fn paced_block_server(breaks: Vec<u64>, clean: bool, per_block: std::time::Duration)
+            -> (std::net::SocketAddr, std::sync::Arc<std::sync::Mutex<Vec<(u64, u64)>>>) {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use prost::Message;
+        use crate::grpc_client::{BlockRange, CompactBlock};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let requests_inner = requests.clone();
+
+        std::thread::spawn(move || {
+            for n in 0.. {
+                let (mut sock, _) = match listener.accept() {
+                    Ok(s)  => s,
+                    Err(_) => return
+                };
+                let (breaks, requests_inner) = (breaks.clone(), requests_inner.clone());
+
+                std::thread::spawn(move || {
+                    let mut preface = [0u8; 24];
+                    sock.read_exact(&mut preface).unwrap();
+                    sock.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+
+                    // Read frames until the whole request message is in
+                    let mut stream_id = [0u8; 4];
+                    let mut body = vec![];
+                    while body.len() < 5 || body.len() < 5 + u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize {
+                        let mut frame = [0u8; 9];
+                        sock.read_exact(&mut frame).unwrap();
+                        let len = ((frame[0] as usize) << 16) | ((frame[1] as usize) << 8) | frame[2] as usize;
+                        let mut payload = vec![0u8; len];
+                        sock.read_exact(&mut payload).unwrap();
+
+                        match (frame[3], frame[4] & 0x1) {
+                            (4, 0) => sock.write_all(&[0, 0, 0, 4, 1, 0, 0, 0, 0]).unwrap(),
+                            (1, _) => stream_id.copy_from_slice(&frame[5..9]),
+                            (0, _) => body.extend_from_slice(&payload),
+                            _      => {}
+                        }
+                    }
+
+                    let range = BlockRange::decode(&body[5..]).unwrap();
+                    let (start, end) = (range.start.unwrap().height, range.end.unwrap().height);
+                    requests_inner.lock().unwrap().push((start, end));
+
+                    let frame = |kind: u8, flags: u8, payload: &[u8]| {
+                        let mut f = vec![0, (payload.len() >> 8) as u8, payload.len() as u8, kind, flags];
+                        f.extend_from_slice(&stream_id);
+                        f.extend_from_slice(payload);
+                        f
+                    };
+
+                    // :status 200, content-type: application/grpc
+                    let mut headers = vec![0x88, 0x0f, 0x10, 16];
+                    headers.extend_from_slice(b"application/grpc");
+                    sock.write_all(&frame(1, 0x4, &headers)).unwrap();
+
+                    let first = if n > 0 && !breaks.is_empty() { start - 1 } else { start };
+                    for height in first..=end {
+                        std::thread::sleep(per_block);
+
+                        let mut m = vec![];
+                        CompactBlock { height, ..Default::default() }.encode(&mut m).unwrap();
+
+                        let mut data = vec![0];
+                        data.extend_from_slice(&(m.len() as u32).to_be_bytes());
+                        data.extend_from_slice(&m);
+                        sock.write_all(&frame(0, 0, &data)).unwrap();
+
+                        if breaks.get(n) == Some(&height) {
+                            break;
+                        }
+                    }
+
+                    if breaks.get(n).is_none() || clean {
+                        // Trailers, grpc-status: 0
+                        let mut trailers = vec![0x00, 11];
+                        trailers.extend_from_slice(b"grpc-status");
+                        trailers.extend_from_slice(&[1, b'0']);
+                        sock.write_all(&frame(1, 0x5, &trailers)).unwrap();
+                    }
+
+                    // Give the client time to read everything before the connection goes
+                    std::thread::sleep(Duration::from_millis(200));
+                    drop(sock);
+                });
+            }
+        });
+
+        (addr, requests)
+    }
+
+    /// A plaintext HTTP/2 server that answers each request, on as many connections and streams as it's asked
+    /// to, with the messages `answer` returns for its body, and then a grpc-status of 0. Returns how many 
+    /// connections it accepted.
+    fn grpc_server<F>(answer: F) -> (std::net::SocketAddr, std::sync::Arc<std::sync::atomic::AtomicUsize>)
+            where F: Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static {
+        use std::collections::HashMap;
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let connections_inner = connections.clone();
+        let answer = Arc::new(answer);
+
+        std::thread::spawn(move || {
+            for sock in listener.incoming() {
+                let mut sock = match sock {
+                    Ok(s)  => s,
+                    Err(_) => return
+                };
+                connections_inner.fetch_add(1, Ordering::SeqCst);
+                let answer = answer.clone();
+
+                std::thread::spawn(move || {
+                    let mut preface = [0u8; 24];
+                    if sock.read_exact(&mut preface).is_err() {
+                        return;
+                    }
+                    sock.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+
+                    // The request bodies so far, by stream
+                    let mut bodies: HashMap<[u8; 4], Vec<u8>> = HashMap::new();
+                    loop {
+                        let mut frame = [0u8; 9];
+                        if sock.read_exact(&mut frame).is_err() {
+                            return;
+                        }
+                        let len = ((frame[0] as usize) << 16) | ((frame[1] as usize) << 8) | frame[2] as usize;
+                        let mut payload = vec![0u8; len];
+                        if sock.read_exact(&mut payload).is_err() {
+                            return;
+                        }
+
+                        let mut stream_id = [0u8; 4];
+                        stream_id.copy_from_slice(&frame[5..9]);
+                        let reply = |kind: u8, flags: u8, payload: &[u8]| {
+                            let mut f = vec![0, (payload.len() >> 8) as u8, payload.len() as u8, kind, flags];
+                            f.extend_from_slice(&stream_id);
+                            f.extend_from_slice(payload);
+                            f
+                        };
+
+                        match (frame[3], frame[4] & 0x1) {
+                            (4, 0) => sock.write_all(&[0, 0, 0, 4, 1, 0, 0, 0, 0]).unwrap(),
+                            (6, 0) => sock.write_all(&reply(6, 0x1, &payload)).unwrap(),
+                            (0, end) => {
+                                bodies.entry(stream_id).or_insert_with(Vec::new).extend_from_slice(&payload);
+                                if end == 0 {
+                                    continue;
+                                }
+
+                                // The whole request is in
+                                let body = bodies.remove(&stream_id).unwrap_or_default();
+                                let messages = answer(if body.len() >= 5 { &body[5..] } else { &[] });
+
+                                // :status 200, content-type: application/grpc
+                                let mut headers = vec![0x88, 0x0f, 0x10, 16];
+                                headers.extend_from_slice(b"application/grpc");
+                                sock.write_all(&reply(1, 0x4, &headers)).unwrap();
+
+                                for m in messages {
+                                    let mut data = vec![0];
+                                    data.extend_from_slice(&(m.len() as u32).to_be_bytes());
+                                    data.extend_from_slice(&m);
+                                    sock.write_all(&reply(0, 0, &data)).unwrap();
+                                }
+
+                                // Trailers, grpc-status: 0
+                                let mut trailers = vec![0x00, 11];
+                                trailers.extend_from_slice(b"grpc-status");
+                                trailers.extend_from_slice(&[1, b'0']);
+                                sock.write_all(&reply(1, 0x5, &trailers)).unwrap();
+                            },
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+
+        (addr, connections)
+    }
+
+    /// A `grpc_server` that answers every GetLightdInfo with a "main" chain at `height`
+    pub fn info_server(height: u64) -> (std::net::SocketAddr, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        flaky_info_server(height, std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true)))
+    }
+
+    /// An `info_server` that, while `up` is false, answers with a message that isn't a LightdInfo, so
+    /// every GetLightdInfo fails
+    pub fn flaky_info_server(height: u64, up: std::sync::Arc<std::sync::atomic::AtomicBool>)
+            -> (std::net::SocketAddr, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::Ordering;
+        use prost::Message;
+        use crate::grpc_client::LightdInfo;
+
+        grpc_server(move |_| {
+            if !up.load(Ordering::SeqCst) {
+                return vec![vec![0xff]];
+            }
+
+            let mut m = vec![];
+            LightdInfo { chain_name: "main".to_string(), block_height: height, ..Default::default() }
+                .encode(&mut m).unwrap();
+            vec![m]
+        })
+    }
+
+    /// Just enough of a lightwalletd for a wallet to sync `blocks`, encoded CompactBlocks in order, from it.
+    /// They can be added to while it runs. Requests are told apart by what they ask for: an empty one is a
+    /// GetLatestBlock, answered with the top block's height and hash, a range is a GetBlockRange, and a t
+    /// address gets the `taddr_txs` (height, raw transaction) in its range, whatever the address. Anything
+    /// else gets an empty message, so GetTransaction gets a transaction that can't be read, and GetLightdInfo
+    /// fails, as if the server didn't say what it supports.
+    pub fn lightwalletd_server(blocks: std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>, taddr_txs: Vec<(u64, Vec<u8>)>)
+            -> std::net::SocketAddr {
+        use prost::Message;
+        use crate::grpc_client::{BlockId, BlockRange, CompactBlock, RawTransaction, TransparentAddressBlockFilter};
+
+        let (addr, _) = grpc_server(move |body| {
+            let blocks = blocks.lock().unwrap().iter()
+                .map(|b| (CompactBlock::decode(&b[..]).unwrap().height, b.clone()))
+                .collect::<Vec<_>>();
+
+            if body.is_empty() {
+                let tip = blocks.iter().map(|(_, b)| CompactBlock::decode(&b[..]).unwrap()).max_by_key(|cb| cb.height);
+                let (height, hash) = tip.map(|cb| (cb.height, cb.hash)).unwrap_or_default();
+
+                let mut m = vec![];
+                BlockId { height, hash }.encode(&mut m).unwrap();
+                return vec![m];
+            }
+
+            if let Ok(filter) = TransparentAddressBlockFilter::decode(body) {
+                if filter.address.starts_with('t') {
+                    let range = filter.range.unwrap_or_default();
+                    let (start, end) = (range.start.unwrap_or_default().height, range.end.unwrap_or_default().height);
+
+                    return taddr_txs.iter()
+                        .filter(|(h, _)| *h >= start && *h <= end)
+                        .map(|(height, data)| {
+                            let mut m = vec![];
+                            RawTransaction { data: data.clone(), height: *height }.encode(&mut m).unwrap();
+                            m
+                        })
+                        .collect();
+                }
+            }
+
+            match BlockRange::decode(body) {
+                Ok(BlockRange { start: Some(start), end: Some(end) }) => {
+                    blocks.into_iter()
+                        .filter(|(h, _)| *h >= start.height && *h <= end.height)
+                        .map(|(_, b)| b)
+                        .collect()
+                },
+                _ => vec![vec![]]
+            }
+        });
+
+        addr
+    }
+
+    fn fetch_heights(addr: std::net::SocketAddr, start: u64, end: u64) -> Vec<u64> {
+        use std::sync::{Arc, Mutex};
+
+        let conn = GrpcConnection::new(format!("http://{}", addr).parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None);
+        let heights = Arc::new(Mutex::new(vec![]));
+        let heights_inner = heights.clone();
+        conn.fetch_blocks(start, end, move |_, height| heights_inner.lock().unwrap().push(height));
+
+        let heights = heights.lock().unwrap().clone();
+        heights
+    }
+
+    #[test]
+    pub fn test_calls_share_one_connection() {
+        use std::sync::atomic::Ordering;
+
+        let (addr, connections) = info_server(1000);
+        let conn = GrpcConnection::new(format!("http://{}", addr).parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None);
+        for _ in 0..10 {
+            assert_eq!(conn.get_info().unwrap().block_height, 1000);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    pub fn test_plaintext_server() {
+        // An http uri speaks h2c straight over TCP, no certificates needed
+        let (addr, _) = info_server(1234);
+        let conn = GrpcConnection::new(format!("http://{}", addr).parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None);
+        let info = conn.get_info().unwrap();
+        assert_eq!(info.chain_name, "main");
+        assert_eq!(info.block_height, 1234);
+
+        // An https uri to the same server starts a TLS handshake, which it doesn't understand
+        let conn = GrpcConnection::new(format!("https://{}", addr).parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None);
+        assert!(conn.get_info().is_err());
+    }
+
+    #[test]
+    pub fn test_fetch_blocks_resumes() {
+        // Each stream starts after the last block the previous one delivered, and the block that is
+        // sent twice is only passed on once
+        let (addr, requests) = block_server(vec![14, 22], false);
+        assert_eq!(fetch_heights(addr, 10, 30), (10..=30).collect::<Vec<_>>());
+        assert_eq!(*requests.lock().unwrap(), vec![(10, 30), (15, 30), (23, 30)]);
+    }
+
+    #[test]
+    pub fn test_fetch_blocks_resumes_short_stream() {
+        // Streams that end without an error, but before the last block, are resumed too, and the
+        // blocks still come in order, each once
+        let (addr, requests) = block_server(vec![14, 22], true);
+        assert_eq!(fetch_heights(addr, 10, 30), (10..=30).collect::<Vec<_>>());
+        assert_eq!(*requests.lock().unwrap(), vec![(10, 30), (15, 30), (23, 30)]);
+    }
+
+    #[test]
+    pub fn test_fetch_blocks_gives_up_resuming() {
+        use super::MAX_STREAM_RESUMES;
+
+        let (addr, requests) = block_server(vec![11, 12, 13, 14, 15], false);
+        assert_eq!(fetch_heights(addr, 10, 30), (10..=14).collect::<Vec<_>>());
+        assert_eq!(requests.lock().unwrap().len(), 1 + MAX_STREAM_RESUMES as usize);
+    }
+
+    #[test]
+    pub fn test_fetch_blocks_concurrently_refetches_failed_chunk() {
+        use std::sync::{Arc, Mutex};
+
+        // The first server keeps breaking off the first chunk until it's given up on, and the second
+        // server, which also has the second chunk, fetches the rest of it
+        let (broken, broken_requests) = block_server(vec![50, 60, 70, 80], false);
+        let (working, working_requests) = block_server(vec![], false);
+        let conns = [broken, working].iter()
+            .map(|addr| Arc::new(GrpcConnection::new(format!("http://{}", addr).parse().unwrap(), false,
+                                                     DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None)))
+            .collect::<Vec<_>>();
+
+        let heights = Arc::new(Mutex::new(vec![]));
+        let heights_inner = heights.clone();
+        GrpcConnection::fetch_blocks_concurrently(&conns, 1, 400, move |_, height| heights_inner.lock().unwrap().push(height));
+
+        let mut heights = heights.lock().unwrap().clone();
+        heights.sort();
+        assert_eq!(heights, (1..=400).collect::<Vec<_>>());
+        assert_eq!(*broken_requests.lock().unwrap(), vec![(1, 200), (51, 200), (61, 200), (71, 200)]);
+
+        let mut working_requests = working_requests.lock().unwrap().clone();
+        working_requests.sort();
+        assert_eq!(working_requests, vec![(81, 200), (201, 400)]);
+    }
+
+    /// Not a test, but how long fetching blocks from a far away server takes over one and more connections.
+    /// Run it with
+    ///     cargo test --release -- --ignored bench_fetch_blocks_concurrently --nocapture
+    #[test]
+    #[ignore]
+    pub fn bench_fetch_blocks_concurrently() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::time::{Duration, Instant};
+        use super::MAX_BLOCK_CONNECTIONS;
+
+        const BLOCKS: u64 = 2_000;
+        let (addr, _) = paced_block_server(vec![], false, Duration::from_millis(1));
+
+        for n in 1..=MAX_BLOCK_CONNECTIONS {
+            let conns = (0..n)
+                .map(|_| Arc::new(GrpcConnection::new(format!("http://{}", addr).parse().unwrap(), false,
+                                                      DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None)))
+                .collect::<Vec<_>>();
+
+            let blocks = Arc::new(AtomicU64::new(0));
+            let blocks_inner = blocks.clone();
+            let start = Instant::now();
+            GrpcConnection::fetch_blocks_concurrently(&conns, 1, BLOCKS, move |_, _| { blocks_inner.fetch_add(1, Ordering::SeqCst); });
+            let elapsed = start.elapsed();
+
+            assert_eq!(blocks.load(Ordering::SeqCst), BLOCKS);
+            println!("{} blocks over {} connection(s): {:?}, {:.0} blocks/s", 
+                BLOCKS, n, elapsed, BLOCKS as f64 / (elapsed.as_millis() as f64 / 1000.0));
+        }
+    }
+
+    #[test]
+    pub fn test_connect_timeout_names_limit() {
+        use std::time::{Duration, Instant};
+
+        // The server never answers the TLS handshake
+        let addr = slow_server(false, false);
+        let conn = GrpcConnection::new(format!("https://{}", addr).parse().unwrap(), false,
+                                       Duration::from_millis(500), DEFAULT_UNARY_TIMEOUT, 1, None);
+
+        let start = Instant::now();
+        let e = conn.get_info().unwrap_err();
+        assert!(e.contains("timed out after 500ms (connect_timeout)"), "{}", e);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    pub fn test_ip_override() {
+        use std::time::Duration;
+
+        // The name doesn't resolve, so without the override it never gets to the server
+        let addr = slow_server(false, false);
+        let uri: http::Uri = format!("https://lightwalletd.invalid:{}", addr.port()).parse().unwrap();
+        let conn = GrpcConnection::new(uri.clone(), false, Duration::from_millis(500), DEFAULT_UNARY_TIMEOUT, 1, None);
+        assert!(conn.get_info().unwrap_err().contains("Couldn't resolve server lightwalletd.invalid"));
+
+        // With it, it connects to the IP, and gets as far as the TLS handshake
+        let conn = conn.with_ip_override(Some(("lightwalletd.invalid".to_string(), addr.ip())));
+        let e = conn.get_info().unwrap_err();
+        assert!(e.contains("timed out after 500ms (connect_timeout)"), "{}", e);
+
+        // Other hosts are still resolved
+        let conn = GrpcConnection::new(uri, false, Duration::from_millis(500), DEFAULT_UNARY_TIMEOUT, 1, None)
+                    .with_ip_override(Some(("example.invalid".to_string(), addr.ip())));
+        assert!(conn.get_info().unwrap_err().contains("Couldn't resolve server lightwalletd.invalid"));
+    }
+
+    #[test]
+    pub fn test_unary_timeout_names_limit() {
+        use std::time::Duration;
+
+        // The connection comes up, but the server never answers the request
+        let addr = slow_server(true, false);
+        let conn = GrpcConnection::new(format!("http://{}", addr).parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, Duration::from_millis(500), 1, None);
+
+        let e = conn.get_info().unwrap_err();
+        assert!(e.contains("timed out after 500ms (unary_timeout)"), "{}", e);
+    }
+
+    #[test]
+    pub fn test_stream_inactivity_timeout_names_limit() {
+        use std::time::Duration;
+
+        // The server starts the response, but never sends anything on the stream
+        let addr = slow_server(true, true);
+        let conn = GrpcConnection::new(format!("http://{}", addr).parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None)
+                    .with_stream_inactivity_timeout(Duration::from_millis(500));
+
+        let e = conn.get_address_txids("t1Address".to_string(), 0, 1).unwrap_err();
+        assert!(e.contains("timed out after 500ms (stream_inactivity_timeout)"), "{}", e);
+    }
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/mtls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    pub fn test_load_client_cert() {
+        use super::load_client_cert;
+        let load = |cert: &str, key: &str| load_client_cert(&Some(testdata(cert)), &Some(testdata(key)));
+
+        assert!(load_client_cert(&None, &None).unwrap().is_none());
+        assert_eq!(load("client.pem", "client.key").unwrap().unwrap().0.len(), 1);
+
+        assert!(load_client_cert(&Some(testdata("client.pem")), &None).unwrap_err().contains("have to be given together"));
+        assert!(load("missing.pem", "client.key").unwrap_err().starts_with("Couldn't read client certificate"));
+        assert!(load("client.pem", "missing.key").unwrap_err().starts_with("Couldn't read client key"));
+        assert!(load("client.key", "client.key").unwrap_err().starts_with("No certificates found"));
+        assert!(load("client.pem", "client.pem").unwrap_err().starts_with("No PKCS8 or RSA private key found"));
+
+        // A key that isn't the certificate's
+        assert!(load("client.pem", "other.key").unwrap_err().contains("doesn't belong to the certificate"));
+        assert!(load("untrusted.pem", "client.key").unwrap_err().contains("doesn't belong to the certificate"));
+    }
+
+    /// A TLS server that requires a client certificate issued by the test CA. It reports whether the
+    /// handshake went through, and with how many client certificates.
+    fn mtls_server() -> (std::net::SocketAddr, std::sync::mpsc::Receiver<Result<usize, String>>) {
+        use std::net::TcpListener;
+        use std::sync::Arc;
+        use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, RootCertStore, ServerConfig, ServerSession, Session};
+        use tokio_rustls::rustls::internal::pemfile;
+
+        let pem = |name: &str| std::fs::read(testdata(name)).unwrap();
+        let ca = pemfile::certs(&mut &pem("ca.pem")[..]).unwrap();
+        let certs = pemfile::certs(&mut &pem("server.pem")[..]).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut &pem("server.key")[..]).unwrap().remove(0);
+
+        let mut roots = RootCertStore::empty();
+        roots.add(&ca[0]).unwrap();
+        let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots));
+        config.set_single_cert(certs, key).unwrap();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut session = ServerSession::new(&config);
+
+            let mut r = Ok(());
+            while r.is_ok() && session.is_handshaking() {
+                r = session.complete_io(&mut sock).map(|_| ());
+            }
+
+            tx.send(r.map(|_| session.get_peer_certificates().map_or(0, |c| c.len())).map_err(|e| e.to_string())).unwrap();
+        });
+
+        (addr, rx)
+    }
+
+    #[test]
+    #[cfg(feature = "dangerous-tls")]
+    pub fn test_client_cert_handshake() {
+        let handshake = |cert: Option<&str>, key: Option<&str>| {
+            let (addr, rx) = mtls_server();
+
+            // The server certificate is from the test CA, so it isn't checked
+            let conn = GrpcConnection::new(format!("https://{}", addr).parse().unwrap(), true,
+                                           DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None)
+                        .with_client_cert(cert.map(testdata), key.map(testdata));
+
+            // The mock server doesn't speak gRPC, so the call itself always fails
+            assert!(conn.get_info().is_err());
+            rx.recv().unwrap()
+        };
+
+        assert_eq!(handshake(Some("client.pem"), Some("client.key")), Ok(1));
+
+        // No certificate, or one from a CA the server doesn't trust. A key that doesn't belong to the
+        // certificate isn't even loaded, see test_load_client_cert.
+        assert!(handshake(None, None).is_err());
+        assert!(handshake(Some("untrusted.pem"), Some("other.key")).is_err());
+    }
+
+    #[test]
+    pub fn test_retry_attempts() {
+        // Connection failures are retried, and the error says how many attempts were made
+        let conn = GrpcConnection::new("https://127.0.0.1:1".parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, DEFAULT_MAX_ATTEMPTS, None);
+
+        let e = conn.get_info().unwrap_err();
+        assert!(e.starts_with(&format!("get_info failed after {} attempts", DEFAULT_MAX_ATTEMPTS)));
+    }
+
+    #[test]
+    pub fn test_failover_needs_a_usable_server() {
+        let servers = vec!["https://127.0.0.1:1".parse().unwrap(), "https://127.0.0.1:2".parse().unwrap()];
+        let conn = GrpcConnection::with_servers(servers, false, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None).unwrap()
+                    .expecting_chain("main".to_string(), 0);
+        assert_eq!(conn.uri(), "https://127.0.0.1:1".parse::<http::Uri>().unwrap());
+
+        // Neither server can be reached, so the call fails, and it stays on the first server
+        assert!(conn.get_info().is_err());
+        assert_eq!(conn.failovers(), 0);
+        assert_eq!(conn.uri(), "https://127.0.0.1:1".parse::<http::Uri>().unwrap());
+    }
+
+    #[test]
+    pub fn test_change_servers() {
+        let servers = vec!["https://127.0.0.1:1".parse().unwrap(), "https://127.0.0.1:2".parse().unwrap()];
+        let conn = GrpcConnection::with_servers(servers, false, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None).unwrap();
+        conn.select_fastest();
+        assert_eq!(conn.measurements().len(), 2);
+
+        // The new server is used right away, and the old measurements are gone
+        conn.change_servers(vec!["http://127.0.0.1:3".parse().unwrap()]).unwrap();
+        assert_eq!(conn.uri(), "http://127.0.0.1:3".parse::<http::Uri>().unwrap());
+        assert!(conn.measurements().is_empty());
+        assert!(conn.get_info().is_err());
+        assert_eq!(conn.uri(), "http://127.0.0.1:3".parse::<http::Uri>().unwrap());
+
+        // There has to be a server to change to
+        assert_eq!(conn.change_servers(vec![]).unwrap_err(), NO_SERVERS_ERROR);
+        assert_eq!(conn.uri(), "http://127.0.0.1:3".parse::<http::Uri>().unwrap());
+        assert!(GrpcConnection::with_servers(vec![], false, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None).is_err());
+    }
+
+    #[test]
+    pub fn test_split_block_range() {
+        assert_eq!(split_block_range(1, 1000, 1), vec![(1, 1000)]);
+        assert_eq!(split_block_range(1, 1000, 4), vec![(1, 250), (251, 500), (501, 750), (751, 1000)]);
+        assert_eq!(split_block_range(1, 1001, 3), vec![(1, 334), (335, 668), (669, 1001)]);
+
+        // Short ranges aren't split into tiny chunks
+        assert_eq!(split_block_range(1, 250, 4), vec![(1, 125), (126, 250)]);
+        assert_eq!(split_block_range(5, 5, 4), vec![(5, 5)]);
+        assert!(split_block_range(6, 5, 4).is_empty());
+    }
+
+    #[test]
+    pub fn test_fetch_blocks_concurrently_without_servers_up() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let conns = ["https://127.0.0.1:1", "https://127.0.0.1:2"].iter()
+            .map(|s| Arc::new(GrpcConnection::new(s.parse().unwrap(), false, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None)))
+            .collect::<Vec<_>>();
+
+        // Every chunk is tried on both connections, and then given up on
+        let blocks = Arc::new(AtomicUsize::new(0));
+        let blocks_inner = blocks.clone();
+        GrpcConnection::fetch_blocks_concurrently(&conns, 1, 1000, move |_, _| { blocks_inner.fetch_add(1, Ordering::SeqCst); });
+        assert_eq!(blocks.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    pub fn test_select_fastest_without_servers_up() {
+        let servers = vec!["https://127.0.0.1:1".parse().unwrap(), "https://127.0.0.1:2".parse().unwrap()];
+        let conn = GrpcConnection::with_servers(servers, false, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None).unwrap();
+        assert!(conn.measurements().is_empty());
+
+        // Every server is measured, none of them is selected, and the connection stays where it was
+        let m = conn.select_fastest();
+        assert_eq!(m.len(), 2);
+        assert!(m.iter().all(|m| m.error.is_some() && m.latency.is_none() && !m.selected));
+        assert_eq!(conn.measurements().len(), 2);
+        assert_eq!(conn.uri(), "https://127.0.0.1:1".parse::<http::Uri>().unwrap());
+    }
+
+    #[test]
+    #[cfg(not(feature = "dangerous-tls"))]
+    pub fn test_no_cert_forbidden() {
+        let conn = GrpcConnection::new("https://127.0.0.1:1".parse().unwrap(), true,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None);
+        assert!(conn.get_info().unwrap_err().contains("doesn't allow turning off TLS certificate verification"));
+    }
+
+    #[test]
+    pub fn test_ping_dead_server() {
+        use std::time::{Duration, Instant};
+
+        let conn = GrpcConnection::new("https://127.0.0.1:2".parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, DEFAULT_MAX_ATTEMPTS, None);
+
+        let start = Instant::now();
+        assert!(conn.ping("https://127.0.0.1:1".parse().unwrap(), 3).is_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    #[cfg(feature = "dangerous-tls")]
+    pub fn test_select_fastest_with_client_cert() {
+        use std::net::{IpAddr, Ipv4Addr};
+        use super::IpVersion;
+
+        // Servers that require a client certificate get the connection's when they are pinged
+        let (addr, rx) = mtls_server();
+        let conn = GrpcConnection::new(format!("https://{}", addr).parse().unwrap(), true,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None)
+                    .with_client_cert(Some(testdata("client.pem")), Some(testdata("client.key")));
+        conn.select_fastest();
+        assert_eq!(rx.recv().unwrap(), Ok(1));
+
+        // And the rest of the connection's settings, only the server is different
+        let conn = conn.with_ip_version(IpVersion::V4)
+                    .with_ip_override(Some(("example.com".to_string(), IpAddr::V4(Ipv4Addr::LOCALHOST))))
+                    .with_client_name(Some("MyWallet/2.1".to_string()));
+        let probe = conn.probe_connection("https://example.com".parse().unwrap());
+        assert_eq!(probe.uri(), "https://example.com".parse::<http::Uri>().unwrap());
+        assert_eq!(probe.client_cert, conn.client_cert);
+        assert_eq!(probe.ip_version, IpVersion::V4);
+        assert_eq!(probe.ip_override, conn.ip_override);
+        assert_eq!(probe.client_header, conn.client_header);
+        assert!(probe.no_cert);
+    }
+
+    #[test]
+    pub fn test_parse_send_response() {
+        use crate::grpc_client::SendResponse;
+
+        let tx = vec![1u8, 2, 3, 4];
+        let txid = txid_of(&tx);
+        assert_eq!(txid.len(), 64);
+
+        // The txid comes from the transaction, whether or not the server echoes it, and however it's quoted
+        for msg in vec![format!("\"{}\"", txid), txid.clone(), "".to_string(), "ok".to_string()] {
+            let r = parse_send_response(&tx, SendResponse{ error_code: 0, error_message: msg.clone() }).unwrap();
+            assert_eq!(r.txid, txid);
+            assert_eq!(r.server_message, msg);
+        }
+
+        let err = |code, msg: &str| parse_send_response(&tx, SendResponse{ error_code: code, error_message: msg.to_string() }).unwrap_err();
+        assert_eq!(err(-26, "18: txn-already-in-mempool"), BroadcastError::AlreadyKnown("18: txn-already-in-mempool".to_string()));
+        assert_eq!(err(-27, "transaction already in block chain"), BroadcastError::AlreadyKnown("transaction already in block chain".to_string()));
+        assert_eq!(err(-26, "tx-expiring-soon"), BroadcastError::Expired("tx-expiring-soon".to_string()));
+        assert_eq!(err(-26, "66: insufficient priority"), BroadcastError::InsufficientFee("66: insufficient priority".to_string()));
+        assert_eq!(err(-26, "16: bad-txns-inputs-spent"), BroadcastError::Rejected(-26, "16: bad-txns-inputs-spent".to_string()));
+    }
+
+    #[test]
+    pub fn test_raw_transaction_data() {
+        use crate::grpc_client::RawTransaction;
+
+        let tx = vec![1u8, 2, 3, 4];
+        let txid = txid_from_hex(&txid_of(&tx)).unwrap();
+
+        let rtx = |data: Vec<u8>| Ok(RawTransaction{ data, height: 0 });
+        assert_eq!(raw_transaction_data(txid, rtx(tx.clone())), Ok(tx.clone()));
+
+        // A pruned transaction, however the server says so
+        for r in vec![rtx(vec![]),
+                      Err("ERR = Status { code: Unknown, message: \"No such mempool or blockchain transaction. Use gettransaction for wallet transactions.\" }".to_string()),
+                      Err("ERR = Status { code: NotFound, message: \"\" }".to_string())] {
+            assert!(raw_transaction_data(txid, r).unwrap_err().contains("may have pruned it"));
+        }
+
+        assert!(raw_transaction_data(txid, Err("Connection refused".to_string())).unwrap_err().contains("Connection refused"));
+        assert!(raw_transaction_data(txid, rtx(vec![5, 6])).unwrap_err().contains("instead of"));
+    }
+
+    #[test]
+    pub fn test_broadcast_outcome() {
+        let tx = vec![1u8, 2, 3, 4];
+        let ok = Ok(BroadcastResult{ txid: txid_of(&tx), server_message: "ok".to_string() });
+        let known = Err(BroadcastError::AlreadyKnown("18: txn-already-in-mempool".to_string()));
+        let down = Err(BroadcastError::Connection("refused".to_string()));
+        let rejected = Err(BroadcastError::Rejected(-26, "16: bad-txns-inputs-spent".to_string()));
+        let results = |rs: Vec<Result<BroadcastResult, BroadcastError>>| rs.into_iter()
+            .enumerate()
+            .map(|(i, r)| (format!("http://127.0.0.1:{}", i + 1).parse::<http::Uri>().unwrap(), r))
+            .collect::<Vec<_>>();
+
+        // Any server accepting it is enough
+        assert_eq!(broadcast_outcome(&tx, &results(vec![down.clone(), ok.clone()])), ok);
+        assert_eq!(broadcast_outcome(&tx, &results(vec![ok.clone(), known.clone()])), ok);
+
+        // Another server already having it means it got out
+        let r = broadcast_outcome(&tx, &results(vec![down.clone(), known.clone()])).unwrap();
+        assert_eq!(r.txid, txid_of(&tx));
+
+        // ...but the wallet's own server already having it means it was sent before
+        assert_eq!(broadcast_outcome(&tx, &results(vec![known.clone(), down.clone()])), known);
+        assert_eq!(broadcast_outcome(&tx, &results(vec![rejected.clone(), down])), rejected);
+        assert!(broadcast_outcome(&tx, &[]).is_err());
+    }
+
+    #[test]
+    pub fn test_broadcast_everywhere_without_servers_up() {
+        use std::sync::Arc;
+
+        let conn = GrpcConnection::new("https://127.0.0.1:1".parse().unwrap(), false, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None);
+        let others = vec![Arc::new(GrpcConnection::new("https://127.0.0.1:2".parse().unwrap(), false, DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, None))];
+
+        // Every server is tried, and reported in order
+        let results = conn.broadcast_raw_tx_everywhere(&others, vec![1u8, 2, 3].into_boxed_slice());
+        assert_eq!(results.iter().map(|(uri, _)| uri.port_part().unwrap().as_u16()).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(results.iter().all(|(_, r)| r.as_ref().unwrap_err().to_string().starts_with("Couldn't send the transaction")));
+    }
+
+    /// A server mounted at `mount`, which answers 404 to anything outside of it
+    struct MountedServer {
+        mount: &'static str,
+    }
+
+    impl tower_service::Service<http::Request<()>> for MountedServer {
+        type Response = http::Response<String>;
+        type Error = ();
+        type Future = futures::future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> futures::Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<()>) -> Self::Future {
+            let status = if req.uri().path().starts_with(self.mount) { 200 } else { 404 };
+            futures::future::ok(http::Response::builder().status(status).body(req.uri().to_string()).unwrap())
+        }
+    }
+
+    #[test]
+    pub fn test_path_prefix() {
+        use futures::Future;
+        use tower_service::Service;
+        use super::PathPrefix;
+
+        let get = |server: &mut PathPrefix<MountedServer>| {
+            let req = http::Request::builder()
+                .uri("https://example.com/cash.z.wallet.sdk.rpc.CompactTxStreamer/GetLightdInfo")
+                .body(())
+                .unwrap();
+            server.call(req).wait().unwrap()
+        };
+
+        // Requests go under the prefix
+        let mut server = PathPrefix { prefix: "/lightwalletd".to_string(), inner: MountedServer { mount: "/lightwalletd/" } };
+        let response = get(&mut server);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "https://example.com/lightwalletd/cash.z.wallet.sdk.rpc.CompactTxStreamer/GetLightdInfo");
+
+        // Without a prefix, requests are unchanged
+        let mut server = PathPrefix { prefix: "".to_string(), inner: MountedServer { mount: "/" } };
+        let response = get(&mut server);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "https://example.com/cash.z.wallet.sdk.rpc.CompactTxStreamer/GetLightdInfo");
+
+        // Which would not find a server behind a reverse proxy
+        let mut server = PathPrefix { prefix: "".to_string(), inner: MountedServer { mount: "/lightwalletd/" } };
+        assert_eq!(get(&mut server).status(), 404);
+    }
+
+    /// A server that answers with the x-client header of the request
+    struct EchoClientHeader {}
+
+    impl tower_service::Service<http::Request<()>> for EchoClientHeader {
+        type Response = Option<String>;
+        type Error = ();
+        type Future = futures::future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> futures::Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<()>) -> Self::Future {
+            futures::future::ok(req.headers().get(super::CLIENT_HEADER).map(|v| v.to_str().unwrap().to_string()))
+        }
+    }
+
+    #[test]
+    pub fn test_client_header() {
+        use futures::Future;
+        use tower_service::Service;
+        use super::{ClientHeader, PathPrefix, client_header_value};
+
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(client_header_value(&None), format!("yecshell/{}", version));
+        assert_eq!(client_header_value(&Some(" MyWallet/2.1 ".to_string())), format!("yecshell/{} MyWallet/2.1", version));
+        assert_eq!(client_header_value(&Some("".to_string())), format!("yecshell/{}", version));
+
+        let mut server = ClientHeader { 
+            value: client_header_value(&Some("MyWallet/2.1".to_string())).parse().unwrap(), 
+            inner: PathPrefix { prefix: "".to_string(), inner: EchoClientHeader {} } 
+        };
+
+        // Unary and streaming calls both have it
+        for method in &["GetLightdInfo", "GetBlockRange"] {
+            let req = http::Request::builder()
+                .uri(format!("https://example.com/cash.z.wallet.sdk.rpc.CompactTxStreamer/{}", method))
+                .body(())
+                .unwrap();
+            assert_eq!(server.call(req).wait().unwrap(), Some(format!("yecshell/{} MyWallet/2.1", version)));
+        }
+    }
+
+    /// A response body that hands out a few bytes at a time, so that messages arrive split up, and then
+    /// trailers saying that the call succeeded
+    struct ChunkedBody {
+        chunks: std::collections::VecDeque<bytes::Bytes>,
+    }
+
+    impl http_body::Body for ChunkedBody {
+        type Data = std::io::Cursor<bytes::Bytes>;
+        type Error = std::io::Error;
+
+        fn poll_data(&mut self) -> futures::Poll<Option<Self::Data>, Self::Error> {
+            Ok(self.chunks.pop_front().map(std::io::Cursor::new).into())
+        }
+
+        fn poll_trailers(&mut self) -> futures::Poll<Option<http::HeaderMap>, Self::Error> {
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", http::header::HeaderValue::from_static("0"));
+            Ok(Some(trailers).into())
+        }
+    }
+
+    /// A server that answers with `messages`, gzipped if the request asks for it and `gzip` is set. 
+    /// If `refuse` is set, it rejects requests that ask for gzip instead, like grpc-go does without gzip support.
+    struct GzipServer {
+        gzip:     bool,
+        refuse:   bool,
+        messages: Vec<Vec<u8>>,
+    }
+
+    impl<B> tower_service::Service<http::Request<B>> for GzipServer {
+        type Response = http::Response<ChunkedBody>;
+        type Error = ();
+        type Future = futures::future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> futures::Poll<(), ()> {
+            Ok(().into())
+        }
+
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            use std::io::Write;
+
+            let asked = req.headers().get("grpc-encoding").map_or(false, |e| e == super::GZIP);
+            let mut response = http::Response::builder();
+
+            if asked && self.refuse {
+                response.header("grpc-status", "12")
+                        .header("grpc-message", "grpc: Decompressor is not installed for grpc-encoding \"gzip\"");
+                return futures::future::ok(response.body(ChunkedBody { chunks: Default::default() }).unwrap());
+            }
+
+            let compress = asked && self.gzip;
+            if compress {
+                response.header("grpc-encoding", super::GZIP);
+            }
+
+            let mut body = vec![];
+            for m in &self.messages {
+                let m = if compress {
+                    let mut e = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                    e.write_all(m).unwrap();
+                    e.finish().unwrap()
+                } else {
+                    m.clone()
+                };
+
+                body.push(compress as u8);
+                body.extend_from_slice(&(m.len() as u32).to_be_bytes());
+                body.extend_from_slice(&m);
+            }
+
+            let chunks = body.chunks(7).map(|c| bytes::Bytes::from(c.to_vec())).collect();
+            futures::future::ok(response.body(ChunkedBody { chunks }).unwrap())
+        }
+    }
+
+    #[test]
+    pub fn test_gzip() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use futures::{Async, Future};
+        use tower_service::Service;
+        use http_body::Body;
+        use bytes::Buf;
+        use prost::Message;
+        use crate::grpc_client::CompactBlock;
+        use super::{Gzip, Instrument, NetworkStats};
+
+        let blocks = (0..20).map(|i| CompactBlock { height: i, hash: vec![i as u8; 500], ..Default::default() }).collect::<Vec<_>>();
+        let messages = blocks.iter().map(|b| {
+            let mut buf = vec![];
+            b.encode(&mut buf).unwrap();
+            buf
+        }).collect::<Vec<_>>();
+        // What tower-grpc gets, a 5 byte header and the message for each
+        let size = messages.iter().map(|m| 5 + m.len() as u64).sum::<u64>();
+
+        let fetch = |server: &mut Gzip<Instrument<GzipServer>>| {
+            let req = http::Request::builder()
+                .uri("https://example.com/cash.z.wallet.sdk.rpc.CompactTxStreamer/GetBlockRange")
+                .body(())
+                .unwrap();
+            let mut body = server.call(req).wait().unwrap().into_body();
+
+            let mut received = vec![];
+            loop {
+                match body.poll_data().unwrap() {
+                    Async::Ready(Some(data)) => {
+                        // tower-grpc gets every message whole, and uncompressed
+                        let frame = data.bytes();
+                        assert_eq!(frame[0], 0);
+                        assert_eq!(u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize, frame.len() - 5);
+                        received.push(CompactBlock::decode(&frame[5..]).unwrap());
+                    },
+                    Async::Ready(None) => {
+                        body.poll_trailers().unwrap();
+                        return received;
+                    },
+                    Async::NotReady    => panic!("The mock body is always ready"),
+                }
+            }
+        };
+        let server = |enabled, gzip, refuse| {
+            let stats = Arc::new(NetworkStats::new());
+            Gzip { 
+                enabled, 
+                refused: Arc::new(AtomicBool::new(false)), 
+                stats:   stats.clone(), 
+                max_message_size: super::DEFAULT_MAX_MESSAGE_SIZE,
+                inner:   Instrument { stats, inner: GzipServer { gzip, refuse, messages: messages.clone() } }
+            }
+        };
+
+        // Compressed messages come out the same, after downloading less
+        let mut s = server(true, true, false);
+        assert_eq!(fetch(&mut s), blocks);
+        assert_eq!(s.stats.bytes_uncompressed(), size);
+        assert!(s.stats.bytes_received() < size / 10);
+
+        // Without compression, or a server that doesn't do gzip, they come through as they are
+        for (enabled, gzip) in &[(false, true), (true, false)] {
+            let mut s = server(*enabled, *gzip, false);
+            assert_eq!(fetch(&mut s), blocks);
+            assert_eq!(s.stats.bytes_received(), size);
+            assert_eq!(s.stats.bytes_uncompressed(), size);
+        }
+
+        // A server that refuses gzip gets asked without it after that
+        let mut s = server(true, true, true);
+        assert!(fetch(&mut s).is_empty());
+        assert!(s.refused.load(Ordering::SeqCst));
+        assert_eq!(fetch(&mut s), blocks);
+        assert_eq!(s.stats.bytes_received(), size);
+
+        // Which counts as a failed call
+        let stats = s.stats.to_json();
+        assert_eq!(stats["GetBlockRange"]["calls"], 2);
+        assert_eq!(stats["GetBlockRange"]["errors"], 1);
+        assert_eq!(stats["GetBlockRange"]["bytes_received"], size);
+    }
+
+    #[test]
+    pub fn test_max_message_size() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicBool;
+        use futures::{Async, Future};
+        use tower_service::Service;
+        use http_body::Body;
+        use super::{Gzip, Instrument, NetworkStats, MESSAGE_TOO_LARGE, without_wrapping};
+
+        const LIMIT: usize = 1000;
+
+        // How many messages came through before the end, or the error
+        let fetch = |gzip: bool, sizes: &[usize]| -> Result<usize, String> {
+            let stats = Arc::new(NetworkStats::new());
+            let messages = sizes.iter().map(|n| vec![7u8; *n]).collect();
+            let mut server = Gzip { 
+                enabled: gzip, 
+                refused: Arc::new(AtomicBool::new(false)), 
+                stats:   stats.clone(), 
+                max_message_size: LIMIT,
+                inner:   Instrument { stats, inner: GzipServer { gzip, refuse: false, messages } }
+            };
+
+            let req = http::Request::builder()
+                .uri("https://example.com/cash.z.wallet.sdk.rpc.CompactTxStreamer/GetBlockRange")
+                .body(())
+                .unwrap();
+            let mut body = server.call(req).wait().unwrap().into_body();
+
+            let mut received = 0;
+            loop {
+                match body.poll_data().map_err(|e| e.to_string())? {
+                    Async::Ready(Some(_)) => received += 1,
+                    Async::Ready(None)    => return Ok(received),
+                    Async::NotReady       => panic!("The mock body is always ready"),
+                }
+            }
+        };
+
+        // Up to the limit is fine, compressed or not
+        assert_eq!(fetch(false, &[10, LIMIT]), Ok(2));
+        assert_eq!(fetch(true, &[10, LIMIT]), Ok(2));
+
+        // Just above it fails, saying which limit it was
+        for gzip in &[false, true] {
+            let e = fetch(*gzip, &[10, LIMIT + 1]).unwrap_err();
+            assert!(e.starts_with(MESSAGE_TOO_LARGE));
+            assert!(e.contains(&format!("limit is {} bytes", LIMIT)));
+            assert!(e.contains("--max-message-size"));
+        }
+        assert!(fetch(false, &[LIMIT + 1]).unwrap_err().contains(&format!("a message of {} bytes", LIMIT + 1)));
+
+        // The error is passed on without what tower-grpc wraps it in
+        let e = fetch(false, &[LIMIT + 1]).unwrap_err();
+        let wrapped = format!("ERR = Status {{ message: {:?} }}", e);
+        assert_eq!(without_wrapping(&wrapped[wrapped.find(MESSAGE_TOO_LARGE).unwrap()..]), e);
+    }
+
+    #[test]
+    pub fn test_network_stats() {
+        use std::time::Duration;
+        use super::{MethodStats, NetworkStats};
+
+        let m = MethodStats::default();
+        assert_eq!(m.average_latency_ms(), None);
+        assert_eq!(m.latency_percentile_ms(0.5), None);
+
+        // 10ms, 20ms, ... 1s, with the slowest one failing
+        for i in 1..=100 {
+            m.finish(i != 100, Duration::from_millis(i * 10));
+        }
+        assert_eq!(m.errors.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(m.average_latency_ms(), Some(505));
+        assert_eq!(m.latency_percentile_ms(0.5), Some(500));
+        assert_eq!(m.latency_percentile_ms(0.9), Some(1_000));
+        assert_eq!(m.latency_percentile_ms(0.01), Some(10));
+
+        // Slower than the last bucket
+        m.finish(true, Duration::from_secs(120));
+        assert_eq!(m.latency_percentile_ms(1.0), None);
+
+        m.reset();
+        assert_eq!(m.average_latency_ms(), None);
+        assert_eq!(m.to_json()["errors"], 0);
+
+        // Methods are counted by the last part of the path
+        let stats = NetworkStats::new();
+        stats.method("/cash.z.wallet.sdk.rpc.CompactTxStreamer/GetLightdInfo").calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(stats.to_json()["GetLightdInfo"]["calls"], 1);
+        assert!(stats.get("GetBlockRange").is_none());
+
+        stats.reset();
+        assert_eq!(stats.to_json()["GetLightdInfo"]["calls"], 0);
+    }
+
+    #[test]
+    pub fn test_interleave_addrs() {
+        use std::net::SocketAddr;
+        let a = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        let addrs = vec![a("[::1]:1"), a("[::1]:2"), a("[::1]:3"), a("127.0.0.1:1"), a("127.0.0.1:2")];
+        assert_eq!(super::interleave_addrs(addrs), 
+                   vec![a("[::1]:1"), a("127.0.0.1:1"), a("[::1]:2"), a("127.0.0.1:2"), a("[::1]:3")]);
+
+        assert_eq!(super::interleave_addrs(vec![a("127.0.0.1:1")]), vec![a("127.0.0.1:1")]);
+        assert!(super::interleave_addrs(vec![]).is_empty());
+    }
+
+    #[test]
+    pub fn test_connect_any() {
+        use std::time::Duration;
+        use tokio::runtime::Runtime;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        let dead = "127.0.0.1:1".parse().unwrap();
+
+        let mut rt = Runtime::new().unwrap();
+
+        // A dead address first doesn't stop it from getting to the one that works
+        let sock = rt.block_on(super::connect_any(vec![dead, live], Duration::from_secs(1))).unwrap();
+        assert_eq!(sock.peer_addr().unwrap(), live);
+
+        // If none work, every attempt is reported, and nothing panics
+        let e = rt.block_on(super::connect_any(vec![dead, dead], Duration::from_secs(1))).unwrap_err();
+        assert_eq!(e.to_string().matches("127.0.0.1:1").count(), 2);
+        assert!(rt.block_on(super::connect_any(vec![], Duration::from_secs(1))).is_err());
+    }
+
+    #[test]
+    pub fn test_txid_from_hex() {
+        let txid = txid_from_hex("00000000000000000000000000000000000000000000000000000000000000ff").unwrap();
+        assert_eq!(txid.0[0], 0xff);
+        assert_eq!(txid.0[31], 0x00);
+
+        // Displaying it reverses it back
+        assert_eq!(format!("{}", txid), "00000000000000000000000000000000000000000000000000000000000000ff");
+
+        assert!(txid_from_hex("00ff").is_err());
+        assert!(txid_from_hex("not hex").is_err());
+    }
+
+    #[test]
+    pub fn test_parse_proxy() {
+        assert_eq!(parse_proxy("socks5://127.0.0.1:9050").unwrap(), "127.0.0.1:9050".parse().unwrap());
+        assert_eq!(parse_proxy("socks5h://127.0.0.1:9050").unwrap(), "127.0.0.1:9050".parse().unwrap());
+        assert_eq!(parse_proxy("127.0.0.1:9050").unwrap(), "127.0.0.1:9050".parse().unwrap());
+
+        assert!(parse_proxy("http://127.0.0.1:8080").is_err());
+        assert!(parse_proxy("socks5://127.0.0.1").is_err());
+    }
+
+    #[test]
+    pub fn test_socks5_proxy_resolves_host() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::sync::mpsc::channel;
+
+        // A fake SOCKS5 proxy that records the requested host and then refuses the connection
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+
+        std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+
+            let mut greeting = [0u8; 3];
+            sock.read_exact(&mut greeting).unwrap();
+            sock.write_all(&[0x05, 0x00]).unwrap();
+
+            let mut header = [0u8; 5];
+            sock.read_exact(&mut header).unwrap();
+            let mut host = vec![0u8; header[4] as usize + 2];
+            sock.read_exact(&mut host).unwrap();
+            tx.send((header[3], String::from_utf8(host[..host.len()-2].to_vec()).unwrap())).unwrap();
+
+            // Connection refused
+            sock.write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).unwrap();
+        });
+
+        // The host doesn't resolve, so this only gets to the proxy if it isn't resolved locally
+        let conn = GrpcConnection::new("https://lightwalletd.invalid:443".parse().unwrap(), false,
+                                       DEFAULT_CONNECT_TIMEOUT, DEFAULT_UNARY_TIMEOUT, 1, 
+                                       Some(format!("socks5://{}", proxy)));
+
+        let e = conn.get_info().unwrap_err();
+        assert!(e.contains("reply code 5"));
+        assert_eq!(rx.recv().unwrap(), (0x03, "lightwalletd.invalid".to_string()));
+    }
 }
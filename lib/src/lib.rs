@@ -5,6 +5,7 @@ pub mod lightclient;
 pub mod grpcconnector;
 pub mod lightwallet;
 pub mod commands;
+pub mod walletmanager;
 
 
 #[derive(RustEmbed)]
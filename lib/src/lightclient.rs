@@ -1,22 +1,29 @@
-use crate::lightwallet::LightWallet;
+use crate::lightwallet::{LightWallet, BlockReorderBuffer, BLOCK_REORDER_WINDOW, DEFAULT_ACCOUNT, COMPRESSED_WALLET_MAGIC, FiatRate, is_wallet_corrupt, utils};
+use crate::lightwallet::scanner::{NoteScanner, CpuScanner};
+use crate::lightwallet::backup::{WalletBackup, BackupAccount};
+use crate::lightwallet::kdf::Kdf;
+use crate::lightwallet::secret::Secret;
 
 use rand::{rngs::OsRng, seq::SliceRandom};
 
 use std::sync::{Arc, RwLock, Mutex};
-use std::sync::atomic::{AtomicU64, AtomicI32, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
 use std::path::{Path, PathBuf};
+use std::net::IpAddr;
 use std::fs::File;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, Error, ErrorKind};
+use std::time::{Duration, Instant};
 
 use protobuf::parse_from_bytes;
 
 use json::{object, array, JsonValue};
 use zcash_primitives::transaction::{TxId, Transaction};
 use zcash_client_backend::{
-    constants::testnet, constants::mainnet, constants::regtest, encoding::encode_payment_address,
+    constants::testnet, constants::mainnet, constants::regtest,
+    encoding::{encode_payment_address, decode_payment_address},
 };
 
 use log::{info, warn, error, LevelFilter};
@@ -30,22 +37,77 @@ use log4rs::append::rolling_file::policy::compound::{
     roll::fixed_window::FixedWindowRoller,
 };
 
-use crate::grpc_client::{BlockId};
-use crate::grpcconnector::{self, *};
+use crate::grpc_client::LightdInfo;
+use crate::grpcconnector::{self, GrpcConnection, IpVersion, NetworkStats, BroadcastError};
 use crate::SaplingParams;
 use crate::ANCHOR_OFFSET;
 
 mod checkpoints;
+mod capabilities;
+mod walletlock;
+mod price;
+
+pub use walletlock::WalletLock;
+pub use price::PriceProvider;
 
 pub const DEFAULT_SERVER: &str = "https://lightwalletd.ycash.xyz:443";
 pub const WALLET_NAME: &str    = "lite_wallet.dat";
+pub const DEFAULT_WALLET_BACKUPS: usize = 3;
 pub const LOGFILE_NAME: &str   = "lite_debug.log";
+pub const SERVER_INFO_NAME: &str = "lightwalletd_info.json";
+
+/// How many blocks a reorg may roll back by default. The wallet only keeps enough blocks for 
+/// `MAX_REORG`, so that is also the upper limit.
+pub const DEFAULT_MAX_REORG_DEPTH: u64 = crate::lightwallet::MAX_REORG as u64;
+
+/// How many blocks a sync scans between checkpoints, see `LightClientConfig::checkpoint_interval`
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 10_000;
+
+/// How long the server's info is used before asking the server again
+pub const DEFAULT_INFO_TTL: Duration = Duration::from_secs(60);
+
+/// Minimum time between two automatic saves during a sync
+const AUTO_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub const SPEND_LIMIT_EXCEEDED: &str = "Spend limit exceeded";
+pub const NO_TADDR_SUPPORT: &str = "This server does not support transparent addresses";
+/// How many blocks below the wallet's scan height a key imported without a birthday is scanned from
+pub const IMPORT_BIRTHDAY_MARGIN: u64 = 10_000;
+
+pub const WALLET_READ_ONLY: &str = "The wallet was opened read-only";
+pub const WALLET_LOCKED_FOR_VIEWING: &str = "Wallet is locked, unlock it for viewing to sync it and see its balances";
+
+const BACKUP_MAGIC: &[u8; 8]   = b"yecbkup\0";
+const BACKUP_VERSION: u64      = 1;
+
+/// A getinfo response, and which server sent it when
+struct ServerInfo {
+    info:    LightdInfo,
+    server:  http::Uri,
+    fetched: Instant,
+}
+
+/// The last balances computed by `do_balance`, without and with the watch-only addresses, and the wallet
+/// state they were computed from
+struct BalanceCache {
+    state:    u64,
+    balances: [Option<JsonValue>; 2],   // Indexed by include_watchonly
+    computed: u64,  // How many times the balance had to be computed
+}
 
 #[derive(Clone, Debug)]
 pub struct WalletStatus {
     pub is_syncing: bool,
     pub total_blocks: u64,
     pub synced_blocks: u64,
+    pub current_height: u64,            // The last block that was scanned
+    pub txns_found_this_sync: u64,      // Number of wallet transactions found in the blocks scanned so far
+    pub seconds_remaining: Option<u64>, // Estimate, based on how fast blocks have been scanned in this sync
+    pub failovers: u64,                 // Number of times the client moved on to another server
+    pub bytes_downloaded: u64,          // gRPC messages received from servers, as sent over the wire
+    pub bytes_uncompressed: u64,        // The same, after decompressing them
+
+    sync_start: Option<(Instant, u64)>, // When this sync started, and at what height
 }
 
 impl WalletStatus {
@@ -53,20 +115,173 @@ impl WalletStatus {
         WalletStatus {
             is_syncing: false,
             total_blocks: 0,
-            synced_blocks: 0
+            synced_blocks: 0,
+            current_height: 0,
+            txns_found_this_sync: 0,
+            seconds_remaining: None,
+            failovers: 0,
+            bytes_downloaded: 0,
+            bytes_uncompressed: 0,
+            sync_start: None,
+        }
+    }
+
+    /// Reset the per-sync counters at the start of a sync
+    fn start_sync(&mut self, from_height: u64, latest_block: u64) {
+        self.is_syncing = true;
+        self.synced_blocks = from_height;
+        self.total_blocks = latest_block;
+        self.current_height = from_height;
+        self.txns_found_this_sync = 0;
+        self.seconds_remaining = None;
+        self.sync_start = Some((Instant::now(), from_height));
+    }
+
+    /// Record a scanned block, and update the estimate of the time remaining
+    fn block_scanned(&mut self, height: u64, txns_found: u64) {
+        self.current_height = height;
+        self.txns_found_this_sync += txns_found;
+
+        if let Some((start_time, start_height)) = self.sync_start {
+            let elapsed = start_time.elapsed().as_secs_f64();
+            let scanned = height.saturating_sub(start_height);
+
+            if scanned > 0 && elapsed > 0.0 {
+                let blocks_per_sec = scanned as f64 / elapsed;
+                self.seconds_remaining = Some((self.total_blocks.saturating_sub(height) as f64 / blocks_per_sec).ceil() as u64);
+            }
+        }
+    }
+
+    fn finish_sync(&mut self, latest_block: u64) {
+        self.is_syncing = false;
+        self.synced_blocks = latest_block;
+        self.total_blocks = latest_block;
+        self.current_height = latest_block;
+        self.seconds_remaining = None;
+        self.sync_start = None;
+    }
+}
+
+/// The order of the transactions listed by `do_list_transactions_sorted`. Ties are broken by txid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxSort {
+    HeightAsc,
+    HeightDesc,
+    AmountDesc,     // By the signed amount, so the biggest incoming transaction comes first
+    DateDesc,
+}
+
+impl TxSort {
+    pub fn from_str(s: &str) -> Option<TxSort> {
+        match s {
+            "height_asc"  => Some(TxSort::HeightAsc),
+            "height_desc" => Some(TxSort::HeightDesc),
+            "amount_desc" => Some(TxSort::AmountDesc),
+            "date_desc"   => Some(TxSort::DateDesc),
+            _             => None
+        }
+    }
+}
+
+/// A payment waiting in the queue for the next `do_flush_sends`
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedSend {
+    pub id:         u64,
+    pub address:    String,
+    pub amount:     u64,
+    pub memo:       Option<String>,
+}
+
+struct SendQueue {
+    next_id:    u64,
+    items:      Vec<QueuedSend>,
+}
+
+impl SendQueue {
+    fn new() -> Self {
+        SendQueue { next_id: 1, items: vec![] }
+    }
+}
+
+/// Group the queued payments into as few transactions as possible, each with at most `max_outputs` 
+/// shielded outputs. Payments to addresses that aren't `is_shielded` don't count towards it. A
+/// transaction can't pay the same address twice, so repeated addresses go into different transactions.
+fn batch_sends<F>(items: &[QueuedSend], max_outputs: usize, is_shielded: F) -> Vec<Vec<QueuedSend>>
+        where F: Fn(&str) -> bool {
+    let mut batches: Vec<Vec<QueuedSend>> = vec![];
+    let shielded = |b: &Vec<QueuedSend>| b.iter().filter(|q| is_shielded(&q.address)).count();
+
+    for item in items {
+        let fits = |b: &Vec<QueuedSend>| !is_shielded(&item.address) || shielded(b) < max_outputs;
+        match batches.iter_mut().find(|b| fits(b) && !b.iter().any(|q| q.address == item.address)) {
+            Some(b) => b.push(item.clone()),
+            None    => batches.push(vec![item.clone()])
         }
     }
+
+    batches
+}
+
+/// Criteria for the notes and utxos listed by `do_list_notes_filtered`
+#[derive(Clone, Debug, Default)]
+pub struct NoteFilter {
+    pub min_value: Option<u64>,
+    pub max_value: Option<u64>,
+    pub min_confirmations: Option<u64>,
+}
+
+impl NoteFilter {
+    fn matches(&self, value: u64, block: i32, last_height: u64) -> bool {
+        let confirmations = (last_height + 1).saturating_sub(block as u64);
+
+        self.min_value.map_or(true, |min| value >= min) &&
+        self.max_value.map_or(true, |max| value <= max) &&
+        self.min_confirmations.map_or(true, |min| confirmations >= min)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct LightClientConfig {
-    pub server                      : http::Uri,
+    pub servers                     : Vec<http::Uri>, // In order of preference, the later ones are failovers
     pub chain_name                  : String,
     pub sapling_activation_height   : u64,
     pub consensus_branch_id         : String,
     pub anchor_offset               : u32,
     pub no_cert_verification        : bool,
-    pub data_dir                    : Option<String>
+    pub data_dir                    : Option<String>,
+    pub connect_timeout             : Duration, // Establishing the TCP connection and TLS session
+    pub unary_timeout               : Duration, // Deadline for each one-shot RPC
+    pub stream_inactivity_timeout   : Duration, // Max gap between two messages of a streaming RPC
+    pub max_attempts                : u32,      // Max number of tries for gRPC calls that fail to connect
+    pub proxy                       : Option<String>, // SOCKS5 proxy, eg. socks5://127.0.0.1:9050 for Tor
+    pub auto_save                   : bool,     // Save the wallet to data_dir after every change
+    pub wallet_backups              : usize,    // Previous versions of the wallet file kept in data_dir, see `do_list_backups`
+    pub auto_select_server          : bool,     // Use the fastest of the servers instead of the first one
+    pub max_reorg_depth             : u64,      // Sync aborts instead of rolling back more blocks than this
+    pub info_ttl                    : Duration, // How long a getinfo response is used before asking the server again
+    pub ip_version                  : IpVersion, // Force connecting to the server over IPv4 or IPv6
+    pub keepalive_interval          : Option<Duration>, // How often to keep an idle connection alive. None to turn it off
+    pub keepalive_timeout           : Duration, // How long an idle connection gets to prove it still works
+    pub client_cert_path            : Option<String>, // PEM client certificate, for servers that require client authentication
+    pub client_key_path             : Option<String>, // PEM private key of the client certificate
+    pub server_ip_override          : Option<IpAddr>, // Connect to the first server at this IP, instead of resolving its name
+    pub client_name                 : Option<String>, // eg. MyWallet/2.1, added to the x-client header that tells the server who is calling
+    pub compression                 : bool, // Ask servers to gzip their responses, to download less while syncing
+    pub max_send_per_tx             : Option<u64>, // Zatoshis a single send may pay out without overriding the limits
+    pub max_send_per_day            : Option<u64>, // Zatoshis that may be sent per day (UTC) without overriding the limits
+    pub min_fee_per_action          : u64,      // The network's minimum fee per logical action (ZIP-317). 0 while it only wants DEFAULT_FEE
+    pub fresh_change_address        : bool,     // Send each transaction's change to a new diversified address
+    pub donate_dust_to_fee          : bool,     // Allow sends whose change is not more than the fee, adding that change to the fee
+    pub offline                     : bool,     // Never connect to the server, see `create_offline`
+    pub block_connections           : usize,    // Connections to fetch blocks over at the same time while syncing, see `do_sync`
+    pub max_message_size            : usize,    // Bytes. Calls where the server sends a larger (decompressed) message fail
+    pub wallet_name                 : Option<String>, // Named wallets are kept in their own file in data_dir, see `WalletManager`
+    pub read_only                   : bool,     // Only look at the wallet: never save it, sync it or send from it, see `check_writable`
+    pub force_unlock                : bool,     // Open the wallet even if another running process holds its lock, see `WalletLock`
+    pub compress_wallet_file        : bool,     // Save the wallet file compressed, see `LightWallet::write_compressed`
+    pub compress_wallet_buffer      : bool,     // Compress `do_save_to_buffer`'s wallet too, for services that read it with yecshell
+    pub checkpoint_interval         : u64,      // Blocks. A sync prunes the witnesses it doesn't need any more and saves this often, 0 for never
 }
 
 impl LightClientConfig {
@@ -74,37 +289,180 @@ impl LightClientConfig {
     // Create an unconnected (to any server) config to test for local wallet etc...
     pub fn create_unconnected(chain_name: String, dir: Option<String>) -> LightClientConfig {
         LightClientConfig {
-            server                      : http::Uri::default(),
+            servers                     : vec![http::Uri::default()],
             chain_name                  : chain_name,
             sapling_activation_height   : 0,
             consensus_branch_id         : "".to_string(),
             anchor_offset               : ANCHOR_OFFSET,
             no_cert_verification        : false,
             data_dir                    : dir,
+            connect_timeout             : grpcconnector::DEFAULT_CONNECT_TIMEOUT,
+            unary_timeout               : grpcconnector::DEFAULT_UNARY_TIMEOUT,
+            stream_inactivity_timeout   : grpcconnector::DEFAULT_STREAM_INACTIVITY_TIMEOUT,
+            max_attempts                : grpcconnector::DEFAULT_MAX_ATTEMPTS,
+            proxy                       : None,
+            auto_save                   : false,
+            wallet_backups              : DEFAULT_WALLET_BACKUPS,
+            auto_select_server          : false,
+            max_reorg_depth             : DEFAULT_MAX_REORG_DEPTH,
+            info_ttl                    : DEFAULT_INFO_TTL,
+            ip_version                  : IpVersion::Any,
+            keepalive_interval          : Some(grpcconnector::DEFAULT_KEEPALIVE_INTERVAL),
+            keepalive_timeout           : grpcconnector::DEFAULT_KEEPALIVE_TIMEOUT,
+            client_cert_path            : None,
+            client_key_path             : None,
+            server_ip_override          : None,
+            client_name                 : None,
+            compression                 : false,
+            max_send_per_tx             : None,
+            max_send_per_day            : None,
+            min_fee_per_action          : 0,
+            fresh_change_address        : true,
+            donate_dust_to_fee          : false,
+            offline                     : false,
+            block_connections           : 1,
+            max_message_size            : grpcconnector::DEFAULT_MAX_MESSAGE_SIZE,
+            wallet_name                 : None,
+            read_only                   : false,
+            force_unlock                : false,
+            compress_wallet_file        : true,
+            compress_wallet_buffer      : false,
+            checkpoint_interval         : DEFAULT_CHECKPOINT_INTERVAL,
+        }
+    }
+
+    /// Create a config to open an existing wallet without a network, eg. to check the balance or export
+    /// keys. The chain parameters come from the server info saved the last time the wallet was online. 
+    /// Everything that needs the server fails with `grpcconnector::OFFLINE_ERROR`.
+    pub fn create_offline(chain_name: String, dir: Option<String>) -> io::Result<LightClientConfig> {
+        let mut config = LightClientConfig::create_unconnected(chain_name, dir);
+        config.servers = vec![DEFAULT_SERVER.parse().unwrap()];
+        config.offline = true;
+
+        if !config.wallet_exists() {
+            return Err(Error::new(ErrorKind::NotFound,
+                    format!("Can't open a wallet offline, there is no wallet at {}", config.get_wallet_path().display())));
+        }
+
+        match config.read_server_info() {
+            Some((server, info)) if info.chain_name == config.chain_name => {
+                config.servers = vec![server];
+                config.sapling_activation_height = info.sapling_activation_height;
+                config.consensus_branch_id = info.consensus_branch_id;
+            },
+            Some((server, info)) => warn!("Ignoring the saved info of {}, which is on chain {}", server, info.chain_name),
+            None => warn!("No saved server info at {}", config.get_server_info_path().display())
         }
+
+        Ok(config)
+    }
+
+    pub fn create(server: http::Uri, dangerous: bool, proxy: Option<String>) -> io::Result<(LightClientConfig, u64)> {
+        LightClientConfig::create_with_servers(vec![server], dangerous, proxy, None, None, None)
     }
 
-    pub fn create(server: http::Uri, dangerous: bool) -> io::Result<(LightClientConfig, u64)> {
+    /// Create a config that fails over between the servers, in order. The chain is taken from the
+    /// first server that responds, and the others have to be on the same chain to be used. Servers
+    /// that require client authentication need `client_cert_path` and `client_key_path`. With
+    /// `server_ip_override`, the first server's host is connected to at that IP instead of being resolved.
+    pub fn create_with_servers(servers: Vec<http::Uri>, dangerous: bool, proxy: Option<String>,
+            client_cert_path: Option<String>, client_key_path: Option<String>, 
+            server_ip_override: Option<IpAddr>) -> io::Result<(LightClientConfig, u64)> {
         use std::net::ToSocketAddrs;
-        // Test for a connection first
-        format!("{}:{}", server.host().unwrap(), server.port_part().unwrap())
-            .to_socket_addrs()?
-            .next()
-            .ok_or(std::io::Error::new(ErrorKind::ConnectionRefused, "Couldn't resolve server!"))?;
+        if servers.is_empty() {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "No server given"));
+        }
+        let ip_override = LightClientConfig::ip_override_for(&servers, server_ip_override);
+
+        // With a proxy, the server's name is resolved by the proxy so it doesn't leak, so just 
+        // check that the proxy is valid.
+        if let Some(p) = &proxy {
+            grpcconnector::parse_proxy(p)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        }
+
+        // Fail early on client certificate files that can't be used
+        grpcconnector::load_client_cert(&client_cert_path, &client_key_path)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
 
         // Do a getinfo first, before opening the wallet
-        let info = grpcconnector::get_info(server.clone(), dangerous)
-            .map_err(|e| std::io::Error::new(ErrorKind::ConnectionRefused, e))?;
+        let mut last_err = None;
+        let mut info = None;
+        for server in servers.iter() {
+            let host = server.host().ok_or_else(||
+                std::io::Error::new(ErrorKind::InvalidInput, format!("No host in server uri {}", server)))?;
+
+            let overridden = ip_override.as_ref().map_or(false, |(h, _)| h.eq_ignore_ascii_case(host));
+            if proxy.is_none() && !overridden {
+                let port = server.port_part().map(|p| p.as_u16()).unwrap_or(grpcconnector::default_port(server.scheme_str()));
+                let resolved = format!("{}:{}", host, port)
+                    .to_socket_addrs()
+                    .map(|mut a| a.next().is_some());
+                if !resolved.unwrap_or(false) {
+                    warn!("Couldn't resolve server {}", server);
+                    last_err = Some(std::io::Error::new(ErrorKind::ConnectionRefused, "Couldn't resolve server!"));
+                    continue;
+                }
+            }
+
+            let conn = GrpcConnection::new(server.clone(), dangerous, grpcconnector::DEFAULT_CONNECT_TIMEOUT,
+                            grpcconnector::DEFAULT_UNARY_TIMEOUT, grpcconnector::DEFAULT_MAX_ATTEMPTS, proxy.clone())
+                .with_client_cert(client_cert_path.clone(), client_key_path.clone())
+                .with_ip_override(ip_override.clone());
+            match conn.get_info().and_then(|i| capabilities::check_server(&i).map(|_| i)) {
+                Ok(i)  => { info = Some(i); break; },
+                Err(e) => {
+                    warn!("Couldn't get info from server {}: {}", server, e);
+                    last_err = Some(std::io::Error::new(ErrorKind::ConnectionRefused, e));
+                }
+            }
+        }
+        let info = match info {
+            Some(i) => i,
+            None    => return Err(last_err.unwrap())
+        };
 
         // Create a Light Client Config
         let config = LightClientConfig {
-            server,
+            servers,
             chain_name                  : info.chain_name,
             sapling_activation_height   : info.sapling_activation_height,
             consensus_branch_id         : info.consensus_branch_id,
             anchor_offset               : ANCHOR_OFFSET,
             no_cert_verification        : dangerous,
             data_dir                    : None,
+            connect_timeout             : grpcconnector::DEFAULT_CONNECT_TIMEOUT,
+            unary_timeout               : grpcconnector::DEFAULT_UNARY_TIMEOUT,
+            stream_inactivity_timeout   : grpcconnector::DEFAULT_STREAM_INACTIVITY_TIMEOUT,
+            max_attempts                : grpcconnector::DEFAULT_MAX_ATTEMPTS,
+            proxy,
+            auto_save                   : false,
+            wallet_backups              : DEFAULT_WALLET_BACKUPS,
+            auto_select_server          : false,
+            max_reorg_depth             : DEFAULT_MAX_REORG_DEPTH,
+            info_ttl                    : DEFAULT_INFO_TTL,
+            ip_version                  : IpVersion::Any,
+            keepalive_interval          : Some(grpcconnector::DEFAULT_KEEPALIVE_INTERVAL),
+            keepalive_timeout           : grpcconnector::DEFAULT_KEEPALIVE_TIMEOUT,
+            client_cert_path,
+            client_key_path,
+            server_ip_override,
+            client_name                 : None,
+            compression                 : false,
+            max_send_per_tx             : None,
+            max_send_per_day            : None,
+            min_fee_per_action          : 0,
+            fresh_change_address        : true,
+            donate_dust_to_fee          : false,
+            offline                     : false,
+            block_connections           : 1,
+            max_message_size            : grpcconnector::DEFAULT_MAX_MESSAGE_SIZE,
+            wallet_name                 : None,
+            read_only                   : false,
+            force_unlock                : false,
+            compress_wallet_file        : true,
+            compress_wallet_buffer      : false,
+            checkpoint_interval         : DEFAULT_CHECKPOINT_INTERVAL,
         };
 
         Ok((config, info.block_height))
@@ -141,29 +499,37 @@ impl LightClientConfig {
             .map_err(|e|Error::new(ErrorKind::Other, format!("{}", e)))
     }
 
-    pub fn get_zcash_data_path(&self) -> Box<Path> {
-        let mut zcash_data_location; 
-        if self.data_dir.is_some() {
-            zcash_data_location = PathBuf::from(&self.data_dir.as_ref().unwrap());
+    /// Where a wallet of `chain_name` is kept when there's no `data_dir`: Ycash in the app data dir on macOS and
+    /// Windows, ~/.ycash elsewhere, with the test chains in a subdir so they never clobber the mainnet wallet
+    pub fn default_data_dir(chain_name: &str) -> PathBuf {
+        let mut zcash_data_location;
+        if cfg!(target_os="macos") || cfg!(target_os="windows") {
+            zcash_data_location = dirs::data_dir().expect("Couldn't determine app data directory!");
+            zcash_data_location.push("Ycash");
         } else {
-            if cfg!(target_os="macos") || cfg!(target_os="windows") {
-                zcash_data_location = dirs::data_dir().expect("Couldn't determine app data directory!");
-                zcash_data_location.push("Ycash");
-            } else {
-                zcash_data_location = dirs::home_dir().expect("Couldn't determine home directory!");
-                zcash_data_location.push(".ycash");
-            };
+            zcash_data_location = dirs::home_dir().expect("Couldn't determine home directory!");
+            zcash_data_location.push(".ycash");
+        };
 
-            match &self.chain_name[..] {
-                "main" => {},
-                "test" => zcash_data_location.push("testnet3"),
-                "regtest" => zcash_data_location.push("regtest"),
-                c         => panic!("Unknown chain {}", c),
-            };
-        }
+        match chain_name {
+            "main"    => {},
+            "test"    => zcash_data_location.push("testnet3"),
+            "regtest" => zcash_data_location.push("regtest"),
+            c         => panic!("Unknown chain {}", c),
+        };
+
+        zcash_data_location
+    }
+
+    /// The dir the wallet, its backups, the log and the server info are kept in, `data_dir` or else
+    /// `default_data_dir`. It's created if it doesn't exist yet.
+    pub fn get_data_dir(&self) -> Box<Path> {
+        let zcash_data_location = match &self.data_dir {
+            Some(dir) => PathBuf::from(dir),
+            None      => LightClientConfig::default_data_dir(&self.chain_name),
+        };
 
-        // Create directory if it doesn't exist
-        match std::fs::create_dir_all(zcash_data_location.clone()) {
+        match create_private_dir(&zcash_data_location) {
             Ok(_) => zcash_data_location.into_boxed_path(),
             Err(e) => {
                 eprintln!("Couldn't create zcash directory!\n{}", e);
@@ -172,37 +538,175 @@ impl LightClientConfig {
         }
     }
 
+    /// The data dir, under its old name
+    #[deprecated(note = "use get_data_dir")]
+    pub fn get_zcash_data_path(&self) -> Box<Path> {
+        self.get_data_dir()
+    }
+
+    /// The dir the previous versions of the wallet file are kept in, backups in the data dir. It's created
+    /// if it doesn't exist yet, so it's only for writing backups, `get_wallet_backup_path` is for reading them.
+    pub fn get_backup_dir(&self) -> io::Result<Box<Path>> {
+        let backup_dir = self.backup_dir_path();
+        create_private_dir(&backup_dir)?;
+
+        Ok(backup_dir)
+    }
+
+    fn backup_dir_path(&self) -> Box<Path> {
+        self.get_data_dir().join("backups").into_boxed_path()
+    }
+
+    /// The wallet file, lite_wallet.dat, or for a named wallet, lite_wallet_<name>.dat
     pub fn get_wallet_path(&self) -> Box<Path> {
-        let mut wallet_location = self.get_zcash_data_path().into_path_buf();
-        wallet_location.push(WALLET_NAME);
+        let mut wallet_location = self.get_data_dir().into_path_buf();
+        match &self.wallet_name {
+            Some(name) => wallet_location.push(wallet_file_name(name)),
+            None       => wallet_location.push(WALLET_NAME),
+        }
         
         wallet_location.into_boxed_path()
     }
 
+    pub fn get_server_info_path(&self) -> Box<Path> {
+        let mut info_location = self.get_data_dir().into_path_buf();
+        info_location.push(SERVER_INFO_NAME);
+
+        info_location.into_boxed_path()
+    }
+
+    /// Save the server's info, so that it can be shown, and the chain parameters used, when offline
+    pub fn save_server_info(&self, server: &http::Uri, info: &LightdInfo) {
+        let mut o = LightClient::info_json(info);
+        o["server"] = server.to_string().into();
+
+        if let Err(e) = std::fs::write(self.get_server_info_path(), o.pretty(2)) {
+            warn!("Couldn't save the server info to {}: {}", self.get_server_info_path().display(), e);
+        }
+    }
+
+    /// The server info saved by `save_server_info`, and the server it came from
+    pub fn read_server_info(&self) -> Option<(http::Uri, LightdInfo)> {
+        let j = json::parse(&std::fs::read_to_string(self.get_server_info_path()).ok()?).ok()?;
+
+        let server = j["server"].as_str()?.parse().ok()?;
+        let info = LightdInfo {
+            version:                    j["version"].as_str()?.to_string(),
+            vendor:                     j["vendor"].as_str()?.to_string(),
+            taddr_support:              j["taddr_support"].as_bool()?,
+            chain_name:                 j["chain_name"].as_str()?.to_string(),
+            sapling_activation_height:  j["sapling_activation_height"].as_u64()?,
+            consensus_branch_id:        j["consensus_branch_id"].as_str()?.to_string(),
+            block_height:               j["latest_block_height"].as_u64()?,
+        };
+
+        Some((server, info))
+    }
+
+    /// The `n`th most recent previous version of the wallet file, eg. backups/lite_wallet.dat.1
+    pub fn get_wallet_backup_path(&self, n: usize) -> Box<Path> {
+        let mut name = self.get_wallet_path().file_name().unwrap().to_os_string();
+        name.push(format!(".{}", n));
+
+        self.backup_dir_path().join(name).into_boxed_path()
+    }
+
+    /// Backups used to be kept next to the wallet file, eg. lite_wallet.dat.1. Move them to the backup dir,
+    /// unless there already is a backup there.
+    fn move_legacy_backups(&self) {
+        let legacy_backups = (1..=self.wallet_backups).any(|n| {
+            let mut name = self.get_wallet_path().into_path_buf().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name).exists()
+        });
+        if !legacy_backups {
+            return;
+        }
+
+        if let Err(e) = self.get_backup_dir() {
+            warn!("Couldn't create the backup dir, leaving the backups next to the wallet file: {}", e);
+            return;
+        }
+
+        for n in 1..=self.wallet_backups {
+            let mut name = self.get_wallet_path().into_path_buf().into_os_string();
+            name.push(format!(".{}", n));
+            let legacy = PathBuf::from(name);
+
+            let path = self.get_wallet_backup_path(n);
+            if legacy.exists() && !path.exists() {
+                match std::fs::rename(&legacy, &path) {
+                    Ok(_)  => info!("Moved backup {} to {}", n, path.display()),
+                    Err(e) => warn!("Couldn't move backup {} to {}: {}", n, path.display(), e),
+                }
+            }
+        }
+    }
+
     pub fn wallet_exists(&self) -> bool {
         return self.get_wallet_path().exists()
     }
 
     pub fn get_log_path(&self) -> Box<Path> {
-        let mut log_path = self.get_zcash_data_path().into_path_buf();
+        let mut log_path = self.get_data_dir().into_path_buf();
         log_path.push(LOGFILE_NAME);
 
         log_path.into_boxed_path()
     }
 
+    /// Create a connection to the configured server. The connection is lazy, it only dials
+    /// the server on the first call, unless `auto_select_server` is set, in which case all the 
+    /// servers are pinged first to pick the fastest one. Fails if there are no servers.
+    pub fn grpc_connection(&self) -> Result<GrpcConnection, String> {
+        let conn = GrpcConnection::with_servers(self.servers.clone(), self.no_cert_verification,
+                            self.connect_timeout, self.unary_timeout, self.max_attempts, self.proxy.clone())?
+            .with_stream_inactivity_timeout(self.stream_inactivity_timeout)
+            .expecting_chain(self.chain_name.clone(), self.sapling_activation_height)
+            .with_ip_version(self.ip_version)
+            .with_keepalive(self.keepalive_interval, self.keepalive_timeout)
+            .with_client_cert(self.client_cert_path.clone(), self.client_key_path.clone())
+            .with_client_name(self.client_name.clone())
+            .with_compression(self.compression)
+            .with_max_message_size(self.max_message_size)
+            .with_offline(self.offline)
+            .with_ip_override(LightClientConfig::ip_override_for(&self.servers, self.server_ip_override));
+
+        if self.auto_select_server && !self.offline && self.servers.len() > 1 {
+            conn.select_fastest();
+        }
+
+        Ok(conn)
+    }
+
+    /// The IP override applies to the first server's host only, so that other servers, and servers
+    /// changed to later, are resolved as usual
+    fn ip_override_for(servers: &[http::Uri], ip: Option<IpAddr>) -> Option<(String, IpAddr)> {
+        match (servers.first().and_then(|s| s.host()), ip) {
+            (Some(host), Some(ip)) => Some((host.to_string(), ip)),
+            _                      => None
+        }
+    }
+
     pub fn get_initial_state(&self, height: u64) -> Option<(u64, &str, &str)> {
         checkpoints::get_closest_checkpoint(&self.chain_name, height)
     }
 
+    /// Parse the server uri. If no scheme is given, https is assumed. http uris connect without TLS,
+    /// and default to port 9067 instead of 443.
     pub fn get_server_or_default(server: Option<String>) -> http::Uri {
         match server {
             Some(s) => {
-                let mut s = if s.starts_with("http") {s} else { "http://".to_string() + &s};
+                let s = if s.starts_with("http") {s} else { "https://".to_string() + &s};
                 let uri: http::Uri = s.parse().unwrap();
-                if uri.port_part().is_none() {
-                    s = s + ":443";
+                if uri.port_part().is_some() {
+                    return uri;
                 }
-                s
+
+                // Add the port to the authority, keeping any path the server is mounted under
+                let port = grpcconnector::default_port(uri.scheme_str());
+                let mut parts = uri.into_parts();
+                parts.authority = parts.authority.map(|a| format!("{}:{}", a, port).parse().unwrap());
+                http::Uri::from_parts(parts).unwrap().to_string()
             }
             None    => DEFAULT_SERVER.to_string()
         }.parse().unwrap()
@@ -235,6 +739,15 @@ impl LightClientConfig {
         }
     }
 
+    pub fn hrp_sapling_viewing_key(&self) -> &str {
+        match &self.chain_name[..] {
+            "main"    => mainnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+            "test"    => testnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+            "regtest" => regtest::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+            c         => panic!("Unknown chain {}", c)
+        }
+    }
+
     pub fn base58_pubkey_address(&self) -> [u8; 2] {
         match &self.chain_name[..] {
             "main"    => mainnet::B58_PUBKEY_ADDRESS_PREFIX,
@@ -254,6 +767,24 @@ impl LightClientConfig {
         }
     }
 
+    pub fn base58_xprv_prefix(&self) -> [u8; 4] {
+        match &self.chain_name[..] {
+            "main"    => [0x04, 0x88, 0xAD, 0xE4],
+            "test"    => [0x04, 0x35, 0x83, 0x94],
+            "regtest" => [0x04, 0x35, 0x83, 0x94],
+            c         => panic!("Unknown chain {}", c)
+        }
+    }
+
+    pub fn base58_xpub_prefix(&self) -> [u8; 4] {
+        match &self.chain_name[..] {
+            "main"    => [0x04, 0x88, 0xB2, 0x1E],
+            "test"    => [0x04, 0x35, 0x87, 0xCF],
+            "regtest" => [0x04, 0x35, 0x87, 0xCF],
+            c         => panic!("Unknown chain {}", c)
+        }
+    }
+
     pub fn base58_secretkey_prefix(&self) -> [u8; 1] {
         match &self.chain_name[..] {
             "main"    => [0x80],
@@ -275,6 +806,16 @@ pub struct LightClient {
 
     sync_lock           : Mutex<()>,
     sync_status         : Arc<RwLock<WalletStatus>>, // The current syncing status of the Wallet.
+
+    grpc                : GrpcConnection,            // Connection to the lightwalletd server, shared by all calls
+    network_stats       : Arc<NetworkStats>,         // Calls, bytes and latency of the calls to the servers
+    server_info         : RwLock<Option<ServerInfo>>, // The last successful getinfo response from the server
+    last_auto_save      : Mutex<Option<Instant>>,    // When the wallet was last saved automatically
+    send_queue          : Mutex<SendQueue>,          // Payments waiting to be sent together by do_flush_sends
+    note_scanner        : RwLock<Arc<dyn NoteScanner>>, // Finds the wallet's notes in the blocks while syncing
+    balance_cache       : Mutex<BalanceCache>,       // Reused by do_balance until the wallet changes
+    shut_down           : AtomicBool,                // Set by do_shutdown, which stops syncs
+    wallet_lock         : Mutex<Option<WalletLock>>, // Keeps other processes from opening the wallet file, see `lock_wallet`
 }
 
 impl LightClient {
@@ -300,21 +841,14 @@ impl LightClient {
     /// Method to create a test-only version of the LightClient
     #[allow(dead_code)]
     pub fn unconnected(seed_phrase: String, dir: Option<String>) -> io::Result<Self> {
-        let config = LightClientConfig::create_unconnected("test".to_string(), dir);
-        let mut l = LightClient {
-                wallet          : Arc::new(RwLock::new(LightWallet::new(Some(seed_phrase), &config, 0)?)),
-                config          : config.clone(),
-                sapling_output  : vec![], 
-                sapling_spend   : vec![],
-                sync_lock       : Mutex::new(()),
-                sync_status     : Arc::new(RwLock::new(WalletStatus::new())),
-            };
-
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir.clone());
+        // Without a dir, the client is never read from or saved to disk, so it mustn't lock the user's wallet
+        let wallet_lock = if dir.is_some() { LightClient::lock_wallet(&config)? } else { None };
+        let l = LightClient::from_parts(&config, LightWallet::new(Some(seed_phrase), &config, 0)?, wallet_lock)?;
         l.set_wallet_initial_state(0);
-        l.read_sapling_params();
 
         info!("Created new wallet!");
-        info!("Created LightClient to {}", &config.server);
+        info!("Created LightClient to {}", &config.servers[0]);
 
         Ok(l)
     }
@@ -327,20 +861,12 @@ impl LightClient {
                     "Cannot create a new wallet from seed, because a wallet already exists"));
         }
 
-        let mut l = LightClient {
-                wallet          : Arc::new(RwLock::new(LightWallet::new(None, config, latest_block)?)),
-                config          : config.clone(),
-                sapling_output  : vec![], 
-                sapling_spend   : vec![],
-                sync_lock       : Mutex::new(()),
-                sync_status     : Arc::new(RwLock::new(WalletStatus::new())),
-            };
-
+        let wallet_lock = LightClient::lock_wallet(config)?;
+        let l = LightClient::from_parts(config, LightWallet::new(None, config, latest_block)?, wallet_lock)?;
         l.set_wallet_initial_state(latest_block);
-        l.read_sapling_params();
 
         info!("Created new wallet with a new seed!");
-        info!("Created LightClient to {}", &config.server);
+        info!("Created LightClient to {}", &config.servers[0]);
 
         // Save
         l.do_save().map_err(|s| io::Error::new(ErrorKind::PermissionDenied, s))?;
@@ -354,21 +880,14 @@ impl LightClient {
                     "Cannot create a new wallet from seed, because a wallet already exists"));
         }
 
-        let mut l = LightClient {
-                wallet          : Arc::new(RwLock::new(LightWallet::new(Some(seed_phrase), config, birthday)?)),
-                config          : config.clone(),
-                sapling_output  : vec![], 
-                sapling_spend   : vec![],
-                sync_lock       : Mutex::new(()),
-                sync_status     : Arc::new(RwLock::new(WalletStatus::new())),
-            };
+        let wallet_lock = LightClient::lock_wallet(config)?;
+        let l = LightClient::from_parts(config, LightWallet::new(Some(seed_phrase), config, birthday)?, wallet_lock)?;
 
         println!("Setting birthday to {}", birthday);
         l.set_wallet_initial_state(birthday);
-        l.read_sapling_params();
 
         info!("Created new wallet!");
-        info!("Created LightClient to {}", &config.server);
+        info!("Created LightClient to {}", &config.servers[0]);
 
         // Save
         l.do_save().map_err(|s| io::Error::new(ErrorKind::PermissionDenied, s))?;
@@ -376,8 +895,91 @@ impl LightClient {
         Ok(l)
     }
 
+    /// Create a wallet from a backup made by `do_export_backup`, with the same seed, addresses, accounts and imported
+    /// viewing keys. It has none of the scan state, so the next sync rescans from the backup's birthday. Will fail
+    /// if a wallet file already exists on disk, or if the password is wrong.
+    pub fn restore_from_backup(backup: &[u8], password: &str, config: &LightClientConfig) -> io::Result<Self> {
+        if config.wallet_exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists,
+                    "Cannot restore the backup, because a wallet already exists"));
+        }
+
+        let backup = WalletBackup::decrypt(backup, password)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if backup.chain_name != config.chain_name {
+            return Err(io::Error::new(ErrorKind::InvalidData,
+                    format!("The backup is of a {} wallet, not {}", backup.chain_name, config.chain_name)));
+        }
+
+        // The wallet wipes its copy of the phrase once it has the seed
+        let l = LightClient::new_from_phrase(backup.seed_phrase.to_string(), config, backup.birthday, false)?;
+        {
+            let wallet = l.wallet.read().unwrap();
+            for (account, a) in backup.accounts.iter().enumerate() {
+                let account = account as u32;
+                if account > 0 {
+                    wallet.add_account(&a.name).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                }
+
+                // Restoring from the seed already derived a few addresses in the default account
+                let (zaddrs, taddrs) = wallet.account_addresses(account);
+                for _ in zaddrs.len() as u32 .. a.zaddrs {
+                    wallet.add_zaddr_to_account(account);
+                }
+                for _ in taddrs.len() as u32 .. a.taddrs {
+                    wallet.add_taddr_to_account(account);
+                }
+            }
+
+            for key in backup.viewing_keys.iter() {
+                wallet.add_watch_only_key(key).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+        }
+
+        info!("Restored wallet from a backup, birthday {}", backup.birthday);
+        l.do_save().map_err(|s| io::Error::new(ErrorKind::PermissionDenied, s))?;
+
+        Ok(l)
+    }
+
+    /// Rebuild a wallet from the JSON of `do_export_json`. The notes have no witnesses, so they can't be spent
+    /// until the wallet is rescanned with `do_rescan`. Will fail if a wallet file already exists on disk.
+    pub fn import_json(json: &JsonValue, config: &LightClientConfig) -> io::Result<Self> {
+        if config.wallet_exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists,
+                    "Cannot import the wallet, because a wallet already exists"));
+        }
+
+        let wallet = LightWallet::from_json(json, config)?;
+        let birthday = wallet.get_birthday();
+        let lc = LightClient::with_wallet(config, wallet)?;
+        lc.set_wallet_initial_state(birthday);
+
+        info!("Imported wallet with birthday {}", birthday);
+        lc.do_save().map_err(|s| io::Error::new(ErrorKind::PermissionDenied, s))?;
+
+        Ok(lc)
+    }
+
     pub fn read_from_buffer<R: Read>(config: &LightClientConfig, mut reader: R) -> io::Result<Self>{
         let wallet = LightWallet::read(&mut reader, config)?;
+        let lc = LightClient::with_wallet(config, wallet)?;
+
+        info!("Read wallet with birthday {}", lc.wallet.read().unwrap().get_first_tx_block());
+        info!("Created LightClient to {}", &config.servers[0]);
+
+        Ok(lc)
+    }
+
+    fn with_wallet(config: &LightClientConfig, wallet: LightWallet) -> io::Result<Self> {
+        let wallet_lock = LightClient::lock_wallet(config)?;
+        LightClient::from_parts(config, wallet, wallet_lock)
+    }
+
+    /// The client for `wallet`, holding `wallet_lock` on its file, see `lock_wallet`. All the constructors end up
+    /// here, once they have the wallet.
+    fn from_parts(config: &LightClientConfig, wallet: LightWallet, wallet_lock: Option<WalletLock>) -> io::Result<Self> {
+        let network_stats = Arc::new(NetworkStats::new());
         let mut lc = LightClient {
             wallet          : Arc::new(RwLock::new(wallet)),
             config          : config.clone(),
@@ -385,14 +987,59 @@ impl LightClient {
             sapling_spend   : vec![],
             sync_lock       : Mutex::new(()),
             sync_status     : Arc::new(RwLock::new(WalletStatus::new())),
+            grpc            : config.grpc_connection().map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+                                    .with_network_stats(network_stats.clone()),
+            network_stats,
+            server_info     : RwLock::new(None),
+            last_auto_save  : Mutex::new(None),
+            send_queue      : Mutex::new(SendQueue::new()),
+            note_scanner    : RwLock::new(Arc::new(CpuScanner{})),
+            balance_cache   : Mutex::new(BalanceCache { state: 0, balances: [None, None], computed: 0 }),
+            shut_down       : AtomicBool::new(false),
+            wallet_lock     : Mutex::new(wallet_lock),
         };
 
         lc.read_sapling_params();
+        Ok(lc)
+    }
 
-        info!("Read wallet with birthday {}", lc.wallet.read().unwrap().get_first_tx_block());
-        info!("Created LightClient to {}", &config.server);
+    /// Lock the wallet file, so that no other process opens it for writing while this client has it open, see
+    /// `WalletLock`. Read-only clients don't lock it, since they never write it.
+    fn lock_wallet(config: &LightClientConfig) -> io::Result<Option<WalletLock>> {
+        if config.read_only {
+            return Ok(None);
+        }
 
-        Ok(lc)
+        WalletLock::acquire(&config.get_wallet_path(), config.force_unlock).map(Some)
+    }
+
+    /// Let other processes open the wallet file again, eg. when the app is done with this client but can't drop
+    /// it yet. Saving the client after this could overwrite their changes.
+    pub fn release_lock(&self) {
+        if self.wallet_lock.lock().unwrap().take().is_some() {
+            info!("Released the lock on the wallet");
+        }
+    }
+
+    /// When the wallet file is corrupt, the most recent backup of it that can still be read
+    fn read_newest_backup(config: &LightClientConfig) -> Option<LightWallet> {
+        (1..=config.wallet_backups).find_map(|n| {
+            let path = config.get_wallet_backup_path(n);
+            let wallet = File::open(&path).and_then(|f| LightWallet::read(BufReader::new(f), config));
+
+            match wallet {
+                Ok(wallet) => {
+                    let m = format!("WARNING: The wallet file is corrupt, so opened backup {} ({}) instead", n, path.display());
+                    warn!("{}", m);
+                    println!("{}", m);
+                    Some(wallet)
+                },
+                Err(e) => {
+                    info!("Couldn't read backup {}: {}", n, e);
+                    None
+                }
+            }
+        })
     }
 
     pub fn read_from_disk(config: &LightClientConfig) -> io::Result<Self> {
@@ -401,22 +1048,26 @@ impl LightClient {
                     format!("Cannot read wallet. No file at {}", config.get_wallet_path().display())));
         }
 
+        // Before reading it, so that it isn't read while another process is still changing it
+        let wallet_lock = LightClient::lock_wallet(config)?;
+        if !config.read_only {
+            config.move_legacy_backups();
+        }
+
         let mut file_buffer = BufReader::new(File::open(config.get_wallet_path())?);
             
-        let wallet = LightWallet::read(&mut file_buffer, config)?;
-        let mut lc = LightClient {
-            wallet          : Arc::new(RwLock::new(wallet)),
-            config          : config.clone(),
-            sapling_output  : vec![], 
-            sapling_spend   : vec![],
-            sync_lock       : Mutex::new(()),
-            sync_status     : Arc::new(RwLock::new(WalletStatus::new())),
+        let wallet = match LightWallet::read(&mut file_buffer, config) {
+            Ok(wallet) => wallet,
+            Err(e)     => if is_wallet_corrupt(&e) {
+                LightClient::read_newest_backup(config).ok_or(e)?
+            } else {
+                return Err(e);
+            }
         };
-
-        lc.read_sapling_params();
+        let lc = LightClient::from_parts(config, wallet, wallet_lock)?;
 
         info!("Read wallet with birthday {}", lc.wallet.read().unwrap().get_first_tx_block());
-        info!("Created LightClient to {}", &config.server);
+        info!("Created LightClient to {}", &config.servers[0]);
 
         if crate::lightwallet::bugs::BugBip39Derivation::has_bug(&lc) {
             let m = format!("WARNING!!!\nYour wallet has a bip39derivation bug that's showing incorrect addresses.\nPlease run 'fixbip39bug' to automatically fix the address derivation in your wallet!\nPlease see: https://github.com/adityapk00/zecwallet-light-cli/blob/master/bip39bug.md");
@@ -443,8 +1094,18 @@ impl LightClient {
         use bip39::{Mnemonic, Language};
         use zcash_primitives::serialize::Vector;
 
-        let mut reader = BufReader::new(File::open(config.get_wallet_path()).unwrap());
-        let version = reader.read_u64::<LittleEndian>().unwrap();
+        let mut file = BufReader::new(File::open(config.get_wallet_path()).unwrap());
+        let mut version = file.read_u64::<LittleEndian>().unwrap();
+
+        // A compressed wallet is the wallet file gzipped. The seed is near the start, so it can be read even
+        // if the rest is damaged.
+        let mut reader: Box<dyn Read> = if version.to_le_bytes() == COMPRESSED_WALLET_MAGIC {
+            let mut decoder = flate2::read::GzDecoder::new(file);
+            version = decoder.read_u64::<LittleEndian>().map_err(|e| format!("Couldn't decompress the wallet. {}", e))?;
+            Box::new(decoder)
+        } else {
+            Box::new(file)
+        };
         println!("Reading wallet version {}", version);
 
         let encrypted = if version >= 4 {
@@ -468,26 +1129,31 @@ impl LightClient {
             vec![]
         };
 
+        let kdf = if version >= 10 {
+            Kdf::read(&mut reader).map_err(|e| format!("Failed to read the key derivation. {}", e))?
+        } else {
+            Kdf::DoubleSha256
+        };
+
         let phrase = if encrypted {
             use sodiumoxide::crypto::secretbox;
-            use crate::lightwallet::double_sha256;
 
-            // Get the doublesha256 of the password, which is the right length
-            let key = secretbox::Key::from_slice(&double_sha256(password.unwrap().as_bytes())).unwrap();
+            let password = Secret::new(password.unwrap());
+            let key = kdf.derive_key(&password).map_err(|e| e.to_string())?;
             let nonce = secretbox::Nonce::from_slice(&nonce).unwrap();
 
             let seed = match secretbox::open(&enc_seed, &nonce, &key) {
-                Ok(s) => s,
+                Ok(s) => Secret::new(s),
                 Err(_) => return Err("Decryption failed. Is your password correct?".to_string())
             };
             
             Mnemonic::from_entropy(&seed, Language::English)
         } else {
             // Seed
-            let mut seed_bytes = [0u8; 32];
-            reader.read_exact(&mut seed_bytes).unwrap();
+            let mut seed_bytes = Secret::new([0u8; 32]);
+            reader.read_exact(&mut *seed_bytes).unwrap();
 
-            Mnemonic::from_entropy(&seed_bytes, Language::English) 
+            Mnemonic::from_entropy(&*seed_bytes, Language::English) 
         }.map_err(|e| format!("Failed to read seed. {:?}", e));
         
         phrase.map(|m| m.phrase().to_string())
@@ -514,7 +1180,7 @@ impl LightClient {
             .map( |(addr, pk)|
                 object!{
                     "address"     => addr.clone(),
-                    "private_key" => pk.clone()
+                    "private_key" => pk.as_str()
                 }
             ).collect::<Vec<JsonValue>>();
 
@@ -527,7 +1193,7 @@ impl LightClient {
             .map( |(addr, sk)|
                 object!{
                     "address"     => addr.clone(),
-                    "private_key" => sk.clone(),
+                    "private_key" => sk.as_str(),
                 }
             ).collect::<Vec<JsonValue>>();
 
@@ -538,12 +1204,39 @@ impl LightClient {
         Ok(all_keys.into())
     }
 
+    /// Export the BIP32 extended keys for the transparent account, so that external software
+    /// can derive the wallet's t-addresses (at <path>/0/<index>)
+    pub fn do_export_transparent_xprv(&self) -> Result<JsonValue, &str> {
+        if !self.wallet.read().unwrap().is_unlocked_for_spending() {
+            error!("Wallet is locked");
+            return Err("Wallet is locked");
+        }
+
+        match self.wallet.read().unwrap().get_t_account_xkeys() {
+            Some((path, xprv, xpub)) => Ok(object!{
+                "derivation_path" => path,
+                "xprv"            => xprv,
+                "xpub"            => xpub,
+            }),
+            None => Err("Wallet is locked")
+        }
+    }
+
     pub fn do_address(&self) -> JsonValue {
         let wallet = self.wallet.read().unwrap();
 
-        // Collect z addresses
-        let z_addresses = wallet.zaddress.read().unwrap().iter().map( |ad| {
-            encode_payment_address(self.config.hrp_sapling_address(), &ad)
+        // Collect z addresses. Make sure each one decodes back to the same address, so that a
+        // corrupted diversifier in the wallet file doesn't show up as an unusable receive address
+        let z_addresses = wallet.zaddress.read().unwrap().iter().filter_map( |ad| {
+            let address = encode_payment_address(self.config.hrp_sapling_address(), &ad);
+
+            match decode_payment_address(self.config.hrp_sapling_address(), &address) {
+                Ok(Some(decoded)) if decoded == *ad => Some(address),
+                _ => {
+                    error!("Skipping invalid z address {:?} in wallet", ad.diversifier.0);
+                    None
+                }
+            }
         }).collect::<Vec<String>>();
 
         // Collect t addresses
@@ -556,8 +1249,26 @@ impl LightClient {
         }
     }
 
+    /// The wallet's balances. Polling UIs call this a lot, so the result is cached until the wallet's
+    /// notes, utxos, blocks or addresses change.
     pub fn do_balance(&self) -> JsonValue {
+        self.do_balance_with_watchonly(true)
+    }
+
+    /// Like `do_balance`, but without `include_watchonly`, the addresses of imported viewing keys are left out,
+    /// along with their funds in "zbalance" and "verified_zbalance". "spendable_only" is always just what can
+    /// be spent now: the verified z balance and the t balance, without the watch-only addresses' funds.
+    pub fn do_balance_with_watchonly(&self, include_watchonly: bool) -> JsonValue {
         let wallet = self.wallet.read().unwrap();
+        if !wallet.is_unlocked_for_viewing() {
+            return object!{ "error" => WALLET_LOCKED_FOR_VIEWING };
+        }
+
+        // Taken before computing, so that a change while computing makes the next call compute again
+        let state = wallet.state();
+        if let Some(balance) = self.cached_balance(state, include_watchonly) {
+            return balance;
+        }
 
         // Collect z addresses
         let z_addresses = wallet.zaddress.read().unwrap().iter().map( |ad| {
@@ -565,10 +1276,23 @@ impl LightClient {
             object!{
                 "address" => address.clone(),
                 "zbalance" => wallet.zbalance(Some(address.clone())),
-                "verified_zbalance" => wallet.verified_zbalance(Some(address)),
+                "verified_zbalance" => wallet.verified_zbalance(Some(address.clone())),
+                "watch_only" => wallet.is_watch_only_address(&address),
             }
         }).collect::<Vec<JsonValue>>();
 
+        let (watch_only, z_addresses): (Vec<JsonValue>, Vec<JsonValue>) = z_addresses.into_iter()
+            .partition(|a| a["watch_only"].as_bool().unwrap_or(false));
+        let watch_only_zbalance = watch_only.iter().map(|a| a["zbalance"].as_u64().unwrap_or(0)).sum::<u64>();
+        let watch_only_verified = watch_only.iter().map(|a| a["verified_zbalance"].as_u64().unwrap_or(0)).sum::<u64>();
+
+        let (zbalance, verified_zbalance, z_addresses) = if include_watchonly {
+            (wallet.zbalance(None), wallet.verified_zbalance(None), z_addresses.into_iter().chain(watch_only).collect())
+        } else {
+            (wallet.zbalance(None) - watch_only_zbalance, wallet.verified_zbalance(None) - watch_only_verified, z_addresses)
+        };
+        let spendable_only = wallet.verified_zbalance(None) - watch_only_verified + wallet.spendable_tbalance();
+
         // Collect t addresses
         let t_addresses = wallet.taddresses.read().unwrap().iter().map( |address| {
             // Get the balance for this address
@@ -577,102 +1301,538 @@ impl LightClient {
             object!{
                 "address" => address.clone(),
                 "balance" => balance,
+                "watch_only" => false,
             }
         }).collect::<Vec<JsonValue>>();
 
-        object!{
-            "zbalance"           => wallet.zbalance(None),
-            "verified_zbalance"  => wallet.verified_zbalance(None),
+        // The balances again, added up by account. Watch-only addresses aren't in any account.
+        let accounts = wallet.account_names().into_iter().enumerate().map(|(n, name)| {
+            let (zaddrs, taddrs) = wallet.account_addresses(n as u32);
+            object!{
+                "account"           => name,
+                "zbalance"          => zaddrs.iter().map(|a| wallet.zbalance(Some(a.clone()))).sum::<u64>(),
+                "verified_zbalance" => zaddrs.iter().map(|a| wallet.verified_zbalance(Some(a.clone()))).sum::<u64>(),
+                "tbalance"          => taddrs.iter().map(|a| wallet.tbalance(Some(a.clone()))).sum::<u64>(),
+            }
+        }).collect::<Vec<JsonValue>>();
+
+        let balance = object!{
+            "zbalance"           => zbalance,
+            "verified_zbalance"  => verified_zbalance,
+            "watch_only_zbalance" => watch_only_zbalance,
+            "spendable_only"     => spendable_only,
             "tbalance"           => wallet.tbalance(None),
             "z_addresses"        => z_addresses,
             "t_addresses"        => t_addresses,
+            "accounts"           => accounts,
+        };
+
+        // Both views are for the same state, so the other one goes when the wallet has changed
+        let mut cache = self.balance_cache.lock().unwrap();
+        if cache.state != state {
+            cache.state = state;
+            cache.balances = [None, None];
         }
+        cache.balances[include_watchonly as usize] = Some(balance.clone());
+        cache.computed += 1;
+
+        balance
     }
 
-    pub fn do_save(&self) -> Result<(), String> {        
-        // If the wallet is encrypted but unlocked, lock it again.
-        {
-            let mut wallet = self.wallet.write().unwrap();
-            if wallet.is_encrypted() && wallet.is_unlocked_for_spending() {
-                match wallet.lock() {
-                    Ok(_) => {},
-                    Err(e) => {
-                        let err = format!("ERR: {}", e);
-                        error!("{}", err);
-                        return Err(e.to_string());
-                    }
-                }
-            }
-        }        
+    fn cached_balance(&self, state: u64, include_watchonly: bool) -> Option<JsonValue> {
+        let cache = self.balance_cache.lock().unwrap();
+        cache.balances[include_watchonly as usize].as_ref().filter(|_| cache.state == state).cloned()
+    }
 
-        let mut file_buffer = BufWriter::with_capacity(
-            1_000_000, // 1 MB write buffer
-            File::create(self.config.get_wallet_path()).unwrap());
-        
-        let r = match self.wallet.write().unwrap().write(&mut file_buffer) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                let err = format!("ERR: {}", e);
-                error!("{}", err);
-                Err(e.to_string())
-            }
-        };
+    pub fn do_save(&self) -> Result<(), String> {
+        self.save_to(&self.config.get_wallet_path()).map(|_| ())
+    }
 
-        file_buffer.flush().map_err(|e| format!("{}", e))?;
+    /// Save the wallet to `path`, or to the wallet file in the data dir if there is none, without ever
+    /// leaving a half-written file behind. Refuses to save during a sync, when the wallet is halfway
+    /// through being updated. Returns the number of bytes written.
+    pub fn do_save_to_path(&self, path: Option<&Path>) -> Result<u64, String> {
+        let _lock = self.sync_lock.try_lock()
+            .map_err(|_| "A sync is in progress, save when it is done".to_string())?;
 
-        r
+        match path {
+            Some(path) => self.save_to(path),
+            None       => self.save_to(&self.config.get_wallet_path()),
+        }
     }
 
+    fn save_to(&self, path: &Path) -> Result<u64, String> {
+        self.check_writable()?;
 
-    pub fn do_save_to_buffer(&self) -> Result<Vec<u8>, String> {
-        // If the wallet is encrypted but unlocked, lock it again.
-        {
-           let mut wallet = self.wallet.write().unwrap();
-           if wallet.is_encrypted() && wallet.is_unlocked_for_spending() {
-               match wallet.lock() {
-                   Ok(_) => {},
-                   Err(e) => {
-                       let err = format!("ERR: {}", e);
-                       error!("{}", err);
-                       return Err(e.to_string());
-                   }
-               }
-           }
-       }        
-
-       let mut buffer: Vec<u8> = vec![];
-       match self.wallet.write().unwrap().write(&mut buffer) {
-           Ok(_) => Ok(buffer),
-           Err(e) => {
-               let err = format!("ERR: {}", e);
-               error!("{}", err);
-               Err(e.to_string())
-           }
-       }
-   }
+        // Saving over the wallet file keeps the versions before it, if there's somewhere to keep them
+        let backups = if *path == *self.config.get_wallet_path() && self.config.wallet_backups > 0 {
+            match self.config.get_backup_dir() {
+                Ok(_)  => (1..=self.config.wallet_backups).map(|n| self.config.get_wallet_backup_path(n)).collect(),
+                Err(e) => {
+                    warn!("Couldn't create the backup dir, saving without keeping a backup: {}", e);
+                    vec![]
+                }
+            }
+        } else {
+            vec![]
+        };
+
+        self.write_wallet_file(path, &backups)
+    }
+
+    /// Write the wallet to `path`, moving the file that was there to the first of `backups`, see `rotate_backups`
+    fn write_wallet_file(&self, path: &Path, backups: &[Box<Path>]) -> Result<u64, String> {
+        // Streamed to the file as it's serialized, so the wallet is never all in memory at once
+        let compress = self.config.compress_wallet_file;
+        self.with_wallet_to_save(|wallet| write_file_atomically(path, backups, |w| {
+            if compress { wallet.write_compressed(w) } else { wallet.write(w) }
+        }))
+    }
+
+    /// Save the wallet file the way auto-saves do. An encrypted wallet that's unlocked for spending is written
+    /// as if it were locked, but stays unlocked, so a sync doesn't lock it under the user. No backup is kept,
+    /// since auto-saves come every AUTO_SAVE_INTERVAL during a sync, and would soon have replaced every backup
+    /// with a copy of the wallet halfway through it.
+    fn auto_save_to(&self, path: &Path) -> Result<u64, String> {
+        self.check_writable()?;
+
+        // Borrowed for writing, like `with_wallet_to_save` does, so that nothing changes while it's saved
+        let compress = self.config.compress_wallet_file;
+        let wallet = self.wallet.write().unwrap();
+        write_file_atomically(path, &[], |w| {
+            if compress { wallet.write_compressed_locked(w) } else { wallet.write_locked(w) }
+        }).map_err(|e| {
+            error!("ERR: {}", e);
+            e
+        })
+    }
+
+    /// Call `save` with the wallet, which is locked again first if it's encrypted but unlocked for spending.
+    /// The wallet stays borrowed for writing in between, so that nothing changes while it's saved.
+    fn with_wallet_to_save<T, F>(&self, save: F) -> Result<T, String>
+            where F: FnOnce(&LightWallet) -> Result<T, String> {
+        let mut wallet = self.wallet.write().unwrap();
+        if wallet.is_encrypted() && wallet.is_unlocked_for_spending() {
+            if let Err(e) = wallet.lock_spending() {
+                error!("ERR: {}", e);
+                return Err(e.to_string());
+            }
+        }
+
+        save(&wallet).map_err(|e| {
+            error!("ERR: {}", e);
+            e
+        })
+    }
+
+    /// The previous versions of the wallet file that are kept in the data dir, most recent first, with
+    /// their size and when they were saved (seconds since the epoch)
+    pub fn do_list_backups(&self) -> JsonValue {
+        let backups = (1..=self.config.wallet_backups)
+            .filter_map(|n| {
+                let path = self.config.get_wallet_backup_path(n);
+                let metadata = std::fs::metadata(&path).ok()?;
+                let modified = metadata.modified().ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |d| d.as_secs());
+
+                Some(object!{
+                    "backup"   => n,
+                    "path"     => path.to_string_lossy().to_string(),
+                    "size"     => metadata.len(),
+                    "modified" => modified,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        JsonValue::Array(backups)
+    }
+
+    /// Replace the wallet with the `n`th backup from `do_list_backups`, if it can be read. The current
+    /// wallet becomes backup 1, and the other backups move down one. Can't be done during a sync.
+    pub fn do_restore_backup(&self, n: usize) -> Result<JsonValue, String> {
+        self.check_writable()?;
+        let _lock = self.sync_lock.try_lock()
+            .map_err(|_| "A sync is in progress, restore the backup when it is done".to_string())?;
+
+        if !(1..=self.config.wallet_backups).contains(&n) {
+            return Err(format!("No backup {}, at most {} are kept", n, self.config.wallet_backups));
+        }
+
+        let path = self.config.get_wallet_backup_path(n);
+        let bytes = std::fs::read(&path).map_err(|e| format!("Couldn't read backup {} ({}): {}", n, path.display(), e))?;
+        let wallet = LightWallet::read(&bytes[..], &self.config)
+            .map_err(|e| format!("Backup {} isn't a readable wallet: {}", n, e))?;
+
+        // The current wallet replaces the wallet file without a backup being kept, so that the save of the restored
+        // one moves it, and nothing else, to backup 1
+        self.write_wallet_file(&self.config.get_wallet_path(), &[])?;
+        *self.wallet.write().unwrap() = wallet;
+        self.do_save()?;
+
+        info!("Restored wallet backup {}", n);
+        Ok(object!{
+            "restored"  => n,
+            "synced_to" => self.wallet.read().unwrap().last_scanned_height(),
+        })
+    }
+
+    /// Whether the wallet is saved without being asked to: during syncs, after sends, and on shutdown
+    fn auto_saves(&self) -> bool {
+        self.config.auto_save && !self.config.read_only
+    }
+
+    /// Save the wallet if auto_save is on. Unless `force` is set, saves are spaced at least
+    /// AUTO_SAVE_INTERVAL apart, so that syncs don't write the wallet after every batch of blocks.
+    /// See `auto_save_to`.
+    fn auto_save(&self, force: bool) {
+        if !self.auto_saves() {
+            return;
+        }
+
+        let mut last_save = self.last_auto_save.lock().unwrap();
+        if !force && last_save.map_or(false, |t| t.elapsed() < AUTO_SAVE_INTERVAL) {
+            return;
+        }
+
+        match self.auto_save_to(&self.config.get_wallet_path()) {
+            Ok(_)  => *last_save = Some(Instant::now()),
+            Err(e) => error!("Auto-save failed: {}", e)
+        }
+    }
+
+
+    /// The wallet's contents as JSON, to move it to a version of yecshell that can't read this one's wallet file,
+    /// or to look at what is in it. See `LightWallet::to_json`. `import_json` rebuilds the wallet from it.
+    pub fn do_export_json(&self) -> JsonValue {
+        self.wallet.read().unwrap().to_json()
+    }
+
+    /// Stop using the client, eg. when the app embedding it is closing. A running sync stops after the batch of
+    /// blocks it is on, the wallet is saved one last time if it is auto-saved, and the connection to the server is
+    /// closed. Syncs fail after this.
+    pub fn do_shutdown(&self) -> Result<(), String> {
+        self.shut_down.store(true, Ordering::SeqCst);
+
+        // Wait for a running sync to notice
+        let _lock = self.sync_lock.lock().unwrap();
+
+        let saved = if self.auto_saves() {
+            self.do_save()
+        } else {
+            Ok(())
+        };
+
+        self.grpc.disconnect();
+        info!("Shut down");
+
+        saved
+    }
+
+    /// The wallet file, in memory, eg. to upload it somewhere. Saving to disk doesn't need this, it streams
+    /// the wallet to the file. The buffer is allocated once, at the wallet's size, instead of growing (and
+    /// being copied) while the wallet is written into it. It's only compressed with `compress_wallet_buffer`,
+    /// since whatever it's uploaded to might read the wallet itself.
+    pub fn do_save_to_buffer(&self) -> Result<Vec<u8>, String> {
+        let mut buffer = vec![];
+        self.write_to_buffer(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Append the wallet file to `buffer`
+    fn write_to_buffer(&self, buffer: &mut Vec<u8>) -> Result<(), String> {
+        if self.config.compress_wallet_buffer {
+            return self.with_wallet_to_save(|wallet| wallet.write_compressed(&mut *buffer).map_err(|e| e.to_string()));
+        }
+
+        self.with_wallet_to_save(|wallet| {
+            buffer.reserve_exact(wallet.estimated_size());
+            wallet.write(&mut *buffer).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Export the whole wallet, scan state included, as a backup: a header describing the wallet (backup format
+    /// version, network, birthday and checkpoint height), followed by the serialized (and possibly encrypted) wallet.
+    /// For a small, password protected backup that is restored by rescanning, see `do_export_backup`.
+    pub fn do_export_full_backup(&self) -> Result<Vec<u8>, String> {
+        use byteorder::{LittleEndian, WriteBytesExt};
+
+        let birthday = self.wallet.read().unwrap().get_birthday();
+        let checkpoint_height = self.config.get_initial_state(birthday).map(|(h, _, _)| h).unwrap_or(0);
+
+        let mut buffer: Vec<u8> = vec![];
+        let write_header = |w: &mut Vec<u8>| -> io::Result<()> {
+            w.write_all(BACKUP_MAGIC)?;
+            w.write_u64::<LittleEndian>(BACKUP_VERSION)?;
+            utils::write_string(&mut *w, &self.config.chain_name)?;
+            w.write_u64::<LittleEndian>(birthday)?;
+            w.write_u64::<LittleEndian>(checkpoint_height)
+        };
+        write_header(&mut buffer).map_err(|e| format!("Couldn't write backup header: {}", e))?;
+
+        // The wallet goes right after the header, instead of being copied there
+        self.write_to_buffer(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Restore a LightClient from a backup made with `do_export_full_backup`. The backup has to be for the
+    /// same network as the config, and its header has to agree with the wallet in it.
+    pub fn do_import_backup(config: &LightClientConfig, bytes: &[u8]) -> io::Result<Self> {
+        use byteorder::{LittleEndian, ReadBytesExt};
+
+        let mut reader = bytes;
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != BACKUP_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a wallet backup"));
+        }
+
+        let version = reader.read_u64::<LittleEndian>()?;
+        if version > BACKUP_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, 
+                format!("Can't read backup version {}, only up to {} is supported", version, BACKUP_VERSION)));
+        }
+
+        let chain_name = utils::read_string(&mut reader)?;
+        if chain_name != config.chain_name {
+            return Err(Error::new(ErrorKind::InvalidData, 
+                format!("Backup is for the {} network, but this wallet is on {}", chain_name, config.chain_name)));
+        }
+
+        let birthday = reader.read_u64::<LittleEndian>()?;
+        let checkpoint_height = reader.read_u64::<LittleEndian>()?;
+        info!("Importing backup with birthday {}, checkpoint {}", birthday, checkpoint_height);
+
+        let expected_checkpoint = config.get_initial_state(birthday).map(|(h, _, _)| h).unwrap_or(0);
+        if checkpoint_height != expected_checkpoint {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("Backup's checkpoint {} doesn't match its birthday {}, which starts from checkpoint {}",
+                        checkpoint_height, birthday, expected_checkpoint)));
+        }
+
+        let lc = LightClient::read_from_buffer(config, reader)?;
+        let wallet_birthday = lc.wallet.read().unwrap().get_birthday();
+        if wallet_birthday != birthday {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("Backup's birthday {} doesn't match the birthday of the wallet in it, {}", birthday, wallet_birthday)));
+        }
 
+        Ok(lc)
+    }
+
+    /// A password protected backup of everything needed to restore the wallet with `restore_from_backup`: the seed,
+    /// the accounts and their addresses, the imported viewing keys and the birthday. See `lightwallet::backup` for
+    /// the format.
+    pub fn do_export_backup(&self, password: &str) -> Result<Vec<u8>, String> {
+        if !self.wallet.read().unwrap().is_unlocked_for_spending() {
+            error!("Wallet is locked");
+            return Err("Wallet is locked".to_string());
+        }
+
+        let wallet = self.wallet.read().unwrap();
+        let accounts = wallet.account_names().into_iter().enumerate().map(|(account, name)| {
+            let (zaddrs, taddrs) = wallet.account_addresses(account as u32);
+            BackupAccount { name, zaddrs: zaddrs.len() as u32, taddrs: taddrs.len() as u32 }
+        }).collect();
+
+        let backup = WalletBackup {
+            chain_name:   self.config.chain_name.clone(),
+            seed_phrase:  wallet.get_seed_phrase(),
+            birthday:     wallet.get_birthday(),
+            accounts,
+            viewing_keys: wallet.get_watch_only_keys(),
+        };
+
+        backup.encrypt(password)
+    }
+
+    /// Find the wallet's notes with `scanner` when syncing, instead of the default `CpuScanner`. Takes
+    /// effect from the next batch of blocks.
+    pub fn set_note_scanner(&self, scanner: Arc<dyn NoteScanner>) {
+        *self.note_scanner.write().unwrap() = scanner;
+    }
+
+    /// The server currently in use, which is not the first configured one after a failover
     pub fn get_server_uri(&self) -> http::Uri {
-        self.config.server.clone()
+        self.grpc.uri()
+    }
+
+    fn info_json(i: &LightdInfo) -> JsonValue {
+        object!{
+            "version" => i.version.clone(),
+            "vendor" => i.vendor.clone(),
+            "taddr_support" => i.taddr_support,
+            "chain_name" => i.chain_name.clone(),
+            "sapling_activation_height" => i.sapling_activation_height,
+            "consensus_branch_id" => i.consensus_branch_id.clone(),
+            "latest_block_height" => i.block_height,
+            "capabilities" => capabilities::to_json(i)
+        }
+    }
+
+    /// The last info the current server sent, if there is one, and it isn't older than `max_age`. Info
+    /// from another server is never used, because the client may have failed over since.
+    fn cached_info(&self, max_age: Option<Duration>) -> Option<LightdInfo> {
+        let server = self.get_server_uri();
+        self.server_info.read().unwrap().as_ref()
+            .filter(|s| s.server == server && max_age.map_or(true, |age| s.fetched.elapsed() <= age))
+            .map(|s| s.info.clone())
+    }
+
+    fn set_server_info(&self, info: LightdInfo) {
+        let server = self.get_server_uri();
+
+        // Say what won't work once per server, not on every refresh
+        let mut server_info = self.server_info.write().unwrap();
+        if server_info.as_ref().map_or(true, |s| s.server != server || s.info.version != info.version) {
+            for feature in capabilities::missing(&info) {
+                warn!("{}, so it is turned off", capabilities::unsupported_error(&info, feature));
+            }
+        }
+
+        *server_info = Some(ServerInfo { info, server, fetched: Instant::now() });
+    }
+
+    /// The server's info, from the cache if it was fetched less than `info_ttl` ago, unless `force_refresh`
+    /// is set, eg. to get the current tip.
+    pub fn get_info(&self, force_refresh: bool) -> Result<LightdInfo, String> {
+        if !force_refresh {
+            if let Some(i) = self.cached_info(Some(self.config.info_ttl)) {
+                return Ok(i);
+            }
+        }
+
+        let i = self.grpc.get_info()?;
+        self.set_server_info(i.clone());
+        self.config.save_server_info(&self.get_server_uri(), &i);
+        Ok(i)
     }
 
-    pub fn do_info(&self) -> String {
-        match get_info(self.get_server_uri(), self.config.no_cert_verification) {
+    /// Get the server's info. If the server can't be reached, the last successful response
+    /// is returned instead, marked as "stale", along with the error. When offline, that is the
+    /// info saved the last time the wallet was online.
+    pub fn do_info(&self, force_refresh: bool) -> String {
+        match self.get_info(force_refresh) {
             Ok(i) => {
-                let o = object!{
-                    "version" => i.version,
-                    "vendor" => i.vendor,
-                    "taddr_support" => i.taddr_support,
-                    "chain_name" => i.chain_name,
-                    "sapling_activation_height" => i.sapling_activation_height,
-                    "consensus_branch_id" => i.consensus_branch_id,
-                    "latest_block_height" => i.block_height
-                };
+                let mut o = LightClient::info_json(&i);
+                o["server"] = self.get_server_uri().to_string().into();
                 o.pretty(2)
             },
-            Err(e) => e
+            Err(e) => {
+                let saved = || self.config.read_server_info()
+                                .filter(|(server, _)| *server == self.get_server_uri())
+                                .map(|(_, i)| i);
+
+                match self.cached_info(None).or_else(saved) {
+                    Some(i) => {
+                        warn!("Couldn't refresh server info, returning cached info. {}", e);
+
+                        let mut o = LightClient::info_json(&i);
+                        o["server"] = self.get_server_uri().to_string().into();
+                        o["stale"] = true.into();
+                        o["error"] = e.into();
+                        o.pretty(2)
+                    },
+                    None => e
+                }
+            }
+        }
+    }
+
+    /// Switch to another server without restarting. The new server has to be reachable and on the 
+    /// wallet's chain, otherwise the current one is kept. This can't be done during a sync, so it fails
+    /// if one is running. The servers in `config` are left as they were started with.
+    pub fn do_change_server(&self, uri: &str) -> Result<JsonValue, String> {
+        let _lock = self.sync_lock.try_lock()
+            .map_err(|_| "A sync is in progress, change the server when it is done".to_string())?;
+
+        let s = if uri.starts_with("http") { uri.to_string() } else { format!("https://{}", uri) };
+        match s.parse::<http::Uri>() {
+            Ok(u) if u.host().is_some() => {},
+            _ => return Err(format!("Invalid server uri {}", uri))
+        }
+        let server = LightClientConfig::get_server_or_default(Some(uri.to_string()));
+
+        // Talk to the new server on its own first, so that nothing changes if it can't be used
+        let mut probe_config = self.config.clone();
+        probe_config.servers = vec![server.clone()];
+        probe_config.auto_select_server = false;
+        probe_config.server_ip_override = None;
+
+        let info = probe_config.grpc_connection().and_then(|conn| conn.get_info())
+            .map_err(|e| format!("Couldn't reach {}: {}", server, e))?;
+        capabilities::check_server(&info)?;
+        if info.chain_name != self.config.chain_name || info.sapling_activation_height != self.config.sapling_activation_height {
+            return Err(format!("{} is on chain {} (sapling at {}), but the wallet is on {} (sapling at {})", server,
+                info.chain_name, info.sapling_activation_height, self.config.chain_name, self.config.sapling_activation_height));
+        }
+
+        self.grpc.change_servers(vec![server.clone()])?;
+        self.set_server_info(info.clone());
+        self.config.save_server_info(&server, &info);
+        info!("Changed server to {}", server);
+
+        let mut o = LightClient::info_json(&info);
+        o["server"] = server.to_string().into();
+        Ok(o)
+    }
+
+    /// Check if the server is reachable, and how long it takes to respond
+    pub fn do_ping(&self, samples: u32) -> JsonValue {
+        let start = Instant::now();
+        let server = self.get_server_uri();
+        if self.config.offline {
+            return object!{
+                "server"      => server.to_string(),
+                "reachable"   => false,
+                "error"       => grpcconnector::OFFLINE_ERROR,
+                "elapsed_ms"  => 0,
+            };
+        }
+
+        match self.grpc.ping(server.clone(), samples) {
+            Ok((info, tip, times)) => {
+                let ms = times.iter().map(|t| t.as_millis() as u64).collect::<Vec<_>>();
+
+                object!{
+                    "server"      => server.to_string(),
+                    "reachable"   => true,
+                    "version"     => info.version,
+                    "chain_tip"   => tip,
+                    "samples"     => ms.len(),
+                    "min_ms"      => *ms.iter().min().unwrap(),
+                    "avg_ms"      => ms.iter().sum::<u64>() / ms.len() as u64,
+                    "max_ms"      => *ms.iter().max().unwrap(),
+                }
+            },
+            Err(e) => object!{
+                "server"      => server.to_string(),
+                "reachable"   => false,
+                "error"       => e,
+                "elapsed_ms"  => start.elapsed().as_millis() as u64,
+            }
         }
     }
 
+    /// The server's chain tip, and how far behind it the wallet is
+    pub fn do_height(&self) -> Result<JsonValue, String> {
+        let tip = self.grpc.get_latest_block()?;
+
+        // Block hashes are displayed byte-reversed, like the full node does
+        let mut hash = tip.hash.clone();
+        hash.reverse();
+
+        let wallet_height = self.last_scanned_height();
+
+        Ok(object!{
+            "server_height" => tip.height,
+            "server_hash"   => hex::encode(hash),
+            "wallet_height" => wallet_height,
+            "lag"           => tip.height.saturating_sub(wallet_height),
+        })
+    }
+
     pub fn do_seed_phrase(&self) -> Result<JsonValue, &str> {
         if !self.wallet.read().unwrap().is_unlocked_for_spending() {
             error!("Wallet is locked");
@@ -681,85 +1841,114 @@ impl LightClient {
 
         let wallet = self.wallet.read().unwrap();
         Ok(object!{
-            "seed"     => wallet.get_seed_phrase(),
+            "seed"     => wallet.get_seed_phrase().as_str(),
             "birthday" => wallet.get_birthday()
         })
     }
 
+    /// The nullifiers of the wallet's unspent notes, with the note each one is for, so that other tools can watch
+    /// the chain for the notes being spent. Anyone who has them can tell when the notes are spent, so the wallet
+    /// has to be unlocked. The nullifiers are hex, in the byte order they are in transactions and compact blocks.
+    pub fn do_nullifiers(&self) -> Result<JsonValue, &str> {
+        if !self.wallet.read().unwrap().is_unlocked_for_spending() {
+            error!("Wallet is locked");
+            return Err("Wallet is locked");
+        }
+
+        let wallet = self.wallet.read().unwrap();
+        let mut nullifiers = wallet.txs.read().unwrap().iter()
+            .flat_map(|(txid, wtx)| {
+                wtx.notes.iter().filter(|nd| nd.spent.is_none()).map(move |nd| object!{
+                    "nullifier"         => hex::encode(nd.nullifier()),
+                    "address"           => LightWallet::note_address(self.config.hrp_sapling_address(), nd),
+                    "value"             => nd.note.value,
+                    "created_in_block"  => wtx.block,
+                    "created_in_txid"   => format!("{}", txid),
+                    "unconfirmed_spent" => nd.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
+                })
+            })
+            .collect::<Vec<JsonValue>>();
+        nullifiers.sort_by_key(|n| n["created_in_block"].as_i32());
+
+        Ok(JsonValue::Array(nullifiers))
+    }
+
     // Return a list of all notes, spent and unspent
     pub fn do_list_notes(&self, all_notes: bool) -> JsonValue {
+        self.do_list_notes_filtered(all_notes, &NoteFilter::default())
+    }
+
+    /// List notes and utxos, only including the ones that match the filter
+    pub fn do_list_notes_filtered(&self, all_notes: bool, filter: &NoteFilter) -> JsonValue {
         let mut unspent_notes: Vec<JsonValue> = vec![];
         let mut spent_notes  : Vec<JsonValue> = vec![];
         let mut pending_notes: Vec<JsonValue> = vec![];
 
-        {
-            // Collect Sapling notes
-            let wallet = self.wallet.read().unwrap();
-            wallet.txs.read().unwrap().iter()
-                .flat_map( |(txid, wtx)| {
-                    wtx.notes.iter().filter_map(move |nd| 
-                        if !all_notes && nd.spent.is_some() {
-                            None
-                        } else {
-                            Some(object!{
-                                "created_in_block"   => wtx.block,
-                                "datetime"           => wtx.datetime,
-                                "created_in_txid"    => format!("{}", txid),
-                                "value"              => nd.note.value,
-                                "is_change"          => nd.is_change,
-                                "address"            => LightWallet::note_address(self.config.hrp_sapling_address(), nd),
-                                "spent"              => nd.spent.map(|spent_txid| format!("{}", spent_txid)),
-                                "unconfirmed_spent"  => nd.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
-                            })
-                        }
-                    )
-                })
-                .for_each( |note| {
-                    if note["spent"].is_null() && note["unconfirmed_spent"].is_null() {
-                        unspent_notes.push(note);
-                    } else if !note["spent"].is_null() {
-                        spent_notes.push(note);
-                    } else {
-                        pending_notes.push(note);
-                    }
-                });
-        }
-        
         let mut unspent_utxos: Vec<JsonValue> = vec![];
         let mut spent_utxos  : Vec<JsonValue> = vec![];
         let mut pending_utxos: Vec<JsonValue> = vec![];
-        
+
+        let last_height = self.last_scanned_height();
+
+        // Each note and utxo goes in its list by its spent fields, before any JSON is made of it
         {
             let wallet = self.wallet.read().unwrap();
-            wallet.txs.read().unwrap().iter()
-                .flat_map( |(txid, wtx)| {
-                    wtx.utxos.iter().filter_map(move |utxo| 
-                        if !all_notes && utxo.spent.is_some() {
-                            None
-                        } else {
-                            Some(object!{
-                                "created_in_block"   => wtx.block,
-                                "datetime"           => wtx.datetime,
-                                "created_in_txid"    => format!("{}", txid),
-                                "value"              => utxo.value,
-                                "scriptkey"          => hex::encode(utxo.script.clone()),
-                                "is_change"          => false, // TODO: Identify notes as change if we send change to taddrs
-                                "address"            => utxo.address.clone(),
-                                "spent"              => utxo.spent.map(|spent_txid| format!("{}", spent_txid)),
-                                "unconfirmed_spent"  => utxo.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
-                            })
-                        }
-                    )
-                })
-                .for_each( |utxo| {
-                    if utxo["spent"].is_null() && utxo["unconfirmed_spent"].is_null() {
-                        unspent_utxos.push(utxo);
-                    } else if !utxo["spent"].is_null() {
-                        spent_utxos.push(utxo);
-                    } else {
-                        pending_utxos.push(utxo);
+            let txs = wallet.txs.read().unwrap();
+
+            for (txid, wtx) in txs.iter() {
+                // Collect Sapling notes
+                for nd in wtx.notes.iter() {
+                    if (!all_notes && nd.spent.is_some()) || !filter.matches(nd.note.value, wtx.block, last_height) {
+                        continue;
+                    }
+
+                    let list = match (nd.spent, nd.unconfirmed_spent) {
+                        (Some(_), _)    => &mut spent_notes,
+                        (None, Some(_)) => &mut pending_notes,
+                        (None, None)    => &mut unspent_notes,
+                    };
+
+                    list.push(object!{
+                        "created_in_block"   => wtx.block,
+                        "datetime"           => wtx.datetime,
+                        "created_in_txid"    => format!("{}", txid),
+                        "value"              => nd.note.value,
+                        "is_change"          => nd.is_change,
+                        "address"            => LightWallet::note_address(self.config.hrp_sapling_address(), nd),
+                        "spent"              => nd.spent.map(|spent_txid| format!("{}", spent_txid)),
+                        "unconfirmed_spent"  => nd.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
+                    });
+                }
+
+                for utxo in wtx.utxos.iter() {
+                    if (!all_notes && utxo.spent.is_some()) || !filter.matches(utxo.value, wtx.block, last_height) {
+                        continue;
                     }
-                });
+
+                    let list = match (utxo.spent, utxo.unconfirmed_spent) {
+                        (Some(_), _)    => &mut spent_utxos,
+                        (None, Some(_)) => &mut pending_utxos,
+                        (None, None)    => &mut unspent_utxos,
+                    };
+
+                    list.push(object!{
+                        "created_in_block"   => wtx.block,
+                        "datetime"           => wtx.datetime,
+                        "created_in_txid"    => format!("{}", txid),
+                        "value"              => utxo.value,
+                        "scriptkey"          => hex::encode(utxo.script.clone()),
+                        "is_change"          => false, // TODO: Identify notes as change if we send change to taddrs
+                        "address"            => utxo.address.clone(),
+                        "spent"              => utxo.spent.map(|spent_txid| format!("{}", spent_txid)),
+                        "unconfirmed_spent"  => utxo.unconfirmed_spent.map(|spent_txid| format!("{}", spent_txid)),
+                    });
+                }
+            }
+        }
+
+        let mut count = unspent_notes.len() + pending_notes.len() + unspent_utxos.len() + pending_utxos.len();
+        if all_notes {
+            count += spent_notes.len() + spent_utxos.len();
         }
 
         let mut res = object!{
@@ -767,6 +1956,7 @@ impl LightClient {
             "pending_notes" => pending_notes,
             "utxos"         => unspent_utxos,
             "pending_utxos" => pending_utxos,
+            "count"         => count,
         };
 
         if all_notes {
@@ -777,29 +1967,72 @@ impl LightClient {
         res
     }
 
+    /// Unlock an encrypted wallet for viewing only: it can sync and show balances, but spending, exporting
+    /// keys and showing the seed still need the wallet to be unlocked for spending. Only the spending keys
+    /// are encrypted, so the viewing lock just gates the commands, see `LightWallet::unlock_viewing`.
+    pub fn do_unlock_viewing(&self, password: String) -> Result<(), String> {
+        self.wallet.write().unwrap().unlock_viewing(password).map_err(|e| e.to_string())
+    }
+
+    /// Whether the wallet is encrypted and locked, and if it's encrypted, how its key is derived from the password.
+    /// "locked" is whether it can't spend, and "unlocked_for_viewing" and "unlocked_for_spending" tell whether it
+    /// can sync and whether it can spend and export keys. A wallet encrypted with an older key derivation is
+    /// encrypted again when it's unlocked for spending, and the next save writes that.
     pub fn do_encryption_status(&self) -> JsonValue {
         let wallet = self.wallet.read().unwrap();
         object!{
             "encrypted" => wallet.is_encrypted(),
-            "locked"    => !wallet.is_unlocked_for_spending()
+            "locked"    => !wallet.is_unlocked_for_spending(),
+            "unlocked_for_viewing"  => wallet.is_unlocked_for_viewing(),
+            "unlocked_for_spending" => wallet.is_unlocked_for_spending(),
+            "kdf"       => if wallet.is_encrypted() { wallet.kdf().to_json() } else { JsonValue::Null }
         }
     }
 
     pub fn do_list_transactions(&self) -> JsonValue {
+        self.do_list_transactions_sorted(TxSort::HeightAsc)
+    }
+
+    pub fn do_list_transactions_sorted(&self, sort: TxSort) -> JsonValue {
+        self.do_list_transactions_with_previews(sort, None)
+    }
+
+    /// Like `do_list_transactions_sorted`, but with `Some(max_chars)`, every memo also gets a "memo_preview",
+    /// shortened to at most that many characters, for UIs that show memos in a list
+    pub fn do_list_transactions_with_previews(&self, sort: TxSort, memo_preview: Option<usize>) -> JsonValue {
         let wallet = self.wallet.read().unwrap();
+        let txs = wallet.txs.read().unwrap();
+
+        // The utxos each transaction spent, as (address, value)
+        let mut utxos_spent: HashMap<TxId, Vec<(String, u64)>> = HashMap::new();
+        for u in txs.values().flat_map(|wtx| wtx.utxos.iter()) {
+            if let Some(spent_in) = &u.spent {
+                utxos_spent.entry(spent_in.clone()).or_default().push((u.address.clone(), u.value));
+            }
+        }
 
         // Create a list of TransactionItems from wallet txns
-        let mut tx_list = wallet.txs.read().unwrap().iter()
+        let mut tx_list = txs.iter()
             .flat_map(| (_k, v) | {
                 let mut txns: Vec<JsonValue> = vec![];
 
+                // What each of our t addresses received and spent in this transaction
+                let mut transparent: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+                for u in v.utxos.iter() {
+                    transparent.entry(u.address.clone()).or_insert((0, 0)).0 += u.value;
+                }
+                for (address, value) in utxos_spent.get(&v.txid).into_iter().flatten() {
+                    transparent.entry(address.clone()).or_insert((0, 0)).1 += value;
+                }
+
                 if v.total_shielded_value_spent + v.total_transparent_value_spent > 0 {
-                    // If money was spent, create a transaction. For this, we'll subtract
-                    // all the change notes. TODO: Add transparent change here to subtract it also
+                    // If money was spent, create a transaction. For this, we'll subtract all the change,
+                    // which is the change notes and whatever came back to our t addresses
+                    let total_transparent_received = v.utxos.iter().map(|u| u.value).sum::<u64>();
                     let total_change: u64 = v.notes.iter()
                         .filter( |nd| nd.is_change )
                         .map( |nd| nd.note.value )
-                        .sum();
+                        .sum::<u64>() + total_transparent_received;
 
                     // TODO: What happens if change is > than sent ?
 
@@ -813,45 +2046,66 @@ impl LightClient {
                         })
                         .collect::<Vec<JsonValue>>();                    
 
-                    txns.push(object! {
+                    // Total sent to all recipients, so that the amount can be reconciled as
+                    // recipients_total + fee = -(amount)
+                    let recipients_total: u64 = v.outgoing_metadata.iter().map(|om| om.value).sum();
+
+                    let mut sent = object! {
                         "block_height" => v.block,
                         "datetime"     => v.datetime,
                         "txid"         => format!("{}", v.txid),
                         "amount"       => total_change as i64 
                                             - v.total_shielded_value_spent as i64 
                                             - v.total_transparent_value_spent as i64,
+                        "change"       => total_change,
+                        "recipients_total" => recipients_total,
                         "outgoing_metadata" => outgoing_json,
-                    });
-                } 
+                        "transparent"  => transparent.iter().map(|(address, (received, spent))| object!{
+                                            "address"  => address.clone(),
+                                            "received" => *received,
+                                            "spent"    => *spent,
+                                          }).collect::<Vec<JsonValue>>(),
+                    };
+
+                    // Sends with their amount in a fiat currency, see `do_send_fiat`
+                    if let Some(rate) = wallet.fiat_rate(&v.txid) {
+                        sent["fiat_rate"] = rate.to_json();
+                    }
+                    txns.push(sent);
+                } else {
+                    // Nothing was spent, so what our t addresses got is received, one entry for each
+                    txns.extend(transparent.iter().map(|(address, (received, _))| object!{
+                        "block_height" => v.block,
+                        "datetime"     => v.datetime,
+                        "txid"         => format!("{}", v.txid),
+                        "amount"       => *received as i64,
+                        "address"      => address.clone(),
+                        "memo"         => None::<String>
+                    }));
+                }
 
-                // For each sapling note that is not a change, add a Tx.
+                // For each sapling note that is not a change, add a Tx. If the memo says where to reply, and
+                // it's an address, that's its "reply_to".
                 txns.extend(v.notes.iter()
                     .filter( |nd| !nd.is_change )
-                    .map ( |nd| 
+                    .map ( |nd| {
+                        let memo = LightWallet::memo_str(&nd.memo);
+                        let reply_to = memo.as_ref()
+                            .and_then(|m| utils::memo_reply_to(m))
+                            .filter(|a| LightWallet::is_valid_address(a, &self.config));
+
                         object! {
                             "block_height" => v.block,
                             "datetime"     => v.datetime,
                             "txid"         => format!("{}", v.txid),
                             "amount"       => nd.note.value as i64,
                             "address"      => LightWallet::note_address(self.config.hrp_sapling_address(), nd),
-                            "memo"         => LightWallet::memo_str(&nd.memo),
+                            "memo"         => memo,
+                            "reply_to"     => reply_to,
+                        }
                     })
                 );
 
-                // Get the total transparent received
-                let total_transparent_received = v.utxos.iter().map(|u| u.value).sum::<u64>();
-                if total_transparent_received > v.total_transparent_value_spent {
-                    // Create an input transaction for the transparent value as well.
-                    txns.push(object!{
-                        "block_height" => v.block,
-                        "datetime"     => v.datetime,
-                        "txid"         => format!("{}", v.txid),
-                        "amount"       => total_transparent_received as i64 - v.total_transparent_value_spent as i64,
-                        "address"      => v.utxos.iter().map(|u| u.address.clone()).collect::<Vec<String>>().join(","),
-                        "memo"         => None::<String>
-                    })
-                }
-
                 txns
             })
             .collect::<Vec<JsonValue>>();
@@ -862,7 +2116,7 @@ impl LightClient {
             use std::convert::TryInto;
             
             let amount: u64 = wtx.outgoing_metadata.iter().map(|om| om.value).sum::<u64>();
-            let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+            let fee: u64 = wtx.fee.unwrap_or(DEFAULT_FEE.try_into().unwrap());
 
             // Collect outgoing metadata
             let outgoing_json = wtx.outgoing_metadata.iter()
@@ -873,28 +2127,123 @@ impl LightClient {
                         "memo"    => LightWallet::memo_str(&Some(om.memo.clone())),
                 }).collect::<Vec<JsonValue>>();                    
 
-            object! {
+            let mut o = object! {
                 "block_height" => wtx.block,
                 "datetime"     => wtx.datetime,
                 "txid"         => format!("{}", wtx.txid),
                 "amount"       => -1 * (fee + amount) as i64,
                 "unconfirmed"  => true,
                 "outgoing_metadata" => outgoing_json,
+            };
+            if let Some(rate) = wallet.fiat_rate(&wtx.txid) {
+                o["fiat_rate"] = rate.to_json();
             }
+            o
         }));
 
-        tx_list.sort_by( |a, b| if a["block_height"] == b["block_height"] {
-                                    a["txid"].as_str().cmp(&b["txid"].as_str())
-                                } else {
-                                    a["block_height"].as_i32().cmp(&b["block_height"].as_i32())
-                                }
-        );
+        tx_list.sort_by( |a, b| {
+            let key = match sort {
+                TxSort::HeightAsc  => a["block_height"].as_i32().cmp(&b["block_height"].as_i32()),
+                TxSort::HeightDesc => b["block_height"].as_i32().cmp(&a["block_height"].as_i32()),
+                TxSort::AmountDesc => b["amount"].as_i64().cmp(&a["amount"].as_i64()),
+                TxSort::DateDesc   => b["datetime"].as_u64().cmp(&a["datetime"].as_u64()),
+            };
+
+            key.then_with(|| a["txid"].as_str().cmp(&b["txid"].as_str()))
+        });
+
+        if let Some(max_chars) = memo_preview {
+            let add_preview = |o: &mut JsonValue| {
+                let preview = o["memo"].as_str().map(|m| utils::memo_preview(m, max_chars));
+                if let Some(preview) = preview {
+                    o["memo_preview"] = preview.into();
+                }
+            };
+
+            for tx in tx_list.iter_mut() {
+                add_preview(tx);
+                if tx.has_key("outgoing_metadata") {
+                    for om in tx["outgoing_metadata"].members_mut() {
+                        add_preview(om);
+                    }
+                }
+            }
+        }
 
         JsonValue::Array(tx_list)
     }
 
+    /// Only the transactions mined at or after `height`, plus everything still in the mempool, so
+    /// that a UI can append what is new after a sync instead of redrawing the whole list.
+    pub fn do_transactions_since(&self, height: u64) -> JsonValue {
+        let txns = self.do_list_transactions()
+            .members()
+            .filter(|tx| tx["unconfirmed"].as_bool().unwrap_or(false) 
+                            || tx["block_height"].as_u64().map_or(false, |h| h >= height))
+            .cloned()
+            .collect::<Vec<JsonValue>>();
+
+        JsonValue::Array(txns)
+    }
+
+    /// The outputs of a transaction this wallet sent, as the wallet built it: each recipient, and the
+    /// change that came back to the wallet. Unconfirmed transactions don't have their change yet.
+    pub fn do_tx_outputs(&self, txid: &str) -> Result<JsonValue, String> {
+        let txid = grpcconnector::txid_from_hex(txid)?;
+
+        let wallet = self.wallet.read().unwrap();
+        let txs = wallet.txs.read().unwrap();
+        let mempool_txs = wallet.mempool_txs.read().unwrap();
+
+        let wtx = match txs.get(&txid).or_else(|| mempool_txs.get(&txid)) {
+            Some(wtx) => wtx,
+            None      => return Err(format!("Transaction {} is not in the wallet", txid))
+        };
+
+        if wtx.outgoing_metadata.is_empty() && wtx.total_shielded_value_spent + wtx.total_transparent_value_spent == 0 {
+            return Err(format!("Transaction {} wasn't sent by this wallet", txid));
+        }
+
+        let mut outputs = wtx.outgoing_metadata.iter()
+            .map(|om| object!{
+                "address" => om.address.clone(),
+                "value"   => om.value,
+                "memo"    => LightWallet::memo_str(&Some(om.memo.clone())),
+                "change"  => false,
+            })
+            .collect::<Vec<JsonValue>>();
+
+        outputs.extend(wtx.notes.iter()
+            .filter(|nd| nd.is_change)
+            .map(|nd| object!{
+                "address" => LightWallet::note_address(self.config.hrp_sapling_address(), nd),
+                "value"   => nd.note.value,
+                "memo"    => LightWallet::memo_str(&nd.memo),
+                "change"  => true,
+            }));
+
+        Ok(JsonValue::Array(outputs))
+    }
+
+    /// Check the wallet's data for inconsistencies, eg. after a crash. A wallet with problems can be 
+    /// fixed with a rescan.
+    pub fn do_check_wallet(&self) -> JsonValue {
+        let problems = self.wallet.read().unwrap().check_consistency();
+
+        object!{
+            "consistent" => problems.is_empty(),
+            "problems"   => problems,
+        }
+    }
+
     /// Create a new address, deriving it from the seed.
     pub fn do_new_address(&self, addr_type: &str) -> Result<JsonValue, String> {
+        self.do_new_address_for_account(addr_type, DEFAULT_ACCOUNT)
+    }
+
+    /// Create a new address in the named account, deriving it from the seed.
+    pub fn do_new_address_for_account(&self, addr_type: &str, account: &str) -> Result<JsonValue, String> {
+        self.check_writable()?;
         if !self.wallet.read().unwrap().is_unlocked_for_spending() {
             error!("Wallet is locked");
             return Err("Wallet is locked".to_string());
@@ -902,10 +2251,11 @@ impl LightClient {
 
         let new_address = {
             let wallet = self.wallet.write().unwrap();
+            let account = wallet.find_account(account).ok_or(format!("No account named {}", account))?;
 
             match addr_type {
-                "z" => wallet.add_zaddr(),
-                "t" => wallet.add_taddr(),
+                "z" => wallet.add_zaddr_to_account(account),
+                "t" => wallet.add_taddr_to_account(account),
                 _   => {
                     let e = format!("Unrecognized address type: {}", addr_type);
                     error!("{}", e);
@@ -919,16 +2269,93 @@ impl LightClient {
         Ok(array![new_address])
     }
 
-    pub fn clear_state(&self) {
-        // First, clear the state from the wallet
-        self.wallet.read().unwrap().clear_blocks();
-
-        // Then set the initial block
-        self.set_wallet_initial_state(self.wallet.read().unwrap().get_birthday());
+    /// Add an account, to keep some of the funds apart from the rest. It starts out with a z and a t address.
+    /// Restoring the seed only brings back the default account's addresses, the others come back by adding 
+    /// the accounts again, in the same order, with as many addresses.
+    pub fn do_new_account(&self, name: &str) -> Result<JsonValue, String> {
+        self.check_writable()?;
+        if !self.wallet.read().unwrap().is_unlocked_for_spending() {
+            error!("Wallet is locked");
+            return Err("Wallet is locked".to_string());
+        }
+
+        let account = self.wallet.read().unwrap().add_account(name)?;
+        let name = name.trim();
+        self.do_new_address_for_account("z", name)?;
+        self.do_new_address_for_account("t", name)?;
+
+        info!("Added account {} ({})", account, name);
+        self.do_get_address_for_account(name)
+    }
+
+    /// The addresses in the named account
+    pub fn do_get_address_for_account(&self, account: &str) -> Result<JsonValue, String> {
+        let wallet = self.wallet.read().unwrap();
+        let n = wallet.find_account(account).ok_or(format!("No account named {}", account))?;
+        let (z_addresses, t_addresses) = wallet.account_addresses(n);
+
+        Ok(object!{
+            "account"     => account,
+            "z_addresses" => z_addresses,
+            "t_addresses" => t_addresses,
+        })
+    }
+
+    /// Import a key, detected by its prefix. Only viewing keys can be imported for now. Their address is
+    /// watched: its notes count towards the balance, but can't be spent. The wallet is rescanned to find
+    /// the address's past notes, from the key's birthday, or the wallet's if that's earlier. Without a
+    /// birthday, the key's is the checkpoint below `IMPORT_BIRTHDAY_MARGIN` blocks before the scan height,
+    /// rather than the whole chain, and a "warning" says older notes may be missed (see `do_rescan_from`).
+    pub fn do_import_key(&self, key: &str, birthday: Option<u64>) -> Result<JsonValue, String> {
+        self.check_writable()?;
+        let key = key.trim();
+        let prefix = |hrp: &str| key.starts_with(&format!("{}1", hrp));
+
+        if prefix(self.config.hrp_sapling_private_key()) {
+            return Err("Spending keys can't be imported. Import its viewing key instead, to watch the address".to_string());
+        }
+        if !prefix(self.config.hrp_sapling_viewing_key()) {
+            return Err(format!("Unknown key type, expected a viewing key starting with {}1", self.config.hrp_sapling_viewing_key()));
+        }
+
+        let address = self.wallet.read().unwrap().add_watch_only_key(key)?;
+        let key_birthday = birthday.unwrap_or_else(|| self.default_import_birthday());
+        self.wallet.write().unwrap().lower_birthday(key_birthday);
+        self.do_save()?;
+
+        let scan_from = self.wallet.read().unwrap().get_birthday();
+        let mut o = object!{
+            "address"    => address,
+            "watch_only" => true,
+            "birthday"   => key_birthday,
+        };
+        if birthday.is_none() {
+            o["warning"] = format!("No birthday was given for the key, so its transactions before block {} are missed. \
+                                    Rescan from an earlier height to find them.", scan_from).into();
+        }
+        o["rescan"] = self.do_rescan()?;
+
+        Ok(o)
+    }
+
+    /// The birthday of an imported key that doesn't have one: the checkpoint below `IMPORT_BIRTHDAY_MARGIN` 
+    /// blocks before the wallet's scan height
+    fn default_import_birthday(&self) -> u64 {
+        let height = (self.wallet.read().unwrap().last_scanned_height().max(0) as u64).saturating_sub(IMPORT_BIRTHDAY_MARGIN);
+        self.config.get_initial_state(height).map(|(h, _, _)| h).unwrap_or(height)
+    }
+
+    pub fn clear_state(&self) {
+        // First, clear the state from the wallet
+        self.wallet.read().unwrap().clear_blocks();
+
+        // Then set the initial block
+        self.set_wallet_initial_state(self.wallet.read().unwrap().get_birthday());
         info!("Cleared wallet state");        
     }
 
     pub fn do_rescan(&self) -> Result<JsonValue, String> {
+        self.check_writable()?;
         if !self.wallet.read().unwrap().is_unlocked_for_spending() {
             warn!("Wallet is locked, new HD addresses won't be added!");
         }
@@ -946,15 +2373,233 @@ impl LightClient {
         response
     }
 
+    /// Rescan from `height`, eg. to find older transactions of a key imported without a birthday. The wallet's
+    /// birthday moves back to `height`, so later rescans start there too. If the birthday is already earlier, 
+    /// the rescan starts from the birthday.
+    pub fn do_rescan_from(&self, height: u64) -> Result<JsonValue, String> {
+        self.check_writable()?;
+        self.wallet.write().unwrap().lower_birthday(height);
+        self.do_rescan()
+    }
+
     /// Return the syncing status of the wallet
     pub fn do_scan_status(&self) -> WalletStatus {
-        self.sync_status.read().unwrap().clone()
+        let mut status = self.sync_status.read().unwrap().clone();
+        status.failovers = self.grpc.failovers();
+        status.bytes_downloaded = self.network_stats.bytes_received();
+        status.bytes_uncompressed = self.network_stats.bytes_uncompressed();
+
+        status
+    }
+
+    /// Calls, errors, bytes and latency of the calls to the servers, for each RPC method. With `reset`,
+    /// counting starts over after this.
+    pub fn do_network_stats(&self, reset: bool) -> JsonValue {
+        let stats = self.network_stats.to_json();
+        if reset {
+            self.network_stats.reset();
+        }
+
+        stats
+    }
+
+    /// Refuse to change the wallet, or send from it, when it was opened read-only (`LightClientConfig::read_only`),
+    /// eg. by a dashboard or a backup script. The wallet file is always replaced in one go when it's saved, so a
+    /// read-only open reads a consistent wallet even while another process keeps saving it.
+    fn check_writable(&self) -> Result<(), String> {
+        if self.config.read_only {
+            return Err(WALLET_READ_ONLY.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Whether the server can look up t address transactions. This comes from the cached getinfo
+    /// response, or the last one if the server can't be reached. Without any, this assumes it does, 
+    /// and lets the transparent call itself fail.
+    fn server_supports_taddrs(&self) -> bool {
+        self.get_info(false).ok()
+            .or_else(|| self.cached_info(None))
+            .map_or(true, |i| capabilities::supports(&i, capabilities::ADDRESS_TXIDS))
+    }
+
+    /// Ask the server for the UTXOs of all our t addresses, and add them to the wallet, so that 
+    /// transparent balances show up before the blocks that created them are scanned.
+    pub fn do_fetch_utxos(&self) -> Result<JsonValue, String> {
+        self.check_writable()?;
+        if !self.server_supports_taddrs() {
+            return Err(NO_TADDR_SUPPORT.to_string());
+        }
+
+        // Like server_supports_taddrs, only known info can turn this off
+        if let Some(i) = self.get_info(false).ok().or_else(|| self.cached_info(None)) {
+            if !capabilities::supports(&i, capabilities::ADDRESS_UTXOS) {
+                return Err(capabilities::unsupported_error(&i, capabilities::ADDRESS_UTXOS));
+            }
+        }
+
+        let addresses = self.wallet.read().unwrap()
+                            .taddresses.read().unwrap().iter().map(|a| a.clone())
+                            .collect::<Vec<String>>();
+
+        let utxos = self.grpc.get_address_utxos(addresses)?;
+
+        let wallet = self.wallet.read().unwrap();
+        let added = utxos.into_iter().filter(|u| {
+            if u.txid.len() != 32 || u.index < 0 || u.value_zat < 0 {
+                warn!("Ignoring invalid UTXO from server for {}", u.address);
+                return false;
+            }
+
+            let mut txid = [0u8; 32];
+            txid.copy_from_slice(&u.txid);
+
+            wallet.add_utxo(u.address.clone(), TxId{0: txid}, u.index as u64, u.script.clone(), 
+                            u.value_zat as u64, u.height as i32)
+        }).count();
+
+        Ok(object!{
+            "added" => added,
+            "tbalance" => wallet.tbalance(None),
+        })
+    }
+
+    /// Extra connections to the current server to fetch blocks over at the same time, or none to fetch
+    /// them over the usual one. They are made for each sync, and closed when it's done.
+    fn block_connections(&self) -> Vec<Arc<GrpcConnection>> {
+        let n = std::cmp::min(self.config.block_connections, grpcconnector::MAX_BLOCK_CONNECTIONS);
+        if n <= 1 || self.config.offline {
+            return vec![];
+        }
+
+        let server = self.grpc.uri();
+        let mut config = self.config.clone();
+        config.auto_select_server = false;
+        if config.servers.first() != Some(&server) {
+            config.server_ip_override = None;
+        }
+        config.servers = vec![server];
+
+        (0..n).filter_map(|_| config.grpc_connection().ok())
+            .map(|conn| Arc::new(conn.with_network_stats(self.network_stats.clone())))
+            .collect()
+    }
+
+    /// A connection to each of the other configured servers, which transactions are also broadcast to
+    fn broadcast_connections(&self) -> Vec<Arc<GrpcConnection>> {
+        if self.config.offline {
+            return vec![];
+        }
+
+        let current = self.grpc.uri();
+        self.config.servers.iter()
+            .filter(|s| **s != current)
+            .filter_map(|server| {
+                let mut config = self.config.clone();
+                config.auto_select_server = false;
+                if config.servers.first() != Some(server) {
+                    config.server_ip_override = None;
+                }
+                config.servers = vec![server.clone()];
+
+                config.grpc_connection().ok().map(|conn| Arc::new(conn.with_network_stats(self.network_stats.clone())))
+            })
+            .collect()
+    }
+
+    /// Broadcast the transaction to all the configured servers at once. Returns the txid if it got out,
+    /// and what each server said.
+    fn broadcast(&self, tx_bytes: Box<[u8]>) -> (Result<String, String>, JsonValue) {
+        let results = self.grpc.broadcast_raw_tx_everywhere(&self.broadcast_connections(), tx_bytes.clone());
+
+        let servers = results.iter().enumerate().map(|(i, (uri, r))| match r {
+            Ok(r) => object!{
+                "server"   => uri.to_string(),
+                "accepted" => true,
+                "message"  => r.server_message.clone(),
+            },
+            // Another server got it to this one first
+            Err(BroadcastError::AlreadyKnown(m)) if i > 0 => object!{
+                "server"   => uri.to_string(),
+                "accepted" => true,
+                "message"  => m.clone(),
+            },
+            Err(e) => object!{
+                "server"   => uri.to_string(),
+                "accepted" => false,
+                "error"    => e.to_string(),
+            },
+        }).collect::<Vec<_>>();
+
+        let r = grpcconnector::broadcast_outcome(&tx_bytes, &results).map(|r| r.txid).map_err(|e| {
+            if results.len() > 1 {
+                format!("{} (no server accepted the transaction)", e)
+            } else {
+                e.to_string()
+            }
+        });
+
+        (r, JsonValue::Array(servers))
+    }
+
+    /// Scan all the transactions involving the wallet's t addresses up to `end_height`. Transactions
+    /// that are found again later by the block scan are merged, not duplicated.
+    fn fetch_transparent_history(&self, end_height: u64) {
+        if !self.server_supports_taddrs() {
+            warn!("Not fetching the t address history. {}", NO_TADDR_SUPPORT);
+            return;
+        }
+
+        let addresses = self.wallet.read().unwrap()
+                            .taddresses.read().unwrap().iter().map(|a| a.clone())
+                            .collect::<Vec<String>>();
+
+        for address in addresses {
+            match self.grpc.get_address_txids(address.clone(), 1, end_height) {
+                Ok(txns) => {
+                    info!("Found {} past transactions for {}", txns.len(), address);
+                    for rtx in txns {
+                        match Transaction::read(&rtx.data[..]) {
+                            Ok(tx) => {
+                                let datetime = self.block_time(rtx.height);
+                                self.wallet.read().unwrap().scan_full_tx(&tx, rtx.height as i32, datetime);
+                            },
+                            Err(e) => error!("Couldn't read transaction at height {}: {}", rtx.height, e)
+                        }
+                    }
+                },
+                Err(e) => warn!("Couldn't fetch the history for {}: {}", address, e)
+            }
+        }
+    }
+
+    /// The time of the block at `height`, from the server, or 0 if it can't be had
+    fn block_time(&self, height: u64) -> u64 {
+        let time = Arc::new(AtomicU64::new(0));
+        let time_inner = time.clone();
+        self.grpc.fetch_blocks(height, height, move |encoded_block: &[u8], _| {
+            let block: Result<zcash_client_backend::proto::compact_formats::CompactBlock, _> = parse_from_bytes(encoded_block);
+            if let Ok(b) = block {
+                time_inner.store(b.time as u64, Ordering::SeqCst);
+            }
+        });
+
+        time.load(Ordering::SeqCst)
     }
 
     pub fn do_sync(&self, print_updates: bool) -> Result<JsonValue, String> {
         // We can only do one sync at a time because we sync blocks in serial order
         // If we allow multiple syncs, they'll all get jumbled up.
         let _lock = self.sync_lock.lock().unwrap();
+        if self.shut_down.load(Ordering::SeqCst) {
+            return Err("The wallet has been shut down".to_string());
+        }
+
+        if !self.wallet.read().unwrap().is_unlocked_for_viewing() {
+            return Err(WALLET_LOCKED_FOR_VIEWING.to_string());
+        }
+
+        self.check_writable()?;
 
         // Sync is 3 parts
         // 1. Get the latest block
@@ -963,14 +2608,8 @@ impl LightClient {
         //    and scan them, mainly to get the memos
         let mut last_scanned_height = self.wallet.read().unwrap().last_scanned_height() as u64;
 
-        // This will hold the latest block fetched from the RPC
-        let latest_block_height = Arc::new(AtomicU64::new(0));
-        let lbh = latest_block_height.clone();
-        fetch_latest_block(&self.get_server_uri(), self.config.no_cert_verification, 
-            move |block: BlockId| {
-                lbh.store(block.height, Ordering::SeqCst);
-            });
-        let latest_block = latest_block_height.load(Ordering::SeqCst);
+        // Get the latest block from the server
+        let latest_block = self.grpc.get_latest_block()?.height;
        
 
         if latest_block < last_scanned_height {
@@ -981,26 +2620,35 @@ impl LightClient {
 
         info!("Latest block is {}", latest_block);
 
-        // Get the end height to scan to.
-        let mut end_height = std::cmp::min(last_scanned_height + 1000, latest_block);
-
         // If there's nothing to scan, just return
         if last_scanned_height == latest_block {
             info!("Nothing to sync, returning");
             return Ok(object!{ "result" => "success" })
         }
 
-        {
-            let mut status = self.sync_status.write().unwrap();
-            status.is_syncing = true;
-            status.synced_blocks = last_scanned_height;
-            status.total_blocks = latest_block;
+        // Each extra connection fetches its own chunk of every batch, so the batches grow with them
+        let block_connections = self.block_connections();
+        let batch_size = 1000 * std::cmp::max(1, block_connections.len()) as u64;
+
+        // Get the end height to scan to.
+        let mut end_height = std::cmp::min(last_scanned_height + batch_size, latest_block);
+
+        self.sync_status.write().unwrap().start_sync(last_scanned_height, latest_block);
+
+        // If this is the first sync of a new or restored wallet, the t addresses might have been used
+        // before the wallet's birthday, so fetch their full history from the server first
+        if last_scanned_height <= self.wallet.read().unwrap().get_birthday() {
+            self.fetch_transparent_history(last_scanned_height);
         }
 
         // Count how many bytes we've downloaded
         let bytes_downloaded = Arc::new(AtomicUsize::new(0));
+        let bytes_received_before = self.network_stats.bytes_received();
+        let mut batch_latency = (0u32, Duration::from_secs(0));   // (batches, total time)
 
         let mut total_reorg = 0;
+        let mut short_batches = 0;
+        let mut last_checkpoint = last_scanned_height;
 
         // Collect all txns in blocks that we have a tx in. We'll fetch all these
         // txs along with our own, so that the server doesn't learn which ones
@@ -1009,11 +2657,19 @@ impl LightClient {
 
         // Fetch CompactBlocks in increments
         loop {
+            // Between batches, the wallet is in a state that can be saved
+            if self.shut_down.load(Ordering::SeqCst) {
+                info!("Shutting down, stopping the sync at {}", last_scanned_height);
+                self.sync_status.write().unwrap().finish_sync(last_scanned_height);
+                return Err("The sync was interrupted by a shutdown".to_string());
+            }
+
             // Collect all block times, because we'll need to update transparent tx
             // datetime via the block height timestamp
             let block_times = Arc::new(RwLock::new(HashMap::new()));
 
             let local_light_wallet = self.wallet.clone();
+            let note_scanner = self.note_scanner.read().unwrap().clone();
             let local_bytes_downloaded = bytes_downloaded.clone();
 
             let start_height = last_scanned_height + 1;
@@ -1038,19 +2694,46 @@ impl LightClient {
             let all_txs = all_new_txs.clone();
             let block_times_inner = block_times.clone();
 
+            let sync_status = self.sync_status.clone();
+
             let last_invalid_height = Arc::new(AtomicI32::new(0));
             let last_invalid_height_inner = last_invalid_height.clone();
-            fetch_blocks(&self.get_server_uri(), start_height, end_height, self.config.no_cert_verification,
-                move |encoded_block: &[u8], height: u64| {
-                    // Process the block only if there were no previous errors
-                    if last_invalid_height_inner.load(Ordering::SeqCst) > 0 {
+
+            // The server should send the blocks in order, but don't count on it. Chunks fetched over
+            // other connections all arrive ahead of the first one, so then the whole batch may be waiting.
+            let window = if block_connections.is_empty() {
+                BLOCK_REORDER_WINDOW
+            } else {
+                std::cmp::max(BLOCK_REORDER_WINDOW, (end_height - start_height + 1) as usize)
+            };
+            let reorder = Arc::new(Mutex::new(BlockReorderBuffer::new(start_height, window)));
+            let reorder_inner = reorder.clone();
+            let reorder_error = Arc::new(RwLock::new(None));
+            let reorder_error_inner = reorder_error.clone();
+
+            let batch_start = Instant::now();
+            let scan = move |encoded_block: &[u8], height: u64| {
+                // Process the block only if there were no previous errors
+                if last_invalid_height_inner.load(Ordering::SeqCst) > 0 || reorder_error_inner.read().unwrap().is_some() {
+                    return;
+                }
+
+                local_bytes_downloaded.fetch_add(encoded_block.len(), Ordering::SeqCst);
+
+                let ready = match reorder_inner.lock().unwrap().push(height, encoded_block.to_vec()) {
+                    Ok(ready) => ready,
+                    Err(e)    => {
+                        error!("{}", e);
+                        *reorder_error_inner.write().unwrap() = Some(e);
                         return;
                     }
+                };
 
+                for (height, encoded_block) in ready {
                     // Parse the block and save it's time. We'll use this timestamp for 
                     // transactions in this block that might belong to us.
                     let block: Result<zcash_client_backend::proto::compact_formats::CompactBlock, _>
-                                        = parse_from_bytes(encoded_block);
+                                        = parse_from_bytes(&encoded_block);
                     match block {
                         Ok(b) => {
                             block_times_inner.write().unwrap().insert(b.height, b.time);
@@ -1058,38 +2741,74 @@ impl LightClient {
                         Err(_) => {}
                     }
 
-                    match local_light_wallet.read().unwrap().scan_block(encoded_block) {
+                    let wallet = local_light_wallet.read().unwrap();
+                    match wallet.scan_block_with(&encoded_block, note_scanner.as_ref()) {
                         Ok(block_txns) => {
+                            // block_txns also has the other txns in the block (as decoys), so only count ours
+                            let found = {
+                                let txs = wallet.txs.read().unwrap();
+                                block_txns.iter().filter(|txid| txs.contains_key(txid)).count()
+                            };
+                            sync_status.write().unwrap().block_scanned(height, found as u64);
+
                             // Add to global tx list
                             all_txs.write().unwrap().extend_from_slice(&block_txns.iter().map(|txid| (txid.clone(), height as i32)).collect::<Vec<_>>()[..]);
                         },
                         Err(invalid_height) => {
                             // Block at this height seems to be invalid, so invalidate up till that point
                             last_invalid_height_inner.store(invalid_height, Ordering::SeqCst);
+                            break;
                         }
                     };
+                }
+            };
+            if block_connections.is_empty() {
+                self.grpc.fetch_blocks(start_height, end_height, scan);
+            } else {
+                GrpcConnection::fetch_blocks_concurrently(&block_connections, start_height, end_height, scan);
+            }
+            batch_latency = (batch_latency.0 + 1, batch_latency.1 + batch_start.elapsed());
 
-                    local_bytes_downloaded.fetch_add(encoded_block.len(), Ordering::SeqCst);
-            });
-
-            // Check if there was any invalid block, which means we might have to do a reorg
+            // Blocks the server left out can't be scanned around. If the stream ended early, the blocks
+            // before the gap are scanned, so fetch the rest of the batch again from there, a few times
+            // as long as that gets further. Otherwise give up on this sync.
             let invalid_height = last_invalid_height.load(Ordering::SeqCst);
+            let reorder_error = reorder_error.read().unwrap().clone()
+                .or_else(|| if invalid_height > 0 { None } else { reorder.lock().unwrap().finish(end_height).err() });
+            if let Some(e) = reorder_error {
+                let scanned = self.wallet.read().unwrap().last_scanned_height() as u64;
+                if scanned >= start_height && short_batches < grpcconnector::MAX_STREAM_RESUMES {
+                    short_batches += 1;
+                    warn!("{}, fetching the blocks again from {} ({}/{})", e, scanned + 1, short_batches, grpcconnector::MAX_STREAM_RESUMES);
+                    last_scanned_height = scanned;
+                    continue;
+                }
+
+                self.sync_status.write().unwrap().finish_sync(last_scanned_height);
+                return Err(e);
+            }
+
+            // Check if there was any invalid block, which means we might have to do a reorg. Make sure
+            // we're not re-orging too much, so a bad server can't make us roll back arbitrarily far.
             if invalid_height > 0 {
-                total_reorg += self.wallet.read().unwrap().invalidate_block(invalid_height);
+                let max_depth = std::cmp::min(self.config.max_reorg_depth, DEFAULT_MAX_REORG_DEPTH);
+                match self.wallet.read().unwrap().invalidate_block_within(invalid_height, max_depth.saturating_sub(total_reorg)) {
+                    Ok(n)  => total_reorg += n,
+                    Err(e) => {
+                        error!("Reorg has exceeded {} blocks! {}", max_depth, e);
+                        return Err(format!("Reorg has exceeded {} blocks (already rolled back {}). Aborting. \
+                                            The server might be misbehaving, try another one, or do a full rescan.", max_depth, total_reorg));
+                    }
+                }
 
                 warn!("Invalidated block at height {}. Total reorg is now {}", invalid_height, total_reorg);
             }
 
-            // Make sure we're not re-orging too much!
-            if total_reorg > (crate::lightwallet::MAX_REORG - 1) as u64 {
-                error!("Reorg has now exceeded {} blocks!", crate::lightwallet::MAX_REORG);
-                return Err(format!("Reorg has exceeded {} blocks. Aborting.", crate::lightwallet::MAX_REORG));
-            } 
-            
+
             if invalid_height > 0 {
                 // Reset the scanning heights
                 last_scanned_height = (invalid_height - 1) as u64;
-                end_height = std::cmp::min(last_scanned_height + 1000, latest_block);
+                end_height = std::cmp::min(last_scanned_height + batch_size, latest_block);
 
                 warn!("Reorg: reset scanning from {} to {}", last_scanned_height, end_height);
 
@@ -1099,9 +2818,11 @@ impl LightClient {
             // If it got here, that means the blocks are scanning properly now. 
             // So, reset the total_reorg
             total_reorg = 0;
+            short_batches = 0;
 
-            // We'll also fetch all the txids that our transparent addresses are involved with
-            {
+            // We'll also fetch all the txids that our transparent addresses are involved with, if
+            // the server can do that
+            if self.server_supports_taddrs() {
                 // Copy over addresses so as to not lock up the wallet, which we'll use inside the callback below. 
                 let addresses = self.wallet.read().unwrap()
                                     .taddresses.read().unwrap().iter().map(|a| a.clone())
@@ -1110,21 +2831,42 @@ impl LightClient {
                     let wallet = self.wallet.clone();
                     let block_times_inner = block_times.clone();
 
-                    fetch_transparent_txids(&self.get_server_uri(), address, start_height, end_height, self.config.no_cert_verification,
+                    let r = self.grpc.fetch_transparent_txids(address.clone(), start_height, end_height,
                         move |tx_bytes: &[u8], height: u64| {
-                            let tx = Transaction::read(tx_bytes).unwrap();
-
-                            // Scan this Tx for transparent inputs and outputs
-                            let datetime = block_times_inner.read().unwrap().get(&height).map(|v| *v).unwrap_or(0);
-                            wallet.read().unwrap().scan_full_tx(&tx, height as i32, datetime as u64); 
+                            // A transaction the server mangled is skipped, rather than taking the sync down with it
+                            match Transaction::read(tx_bytes) {
+                                Ok(tx) => {
+                                    // Scan this Tx for transparent inputs and outputs
+                                    let datetime = block_times_inner.read().unwrap().get(&height).map(|v| *v).unwrap_or(0);
+                                    wallet.read().unwrap().scan_full_tx(&tx, height as i32, datetime as u64);
+                                },
+                                Err(e) => error!("Couldn't read transaction at height {}: {}", height, e)
+                            }
                         }
                     );
+                    if let Err(e) = r {
+                        error!("Error while executing fetch_transparent_txids for {}: {}", address, e);
+                        eprintln!("{}", e);
+                    }
                 }
             }           
             
             // Do block height accounting
             last_scanned_height = end_height;
-            end_height = last_scanned_height + 1000;
+            end_height = last_scanned_height + batch_size;
+
+            // Every checkpoint_interval blocks, drop the witnesses that aren't needed any more and save the
+            // wallet, so that a long rescan doesn't keep growing in memory. Otherwise save every now and then.
+            let interval = self.config.checkpoint_interval;
+            if interval > 0 && last_scanned_height >= last_checkpoint + interval {
+                let pruned = self.wallet.read().unwrap().prune_witnesses();
+                info!("Checkpoint at {}, pruned {} witnesses", last_scanned_height, pruned);
+
+                self.auto_save(true);
+                last_checkpoint = last_scanned_height;
+            } else {
+                self.auto_save(false);
+            }
 
             if last_scanned_height >= latest_block {
                 break;
@@ -1138,12 +2880,7 @@ impl LightClient {
         }
         
         info!("Synced to {}, Downloaded {} kB", latest_block, bytes_downloaded.load(Ordering::SeqCst) / 1024);
-        {
-            let mut status = self.sync_status.write().unwrap();
-            status.is_syncing = false;
-            status.synced_blocks = latest_block;
-            status.total_blocks = latest_block;
-        }
+        self.sync_status.write().unwrap().finish_sync(latest_block);
 
         // Get the Raw transaction for all the wallet transactions
 
@@ -1165,83 +2902,635 @@ impl LightClient {
         // And go and fetch the txids, getting the full transaction, so we can 
         // read the memos
         for (txid, height) in txids_to_fetch {
+            // The ones that aren't fetched are fetched by the next sync
+            if self.shut_down.load(Ordering::SeqCst) {
+                break;
+            }
+
             let light_wallet_clone = self.wallet.clone();
             info!("Fetching full Tx: {}", txid);
 
-            fetch_full_tx(&self.get_server_uri(), txid, self.config.no_cert_verification, move |tx_bytes: &[u8] | {
-                let tx = Transaction::read(tx_bytes).unwrap();
-
-                light_wallet_clone.read().unwrap().scan_full_tx(&tx, height, 0);
+            self.grpc.fetch_full_tx(txid, move |tx_bytes: &[u8] | {
+                match Transaction::read(tx_bytes) {
+                    Ok(tx) => light_wallet_clone.read().unwrap().scan_full_tx(&tx, height, 0),
+                    Err(e) => error!("Couldn't read full Tx at height {}: {}", height, e)
+                }
             });
         };
 
+        self.auto_save(true);
+
+        let network = format!("Downloaded {} kB from the server, {}ms per batch of blocks on average", 
+                              self.network_stats.bytes_received().saturating_sub(bytes_received_before) / 1024,
+                              (batch_latency.1 / std::cmp::max(batch_latency.0, 1)).as_millis());
+        info!("{}", network);
+
         Ok(object!{
             "result" => "success",
             "latest_block" => latest_block,
-            "downloaded_bytes" => bytes_downloaded.load(Ordering::SeqCst)
+            "downloaded_bytes" => bytes_downloaded.load(Ordering::SeqCst),
+            "network" => network
         })
     }
 
+    /// Encrypt a short message to the z address, off-chain. The result is hex, to be passed on to 
+    /// the recipient, who can read it with `do_decrypt_message`.
+    pub fn do_encrypt_message(&self, to_addr: String, msg: String) -> String {
+        let to = match decode_payment_address(self.config.hrp_sapling_address(), &to_addr) {
+            Ok(Some(to)) => to,
+            _            => return object!{ "error" => format!("{} is not a valid z address", to_addr) }.pretty(2)
+        };
+
+        match self.wallet.read().unwrap().encrypt_message(to, &msg) {
+            Ok(blob) => object!{ "encrypted_message" => hex::encode(blob) },
+            Err(e)   => object!{ "error" => e }
+        }.pretty(2)
+    }
+
+    /// Decrypt a message from `do_encrypt_message` that was sent to one of our z addresses
+    pub fn do_decrypt_message(&self, blob: String) -> Result<String, String> {
+        let bytes = hex::decode(blob.trim()).map_err(|e| format!("Couldn't decode message: {}", e))?;
+        let (to, msg) = self.wallet.read().unwrap().decrypt_message(&bytes)?;
+
+        Ok(object!{
+            "to" => to,
+            "message" => msg
+        }.pretty(2))
+    }
+
+    /// Queue a payment, to be sent along with the other queued ones by `do_flush_sends`. Returns
+    /// the payment's id in the queue.
+    pub fn do_queue_send(&self, addr: String, amount: u64, memo: Option<String>) -> Result<u64, String> {
+        self.check_writable()?;
+        let addr = LightWallet::resolve_recipient(&addr, &self.config)?;
+
+        if memo.is_some() && !LightWallet::is_shielded_address(&addr, &self.config) {
+            return Err(format!("Can't send a memo to the non-shielded address {}", addr));
+        }
+
+        let mut queue = self.send_queue.lock().unwrap();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.items.push(QueuedSend { id, address: addr, amount, memo });
+
+        Ok(id)
+    }
+
+    /// The payments waiting to be sent
+    pub fn do_list_queued_sends(&self) -> Vec<QueuedSend> {
+        self.send_queue.lock().unwrap().items.clone()
+    }
+
+    /// Send all the queued payments, in as few transactions as the output limit allows. Returns which
+    /// transaction each payment went out in. Payments in a transaction that failed stay in the queue,
+    /// with the error, so they can be flushed again.
+    pub fn do_flush_sends(&self) -> JsonValue {
+        let items = self.send_queue.lock().unwrap().items.clone();
+
+        let mut results = vec![];
+        // One of each transaction's shielded outputs may be the change
+        let is_shielded = |address: &str| LightWallet::is_shielded_address(&address.to_string(), &self.config);
+        for batch in batch_sends(&items, crate::lightwallet::MAX_SAPLING_OUTPUTS - 1, is_shielded) {
+            let tos = batch.iter().map(|q| (q.address.as_str(), q.amount, q.memo.clone(), 1)).collect::<Vec<_>>();
+            let r = self.do_send_with_splits(tos);
+
+            if r.is_ok() {
+                self.send_queue.lock().unwrap().items.retain(|q| !batch.iter().any(|b| b.id == q.id));
+            }
+
+            for q in batch {
+                let mut o = object!{
+                    "id"      => q.id,
+                    "address" => q.address,
+                    "amount"  => q.amount,
+                };
+                match &r {
+                    Ok(txid) => o["txid"] = txid.clone().into(),
+                    Err(e)   => o["error"] = e.clone().into(),
+                }
+                results.push(o);
+            }
+        }
+
+        JsonValue::Array(results)
+    }
+
     pub fn do_send(&self, addrs: Vec<(&str, u64, Option<String>)>) -> Result<String, String> {
+        self.do_send_with_splits(addrs.into_iter().map(|(a, v, m)| (a, v, m, 1)).collect())
+    }
+
+    /// Send `amount` of the fiat currency `currency`, eg. 5 USD, to `address`. It is converted to zatoshis at
+    /// the price `provider` gives right before sending, and that price is kept with the transaction, so
+    /// `do_list_transactions` shows its "fiat_rate". Otherwise it's the same as `do_send`.
+    pub fn do_send_fiat(&self, provider: &dyn PriceProvider, address: &str, amount: f64, currency: &str, memo: Option<String>)
+            -> Result<JsonValue, String> {
+        let currency = currency.trim().to_uppercase();
+        let price = provider.get_yec_price(&currency)
+            .map_err(|e| format!("Couldn't get the price of YEC in {}: {}", currency, e))?;
+        let zats = price::fiat_to_zats(amount, price)?;
+        info!("Sending {} {} as {} zatoshis, at {} {} per YEC", amount, currency, zats, price, currency);
+
+        let txid = self.do_send(vec![(address, zats, memo)])?;
+
+        let rate = FiatRate { currency: currency.clone(), amount, price };
+        match grpcconnector::txid_from_hex(&txid) {
+            Ok(id) => {
+                self.wallet.read().unwrap().set_fiat_rate(&id, rate);
+                self.auto_save(true);
+            },
+            Err(e) => warn!("Couldn't keep the exchange rate of {}: {}", txid, e),
+        }
+
+        Ok(object!{
+            "txid"     => txid,
+            "amount"   => zats,
+            "currency" => currency,
+            "fiat_amount" => amount,
+            "price"    => price,
+        })
+    }
+
+    /// Send, with the amount to each address split across the given number of outputs
+    pub fn do_send_with_splits(&self, addrs: Vec<(&str, u64, Option<String>, usize)>) -> Result<String, String> {
+        self.do_send_with_raw_tx(addrs, false).map(|(txid, _, _)| txid)
+    }
+
+    /// The address each recipient is paid at, see `LightWallet::resolve_recipient`
+    fn resolve_recipients(&self, addrs: &[(&str, u64, Option<String>, usize)]) -> Result<Vec<String>, String> {
+        addrs.iter().map(|(a, _, _, _)| LightWallet::resolve_recipient(a, &self.config)).collect()
+    }
+
+    /// Check a send of `amount` against the configured per transaction and daily limits
+    fn check_spend_limits(&self, amount: u64) -> Result<(), String> {
+        self.check_tx_limit(amount)?;
+
+        let sent = self.wallet.read().unwrap().sent_today();
+        match self.config.max_send_per_day {
+            Some(max) if sent + amount > max => Err(self.daily_limit_error(sent, amount)),
+            _                                => Ok(())
+        }
+    }
+
+    fn check_tx_limit(&self, amount: u64) -> Result<(), String> {
+        match self.config.max_send_per_tx {
+            Some(max) if amount > max => Err(format!("{}: sending {} is more than the limit of {} per transaction", 
+                                                     SPEND_LIMIT_EXCEEDED, amount, max)),
+            _                         => Ok(())
+        }
+    }
+
+    fn daily_limit_error(&self, sent: u64, amount: u64) -> String {
+        format!("{}: {} was already sent today, and sending {} more would go over the daily limit of {}", 
+                SPEND_LIMIT_EXCEEDED, sent, amount, self.config.max_send_per_day.unwrap_or(0))
+    }
+
+    /// Count a send of `amount` towards today's total, once it's checked against the spend limits, unless
+    /// `override_limits` is set. The daily limit is checked as the send is counted, so that sends made at
+    /// the same time can't go over it together. A send that doesn't go out has to be taken back with
+    /// `LightWallet::remove_sent_today`.
+    fn count_spend(&self, amount: u64, override_limits: bool) -> Result<(), String> {
+        if override_limits {
+            self.wallet.read().unwrap().add_sent_today(amount);
+            return Ok(());
+        }
+
+        self.check_tx_limit(amount)?;
+        self.wallet.read().unwrap().try_add_sent_today(amount, self.config.max_send_per_day)
+            .map_err(|sent| self.daily_limit_error(sent, amount))
+    }
+
+    /// What a send would spend, and how it would weaken the wallet's privacy, without building or
+    /// broadcasting it
+    pub fn do_send_estimate(&self, addrs: Vec<(&str, u64, Option<String>, usize)>) -> Result<JsonValue, String> {
+        if !self.wallet.read().unwrap().is_unlocked_for_spending() {
+            error!("Wallet is locked");
+            return Err("Wallet is locked".to_string());
+        }
+
+        let resolved = self.resolve_recipients(&addrs)?;
+        let addrs = addrs.iter().zip(resolved.iter())
+            .map(|((_, v, m, n), a)| (a.as_str(), *v, m.clone(), *n))
+            .collect::<Vec<_>>();
+
+        let wallet = self.wallet.read().unwrap();
+        let mut new_recipients = addrs.iter().map(|(a, _, _, _)| a.to_string()).filter(|a| !wallet.has_sent_to(a)).collect::<Vec<_>>();
+        new_recipients.sort();
+        new_recipients.dedup();
+
+        let estimate = wallet.estimate_send(addrs)?;
+        Ok(object!{
+            "total"    => estimate.total,
+            "fee"      => estimate.fee,
+            "change"   => estimate.change,
+            "notes"    => estimate.notes,
+            "utxos"    => estimate.utxos,
+            "warnings" => estimate.warnings,
+            "over_spend_limits" => self.check_spend_limits(estimate.total).is_err(),
+            "new_recipients" => new_recipients,
+        })
+    }
+
+    /// Same as `do_send_with_splits`, but also returns the raw transaction that was broadcast, 
+    /// as hex, so the caller can keep its own record of it, and details about it: what each server said
+    /// about it ("servers"), and its fee, logical actions and fee rate, as in `do_tx_fee_rate`. The
+    /// transaction goes to all the configured servers, and the send succeeds if any of them took it.
+    /// Sends over the spend limits in the config are refused, unless `override_limits` is set.
+    pub fn do_send_with_raw_tx(&self, addrs: Vec<(&str, u64, Option<String>, usize)>, override_limits: bool) 
+            -> Result<(String, String, JsonValue), String> {
+        self.check_writable()?;
+
         if !self.wallet.read().unwrap().is_unlocked_for_spending() {
             error!("Wallet is locked");
             return Err("Wallet is locked".to_string());
         }
 
+        // Recipients given as viewing keys are sent to the key's default address
+        let resolved = self.resolve_recipients(&addrs)?;
+        let addrs = addrs.iter().zip(resolved.iter())
+            .map(|((_, v, m, n), a)| (a.as_str(), *v, m.clone(), *n))
+            .collect::<Vec<_>>();
+
+        // Work out what "max" is now, so that the spend limits see the actual amount
+        let addrs = self.wallet.read().unwrap().resolve_send_max(addrs)?;
+
+        let amount = addrs.iter().map(|(_, v, _, _)| *v).sum::<u64>();
+        if !override_limits {
+            self.check_tx_limit(amount)?;
+        }
+
+        if addrs.iter().any(|(a, _, _, _)| !LightWallet::is_shielded_address(&a.to_string(), &self.config)) 
+                && !self.server_supports_taddrs() {
+            return Err(NO_TADDR_SUPPORT.to_string());
+        }
+
+        // Counted before the transaction is built, and taken back if it doesn't go out
+        self.count_spend(amount, override_limits)?;
+
         info!("Creating transaction");
 
-        let rawtx = self.wallet.write().unwrap().send_to_address(
+        let recipients = addrs.iter().map(|(a, _, _, _)| a.to_string()).collect::<Vec<_>>();
+        let rawtx = self.wallet.write().unwrap().send_to_address_with_splits(
             u32::from_str_radix(&self.config.consensus_branch_id, 16).unwrap(), 
             &self.sapling_spend, &self.sapling_output,
             addrs
         );
         
-        match rawtx {
-            Ok(txbytes)   => broadcast_raw_tx(&self.get_server_uri(), self.config.no_cert_verification, txbytes),
+        let r = match rawtx {
+            Ok(txbytes)   => {
+                let txhex = hex::encode(&txbytes);
+                let mut details = self.fee_rate_json(&txbytes).unwrap_or_else(|_| JsonValue::new_object());
+                let (r, servers) = self.broadcast(txbytes);
+                details["servers"] = servers;
+                r.map(|txid| {
+                    self.wallet.read().unwrap().add_sent_to(&recipients.iter().map(|a| a.as_str()).collect::<Vec<_>>());
+                    (txid, txhex, details)
+                })
+            },
             Err(e)        => Err(format!("Error: No Tx to broadcast. Error was: {}", e))
+        };
+        if r.is_err() {
+            self.wallet.read().unwrap().remove_sent_today(amount);
         }
+
+        // The spent notes are now marked as pending, so save that even if the broadcast failed
+        self.auto_save(true);
+
+        r
     }
-}
 
-#[cfg(test)]
-pub mod tests {
-    use lazy_static::lazy_static;
-    use tempdir::TempDir;
-    use super::{LightClient, LightClientConfig};
+    /// The fee a raw transaction, given as hex, pays, how many logical actions (ZIP-317) it has, and the
+    /// fee per action that works out to, along with the lowest fee the network takes for it. The fee is
+    /// only known if all the transaction's transparent inputs are the wallet's, eg. for `send rawtx`.
+    pub fn do_tx_fee_rate(&self, rawtx: &str) -> Result<JsonValue, String> {
+        let txbytes = hex::decode(rawtx.trim()).map_err(|e| format!("Couldn't decode the transaction: {}", e))?;
+        self.fee_rate_json(&txbytes)
+    }
 
-    lazy_static!{
-        static ref TEST_SEED: String = "youth strong sweet gorilla hammer unhappy congress stamp left stereo riot salute road tag clean toilet artefact fork certain leopard entire civil degree wonder".to_string();
+    fn fee_rate_json(&self, txbytes: &[u8]) -> Result<JsonValue, String> {
+        let tx = Transaction::read(txbytes).map_err(|_| "Not a valid transaction".to_string())?;
+        let fee = self.wallet.read().unwrap().tx_fee(&tx)
+            .ok_or_else(|| "The transaction spends transparent funds that aren't the wallet's, so its fee isn't known".to_string())?;
+
+        let actions = LightWallet::logical_actions(&tx);
+        let min_fee = LightWallet::min_fee(actions, self.config.min_fee_per_action);
+        Ok(object!{
+            "fee"             => fee,
+            "logical_actions" => actions,
+            "fee_rate"        => LightWallet::fee_rate(fee, actions),
+            "min_fee"         => min_fee,
+            "below_min_fee"   => fee < min_fee,
+        })
     }
 
-    #[test]
-    pub fn test_encrypt_decrypt() {
-        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+    /// The addresses the wallet has sent to, most recently used first, eg. to autocomplete addresses, or to
+    /// warn before sending to an address for the first time
+    pub fn do_send_history(&self) -> JsonValue {
+        let history = self.wallet.read().unwrap().sent_to_history().into_iter()
+            .map(|(address, last_used, count)| object!{
+                "address"   => address,
+                "last_used" => last_used,
+                "count"     => count,
+            })
+            .collect::<Vec<_>>();
 
-        assert!(!lc.do_export(None).is_err());
-        assert!(!lc.do_new_address("z").is_err());
-        assert!(!lc.do_new_address("t").is_err());
-        assert_eq!(lc.do_seed_phrase().unwrap()["seed"], TEST_SEED.to_string());
+        JsonValue::Array(history)
+    }
 
-        // Encrypt and Lock the wallet
-        lc.wallet.write().unwrap().encrypt("password".to_string()).unwrap();
+    /// Broadcast a raw transaction, given as hex, again, eg. one that a server dropped. Like a send, it
+    /// goes to all the configured servers.
+    pub fn do_broadcast(&self, rawtx: &str) -> Result<JsonValue, String> {
+        self.check_writable()?;
+        let txbytes = hex::decode(rawtx.trim()).map_err(|e| format!("Couldn't decode the transaction: {}", e))?;
+        if Transaction::read(&txbytes[..]).is_err() {
+            return Err("Not a valid transaction".to_string());
+        }
+
+        let (r, servers) = self.broadcast(txbytes.into_boxed_slice());
+        r.map(|txid| object!{ "txid" => txid, "servers" => servers })
+    }
+
+    /// Rebuild the pending transaction `txid`, which this wallet sent, with a fee of `new_fee`, and broadcast it.
+    /// The new transaction pays the same recipients out of the same notes and utxos, so at most one of the two
+    /// can be mined. Returns the new txid. Once it's broadcast, the new transaction is listed instead of `txid`.
+    ///
+    /// The full nodes have no replace-by-fee: a node rejects a transaction that spends the same notes as one
+    /// already in its mempool, and doesn't evict the old one for a higher fee. So the new transaction only gets
+    /// through once the servers have dropped `txid`, eg. because it expired. Until then the broadcast fails,
+    /// and nothing in the wallet changes.
+    pub fn do_bump_fee(&self, txid: &str, new_fee: u64) -> Result<String, String> {
+        self.check_writable()?;
+
+        if !self.wallet.read().unwrap().is_unlocked_for_spending() {
+            error!("Wallet is locked");
+            return Err("Wallet is locked".to_string());
+        }
+
+        let txid = grpcconnector::txid_from_hex(txid)?;
+        let (tx, fee) = self.wallet.read().unwrap().build_fee_bump(
+            u32::from_str_radix(&self.config.consensus_branch_id, 16).unwrap(),
+            &self.sapling_spend, &self.sapling_output,
+            &txid, new_fee
+        )?;
+
+        let mut txbytes = vec![];
+        tx.write(&mut txbytes).map_err(|e| format!("Couldn't write the transaction: {}", e))?;
+
+        let (r, _) = self.broadcast(txbytes.into_boxed_slice());
+        match r {
+            Ok(new_txid) => {
+                self.wallet.read().unwrap().replace_pending_tx(&txid, &tx, fee);
+                self.auto_save(true);
+                Ok(new_txid)
+            },
+            Err(e) => Err(format!("{}. {} is still pending. The servers won't take a transaction that spends the same \
+                                   notes as one they already have, so the higher fee can only go out once they've \
+                                   dropped it, eg. when it expires", e, txid))
+        }
+    }
+
+    /// Get the full raw transaction with `txid` from the server, as hex, eg. to inspect it or to broadcast it
+    /// again. The wallet itself only keeps what it decrypted from its transactions.
+    pub fn do_fetch_raw_transaction(&self, txid: &str) -> Result<String, String> {
+        let txid = grpcconnector::txid_from_hex(txid)?;
+        self.grpc.fetch_transaction(txid).map(hex::encode)
+    }
+}
+
+/// The file of the wallet called `name`, see `LightClientConfig::wallet_name`
+pub fn wallet_file_name(name: &str) -> String {
+    format!("{}_{}.dat", WALLET_NAME.trim_end_matches(".dat"), name)
+}
+
+/// Create `dir` and the dirs above it that don't exist yet. On unix, only the user can get into the ones that
+/// are created, since they hold the wallet.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+    }
+
+    #[cfg(not(unix))]
+    {
+        std::fs::create_dir_all(dir)
+    }
+}
+
+/// Move each of `backups` down by one, dropping the last one, and make `path` the first. `path` itself
+/// stays where it is, so that there's always a wallet file, and each rename replaces one file with another,
+/// so that a crash part way through at worst leaves two copies of a backup.
+fn rotate_backups(path: &Path, backups: &[Box<Path>]) -> io::Result<()> {
+    if backups.is_empty() || !path.exists() {
+        return Ok(());
+    }
+
+    for i in (1..backups.len()).rev() {
+        if backups[i - 1].exists() {
+            std::fs::rename(&backups[i - 1], &backups[i])?;
+        }
+    }
+
+    // A hard link keeps the current file as the backup without copying it. Where the file system can't
+    // link, it's copied instead, the same way the file itself is saved.
+    let first = &backups[0];
+    if first.exists() {
+        std::fs::remove_file(first)?;
+    }
+    if std::fs::hard_link(path, first).is_err() {
+        let tmp = first.with_extension("tmp");
+        std::fs::copy(path, &tmp)?;
+        std::fs::rename(&tmp, first)?;
+    }
+
+    Ok(())
+}
+
+/// Write the file at `path` with `write`, without ever leaving it half-written: it's written to a temporary
+/// file next to it first, which is synced to disk and then renamed over `path`. If writing fails, or the
+/// process dies before the rename, `path` is left as it was. Returns the number of bytes written.
+/// 
+/// Once the new file is written, the previous one is kept as the first of `backups`, which move down by one.
+fn write_file_atomically<F>(path: &Path, backups: &[Box<Path>], write: F) -> Result<u64, String>
+        where F: FnOnce(&mut BufWriter<File>) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file_buffer = BufWriter::with_capacity(
+        1_000_000, // 1 MB write buffer
+        File::create(&tmp_path).map_err(|e| format!("Couldn't create {}: {}", tmp_path.display(), e))?);
+
+    let r = write(&mut file_buffer)
+        .and_then(|_| file_buffer.flush())
+        .and_then(|_| file_buffer.get_ref().sync_all())
+        .and_then(|_| file_buffer.get_ref().metadata());
+    drop(file_buffer);
+
+    let len = match r {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e.to_string());
+        }
+    };
+
+    if let Err(e) = rotate_backups(path, backups) {
+        warn!("Couldn't keep a backup of {}: {}", path.display(), e);
+    }
+
+    std::fs::rename(&tmp_path, path).map_err(|e| format!("Couldn't save {}: {}", path.display(), e))?;
+
+    // So that the rename itself survives a crash. Not every platform can open a directory, which is fine.
+    if let Some(dir) = path.parent().and_then(|d| File::open(d).ok()) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use lazy_static::lazy_static;
+    use tempdir::TempDir;
+    use super::{LightClient, LightClientConfig};
+
+    lazy_static!{
+        static ref TEST_SEED: String = "youth strong sweet gorilla hammer unhappy congress stamp left stereo riot salute road tag clean toilet artefact fork certain leopard entire civil degree wonder".to_string();
+    }
+
+    #[test]
+    pub fn test_broadcast_checks_transaction() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+
+        // Nothing is sent to the servers unless it's a transaction
+        assert!(lc.do_broadcast("not hex").unwrap_err().starts_with("Couldn't decode"));
+        assert_eq!(lc.do_broadcast("00112233").unwrap_err(), "Not a valid transaction");
+    }
+
+    #[test]
+    pub fn test_encrypt_decrypt() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+
+        assert!(!lc.do_export(None).is_err());
+        assert!(!lc.do_export_transparent_xprv().is_err());
+        assert!(!lc.do_new_address("z").is_err());
+        assert!(!lc.do_new_address("t").is_err());
+        assert_eq!(lc.do_seed_phrase().unwrap()["seed"], TEST_SEED.to_string());
+
+        assert!(lc.do_encryption_status()["kdf"].is_null());
+
+        // Encrypt and Lock the wallet
+        lc.wallet.write().unwrap().encrypt("password".to_string()).unwrap();
+        assert_eq!(lc.do_encryption_status()["kdf"]["algorithm"], "argon2id");
         assert!(lc.do_export(None).is_err());
+        assert!(lc.do_export_transparent_xprv().is_err());
         assert!(lc.do_seed_phrase().is_err());
+        assert!(lc.do_nullifiers().is_err());
         assert!(lc.do_new_address("t").is_err());
         assert!(lc.do_new_address("z").is_err());
         assert!(lc.do_send(vec![("z", 0, None)]).is_err());
 
+        // Locked, it can't even show balances
+        assert!(!lc.do_balance()["error"].is_null());
+        assert_eq!(lc.do_encryption_status()["unlocked_for_viewing"], false);
+
+        // Unlocked for viewing, it shows balances, but still can't spend or export keys
+        assert!(lc.do_unlock_viewing("wrongpassword".to_string()).is_err());
+        lc.do_unlock_viewing("password".to_string()).unwrap();
+        assert!(lc.do_unlock_viewing("password".to_string()).is_err());
+        assert!(lc.do_balance()["error"].is_null());
+        assert_eq!(lc.do_encryption_status()["unlocked_for_viewing"], true);
+        assert_eq!(lc.do_encryption_status()["unlocked_for_spending"], false);
+        assert!(lc.do_export(None).is_err());
+        assert!(lc.do_seed_phrase().is_err());
+        assert!(lc.do_send(vec![("z", 0, None)]).is_err());
+
         // Do a unlock, and make sure it all works now
         lc.wallet.write().unwrap().unlock("password".to_string()).unwrap();
         assert!(!lc.do_export(None).is_err());
         assert!(!lc.do_seed_phrase().is_err());
+        assert!(!lc.do_nullifiers().is_err());
 
-        // This will lock the wallet again, so after this, we'll need to unlock again
+        // This will lock the wallet again, so after this, we'll need to unlock again. It stays unlocked for viewing.
         assert!(!lc.do_new_address("t").is_err());
+        assert_eq!(lc.do_encryption_status()["unlocked_for_viewing"], true);
+        assert_eq!(lc.do_encryption_status()["unlocked_for_spending"], false);
         lc.wallet.write().unwrap().unlock("password".to_string()).unwrap();
         
         assert!(!lc.do_new_address("z").is_err());
     }
 
+    #[test]
+    pub fn test_transparent_history_block_time() {
+        use std::sync::{Arc, Mutex};
+        use protobuf::parse_from_bytes;
+        use zcash_client_backend::proto::compact_formats::CompactBlock;
+        use crate::grpcconnector::tests::lightwalletd_server;
+        use crate::lightwallet::tests::{fake_blocks_paying, fake_tx_paying_taddr};
+
+        // The seed's t address was paid at height 2, before the wallet's birthday
+        let tx_bytes = fake_tx_paying_taddr(&LightClient::unconnected(TEST_SEED.to_string(), None).unwrap().wallet.read().unwrap(), 50_000);
+        let blocks = fake_blocks_paying(1, 4, &[]);
+        let block_time = parse_from_bytes::<CompactBlock>(&blocks[1]).unwrap().time as u64;
+        let server = lightwalletd_server(Arc::new(Mutex::new(blocks.clone())), vec![(2, tx_bytes)]);
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let config = LightClientConfig {
+            servers: vec![format!("http://{}", server).parse().unwrap()],
+            sapling_activation_height: 1,
+            max_attempts: 1,
+            ..LightClientConfig::create_unconnected("test".to_string(), tmp.path().to_str().map(|s| s.to_string()))
+        };
+
+        // Restored at 3, and scanned up to there, so the sync only scans block 4 and has to fetch the t
+        // address's history before it
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 3, false).unwrap();
+        for block in &blocks[..3] {
+            lc.wallet.read().unwrap().scan_block(block).unwrap();
+        }
+        lc.do_sync(false).unwrap();
+
+        let wallet = lc.wallet.read().unwrap();
+        let txs = wallet.txs.read().unwrap();
+        assert_eq!(txs.len(), 1);
+        let wtx = txs.values().next().unwrap();
+        assert_eq!(wtx.block, 2);
+        assert_eq!(wtx.datetime, block_time);
+        assert_eq!(wallet.tbalance(None), 50_000);
+    }
+
+    #[test]
+    pub fn test_height() {
+        use std::sync::{Arc, Mutex};
+        use protobuf::parse_from_bytes;
+        use zcash_client_backend::proto::compact_formats::CompactBlock;
+        use crate::grpcconnector::tests::lightwalletd_server;
+        use crate::lightwallet::tests::fake_blocks_paying;
+
+        let blocks = fake_blocks_paying(1, 5, &[]);
+        let mut tip_hash = parse_from_bytes::<CompactBlock>(&blocks[4]).unwrap().hash;
+        let server = lightwalletd_server(Arc::new(Mutex::new(blocks.clone())), vec![]);
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let config = LightClientConfig {
+            servers: vec![format!("http://{}", server).parse().unwrap()],
+            sapling_activation_height: 1,
+            max_attempts: 1,
+            ..LightClientConfig::create_unconnected("test".to_string(), tmp.path().to_str().map(|s| s.to_string()))
+        };
+
+        // Scanned up to 2, so 3 blocks behind the server
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+        for block in &blocks[..2] {
+            lc.wallet.read().unwrap().scan_block(block).unwrap();
+        }
+
+        tip_hash.reverse();
+        let height = lc.do_height().unwrap();
+        assert_eq!(height["server_height"], 5);
+        assert_eq!(height["server_hash"], hex::encode(tip_hash));
+        assert_eq!(height["wallet_height"], 2);
+        assert_eq!(height["lag"], 3);
+    }
+
     #[test]
     pub fn test_addresses() {
         let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
@@ -1268,19 +3557,11 @@ pub mod tests {
         assert_eq!(addresses["t_addresses"][6], taddr1);
         assert_eq!(addresses["t_addresses"][7], taddr2);
 
-        use std::sync::{Arc, RwLock, Mutex};
-        use crate::lightclient::{WalletStatus, LightWallet};
+        use crate::lightclient::LightWallet;
 
         // When creating a new wallet, there is only 1 address
         let config = LightClientConfig::create_unconnected("test".to_string(), None);
-        let lc = LightClient {
-            wallet          : Arc::new(RwLock::new(LightWallet::new(None, &config, 0).unwrap())),
-            config          : config,
-            sapling_output  : vec![], 
-            sapling_spend   : vec![],
-            sync_lock       : Mutex::new(()),
-            sync_status     : Arc::new(RwLock::new(WalletStatus::new())),
-        };
+        let lc = LightClient::from_parts(&config, LightWallet::new(None, &config, 0).unwrap(), None).unwrap();
         {
             let addresses = lc.do_address();
             // New wallets have only 1 address
@@ -1289,6 +3570,827 @@ pub mod tests {
         }
     }
 
+    #[test]
+    pub fn test_addresses_round_trip() {
+        use pairing::bls12_381::Bls12;
+        use zcash_client_backend::encoding::decode_payment_address;
+        use zcash_primitives::{JUBJUB, primitives::Diversifier};
+
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let hrp = lc.config.hrp_sapling_address();
+
+        // Every address the wallet has is listed, and decodes back to itself
+        let zaddresses = lc.wallet.read().unwrap().zaddress.read().unwrap().clone();
+        let listed = lc.do_address()["z_addresses"].members().map(|a| a.as_str().unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(listed.len(), zaddresses.len());
+        for (address, ad) in listed.iter().zip(zaddresses.iter()) {
+            assert_eq!(decode_payment_address(hrp, address).unwrap().unwrap(), *ad);
+        }
+
+        // An address with a diversifier that has no base point can't be decoded, so it's left out
+        let mut bad = zaddresses[0].clone();
+        bad.diversifier = (0..=255u8).map(|i| Diversifier([i; 11]))
+                            .find(|d| d.g_d::<Bls12>(&JUBJUB).is_none())
+                            .unwrap();
+        lc.wallet.read().unwrap().zaddress.write().unwrap().push(bad);
+        assert_eq!(lc.do_address()["z_addresses"].members().map(|a| a.as_str().unwrap().to_string()).collect::<Vec<_>>(), listed);
+    }
+
+    #[test]
+    pub fn test_export_transparent_xprv() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+
+        let xkeys = lc.do_export_transparent_xprv().unwrap();
+        assert_eq!(xkeys["derivation_path"], format!("m/44'/{}'/0'", lc.config.get_coin_type()));
+
+        // Testnet extended keys
+        assert!(xkeys["xprv"].as_str().unwrap().starts_with("tprv"));
+        assert!(xkeys["xpub"].as_str().unwrap().starts_with("tpub"));
+
+        // Deterministic for the same seed
+        assert_eq!(xkeys, lc.do_export_transparent_xprv().unwrap());
+    }
+
+    #[test]
+    pub fn test_auto_save() {
+        use super::LightWallet;
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+
+        let mut config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        config.auto_save = true;
+
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+        std::fs::remove_file(config.get_wallet_path()).unwrap();
+
+        // Forced saves always happen
+        lc.auto_save(true);
+        assert!(config.wallet_exists());
+
+        // But others are debounced
+        std::fs::remove_file(config.get_wallet_path()).unwrap();
+        lc.auto_save(false);
+        assert!(!config.wallet_exists());
+
+        // An unlocked encrypted wallet is saved too, without its spending keys, and stays unlocked
+        lc.wallet.write().unwrap().encrypt("password".to_string()).unwrap();
+        lc.wallet.write().unwrap().unlock("password".to_string()).unwrap();
+        std::fs::remove_file(config.get_wallet_path()).ok();
+        *lc.last_auto_save.lock().unwrap() = None;
+        lc.auto_save(false);
+        assert!(config.wallet_exists());
+        assert!(lc.wallet.read().unwrap().is_unlocked_for_spending());
+
+        let mut saved = LightWallet::read(std::fs::File::open(config.get_wallet_path()).unwrap(), &config).unwrap();
+        assert!(saved.is_encrypted());
+        assert!(!saved.is_unlocked_for_spending());
+        saved.unlock("password".to_string()).unwrap();
+        assert_eq!(saved.get_seed_phrase().as_str(), TEST_SEED.as_str());
+
+        // Auto-saves don't keep backups, so they don't push out the ones from before the sync
+        assert!((1..=config.wallet_backups).all(|n| !config.get_wallet_backup_path(n).exists()));
+        lc.auto_save(true);
+        assert!((1..=config.wallet_backups).all(|n| !config.get_wallet_backup_path(n).exists()));
+
+        // And nothing is saved with auto_save off
+        let mut config = config.clone();
+        config.auto_save = false;
+        let lc = LightClient::read_from_buffer(&config, &lc.do_save_to_buffer().unwrap()[..]).unwrap();
+        lc.auto_save(true);
+        assert!(!config.wallet_exists());
+    }
+
+    #[test]
+    pub fn test_import_birthday() {
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+
+        // Starting at the 550000 checkpoint, a key without a birthday is scanned from the checkpoint before 540000
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 600000, false).unwrap();
+        assert_eq!(lc.wallet.read().unwrap().get_birthday(), 600000);
+        assert_eq!(lc.wallet.read().unwrap().last_scanned_height(), 550000);
+        assert_eq!(lc.default_import_birthday(), 350000);
+
+        // The birthday only moves back
+        let mut wallet = lc.wallet.write().unwrap();
+        wallet.lower_birthday(400000);
+        assert_eq!(wallet.get_birthday(), 400000);
+        wallet.lower_birthday(500000);
+        assert_eq!(wallet.get_birthday(), 400000);
+        wallet.lower_birthday(300000);
+        assert_eq!(wallet.get_birthday(), 300000);
+    }
+
+    #[test]
+    pub fn test_shutdown() {
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+
+        let mut config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        config.auto_save = true;
+
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+        std::fs::remove_file(config.get_wallet_path()).unwrap();
+
+        // The wallet is saved one last time
+        lc.do_shutdown().unwrap();
+        assert!(config.wallet_exists());
+
+        // And it doesn't sync anymore, without trying to reach the server
+        assert!(lc.do_sync(false).unwrap_err().contains("shut down"));
+
+        // A wallet that isn't auto-saved isn't saved on shutdown either
+        drop(lc);
+        std::fs::remove_file(config.get_wallet_path()).unwrap();
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &LightClientConfig { auto_save: false, ..config.clone() }, 0, false).unwrap();
+        std::fs::remove_file(config.get_wallet_path()).unwrap();
+        lc.do_shutdown().unwrap();
+        assert!(!config.wallet_exists());
+
+        // And neither is one that was opened read-only
+        drop(lc);
+        LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+        let lc = LightClient::read_from_disk(&LightClientConfig { read_only: true, ..config.clone() }).unwrap();
+        std::fs::remove_file(config.get_wallet_path()).unwrap();
+        lc.do_shutdown().unwrap();
+        assert!(!config.wallet_exists());
+    }
+
+    #[test]
+    pub fn test_read_only() {
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+        let saved = std::fs::read(config.get_wallet_path()).unwrap();
+
+        let read_only = LightClientConfig { read_only: true, ..config.clone() };
+        let lc = LightClient::read_from_disk(&read_only).unwrap();
+
+        // Looking at the wallet works
+        assert!(lc.do_balance()["error"].is_null());
+        assert_eq!(lc.do_address(), LightClient::read_from_disk(&config).unwrap().do_address());
+        assert!(lc.do_list_transactions().is_array());
+        assert!(lc.do_export(None).is_ok());
+
+        // Changing it doesn't
+        assert_eq!(lc.do_save().unwrap_err(), WALLET_READ_ONLY);
+        assert_eq!(lc.do_new_address("z").unwrap_err(), WALLET_READ_ONLY);
+        assert_eq!(lc.do_sync(false).unwrap_err(), WALLET_READ_ONLY);
+        assert_eq!(lc.do_rescan().unwrap_err(), WALLET_READ_ONLY);
+        assert_eq!(lc.do_send(vec![("z", 0, None)]).unwrap_err(), WALLET_READ_ONLY);
+        assert_eq!(lc.do_queue_send("z".to_string(), 0, None).unwrap_err(), WALLET_READ_ONLY);
+        lc.do_shutdown().unwrap();
+
+        assert_eq!(std::fs::read(config.get_wallet_path()).unwrap(), saved);
+    }
+
+    #[test]
+    pub fn test_send_fiat() {
+        use super::PriceProvider;
+
+        struct FixedPrice(Result<f64, String>);
+        impl PriceProvider for FixedPrice {
+            fn get_yec_price(&self, fiat: &str) -> Result<f64, String> {
+                assert_eq!(fiat, "USD");
+                self.0.clone()
+            }
+        }
+
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let zaddr = lc.do_address()["z_addresses"][0].as_str().unwrap().to_string();
+
+        let e = lc.do_send_fiat(&FixedPrice(Err("no price".to_string())), &zaddr, 5.0, "usd", None).unwrap_err();
+        assert_eq!(e, "Couldn't get the price of YEC in USD: no price");
+        assert!(lc.do_send_fiat(&FixedPrice(Ok(0.0)), &zaddr, 5.0, "USD", None).unwrap_err().contains("Invalid price"));
+        assert!(lc.do_send_fiat(&FixedPrice(Ok(0.25)), &zaddr, -5.0, "USD", None).unwrap_err().contains("Invalid amount"));
+
+        // The send itself fails the same as `do_send`, here because there's nothing to spend, and no rate is kept
+        assert!(lc.do_send_fiat(&FixedPrice(Ok(0.25)), &zaddr, 5.0, "USD", None).is_err());
+        assert!(lc.do_list_transactions().members().all(|t| t["fiat_rate"].is_null()));
+    }
+
+    #[test]
+    pub fn test_wallet_lock() {
+        use super::WalletLock;
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        let lock_path = WalletLock::lock_path(&config.get_wallet_path());
+
+        // The lock is held as long as a client of this process has the wallet open
+        let lc = LightClient::new(&config, 0).unwrap();
+        assert!(lock_path.exists());
+        let lc2 = LightClient::read_from_disk(&config).unwrap();
+        drop(lc);
+        assert!(lock_path.exists());
+        drop(lc2);
+        assert!(!lock_path.exists());
+
+        // A lock left by a process that is gone is taken over
+        std::fs::write(&lock_path, "4294967294\n0\n").unwrap();
+        let lc = LightClient::read_from_disk(&config).unwrap();
+        assert!(std::fs::read_to_string(&lock_path).unwrap().starts_with(&std::process::id().to_string()));
+        lc.release_lock();
+        assert!(!lock_path.exists());
+        drop(lc);
+
+        // Nothing is left behind from taking the lock
+        let lock_name = lock_path.file_name().unwrap().to_str().unwrap().to_string();
+        assert!(std::fs::read_dir(lock_path.parent().unwrap()).unwrap()
+            .all(|e| !e.unwrap().file_name().to_str().unwrap().starts_with(&lock_name)));
+
+        // Another process that is still running has it open
+        if cfg!(unix) {
+            let mut other = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+            std::fs::write(&lock_path, format!("{}\n0\n", other.id())).unwrap();
+
+            let e = LightClient::read_from_disk(&config).err().unwrap();
+            assert!(e.to_string().contains(&format!("in use by process {}", other.id())));
+
+            // Read-only clients don't write the wallet, so they don't need the lock
+            assert!(LightClient::read_from_disk(&LightClientConfig { read_only: true, ..config.clone() }).is_ok());
+
+            // ...and it can be forced
+            let forced = LightClientConfig { force_unlock: true, ..config.clone() };
+            let lc = LightClient::read_from_disk(&forced).unwrap();
+            assert!(std::fs::read_to_string(&lock_path).unwrap().starts_with(&std::process::id().to_string()));
+            drop(lc);
+            assert!(!lock_path.exists());
+
+            other.kill().unwrap();
+            other.wait().unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_balance_cache() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let computed = || lc.balance_cache.lock().unwrap().computed;
+
+        // The second call comes from the cache, without going through the wallet's transactions
+        let balance = lc.do_balance();
+        assert_eq!(computed(), 1);
+        assert_eq!(lc.do_balance(), balance);
+        assert_eq!(computed(), 1);
+
+        // Until the wallet changes
+        let taddr = lc.wallet.read().unwrap().add_taddr();
+        let balance = lc.do_balance();
+        assert_eq!(computed(), 2);
+        assert!(balance["t_addresses"].members().any(|a| a["address"] == taddr));
+
+        lc.wallet.read().unwrap().clear_blocks();
+        lc.do_balance();
+        lc.do_balance();
+        assert_eq!(computed(), 3);
+    }
+
+    #[test]
+    pub fn test_balance_without_watchonly() {
+        use zcash_client_backend::encoding::encode_extended_full_viewing_key;
+        use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+        use crate::lightwallet::tests::{fake_blocks_paying, first_extfvk};
+
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let watched = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[2u8; 32]));
+        let key = encode_extended_full_viewing_key(lc.config.hrp_sapling_viewing_key(), &watched);
+        let watched_address = lc.wallet.read().unwrap().add_watch_only_key(&key).unwrap();
+
+        // The wallet and the watched address are paid, and the wallet is paid again in the last block, 
+        // which isn't confirmed enough to spend yet
+        let own = first_extfvk(&lc.wallet.read().unwrap());
+        for block in fake_blocks_paying(0, 2, &[(0, own.clone(), 100_000), (0, watched, 30_000), (1, own, 7_000)]) {
+            lc.wallet.read().unwrap().scan_block(&block).unwrap();
+        }
+
+        // The watch-only address is labelled as such, and its funds are counted, but can't be spent
+        let balance = lc.do_balance();
+        let z_addresses = balance["z_addresses"].members().collect::<Vec<_>>();
+        assert!(z_addresses.iter().any(|a| a["address"] == watched_address && a["watch_only"] == true && a["zbalance"] == 30_000));
+        assert!(z_addresses.iter().filter(|a| a["address"] != watched_address).all(|a| a["watch_only"] == false));
+        assert!(balance["t_addresses"].members().all(|a| a["watch_only"] == false));
+        assert_eq!(balance["zbalance"], 137_000);
+        assert_eq!(balance["verified_zbalance"], 130_000);
+        assert_eq!(balance["watch_only_zbalance"], 30_000);
+        assert_eq!(balance["spendable_only"], 100_000);
+
+        // ...and left out without watch-only addresses
+        let without = lc.do_balance_with_watchonly(false);
+        assert_eq!(without["z_addresses"].len(), z_addresses.len() - 1);
+        assert!(without["z_addresses"].members().all(|a| a["watch_only"] == false));
+        assert_eq!(without["zbalance"], 107_000);
+        assert_eq!(without["verified_zbalance"], 100_000);
+        assert_eq!(without["spendable_only"], 100_000);
+        assert_eq!(lc.balance_cache.lock().unwrap().computed, 2);
+
+        // Both views are cached, so going back and forth doesn't compute them again
+        assert_eq!(lc.do_balance(), balance);
+        assert_eq!(lc.do_balance_with_watchonly(false), without);
+        assert_eq!(lc.balance_cache.lock().unwrap().computed, 2);
+    }
+
+    #[test]
+    pub fn test_offline() {
+        use crate::grpc_client::LightdInfo;
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+
+        // There has to be a wallet to open
+        assert!(LightClientConfig::create_offline("test".to_string(), dir_name.clone()).is_err());
+
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name.clone());
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+        lc.do_save().unwrap();
+
+        // Without saved info, the wallet still opens
+        let offline = LightClientConfig::create_offline("test".to_string(), dir_name.clone()).unwrap();
+        assert!(offline.offline);
+        assert!(LightClient::read_from_disk(&offline).unwrap().do_info(false).starts_with(crate::grpcconnector::OFFLINE_ERROR));
+
+        // The chain parameters come from the last info saved online
+        let server: http::Uri = "https://example.com:443".parse().unwrap();
+        config.save_server_info(&server, &LightdInfo{ chain_name: "test".to_string(), sapling_activation_height: 280000, 
+                consensus_branch_id: "76b809bb".to_string(), block_height: 100, ..Default::default() });
+
+        let offline = LightClientConfig::create_offline("test".to_string(), dir_name).unwrap();
+        assert_eq!(offline.servers, vec![server.clone()]);
+        assert_eq!(offline.sapling_activation_height, 280000);
+        assert_eq!(offline.consensus_branch_id, "76b809bb");
+
+        let lc = LightClient::read_from_disk(&offline).unwrap();
+
+        // The wallet works, but nothing that needs the server does
+        assert_eq!(lc.do_balance()["zbalance"].as_u64().unwrap(), 0);
+        assert!(!lc.do_address()["z_addresses"].is_empty());
+        assert_eq!(lc.do_sync(true).unwrap_err(), crate::grpcconnector::OFFLINE_ERROR);
+        assert_eq!(lc.do_ping(1)["reachable"], false);
+        assert!(lc.do_fetch_raw_transaction(&"ab".repeat(32)).unwrap_err().ends_with(crate::grpcconnector::OFFLINE_ERROR));
+        assert!(lc.do_fetch_raw_transaction("not a txid").is_err());
+
+        let info = json::parse(&lc.do_info(true)).unwrap();
+        assert_eq!(info["latest_block_height"], 100);
+        assert_eq!(info["server"], server.to_string());
+        assert_eq!(info["stale"], true);
+    }
+
+    #[test]
+    pub fn test_no_taddr_support() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let taddr = lc.do_address()["t_addresses"][0].as_str().unwrap().to_string();
+
+        // The server said it doesn't do t addresses, so nothing transparent is attempted
+        lc.set_server_info(crate::grpc_client::LightdInfo{ taddr_support: false, ..Default::default() });
+
+        assert_eq!(lc.do_fetch_utxos().unwrap_err(), super::NO_TADDR_SUPPORT);
+        assert_eq!(lc.do_send(vec![(&taddr, 1000, None)]).unwrap_err(), super::NO_TADDR_SUPPORT);
+    }
+
+    #[test]
+    pub fn test_info_cache() {
+        use crate::grpc_client::LightdInfo;
+
+        let mut lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        lc.set_server_info(LightdInfo{ block_height: 100, ..Default::default() });
+
+        // Served from the cache, since nothing is listening
+        assert_eq!(lc.get_info(false).unwrap().block_height, 100);
+        assert!(lc.get_info(true).is_err());
+
+        // Which is still there if the server can't be reached
+        let info = json::parse(&lc.do_info(true)).unwrap();
+        assert_eq!(info["latest_block_height"], 100);
+        assert_eq!(info["stale"], true);
+
+        // Until it expires
+        lc.config.info_ttl = std::time::Duration::from_secs(0);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(lc.get_info(false).is_err());
+
+        // Info from another server doesn't count
+        lc.config.info_ttl = super::DEFAULT_INFO_TTL;
+        lc.server_info.write().unwrap().as_mut().unwrap().server = "https://example.com:443".parse().unwrap();
+        assert!(lc.get_info(false).is_err());
+        assert!(lc.cached_info(None).is_none());
+    }
+
+    #[test]
+    pub fn test_info_stale_after_refresh() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use crate::grpcconnector::tests::flaky_info_server;
+
+        let up = Arc::new(AtomicBool::new(true));
+        let (server, _) = flaky_info_server(1234, up.clone());
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let config = LightClientConfig {
+            servers: vec![format!("http://{}", server).parse().unwrap()],
+            max_attempts: 1,
+            ..LightClientConfig::create_unconnected("main".to_string(), tmp.path().to_str().map(|s| s.to_string()))
+        };
+        let lc = LightClient::new(&config, 0).unwrap();
+
+        // Fresh from the server
+        let info = json::parse(&lc.do_info(true)).unwrap();
+        assert_eq!(info["latest_block_height"], 1234);
+        assert!(info["stale"].is_null());
+        assert!(info["error"].is_null());
+
+        // Once it stops answering, the last info it sent is returned, marked as stale
+        up.store(false, Ordering::SeqCst);
+        assert!(lc.get_info(true).is_err());
+        let info = json::parse(&lc.do_info(true)).unwrap();
+        assert_eq!(info["latest_block_height"], 1234);
+        assert_eq!(info["server"], lc.get_server_uri().to_string());
+        assert_eq!(info["stale"], true);
+        assert!(info["error"].is_string());
+    }
+
+    #[test]
+    pub fn test_change_server() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let server = lc.get_server_uri();
+
+        assert!(lc.do_change_server("http://").unwrap_err().starts_with("Invalid server uri"));
+        assert!(lc.do_change_server("http://127.0.0.1:1").unwrap_err().starts_with("Couldn't reach http://127.0.0.1:1/"));
+        {
+            let _lock = lc.sync_lock.lock().unwrap();
+            assert!(lc.do_change_server("http://127.0.0.1:1").unwrap_err().starts_with("A sync is in progress"));
+        }
+
+        // Nothing changed, since none of them could be used
+        assert_eq!(lc.get_server_uri(), server);
+    }
+
+    #[test]
+    pub fn test_change_server_to_reachable() {
+        use crate::grpcconnector::tests::info_server;
+
+        let (server, _) = info_server(1234);
+        let uri = format!("http://{}", server);
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let config = LightClientConfig::create_unconnected("main".to_string(), tmp.path().to_str().map(|s| s.to_string()));
+        let lc = LightClient::new(&config, 0).unwrap();
+
+        // It's on the wallet's chain, so it's used from now on, and its info is cached
+        let info = lc.do_change_server(&uri).unwrap();
+        assert_eq!(info["server"], format!("{}/", uri));
+        assert_eq!(info["latest_block_height"], 1234);
+        assert_eq!(lc.get_server_uri().to_string(), format!("{}/", uri));
+        assert_eq!(lc.get_info(false).unwrap().block_height, 1234);
+        assert_eq!(lc.config.read_server_info().unwrap().1.block_height, 1234);
+
+        // The configured servers are left alone
+        assert_eq!(lc.config.servers, config.servers);
+    }
+
+    #[test]
+    pub fn test_batch_sends() {
+        use super::{QueuedSend, batch_sends};
+        let q = |id, addr: &str| QueuedSend { id, address: addr.to_string(), amount: 1000, memo: None };
+
+        let all = |_: &str| true;
+
+        // Fits in one transaction
+        let items = vec![q(1, "a"), q(2, "b"), q(3, "c")];
+        assert_eq!(batch_sends(&items, 5, all), vec![items.clone()]);
+
+        // Split by the output limit, in order
+        assert_eq!(batch_sends(&items, 2, all), vec![vec![q(1, "a"), q(2, "b")], vec![q(3, "c")]]);
+
+        // Only shielded outputs count towards the limit
+        let items = vec![q(1, "z1"), q(2, "t1"), q(3, "z2"), q(4, "t2"), q(5, "z3")];
+        let z = |a: &str| a.starts_with('z');
+        assert_eq!(batch_sends(&items, 2, z), vec![vec![q(1, "z1"), q(2, "t1"), q(3, "z2"), q(4, "t2")], vec![q(5, "z3")]]);
+
+        // Paying the same address twice needs two transactions
+        let items = vec![q(1, "a"), q(2, "a"), q(3, "b")];
+        assert_eq!(batch_sends(&items, 5, all), vec![vec![q(1, "a"), q(3, "b")], vec![q(2, "a")]]);
+
+        assert!(batch_sends(&[], 5, all).is_empty());
+    }
+
+    #[test]
+    pub fn test_queue_send() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let zaddr = lc.do_address()["z_addresses"][0].as_str().unwrap().to_string();
+        let taddr = lc.do_address()["t_addresses"][0].as_str().unwrap().to_string();
+
+        assert!(lc.do_queue_send("notanaddress".to_string(), 1000, None).is_err());
+        assert!(lc.do_queue_send(taddr.clone(), 1000, Some("memo".to_string())).is_err());
+
+        assert_eq!(lc.do_queue_send(zaddr.clone(), 1000, Some("memo".to_string())), Ok(1));
+        assert_eq!(lc.do_queue_send(taddr.clone(), 2000, None), Ok(2));
+        assert_eq!(lc.do_list_queued_sends().len(), 2);
+
+        // There are no funds, so the batch fails, and stays queued
+        let r = lc.do_flush_sends();
+        assert_eq!(r.len(), 2);
+        assert!(r[0]["error"].is_string() && r[1]["error"].is_string());
+        assert_eq!(lc.do_list_queued_sends().len(), 2);
+    }
+
+    #[test]
+    pub fn test_transactions_since() {
+        use json::JsonValue;
+        use zcash_primitives::transaction::TxId;
+
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let taddr = lc.do_address()["t_addresses"][0].as_str().unwrap().to_string();
+
+        for (i, height) in [100, 105, 110].iter().enumerate() {
+            let txid = TxId{0: [i as u8 + 1; 32]};
+            assert!(lc.wallet.read().unwrap().add_utxo(taddr.clone(), txid, 0, vec![], 1000, *height));
+        }
+
+        let heights = |txns: JsonValue| txns.members().map(|tx| tx["block_height"].as_u64().unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(heights(lc.do_transactions_since(0)),   vec![100, 105, 110]);
+        assert_eq!(heights(lc.do_transactions_since(105)), vec![105, 110]);
+        assert!(lc.do_transactions_since(111).is_empty());
+    }
+
+    #[test]
+    pub fn test_spend_limits() {
+        let mut lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        lc.config.max_send_per_tx = Some(1000);
+        lc.config.max_send_per_day = Some(1500);
+        let zaddr = lc.do_address()["z_addresses"][0].as_str().unwrap().to_string();
+
+        let send = |lc: &LightClient, amount: u64, override_limits: bool| 
+            lc.do_send_with_raw_tx(vec![(&zaddr, amount, None, 0)], override_limits).unwrap_err();
+
+        assert!(send(&lc, 1001, false).starts_with(super::SPEND_LIMIT_EXCEEDED));
+        assert!(!send(&lc, 1000, false).starts_with(super::SPEND_LIMIT_EXCEEDED));
+
+        lc.wallet.read().unwrap().add_sent_today(1000);
+        assert!(send(&lc, 501, false).starts_with(super::SPEND_LIMIT_EXCEEDED));
+        assert!(!send(&lc, 500, false).starts_with(super::SPEND_LIMIT_EXCEEDED));
+
+        // With the override, the limits aren't checked, and the send fails for lack of funds instead
+        assert!(!send(&lc, 5000, true).starts_with(super::SPEND_LIMIT_EXCEEDED));
+
+        // None of the sends that failed count
+        assert_eq!(lc.wallet.read().unwrap().sent_today(), 1000);
+
+        // Of sends made at the same time, only as many as fit under the daily limit are counted
+        let wallet = lc.wallet.clone();
+        let counted = (0..8).map(|_| {
+                let wallet = wallet.clone();
+                std::thread::spawn(move || wallet.read().unwrap().try_add_sent_today(300, Some(1500)).is_ok())
+            })
+            .collect::<Vec<_>>().into_iter()
+            .map(|t| t.join().unwrap())
+            .filter(|counted| *counted)
+            .count();
+        assert_eq!(counted, 1);
+        assert_eq!(lc.wallet.read().unwrap().sent_today(), 1300);
+        assert_eq!(lc.wallet.read().unwrap().try_add_sent_today(300, Some(1500)), Err(1300));
+    }
+
+    #[test]
+    pub fn test_transactions_sorted() {
+        use super::TxSort;
+        use crate::lightwallet::tests::{fake_blocks_paying, first_extfvk};
+
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let own = first_extfvk(&lc.wallet.read().unwrap());
+        for block in fake_blocks_paying(0, 3, &[(0, own.clone(), 5_000), (1, own.clone(), 30_000), (2, own, 10_000)]) {
+            lc.wallet.read().unwrap().scan_block(&block).unwrap();
+        }
+
+        let list = |sort| lc.do_list_transactions_sorted(sort).members()
+                            .map(|tx| (tx["block_height"].as_i32().unwrap(), tx["amount"].as_i64().unwrap()))
+                            .collect::<Vec<_>>();
+        assert_eq!(list(TxSort::HeightAsc),  vec![(0, 5_000), (1, 30_000), (2, 10_000)]);
+        assert_eq!(list(TxSort::HeightDesc), vec![(2, 10_000), (1, 30_000), (0, 5_000)]);
+        assert_eq!(list(TxSort::AmountDesc), vec![(1, 30_000), (2, 10_000), (0, 5_000)]);
+        assert_eq!(list(TxSort::DateDesc),   vec![(2, 10_000), (1, 30_000), (0, 5_000)]);
+        assert_eq!(lc.do_list_transactions(), lc.do_list_transactions_sorted(TxSort::HeightAsc));
+
+        assert_eq!(TxSort::from_str("amount_desc"), Some(TxSort::AmountDesc));
+        assert_eq!(TxSort::from_str("amount"), None);
+    }
+
+    #[test]
+    pub fn test_tx_outputs() {
+        use zcash_primitives::transaction::TxId;
+
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let taddr = lc.do_address()["t_addresses"][0].as_str().unwrap().to_string();
+        let txid = TxId{0: [1; 32]};
+        assert!(lc.wallet.read().unwrap().add_utxo(taddr, txid, 0, vec![], 1000, 100));
+
+        assert!(lc.do_tx_outputs("not a txid").is_err());
+        assert!(lc.do_tx_outputs(&TxId{0: [2; 32]}.to_string()).unwrap_err().contains("not in the wallet"));
+
+        // Only received, so there's nothing the wallet built
+        assert!(lc.do_tx_outputs(&txid.to_string()).unwrap_err().contains("wasn't sent by this wallet"));
+    }
+
+    #[test]
+    pub fn test_tx_outputs_sent() {
+        use crate::lightwallet::tests::{fake_blocks_paying, fake_sent, first_extfvk};
+
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let zaddr = lc.do_address()["z_addresses"][0].as_str().unwrap().to_string();
+        let to = "ys1recipient";
+
+        // A transaction that spent 150_000, sent 40_000 and 9_000 out, and got 100_000 back as change
+        let own = first_extfvk(&lc.wallet.read().unwrap());
+        let txids = fake_blocks_paying(0, 1, &[(0, own, 100_000)]).iter()
+                        .flat_map(|block| lc.wallet.read().unwrap().scan_block(block).unwrap())
+                        .collect::<Vec<_>>();
+        fake_sent(&lc.wallet.read().unwrap(), &txids[0], 150_000, &[(to, 40_000, "thanks"), (to, 9_000, "")]);
+
+        let outputs = lc.do_tx_outputs(&txids[0].to_string()).unwrap();
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0], json::object!{"address" => to, "value" => 40_000, "memo" => "thanks", "change" => false});
+        assert_eq!(outputs[1]["value"], 9_000);
+        assert_eq!(outputs[1]["change"], false);
+        assert_eq!(outputs[2], json::object!{"address" => zaddr, "value" => 100_000, "memo" => json::Null, "change" => true});
+    }
+
+    #[test]
+    pub fn test_note_filter() {
+        let filter = super::NoteFilter { min_value: Some(100), max_value: Some(1000), min_confirmations: Some(3) };
+
+        assert!( filter.matches(100,  10, 12));
+        assert!( filter.matches(1000, 10, 20));
+        assert!(!filter.matches(99,   10, 20));
+        assert!(!filter.matches(1001, 10, 20));
+        assert!(!filter.matches(500,  10, 11));     // Only 2 confirmations
+
+        assert!(super::NoteFilter::default().matches(0, 10, 0));
+    }
+
+    #[test]
+    pub fn test_backup_roundtrip() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let addresses = lc.do_address();
+
+        let backup = lc.do_export_full_backup().unwrap();
+        let restored = LightClient::do_import_backup(&lc.config, &backup).unwrap();
+        assert_eq!(restored.do_address(), addresses);
+        assert_eq!(restored.do_seed_phrase().unwrap()["seed"], TEST_SEED.to_string());
+
+        // Wrong network
+        let mut config = lc.config.clone();
+        config.chain_name = "main".to_string();
+        assert!(LightClient::do_import_backup(&config, &backup).is_err());
+
+        // Not a backup
+        assert!(LightClient::do_import_backup(&lc.config, &lc.do_save_to_buffer().unwrap()).is_err());
+        assert!(LightClient::do_import_backup(&lc.config, &backup[..20]).is_err());
+
+        // A header that doesn't match the wallet: the birthday and checkpoint come after the magic, the
+        // version and the chain name
+        use byteorder::{ByteOrder, LittleEndian};
+        let at = 8 + 8 + 8 + lc.config.chain_name.len();
+        let header = |birthday: u64, checkpoint: u64| {
+            let mut changed = backup.clone();
+            LittleEndian::write_u64(&mut changed[at..at + 8], birthday);
+            LittleEndian::write_u64(&mut changed[at + 8..at + 16], checkpoint);
+            changed
+        };
+        let birthday = lc.wallet.read().unwrap().get_birthday();
+        assert!(LightClient::do_import_backup(&lc.config, &header(birthday, 0)).is_ok());
+        let e = LightClient::do_import_backup(&lc.config, &header(birthday, 350_000)).err().unwrap();
+        assert!(e.to_string().contains("doesn't match its birthday"));
+        let e = LightClient::do_import_backup(&lc.config, &header(350_001, 350_000)).err().unwrap();
+        assert!(e.to_string().contains("doesn't match the birthday of the wallet"));
+    }
+
+    #[test]
+    pub fn test_json_export() {
+        let lc = super::LightClient::unconnected(TEST_SEED.to_string(), None).unwrap();
+        let json = lc.do_export_json();
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+
+        let imported = LightClient::import_json(&json, &config).unwrap();
+        assert_eq!(imported.do_address(), lc.do_address());
+        assert_eq!(imported.do_list_transactions(), lc.do_list_transactions());
+        assert_eq!(imported.do_export_json(), json);
+        assert!(config.wallet_exists());
+
+        // It doesn't overwrite a wallet
+        assert!(LightClient::import_json(&json, &config).is_err());
+    }
+
+    #[test]
+    pub fn test_portable_backup() {
+        use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+        use zcash_client_backend::encoding::encode_extended_full_viewing_key;
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let lc = LightClient::new(&LightClientConfig::create_unconnected("test".to_string(), dir_name), 0).unwrap();
+        lc.do_new_account("savings").unwrap();
+        lc.do_new_address_for_account("z", "savings").unwrap();
+        let watched = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[2u8; 32]));
+        let key = encode_extended_full_viewing_key(lc.config.hrp_sapling_viewing_key(), &watched);
+        lc.wallet.read().unwrap().add_watch_only_key(&key).unwrap();
+
+        let backup = lc.do_export_backup("backup password").unwrap();
+
+        // None of the wallet is readable without the password
+        let seed = lc.do_seed_phrase().unwrap()["seed"].to_string();
+        let first_word = seed.split(' ').next().unwrap();
+        assert!(!backup.windows(first_word.len()).any(|w| w == first_word.as_bytes()));
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+
+        // Wrong password, or a changed header or contents
+        assert!(LightClient::restore_from_backup(&backup, "wrong password", &config).unwrap_err().to_string().contains("password is wrong"));
+        for i in &[9, 30, backup.len() - 1] {
+            let mut changed = backup.clone();
+            changed[*i] ^= 1;
+            assert!(LightClient::restore_from_backup(&changed, "backup password", &config).is_err());
+        }
+        assert!(LightClient::restore_from_backup(&backup[..40], "backup password", &config).is_err());
+        assert!(!config.wallet_exists());
+
+        let restored = LightClient::restore_from_backup(&backup, "backup password", &config).unwrap();
+        assert_eq!(restored.do_seed_phrase().unwrap(), lc.do_seed_phrase().unwrap());
+        assert_eq!(restored.do_get_address_for_account("savings").unwrap(), lc.do_get_address_for_account("savings").unwrap());
+
+        // Restoring from the seed derives a few more addresses in the default account
+        let default_zaddrs = |l: &LightClient| l.do_get_address_for_account("default").unwrap()["z_addresses"].clone();
+        assert_eq!(default_zaddrs(&restored)[0], default_zaddrs(&lc)[0]);
+        assert!(default_zaddrs(&restored).len() > 1);
+        assert_eq!(restored.wallet.read().unwrap().get_watch_only_keys(), vec![key]);
+        assert!(config.wallet_exists());
+
+        // It doesn't overwrite a wallet
+        assert!(LightClient::restore_from_backup(&backup, "backup password", &config).is_err());
+    }
+
+    #[test]
+    pub fn test_wallet_status_progress() {
+        let mut status = super::WalletStatus::new();
+        status.start_sync(100, 1100);
+        assert!(status.is_syncing);
+        assert_eq!(status.seconds_remaining, None);
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        status.block_scanned(101, 1);
+        status.block_scanned(102, 0);
+        status.block_scanned(600, 2);
+
+        assert_eq!(status.current_height, 600);
+        assert_eq!(status.txns_found_this_sync, 3);
+        assert!(status.seconds_remaining.is_some());
+
+        status.finish_sync(1100);
+        assert!(!status.is_syncing);
+        assert_eq!(status.current_height, 1100);
+        assert_eq!(status.seconds_remaining, None);
+    }
+
+    #[test]
+    pub fn test_create_without_port() {
+        // Nothing is listening, so this fails, but it mustn't panic over the missing port
+        let e = LightClientConfig::create("http://127.0.0.1".parse().unwrap(), false, None).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused);
+
+        let e = LightClientConfig::create("/no/host".parse().unwrap(), false, None).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    pub fn test_server_uri_defaults() {
+        let uri = |s: &str| LightClientConfig::get_server_or_default(Some(s.to_string())).to_string();
+
+        // No scheme means TLS
+        assert_eq!(uri("lightwalletd.ycash.xyz"), "https://lightwalletd.ycash.xyz:443/");
+        assert_eq!(uri("https://lightwalletd.ycash.xyz"), "https://lightwalletd.ycash.xyz:443/");
+
+        // Plaintext connections default to lightwalletd's port
+        assert_eq!(uri("http://127.0.0.1"), "http://127.0.0.1:9067/");
+
+        // Explicit ports are kept
+        assert_eq!(uri("http://127.0.0.1:1234"), "http://127.0.0.1:1234/");
+        assert_eq!(uri("127.0.0.1:9067"), "https://127.0.0.1:9067/");
+
+        // Servers mounted under a path behind a reverse proxy keep their path
+        assert_eq!(uri("https://example.com/lightwalletd/"), "https://example.com:443/lightwalletd/");
+        assert_eq!(uri("example.com:8443/lightwalletd"), "https://example.com:8443/lightwalletd");
+
+        assert_eq!(LightClientConfig::get_server_or_default(None).to_string(), format!("{}/", super::DEFAULT_SERVER));
+    }
+
     #[test]
     pub fn test_wallet_creation() {
         // Create a new tmp director
@@ -1334,6 +4436,242 @@ pub mod tests {
         }
     }
 
+    #[test]
+    pub fn test_save_to_path() {
+        use std::io::Write;
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        let lc = LightClient::new(&config, 0).unwrap();
+
+        // By default, it's the wallet file
+        let len = lc.do_save_to_path(None).unwrap();
+        let wallet_path = config.get_wallet_path();
+        assert_eq!(std::fs::metadata(&wallet_path).unwrap().len(), len);
+        assert!(!wallet_path.with_extension("tmp").exists());
+
+        let other = tmp.path().join("copy.dat");
+        assert_eq!(lc.do_save_to_path(Some(&other)).unwrap(), len);
+        assert_eq!(std::fs::read(&other).unwrap(), std::fs::read(&wallet_path).unwrap());
+
+        // Not while syncing
+        {
+            let _lock = lc.sync_lock.lock().unwrap();
+            assert!(lc.do_save_to_path(None).unwrap_err().starts_with("A sync is in progress"));
+        }
+
+        // Failing after part of the file was written leaves the old file as it was
+        let saved = std::fs::read(&wallet_path).unwrap();
+        let e = super::write_file_atomically(&wallet_path, &[], |w| {
+            w.write_all(b"half a wallet")?;
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "disk full"))
+        }).unwrap_err();
+        assert_eq!(e, "disk full");
+        assert_eq!(std::fs::read(&wallet_path).unwrap(), saved);
+        assert!(!wallet_path.with_extension("tmp").exists());
+
+        // And so does dying before the rename, which leaves just the temporary file behind
+        std::fs::write(wallet_path.with_extension("tmp"), b"half a wallet").unwrap();
+        let lc2 = LightClient::read_from_disk(&config).unwrap();
+        assert_eq!(lc2.do_seed_phrase().unwrap(), lc.do_seed_phrase().unwrap());
+
+        // Which the next save replaces
+        assert_eq!(lc.do_save_to_path(None).unwrap(), len);
+        assert!(!wallet_path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    pub fn test_wallet_backups() {
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        let lc = LightClient::new(&config, 0).unwrap();
+        let zaddrs = |lc: &LightClient| lc.do_address()["z_addresses"].len();
+
+        // Every save keeps the one before, up to wallet_backups of them
+        lc.do_save().unwrap();
+        let first_zaddrs = zaddrs(&lc);
+        for _ in 0..4 {
+            lc.do_new_address("z").unwrap();
+            lc.do_save().unwrap();
+        }
+        assert_eq!(zaddrs(&lc), first_zaddrs + 4);
+
+        let backups = lc.do_list_backups();
+        assert_eq!(backups.len(), config.wallet_backups);
+        assert!(!config.get_wallet_backup_path(config.wallet_backups + 1).exists());
+        assert_eq!(backups[0]["backup"], 1);
+        assert_eq!(backups[0]["size"].as_u64(), Some(std::fs::metadata(config.get_wallet_backup_path(1)).unwrap().len()));
+        assert!(backups[0]["modified"].as_u64().unwrap() > 0);
+
+        // Backup 2 is from two saves ago
+        let backup1 = std::fs::read(config.get_wallet_backup_path(1)).unwrap();
+        let r = lc.do_restore_backup(2).unwrap();
+        assert_eq!(r["restored"], 2);
+        assert_eq!(zaddrs(&lc), first_zaddrs + 2);
+
+        // The wallet from before the restore is backup 1 now, and the backups before it moved down one
+        assert_eq!(std::fs::read(config.get_wallet_backup_path(2)).unwrap(), backup1);
+        let lc2 = LightClient::read_from_disk(&config).unwrap();
+        assert_eq!(zaddrs(&lc2), first_zaddrs + 2);
+        lc2.do_restore_backup(1).unwrap();
+        assert_eq!(zaddrs(&lc2), first_zaddrs + 4);
+
+        // Backups that aren't there, or aren't wallets, leave the wallet as it is
+        {
+            let _lock = lc.sync_lock.lock().unwrap();
+            assert!(lc.do_restore_backup(1).unwrap_err().starts_with("A sync is in progress"));
+        }
+        assert!(lc.do_restore_backup(0).is_err());
+        assert!(lc.do_restore_backup(config.wallet_backups + 1).is_err());
+        std::fs::write(config.get_wallet_backup_path(3), b"not a wallet").unwrap();
+        assert!(lc.do_restore_backup(3).unwrap_err().contains("isn't a readable wallet"));
+        assert_eq!(zaddrs(&lc), first_zaddrs + 2);
+
+        // Saving elsewhere doesn't touch the backups
+        let backup1 = std::fs::read(config.get_wallet_backup_path(1)).unwrap();
+        lc.do_save_to_path(Some(&tmp.path().join("copy.dat"))).unwrap();
+        assert_eq!(std::fs::read(config.get_wallet_backup_path(1)).unwrap(), backup1);
+    }
+
+    #[test]
+    pub fn test_corrupt_wallet_opens_backup() {
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        let lc = LightClient::new(&config, 0).unwrap();
+
+        lc.do_save().unwrap();
+        lc.do_new_address("z").unwrap();
+        lc.do_save().unwrap();
+        let zaddrs = lc.do_address()["z_addresses"].len();
+
+        // A truncated wallet file opens the newest backup, skipping the ones that are corrupt too
+        let wallet = std::fs::read(config.get_wallet_path()).unwrap();
+        std::fs::write(config.get_wallet_path(), &wallet[..wallet.len() / 2]).unwrap();
+        let lc2 = LightClient::read_from_disk(&config).unwrap();
+        assert_eq!(lc2.do_address()["z_addresses"].len(), zaddrs - 1);
+
+        std::fs::write(config.get_wallet_backup_path(1), b"not a wallet").unwrap();
+        std::fs::write(config.get_wallet_backup_path(2), &wallet).unwrap();
+        let lc3 = LightClient::read_from_disk(&config).unwrap();
+        assert_eq!(lc3.do_address()["z_addresses"].len(), zaddrs);
+
+        // With no backup to go back to, it's the original error
+        for n in 1..=config.wallet_backups {
+            std::fs::remove_file(config.get_wallet_backup_path(n)).ok();
+        }
+        let e = LightClient::read_from_disk(&config).err().unwrap();
+        assert!(super::is_wallet_corrupt(&e));
+    }
+
+    #[test]
+    pub fn test_data_dirs() {
+        // Without a data_dir, each chain has its own dir, so a testnet wallet never replaces the mainnet one
+        let main = LightClientConfig::default_data_dir("main");
+        assert_eq!(LightClientConfig::default_data_dir("test"), main.join("testnet3"));
+        assert_eq!(LightClientConfig::default_data_dir("regtest"), main.join("regtest"));
+
+        // A data_dir that doesn't exist yet is created, with everything in it
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir = tmp.path().join("a").join("b");
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir.to_str().map(|s| s.to_string()));
+        assert_eq!(*config.get_data_dir(), *dir);
+        assert_eq!(config.get_wallet_path().parent(), Some(dir.as_path()));
+        assert_eq!(config.get_log_path().parent(), Some(dir.as_path()));
+        assert_eq!(*config.get_backup_dir().unwrap(), *dir.join("backups"));
+        assert_eq!(config.get_wallet_backup_path(1).parent(), Some(dir.join("backups").as_path()));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for d in vec![tmp.path().join("a"), dir.clone(), dir.join("backups")] {
+                assert_eq!(std::fs::metadata(&d).unwrap().permissions().mode() & 0o777, 0o700);
+            }
+        }
+
+        // Backups next to the wallet file, where they used to be kept, are moved to the backup dir
+        let lc = LightClient::new(&config, 0).unwrap();
+        lc.do_save().unwrap();
+        drop(lc);
+        let legacy = dir.join(format!("{}.2", super::WALLET_NAME));
+        std::fs::copy(config.get_wallet_path(), &legacy).unwrap();
+
+        let lc = LightClient::read_from_disk(&config).unwrap();
+        assert!(!legacy.exists());
+        assert!(config.get_wallet_backup_path(2).exists());
+        assert!(lc.do_list_backups().members().any(|b| b["backup"] == 2));
+
+        // Listing the backups doesn't create the backup dir, so it works where it can't be, like on a read-only
+        // file system. Saving works too, just without keeping a backup.
+        let tmp = TempDir::new("lctest").unwrap();
+        let config = LightClientConfig::create_unconnected("test".to_string(), tmp.path().to_str().map(|s| s.to_string()));
+        std::fs::write(tmp.path().join("backups"), b"").unwrap();
+        assert!(config.get_backup_dir().is_err());
+
+        let lc = LightClient::new(&config, 0).unwrap();
+        assert_eq!(lc.do_list_backups().len(), 0);
+        lc.do_save().unwrap();
+        lc.do_save().unwrap();
+        assert!(config.wallet_exists());
+        assert_eq!(lc.do_list_backups().len(), 0);
+    }
+
+    #[test]
+    pub fn test_compressed_wallet_file() {
+        use crate::lightwallet::COMPRESSED_WALLET_MAGIC;
+
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        let lc = LightClient::new_from_phrase(TEST_SEED.to_string(), &config, 0, false).unwrap();
+
+        // The file is compressed, the buffer isn't, and both read back
+        assert_eq!(std::fs::read(config.get_wallet_path()).unwrap()[..8], COMPRESSED_WALLET_MAGIC);
+        let buffer = lc.do_save_to_buffer().unwrap();
+        assert_eq!(buffer[..8], super::LightWallet::serialized_version().to_le_bytes());
+        assert_eq!(LightClient::read_from_buffer(&config, &buffer[..]).unwrap().do_address(), lc.do_address());
+        assert_eq!(LightClient::read_from_disk(&config).unwrap().do_address(), lc.do_address());
+        assert_eq!(LightClient::attempt_recover_seed(&config, None).unwrap(), *TEST_SEED);
+
+        // Either can be switched
+        let switched = LightClientConfig { compress_wallet_file: false, compress_wallet_buffer: true, ..config.clone() };
+        let lc = LightClient::read_from_disk(&switched).unwrap();
+        lc.do_save().unwrap();
+        assert_eq!(std::fs::read(config.get_wallet_path()).unwrap()[..8], super::LightWallet::serialized_version().to_le_bytes());
+        assert_eq!(lc.do_save_to_buffer().unwrap()[..8], COMPRESSED_WALLET_MAGIC);
+        assert_eq!(LightClient::read_from_disk(&config).unwrap().do_address(), lc.do_address());
+    }
+
+    #[test]
+    pub fn test_accounts() {
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+        let config = LightClientConfig::create_unconnected("test".to_string(), dir_name);
+        let lc = LightClient::new(&config, 0).unwrap();
+
+        let savings = lc.do_new_account("savings").unwrap();
+        assert_eq!(savings["account"], "savings");
+        assert_eq!(savings["z_addresses"].len(), 1);
+        assert_eq!(savings["t_addresses"].len(), 1);
+        assert!(lc.do_new_account("savings").is_err());
+
+        lc.do_new_address_for_account("z", "savings").unwrap();
+        assert!(lc.do_new_address_for_account("z", "spending").is_err());
+        assert_eq!(lc.do_get_address_for_account("savings").unwrap()["z_addresses"].len(), 2);
+        assert_eq!(lc.do_get_address_for_account("default").unwrap()["z_addresses"].len(), 1);
+        assert_eq!(lc.do_address()["z_addresses"].len(), 3);
+
+        let balance = lc.do_balance();
+        assert_eq!(balance["accounts"].len(), 2);
+        assert_eq!(balance["accounts"][1]["account"], "savings");
+        assert_eq!(balance["accounts"][1]["zbalance"], 0);
+
+        // The accounts' addresses aren't mistaken for the old derivation bug
+        assert!(!crate::lightwallet::bugs::BugBip39Derivation::has_bug(&lc));
+    }
+
     #[test]
     pub fn test_recover_seed() {
         // Create a new tmp director
@@ -0,0 +1,129 @@
+use json::JsonValue;
+
+use crate::grpc_client::LightdInfo;
+
+/// The oldest lightwalletd this wallet works with at all
+pub const MIN_SERVER_VERSION: (u64, u64, u64) = (0, 2, 0);
+
+/// Looking up the transactions of a t address, if the server also says it has taddr support
+pub const ADDRESS_TXIDS: &str = "address_txids";
+/// Fetching the utxos of t addresses
+pub const ADDRESS_UTXOS: &str = "address_utxos";
+
+/// The features not every server has, and the lightwalletd version that added them
+const FEATURES: [(&str, (u64, u64, u64)); 2] = [
+    (ADDRESS_TXIDS, (0, 2, 0)),
+    (ADDRESS_UTXOS, (0, 4, 0)),
+];
+
+/// Parse a lightwalletd version, like "v0.4.1" or "0.3.0-ycash", into (major, minor, patch). A missing
+/// patch counts as 0.
+pub fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let v = version.trim();
+    let v = v.trim_start_matches('v');
+
+    // Anything after the numbers, like "-rc1" or "+build", is ignored
+    let end = v.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or_else(|| v.len());
+    let mut parts = v[..end].split('.').map(|p| p.parse::<u64>());
+
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), None)            => Some((major, minor, 0)),
+        (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch))) => Some((major, minor, patch)),
+        _ => None
+    }
+}
+
+fn version_string(v: (u64, u64, u64)) -> String {
+    format!("{}.{}.{}", v.0, v.1, v.2)
+}
+
+/// Refuse servers older than MIN_SERVER_VERSION. A version that can't be parsed, eg. from a fork with
+/// its own numbering, gets the benefit of the doubt.
+pub fn check_server(info: &LightdInfo) -> Result<(), String> {
+    match parse_version(&info.version) {
+        Some(v) if v < MIN_SERVER_VERSION => Err(format!("Server too old: {} {} is older than {}, the oldest lightwalletd this wallet supports",
+                                                         info.vendor, info.version, version_string(MIN_SERVER_VERSION))),
+        _ => Ok(())
+    }
+}
+
+/// Whether the server supports `feature`, going by its version. Servers whose version can't be parsed
+/// are assumed to support everything, and to fail the calls they don't.
+pub fn supports(info: &LightdInfo, feature: &str) -> bool {
+    if feature == ADDRESS_TXIDS && !info.taddr_support {
+        return false;
+    }
+
+    match (parse_version(&info.version), FEATURES.iter().find(|(f, _)| *f == feature)) {
+        (Some(v), Some((_, min))) => v >= *min,
+        _                         => true
+    }
+}
+
+/// The error for a feature the server doesn't support
+pub fn unsupported_error(info: &LightdInfo, feature: &str) -> String {
+    match FEATURES.iter().find(|(f, _)| *f == feature) {
+        Some((_, min)) if parse_version(&info.version).map_or(false, |v| v < *min) =>
+            format!("Server too old: {} needs lightwalletd {} or newer, but the server is {} {}",
+                    feature, version_string(*min), info.vendor, info.version),
+        _ => format!("The server doesn't support {}", feature)
+    }
+}
+
+/// The features that aren't supported, to warn about when connecting to a server
+pub fn missing(info: &LightdInfo) -> Vec<&'static str> {
+    FEATURES.iter().map(|(f, _)| *f).filter(|f| !supports(info, f)).collect()
+}
+
+/// For `info`: each feature, and whether the server supports it
+pub fn to_json(info: &LightdInfo) -> JsonValue {
+    let mut o = JsonValue::new_object();
+    for (f, _) in FEATURES.iter() {
+        o[*f] = supports(info, f).into();
+    }
+    o
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn info(version: &str, taddr_support: bool) -> LightdInfo {
+        LightdInfo { version: version.to_string(), vendor: "test".to_string(), taddr_support, ..Default::default() }
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("v0.4.1"), Some((0, 4, 1)));
+        assert_eq!(parse_version("0.3.0-ycash"), Some((0, 3, 0)));
+        assert_eq!(parse_version("v1.2"), Some((1, 2, 0)));
+        assert_eq!(parse_version("v0.4.1+build.5"), Some((0, 4, 1)));
+        assert_eq!(parse_version(""), None);
+        assert_eq!(parse_version("v1"), None);
+        assert_eq!(parse_version("dev"), None);
+    }
+
+    #[test]
+    fn test_check_server() {
+        assert!(check_server(&info("v0.1.9", true)).unwrap_err().starts_with("Server too old"));
+        assert!(check_server(&info("v0.2.0", true)).is_ok());
+        assert!(check_server(&info("fork-build", true)).is_ok());
+    }
+
+    #[test]
+    fn test_supports() {
+        let old = info("v0.3.2", true);
+        assert!(supports(&old, ADDRESS_TXIDS));
+        assert!(!supports(&old, ADDRESS_UTXOS));
+        assert_eq!(missing(&old), vec![ADDRESS_UTXOS]);
+        assert!(unsupported_error(&old, ADDRESS_UTXOS).contains("0.4.0"));
+        assert_eq!(to_json(&old)[ADDRESS_UTXOS], false);
+
+        // taddr_support still counts
+        assert!(!supports(&info("v0.4.0", false), ADDRESS_TXIDS));
+        assert!(supports(&info("v0.4.0", true), ADDRESS_UTXOS));
+
+        // Unknown versions are given the benefit of the doubt
+        assert!(missing(&info("custom", true)).is_empty());
+    }
+}
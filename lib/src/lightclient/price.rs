@@ -0,0 +1,53 @@
+/// Zatoshis in 1 YEC
+const ZATS_PER_YEC: f64 = 100_000_000.0;
+
+/// The most that can ever exist, 21 million YEC
+const MAX_ZATS: f64 = 21_000_000.0 * ZATS_PER_YEC;
+
+/// Where `LightClient::do_send_fiat` gets the price of YEC from. The app embedding the wallet implements
+/// it, eg. with the API of an exchange it trusts, and passes it in: the wallet never asks anyone for prices
+/// on its own.
+pub trait PriceProvider {
+    /// What 1 YEC is worth in the fiat currency `fiat`, eg. "USD", right now
+    fn get_yec_price(&self, fiat: &str) -> Result<f64, String>;
+}
+
+/// The zatoshis that `amount` of a currency buys when 1 YEC is worth `price` in it, to the nearest zatoshi
+pub fn fiat_to_zats(amount: f64, price: f64) -> Result<u64, String> {
+    if !price.is_finite() || price <= 0.0 {
+        return Err(format!("Invalid price {}", price));
+    }
+    if !amount.is_finite() || amount <= 0.0 {
+        return Err(format!("Invalid amount {}", amount));
+    }
+
+    let zats = (amount / price * ZATS_PER_YEC).round();
+    if zats < 1.0 {
+        return Err(format!("{} is less than a zatoshi at a price of {}", amount, price));
+    }
+    if zats > MAX_ZATS {
+        return Err(format!("{} is more YEC than there are at a price of {}", amount, price));
+    }
+
+    Ok(zats as u64)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fiat_to_zats() {
+        assert_eq!(fiat_to_zats(5.0, 0.5), Ok(1_000_000_000));
+        assert_eq!(fiat_to_zats(1.0, 3.0), Ok(33_333_333));
+        assert_eq!(fiat_to_zats(0.01, 0.25), Ok(4_000_000));
+
+        assert!(fiat_to_zats(5.0, 0.0).is_err());
+        assert!(fiat_to_zats(5.0, -1.0).is_err());
+        assert!(fiat_to_zats(5.0, std::f64::NAN).is_err());
+        assert!(fiat_to_zats(0.0, 1.0).is_err());
+        assert!(fiat_to_zats(-5.0, 1.0).is_err());
+        assert!(fiat_to_zats(1e-12, 1.0).is_err());
+        assert!(fiat_to_zats(1e12, 1e-6).is_err());
+    }
+}
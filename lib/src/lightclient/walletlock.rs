@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::{info, warn};
+
+// How many times to try again when another process takes over a stale lock at the same time
+const MAX_TAKEOVER_ATTEMPTS: usize = 5;
+
+// How long a lock file that has no owner in it yet is left alone, since another process could be writing it
+const NEW_LOCK_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    // The lock files this process holds, and how many clients hold each. A process can open the same
+    // wallet more than once, eg. to read it back after saving it.
+    static ref HELD: Mutex<HashMap<PathBuf, usize>> = Mutex::new(HashMap::new());
+}
+
+/// An advisory lock on a wallet file, so that two processes don't both open it for writing. Otherwise both
+/// sync on their own and whichever saves last wins, losing the other's transactions. The lock is a file next
+/// to the wallet, eg. lite_wallet.dat.lock, with the pid of the process holding it and when it took the lock.
+/// It is removed when the last `WalletLock` of this process on the wallet is dropped.
+pub struct WalletLock {
+    path: PathBuf,
+}
+
+/// `path` with `suffix` added to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Who holds a lock: the pid, and the time the lock was taken, in seconds since the epoch
+fn read_owner(path: &Path) -> Option<(u32, u64)> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut lines = contents.lines();
+
+    let pid = lines.next()?.trim().parse().ok()?;
+    let since = lines.next().and_then(|l| l.trim().parse().ok()).unwrap_or(0);
+    Some((pid, since))
+}
+
+/// If the process is still running. When that can't be found out, it is assumed to be, so that a lock
+/// is never taken away from a live process.
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_running(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .arg("-0").arg(pid.to_string())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(true)
+}
+
+#[cfg(windows)]
+fn is_running(pid: u32) -> bool {
+    std::process::Command::new("tasklist")
+        .args(&["/FI", &format!("PID eq {}", pid), "/NH"])
+        .output()
+        .map(|o| String::from_utf8_lossy(&o.stdout).split_whitespace().any(|w| w == pid.to_string()))
+        .unwrap_or(true)
+}
+
+impl WalletLock {
+    /// The lock file of the wallet at `wallet_path`
+    pub fn lock_path(wallet_path: &Path) -> PathBuf {
+        with_suffix(wallet_path, ".lock")
+    }
+
+    /// Lock the wallet at `wallet_path`. Fails if another process that is still running holds the lock, unless
+    /// `force` is set, eg. to recover after a crash left a lock that looks held. A lock left by a process that
+    /// has died is taken over.
+    pub fn acquire(wallet_path: &Path, force: bool) -> io::Result<WalletLock> {
+        let path = WalletLock::lock_path(wallet_path);
+
+        let mut held = HELD.lock().unwrap();
+        if let Some(count) = held.get_mut(&path) {
+            *count += 1;
+            return Ok(WalletLock { path });
+        }
+
+        // Write the lock under a name of its own, and then link it into place, which fails if there already is
+        // one. That way the lock file never exists without its owner in it, except on file systems that can't
+        // link files, see `place`.
+        let pid = std::process::id();
+        let tmp = with_suffix(&path, &format!(".{}", pid));
+        {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let mut file = File::create(&tmp)?;
+            writeln!(file, "{}\n{}", pid, now)?;
+            file.sync_all()?;
+        }
+        let r = WalletLock::link(&tmp, &path, force, true);
+        if let Err(e) = fs::remove_file(&tmp) {
+            warn!("Couldn't remove {}: {}", tmp.display(), e);
+        }
+        r?;
+
+        held.insert(path.clone(), 1);
+        Ok(WalletLock { path })
+    }
+
+    /// Put the lock in `tmp` into place at `path`, failing with AlreadyExists if there already is a lock. It's
+    /// linked there, unless `hard_link` is false or the file system can't link files, eg. FAT or some network
+    /// shares. Then the lock file is created only if it doesn't exist yet (O_EXCL), and the owner is written
+    /// into it. Until it is, the lock looks unreadable, which `link` leaves alone for NEW_LOCK_GRACE_PERIOD.
+    fn place(tmp: &Path, path: &Path, hard_link: bool) -> io::Result<()> {
+        if hard_link {
+            match fs::hard_link(tmp, path) {
+                Err(e) if e.kind() != ErrorKind::AlreadyExists =>
+                    info!("Couldn't link the lock on the wallet into place, creating it instead: {}", e),
+                r => return r,
+            }
+        }
+
+        let owner = fs::read(tmp)?;
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(&owner)?;
+        file.sync_all()
+    }
+
+    /// If the lock at `path` was created within NEW_LOCK_GRACE_PERIOD
+    fn is_new(path: &Path) -> bool {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+            .and_then(|t| t.elapsed().ok())
+            .map_or(false, |age| age < NEW_LOCK_GRACE_PERIOD)
+    }
+
+    /// Put `tmp` into place as the lock at `path`, see `place`, taking over a lock that is stale, or held by a
+    /// running process if `force` is set
+    fn link(tmp: &Path, path: &Path, force: bool, hard_link: bool) -> io::Result<()> {
+        for _ in 0..MAX_TAKEOVER_ATTEMPTS {
+            match WalletLock::place(tmp, path, hard_link) {
+                Ok(()) => return Ok(()),
+                Err(e) => if e.kind() != ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+            }
+
+            let owner = read_owner(path);
+            match owner {
+                Some((pid, since)) if pid != std::process::id() && is_running(pid) => {
+                    if !force {
+                        return Err(Error::new(ErrorKind::WouldBlock,
+                            format!("The wallet is in use by process {} (since {}). If it isn't, remove {} or open it with force_unlock",
+                                pid, since, path.display())));
+                    }
+                    warn!("Taking over the lock on the wallet from process {}", pid);
+                },
+                Some((pid, _)) => info!("Removing the stale lock on the wallet of process {}", pid),
+                None if !force && WalletLock::is_new(path) => {
+                    return Err(Error::new(ErrorKind::WouldBlock,
+                        format!("Another process is locking the wallet. If none is, remove {} or open it with force_unlock",
+                            path.display())));
+                },
+                None           => info!("Removing an unreadable lock on the wallet"),
+            }
+
+            // Move the old lock out of the way. When another process takes it over at the same time, only one of
+            // the renames succeeds, and the other one starts over.
+            let old = with_suffix(path, &format!(".old.{}", std::process::id()));
+            match fs::rename(path, &old) {
+                Ok(()) => {},
+                Err(e) => if e.kind() == ErrorKind::NotFound {
+                    continue;
+                } else {
+                    return Err(e);
+                }
+            }
+
+            // Another process could have replaced the lock between reading and moving it, so put its lock back
+            if read_owner(&old) != owner {
+                let _ = WalletLock::place(&old, path, hard_link);
+            }
+            fs::remove_file(&old)?;
+        }
+
+        let pid = read_owner(path).map(|(pid, _)| pid.to_string()).unwrap_or_else(|| "?".to_string());
+        Err(Error::new(ErrorKind::WouldBlock, format!("The wallet is in use by process {}", pid)))
+    }
+}
+
+impl Drop for WalletLock {
+    fn drop(&mut self) {
+        let mut held = HELD.lock().unwrap();
+        let remaining = match held.get_mut(&self.path) {
+            Some(count) => { *count -= 1; *count },
+            None        => return,
+        };
+        if remaining > 0 {
+            return;
+        }
+        held.remove(&self.path);
+
+        // Unless another process was forced to take it over
+        if read_owner(&self.path).map(|(pid, _)| pid) == Some(std::process::id()) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Couldn't remove the lock on the wallet: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    pub fn test_lock_without_hard_links() {
+        let tmp = TempDir::new("locktest").unwrap();
+        let path = tmp.path().join("wallet.dat.lock");
+        let owner = tmp.path().join("wallet.dat.lock.owner");
+        std::fs::write(&owner, "4294967294\n7\n").unwrap();
+
+        // The lock is created with the owner in it, but only if there isn't one
+        WalletLock::place(&owner, &path, false).unwrap();
+        assert_eq!(read_owner(&path), Some((4294967294, 7)));
+        assert_eq!(WalletLock::place(&owner, &path, false).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        // A stale lock is taken over the same way
+        std::fs::write(&owner, format!("{}\n8\n", std::process::id())).unwrap();
+        WalletLock::link(&owner, &path, false, false).unwrap();
+        assert_eq!(read_owner(&path), Some((std::process::id(), 8)));
+
+        // A lock that is still being written is left alone, unless it's forced
+        std::fs::write(&path, "").unwrap();
+        assert_eq!(WalletLock::link(&owner, &path, false, false).unwrap_err().kind(), ErrorKind::WouldBlock);
+        WalletLock::link(&owner, &path, true, false).unwrap();
+        assert_eq!(read_owner(&path), Some((std::process::id(), 8)));
+
+        // Nothing is left behind
+        assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 2);
+    }
+}
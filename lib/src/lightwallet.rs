@@ -1,8 +1,9 @@
 use std::time::SystemTime;
 use std::io::{self, Read, Write};
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::io::{Error, ErrorKind};
 
 use rand::{Rng, rngs::OsRng};
@@ -11,16 +12,22 @@ use log::{info, warn, error};
 
 use protobuf::parse_from_bytes;
 
+use json::{object, JsonValue};
+
 use secp256k1::SecretKey;
 use bip39::{Mnemonic, Language};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use pairing::bls12_381::{Bls12};
+use ff::{Field, PrimeField, PrimeFieldRepr};
+use pairing::bls12_381::{Bls12, Fr, FrRepr};
 use sha2::{Sha256, Digest};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
 
 use zcash_client_backend::{
-    encoding::{encode_payment_address, encode_extended_spending_key},
-    proto::compact_formats::CompactBlock, welding_rig::scan_block,
+    constants::{mainnet, testnet, regtest},
+    encoding::{encode_payment_address, encode_extended_spending_key, encode_extended_full_viewing_key, decode_extended_full_viewing_key,
+               decode_extended_spending_key},
+    proto::compact_formats::CompactBlock,
 };
 
 use zcash_primitives::{
@@ -33,31 +40,62 @@ use zcash_primitives::{
         TxId, Transaction, 
     },
      legacy::{Script, TransparentAddress},
-    note_encryption::{Memo, try_sapling_note_decryption, try_sapling_output_recovery},
-    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey, ChildIndex},
+    note_encryption::{Memo, SaplingNoteEncryption, try_sapling_note_decryption, try_sapling_output_recovery},
+    zip32::{ExtendedFullViewingKey, ExtendedSpendingKey, ChildIndex, DiversifierIndex},
     JUBJUB,
-    primitives::{PaymentAddress},
+    jubjub::{edwards, fs::Fs},
+    primitives::{Note, PaymentAddress},
 };
 
 use crate::lightclient::{LightClientConfig};
 
 mod data;
 mod extended_key;
-mod utils;
+pub mod utils;
 mod address;
 mod prover;
 pub mod bugs;
-
-use data::{BlockData, WalletTx, Utxo, SaplingNoteData, SpendableNote, OutgoingTxMetadata};
+pub mod scanner;
+pub mod backup;
+pub mod kdf;
+pub mod secret;
+
+use scanner::{NoteScanner, CpuScanner};
+use data::{BlockData, WalletTx, Utxo, SaplingNoteData, SpendableNote, OutgoingTxMetadata, Accounts};
+pub use data::FiatRate;
+use kdf::Kdf;
+use secret::{Secret, Wipe};
 use extended_key::{KeyIndex, ExtendedPrivKey};
 
 pub const MAX_REORG: usize = 100;
 pub const GAP_RULE_UNUSED_ADDRESSES: usize = 5;
+pub const MAX_SPLIT_OUTPUTS: usize = 20;
+pub const MAX_SAPLING_OUTPUTS: usize = 50; // Per transaction, to keep the proving time and tx size reasonable
+pub const SEND_MAX: u64 = u64::MAX;     // As the last recipient's amount, send everything the other recipients and the fee leave
+pub const BLOCK_REORDER_WINDOW: usize = 100; // Blocks that can arrive ahead of a missing one before giving up on it
+pub const GRACE_ACTIONS: u64 = 2;       // As in ZIP-317, every transaction pays for at least this many logical actions
+pub const FEE_TOO_LOW: &str = "Fee too low";
+pub const DUST_CHANGE: &str = "Dust change";
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+// The z addresses of account n > 0 are derived at m/32'/coin'/(ACCOUNT_KEY_BASE + n)'/pos', out of the 
+// way of the default account's, which are at m/32'/coin'/pos'
+const ACCOUNT_KEY_BASE: u32 = 0x4000_0000;
+
+// Off-chain encrypted messages are MESSAGE_MAGIC, followed by the epk, cmu and encrypted ciphertext 
+// of a zero-value note that carries the message as its memo.
+const MESSAGE_MAGIC: &[u8; 8] = b"yecmsg1\0";
+const ENC_CIPHERTEXT_SIZE: usize = 580;
 
 fn now() -> f64 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as f64
 }
 
+/// The current day (UTC), as the number of days since the epoch
+fn today() -> u64 {
+    now() as u64 / (24 * 60 * 60)
+}
+
 /// Sha256(Sha256(value))
 pub fn double_sha256(payload: &[u8]) -> Vec<u8> {
     let h1 = Sha256::digest(&payload);
@@ -89,17 +127,76 @@ impl ToBase58Check for [u8] {
     }
 }
 
+/// Puts compact blocks that a server sent out of order back in order, because they have to be scanned
+/// in order. Blocks that arrive early are held back until the ones before them have arrived, but only
+/// up to `window` of them, so that a block that never comes doesn't make this buffer the whole sync.
+pub struct BlockReorderBuffer {
+    next_height: u64,
+    window:      usize,
+    pending:     BTreeMap<u64, Vec<u8>>,
+}
+
+impl BlockReorderBuffer {
+    pub fn new(next_height: u64, window: usize) -> Self {
+        BlockReorderBuffer { next_height, window, pending: BTreeMap::new() }
+    }
+
+    /// Add a block, and take out the blocks that can be scanned now, in order
+    pub fn push(&mut self, height: u64, block: Vec<u8>) -> Result<Vec<(u64, Vec<u8>)>, String> {
+        if height < self.next_height || self.pending.contains_key(&height) {
+            return Err(format!("Server sent block {} twice", height));
+        }
+        self.pending.insert(height, block);
+
+        let mut ready = vec![];
+        while let Some(block) = self.pending.remove(&self.next_height) {
+            ready.push((self.next_height, block));
+            self.next_height += 1;
+        }
+
+        if self.pending.len() > self.window {
+            return Err(format!("Server didn't send block {}, but sent {} blocks after it", self.next_height, self.pending.len()));
+        }
+
+        Ok(ready)
+    }
+
+    /// Check that no blocks up to `end_height` are still missing, once the server sent them all
+    pub fn finish(&self, end_height: u64) -> Result<(), String> {
+        match self.pending.keys().next() {
+            Some(first) => Err(format!("Server didn't send blocks {} to {}", self.next_height, first - 1)),
+            None if self.next_height <= end_height => 
+                           Err(format!("Server didn't send blocks {} to {}", self.next_height, end_height)),
+            None        => Ok(())
+        }
+    }
+}
+
+/// What a send would do, see `LightWallet::estimate_send`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SendEstimate {
+    pub total:    u64,          // Sent to the recipients
+    pub fee:      u64,
+    pub change:   u64,
+    pub notes:    usize,        // Notes spent
+    pub utxos:    usize,        // Utxos spent
+    pub warnings: Vec<String>,  // Ways the send weakens privacy
+}
+
 pub struct LightWallet {
     // Is the wallet encrypted? If it is, then when writing to disk, the seed is always encrypted 
     // and the individual spending keys are not written    
     encrypted: bool,       
 
-    // In memory only (i.e, this field is not written to disk). Is the wallet unlocked and are
-    // the spending keys present to allow spending from this wallet?
-    unlocked: bool,
+    // In memory only (i.e, these fields are not written to disk). Was the password given to use the
+    // wallet's viewing keys, to sync and show balances, and is the wallet unlocked with the spending 
+    // keys present to allow spending from this wallet? Unlocking for spending also unlocks for viewing.
+    unlocked_for_viewing: bool,
+    unlocked_for_spending: bool,
 
     enc_seed: [u8; 48], // If locked, this contains the encrypted seed
     nonce: Vec<u8>,     // Nonce used to encrypt the wallet. 
+    kdf: Kdf,           // How the key that encrypted the seed was derived from the password
 
     seed: [u8; 32],    // Seed phrase for this wallet. If wallet is locked, this is 0
 
@@ -109,6 +206,10 @@ pub struct LightWallet {
     extfvks: Arc<RwLock<Vec<ExtendedFullViewingKey>>>,
 
     pub zaddress: Arc<RwLock<Vec<PaymentAddress<Bls12>>>>,
+
+    // How many of the last extfvks (and zaddresses) are imported viewing keys. They are watched, but have
+    // no spending keys. The ones derived from the seed always come first.
+    watch_only: Arc<RwLock<usize>>,
     
     // Transparent keys. If the wallet is locked, then the secret keys will be encrypted,
     // but the addresses will be present. 
@@ -126,40 +227,179 @@ pub struct LightWallet {
     // will start from here.
     birthday: u64,
 
+    // The day (UTC, in days since the epoch) of the last send, and how much was sent on that day, 
+    // for the daily spend limit
+    daily_sent: Arc<RwLock<(u64, u64)>>,
+
+    // Every address the wallet has sent to, with when it was last sent to (seconds since the epoch), and
+    // how many sends went to it
+    sent_to: Arc<RwLock<HashMap<String, (u64, u64)>>>,
+
+    // The accounts, and which one each address derived from the seed is in
+    accounts: Arc<RwLock<Accounts>>,
+
+    // The exchange rate of each send whose amount was given in a fiat currency
+    fiat_rates: Arc<RwLock<HashMap<TxId, FiatRate>>>,
+
     // Non-serialized fields
     config: LightClientConfig,
+
+    // Changes whenever the notes, utxos, blocks or addresses change, so that what is computed from them 
+    // can be cached until then
+    state: AtomicU64,
+}
+
+impl Drop for LightWallet {
+    fn drop(&mut self) {
+        self.seed.wipe();
+        if let Ok(mut tkeys) = self.tkeys.write() {
+            tkeys.wipe();
+        }
+        if let Ok(mut extsks) = self.extsks.write() {
+            extsks.wipe();
+        }
+    }
+}
+
+/// Every wallet state gets a different number, even across wallets, so that a state of a wallet that was
+/// replaced (eg. by a restore) can't be mistaken for the current one
+static NEXT_WALLET_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn next_wallet_state() -> u64 {
+    NEXT_WALLET_STATE.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Size of the Sha256 checksum at the end of the wallet file, since wallet version 8
+const CHECKSUM_SIZE: usize = 32;
+
+/// How many transactions `estimated_size` writes out to find their average size, and what it adds for the rest
+/// of the wallet
+const SIZE_ESTIMATE_TX_SAMPLES: usize = 16;
+const SIZE_ESTIMATE_MARGIN: usize = 64 * 1024;
+
+/// What a compressed wallet file starts with, instead of the version: see `write_compressed`. Read as a 
+/// version, it's far higher than any, so older versions of yecshell refuse it instead of misreading it.
+pub const COMPRESSED_WALLET_MAGIC: [u8; 8] = *b"YECWLTZ1";
+
+/// The wallet file doesn't match its checksum, because it was cut short or damaged on disk. Comes
+/// wrapped in an io::Error, see `is_wallet_corrupt`.
+#[derive(Debug)]
+pub struct WalletCorrupt(pub String);
+
+impl std::fmt::Display for WalletCorrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Wallet file is corrupt: {}", self.0)
+    }
+}
+
+impl std::error::Error for WalletCorrupt {}
+
+/// Whether reading a wallet failed because the file is corrupt, rather than eg. being unreadable or 
+/// for another chain
+pub fn is_wallet_corrupt(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |inner| inner.is::<WalletCorrupt>())
+}
+
+fn wallet_corrupt(reason: &str) -> io::Error {
+    Error::new(ErrorKind::InvalidData, WalletCorrupt(reason.to_string()))
+}
+
+/// Passes writes through, hashing everything written, so the checksum can be written after it
+struct ChecksumWriter<W: Write> {
+    inner:  W,
+    hasher: Sha256,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        ChecksumWriter { inner, hasher: Sha256::new() }
+    }
+
+    /// Write the checksum of everything written so far
+    fn finish(mut self) -> io::Result<()> {
+        self.inner.write_all(&self.hasher.result())?;
+        self.inner.flush()
+    }
+}
+
+/// Counts what is written to it, and throws it away
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.input(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
+/// Upgrades for wallets read from a file older than the version that added something to it, as (that
+/// version, its upgrade), in order. What the version added starts out empty when reading an older file,
+/// and the upgrade fills it in from the rest of the wallet, where it can.
+const MIGRATIONS: &[(u64, fn(&LightWallet))] = &[
+    (5, LightWallet::migrate_daily_sent),
+    (7, LightWallet::migrate_sent_to),
+];
+
 impl LightWallet {
+    /// The version of the wallet file this writes. The versions added:
+    ///  4: encryption, and the viewing keys and t addresses, which were derived from the keys before
+    ///  5: how much was sent today
+    ///  6: imported viewing keys
+    ///  7: the addresses sent to
+    ///  8: the checksum at the end
+    ///  9: accounts
+    /// 10: the key derivation of the encrypted seed, right after the nonce
+    /// 11: the exchange rates of sends in a fiat currency
+    /// Something new goes at the end, read only from files of the version that added it, and if it can
+    /// be worked out for older files, with an upgrade in MIGRATIONS. The key derivation is the exception,
+    /// it is next to the encrypted seed so that `attempt_recover_seed` can still decrypt a damaged file.
     pub fn serialized_version() -> u64 {
-        return 4;
+        return 11;
     }
 
-    fn get_taddr_from_bip39seed(config: &LightClientConfig, bip39_seed: &[u8], pos: u32) -> SecretKey {
+    fn get_taddr_from_bip39seed(config: &LightClientConfig, bip39_seed: &[u8], account: u32, pos: u32) -> SecretKey {
         assert_eq!(bip39_seed.len(), 64);
 
         let ext_t_key = ExtendedPrivKey::with_seed(bip39_seed).unwrap();
         ext_t_key
             .derive_private_key(KeyIndex::hardened_from_normalize_index(44).unwrap()).unwrap()
             .derive_private_key(KeyIndex::hardened_from_normalize_index(config.get_coin_type()).unwrap()).unwrap()
-            .derive_private_key(KeyIndex::hardened_from_normalize_index(0).unwrap()).unwrap()
+            .derive_private_key(KeyIndex::hardened_from_normalize_index(account).unwrap()).unwrap()
             .derive_private_key(KeyIndex::Normal(0)).unwrap()
             .derive_private_key(KeyIndex::Normal(pos)).unwrap()
             .private_key
     }
 
 
-    fn get_zaddr_from_bip39seed(config: &LightClientConfig, bip39_seed: &[u8], pos: u32) ->
+    fn get_zaddr_from_bip39seed(config: &LightClientConfig, bip39_seed: &[u8], account: u32, pos: u32) ->
             (ExtendedSpendingKey, ExtendedFullViewingKey, PaymentAddress<Bls12>) {
         assert_eq!(bip39_seed.len(), 64);
         
+        let mut path = vec![ChildIndex::Hardened(32), ChildIndex::Hardened(config.get_coin_type())];
+        if account > 0 {
+            path.push(ChildIndex::Hardened(ACCOUNT_KEY_BASE + account));
+        }
+        path.push(ChildIndex::Hardened(pos));
+
         let extsk: ExtendedSpendingKey = ExtendedSpendingKey::from_path(
             &ExtendedSpendingKey::master(bip39_seed),
-            &[
-                ChildIndex::Hardened(32),
-                ChildIndex::Hardened(config.get_coin_type()),
-                ChildIndex::Hardened(pos)
-            ],
+            &path,
         );
         let extfvk  = ExtendedFullViewingKey::from(&extsk);
         let address = extfvk.default_address().unwrap().1;
@@ -167,6 +407,56 @@ impl LightWallet {
         (extsk, extfvk, address)
     }
 
+    pub fn is_valid_address(addr: &String, config: &LightClientConfig) -> bool {
+        address::RecipientAddress::from_str(addr,
+                config.hrp_sapling_address(), 
+                config.base58_pubkey_address(), 
+                config.base58_script_address()).is_some()
+    }
+
+    /// The chains that `addr` is a valid address on, to tell an address for another chain from a mistyped one.
+    /// The test chains share their transparent addresses, so a t address can be on more than one.
+    pub fn address_chains(addr: &str) -> Vec<&'static str> {
+        let chains = [("main",    mainnet::HRP_SAPLING_PAYMENT_ADDRESS, mainnet::B58_PUBKEY_ADDRESS_PREFIX, mainnet::B58_SCRIPT_ADDRESS_PREFIX),
+                      ("test",    testnet::HRP_SAPLING_PAYMENT_ADDRESS, testnet::B58_PUBKEY_ADDRESS_PREFIX, testnet::B58_SCRIPT_ADDRESS_PREFIX),
+                      ("regtest", regtest::HRP_SAPLING_PAYMENT_ADDRESS, regtest::B58_PUBKEY_ADDRESS_PREFIX, regtest::B58_SCRIPT_ADDRESS_PREFIX)];
+
+        chains.iter()
+            .filter(|(_, hrp, pubkey, script)| address::RecipientAddress::from_str(addr, hrp, *pubkey, *script).is_some())
+            .map(|(chain, _, _, _)| *chain)
+            .collect()
+    }
+
+    /// The address to send to for a recipient given as either an address or an extended full viewing key,
+    /// in which case it's the key's default address. A spending key is rejected without being echoed back,
+    /// so it doesn't end up in an error message or a log.
+    pub fn resolve_recipient(addr: &str, config: &LightClientConfig) -> Result<String, String> {
+        if LightWallet::is_valid_address(&addr.to_string(), config) {
+            return Ok(addr.to_string());
+        }
+
+        if let Ok(Some(extfvk)) = decode_extended_full_viewing_key(config.hrp_sapling_viewing_key(), addr) {
+            let address = extfvk.default_address().unwrap().1;
+            return Ok(encode_payment_address(config.hrp_sapling_address(), &address));
+        }
+
+        let viewing_keys = [mainnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+                            testnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY,
+                            regtest::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY];
+        if viewing_keys.iter().any(|hrp| match decode_extended_full_viewing_key(hrp, addr) { Ok(Some(_)) => true, _ => false }) {
+            return Err(format!("The viewing key '{}' is not for {}", addr, config.chain_name));
+        }
+
+        let spending_keys = [mainnet::HRP_SAPLING_EXTENDED_SPENDING_KEY,
+                             testnet::HRP_SAPLING_EXTENDED_SPENDING_KEY,
+                             regtest::HRP_SAPLING_EXTENDED_SPENDING_KEY];
+        if spending_keys.iter().any(|hrp| match decode_extended_spending_key(hrp, addr) { Ok(Some(_)) => true, _ => false }) {
+            return Err("Invalid recipient address".to_string());
+        }
+
+        Err(format!("Invalid recipient address: '{}'", addr))
+    }
+
     pub fn is_shielded_address(addr: &String, config: &LightClientConfig) -> bool {
         match address::RecipientAddress::from_str(addr,
                 config.hrp_sapling_address(), 
@@ -178,6 +468,8 @@ impl LightWallet {
     }
 
     pub fn new(seed_phrase: Option<String>, config: &LightClientConfig, latest_block: u64) -> io::Result<Self> {
+        let seed_phrase = seed_phrase.map(|p| utils::normalize_seed_phrase(&Secret::new(p)));
+
         // This is the source entropy that corresponds to the 24-word seed phrase
         let mut seed_bytes = [0u8; 32];
 
@@ -186,8 +478,8 @@ impl LightWallet {
             let mut system_rng = OsRng;
             system_rng.fill(&mut seed_bytes);
         } else {
-            let phrase = match Mnemonic::from_phrase(seed_phrase.clone().unwrap(), Language::English) {
-                Ok(p) => p,
+            let phrase = match Mnemonic::from_phrase(seed_phrase.as_ref().unwrap().as_str(), Language::English) {
+                Ok(p) => Secret::new(p),
                 Err(e) => {
                     let e = format!("Error parsing phrase: {}", e);
                     error!("{}", e);
@@ -200,26 +492,29 @@ impl LightWallet {
 
         // The seed bytes is the raw entropy. To pass it to HD wallet generation, 
         // we need to get the 64 byte bip39 entropy
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&seed_bytes, Language::English).unwrap(), "");
+        let bip39_seed = LightWallet::bip39_seed(&seed_bytes);
 
         // Derive only the first sk and address
-        let tpk = LightWallet::get_taddr_from_bip39seed(&config, &bip39_seed.as_bytes(), 0);
+        let tpk = LightWallet::get_taddr_from_bip39seed(&config, &bip39_seed.as_bytes(), 0, 0);
         let taddr = LightWallet::address_from_prefix_sk(&config.base58_pubkey_address(), &tpk);
 
         // TODO: We need to monitor addresses, and always keep 1 "free" address, so 
         // users can import a seed phrase and automatically get all used addresses
         let (extsk, extfvk, address)
-            = LightWallet::get_zaddr_from_bip39seed(&config, &bip39_seed.as_bytes(), 0);
+            = LightWallet::get_zaddr_from_bip39seed(&config, &bip39_seed.as_bytes(), 0, 0);
 
         let lw = LightWallet {
             encrypted:   false,
-            unlocked:    true,
+            unlocked_for_viewing:  true,
+            unlocked_for_spending: true,
             enc_seed:    [0u8; 48],
             nonce:       vec![],
+            kdf:         Kdf::DoubleSha256,
             seed:        seed_bytes,
             extsks:      Arc::new(RwLock::new(vec![extsk])),
             extfvks:     Arc::new(RwLock::new(vec![extfvk])),
             zaddress:    Arc::new(RwLock::new(vec![address])),
+            watch_only:  Arc::new(RwLock::new(0)),
             tkeys:       Arc::new(RwLock::new(vec![tpk])),
             taddresses:  Arc::new(RwLock::new(vec![taddr])),
             blocks:      Arc::new(RwLock::new(vec![])),
@@ -227,8 +522,16 @@ impl LightWallet {
             mempool_txs: Arc::new(RwLock::new(HashMap::new())),
             config:      config.clone(),
             birthday:    latest_block,
+            daily_sent:  Arc::new(RwLock::new((0, 0))),
+            sent_to:     Arc::new(RwLock::new(HashMap::new())),
+            accounts:    Arc::new(RwLock::new(Accounts::new(1, 1))),
+            fiat_rates:  Arc::new(RwLock::new(HashMap::new())),
+            state:       AtomicU64::new(next_wallet_state()),
         };
 
+        // The wallet has its own copy
+        seed_bytes.wipe();
+
         // If restoring from seed, make sure we are creating 5 addresses for users
         if seed_phrase.is_some() {
             for _i in 0..5 {
@@ -240,10 +543,52 @@ impl LightWallet {
         Ok(lw)
     }
 
+    /// The 64 byte bip39 seed that the HD keys are derived from, for the raw entropy of the seed phrase
+    fn bip39_seed(seed: &[u8]) -> Secret<bip39::Seed> {
+        let mnemonic = Secret::new(Mnemonic::from_entropy(seed, Language::English).unwrap());
+        Secret::new(bip39::Seed::new(&mnemonic, ""))
+    }
+
+    /// Read a wallet written by `write` or `write_compressed`, of this or an earlier version
     pub fn read<R: Read>(mut reader: R, config: &LightClientConfig) -> io::Result<Self> {
         let version = reader.read_u64::<LittleEndian>()?;
+        if version.to_le_bytes() == COMPRESSED_WALLET_MAGIC {
+            return LightWallet::read_compressed(reader, config);
+        }
+
+        LightWallet::read_version(version, reader, config)
+    }
+
+    fn read_compressed<R: Read>(mut reader: R, config: &LightClientConfig) -> io::Result<Self> {
+        // The compressed file is small, so it's checked against its checksum before it's decompressed
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest)?;
+        if rest.len() < CHECKSUM_SIZE {
+            return Err(wallet_corrupt("it's too short to have a checksum"));
+        }
+
+        let (compressed, checksum) = rest.split_at(rest.len() - CHECKSUM_SIZE);
+        let mut hasher = Sha256::new();
+        hasher.input(&COMPRESSED_WALLET_MAGIC);
+        hasher.input(compressed);
+        if hasher.result()[..] != checksum[..] {
+            return Err(wallet_corrupt("the checksum doesn't match"));
+        }
+
+        let mut decoder = GzDecoder::new(compressed);
+        let version = decoder.read_u64::<LittleEndian>()
+            .map_err(|e| wallet_corrupt(&format!("it can't be decompressed: {}", e)))?;
+        if version.to_le_bytes() == COMPRESSED_WALLET_MAGIC {
+            return Err(wallet_corrupt("it's compressed twice"));
+        }
+
+        LightWallet::read_version(version, decoder, config)
+    }
+
+    fn read_version<R: Read>(version: u64, reader: R, config: &LightClientConfig) -> io::Result<Self> {
         if version > LightWallet::serialized_version() {
-            let e = format!("Don't know how to read wallet version {}. Do you have the latest version?", version);
+            let e = format!("The wallet was created by a newer version of yecshell. It's wallet version {}, and this \
+                             version of yecshell reads up to {}. Please upgrade to open it.", version, LightWallet::serialized_version());
             error!("{}", e);
             return Err(io::Error::new(ErrorKind::InvalidData, e));
         }
@@ -251,6 +596,71 @@ impl LightWallet {
         println!("Reading wallet version {}", version);
         info!("Reading wallet version {}", version);
 
+        let wallet = if version < 8 {
+            LightWallet::read_contents(version, reader, config)?
+        } else {
+            LightWallet::read_checksummed(version, reader, config)?
+        };
+
+        for (added_in, migrate) in MIGRATIONS.iter().filter(|(added_in, _)| version < *added_in) {
+            info!("Upgrading the wallet from version {} to {}", version, added_in);
+            migrate(&wallet);
+        }
+
+        Ok(wallet)
+    }
+
+    fn read_checksummed<R: Read>(version: u64, mut reader: R, config: &LightClientConfig) -> io::Result<Self> {
+
+        // Check the whole file against its checksum first, so a damaged file doesn't fail halfway 
+        // through with some unrelated error, or worse, read as a different wallet
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest)?;
+        if rest.len() < CHECKSUM_SIZE {
+            return Err(wallet_corrupt("it's too short to have a checksum"));
+        }
+
+        let (contents, checksum) = rest.split_at(rest.len() - CHECKSUM_SIZE);
+        let mut hasher = Sha256::new();
+        hasher.input(&version.to_le_bytes());
+        hasher.input(contents);
+        if hasher.result()[..] != checksum[..] {
+            return Err(wallet_corrupt("the checksum doesn't match"));
+        }
+
+        LightWallet::read_contents(version, contents, config)
+    }
+
+    /// Check that the parts of a wallet that was just read fit together, so a damaged file that still 
+    /// parses is an error instead of a wallet that panics later on
+    fn check_read_invariants(encrypted: bool, extsks: &[ExtendedSpendingKey], spendable_extfvks: usize,
+                             tkeys: &[SecretKey], taddresses: &[String], blocks: &[BlockData],
+                             txs: &HashMap<TxId, WalletTx>) -> io::Result<()> {
+        let invalid = |e: String| Err(Error::new(ErrorKind::InvalidData, e));
+
+        // Locked wallets don't have the spending keys in the clear
+        if !encrypted {
+            if extsks.len() != spendable_extfvks {
+                return invalid(format!("{} spending keys, but {} viewing keys for them", extsks.len(), spendable_extfvks));
+            }
+            if tkeys.len() != taddresses.len() {
+                return invalid(format!("{} t address keys, but {} t addresses", tkeys.len(), taddresses.len()));
+            }
+        }
+
+        if let Some(w) = blocks.windows(2).find(|w| w[1].height <= w[0].height) {
+            return invalid(format!("Block {} comes after block {}", w[1].height, w[0].height));
+        }
+
+        if let Some((txid, wtx)) = txs.iter().find(|(txid, wtx)| **txid != wtx.txid) {
+            return invalid(format!("Transaction {} is stored as {}", wtx.txid, txid));
+        }
+
+        Ok(())
+    }
+
+    /// Read everything after the version, which has been checked against the checksum if there is one
+    fn read_contents<R: Read>(version: u64, mut reader: R, config: &LightClientConfig) -> io::Result<Self> {
         let encrypted = if version >= 4 {
             reader.read_u8()? > 0
         } else {
@@ -269,6 +679,13 @@ impl LightWallet {
             vec![]
         };
 
+        // Before there was a choice, the key was the double SHA-256 of the password
+        let kdf = if version >= 10 {
+            Kdf::read(&mut reader)?
+        } else {
+            Kdf::DoubleSha256
+        };
+
         // Seed
         let mut seed_bytes = [0u8; 32];
         reader.read_exact(&mut seed_bytes)?;
@@ -285,8 +702,10 @@ impl LightWallet {
         };
         
         // Calculate the addresses
-        let addresses = extfvks.iter().map( |fvk| fvk.default_address().unwrap().1 )
-            .collect::<Vec<PaymentAddress<Bls12>>>();
+        let addresses = extfvks.iter()
+            .map(|fvk| fvk.default_address().map(|(_, address)| address)
+                          .map_err(|_| Error::new(ErrorKind::InvalidData, "Viewing key has no valid address")))
+            .collect::<io::Result<Vec<PaymentAddress<Bls12>>>>()?;
 
         let tkeys = Vector::read(&mut reader, |r| {
             let mut tpk_bytes = [0u8; 32];
@@ -308,7 +727,7 @@ impl LightWallet {
             let mut txid_bytes = [0u8; 32];
             r.read_exact(&mut txid_bytes)?;
 
-            Ok((TxId{0: txid_bytes}, WalletTx::read(r).unwrap()))
+            Ok((TxId{0: txid_bytes}, WalletTx::read(r)?))
         })?;
         let txs = txs_tuples.into_iter().collect::<HashMap<TxId, WalletTx>>();
 
@@ -321,15 +740,68 @@ impl LightWallet {
 
         let birthday = reader.read_u64::<LittleEndian>()?;
 
+        let daily_sent = if version >= 5 {
+            (reader.read_u64::<LittleEndian>()?, reader.read_u64::<LittleEndian>()?)
+        } else {
+            (0, 0)
+        };
+
+        let watch_only = if version >= 6 {
+            reader.read_u64::<LittleEndian>()? as usize
+        } else {
+            0
+        };
+        if watch_only > extfvks.len() {
+            return Err(Error::new(ErrorKind::InvalidData, 
+                                  format!("{} watch-only keys, but only {} viewing keys", watch_only, extfvks.len())));
+        }
+
+        LightWallet::check_read_invariants(encrypted, &extsks, extfvks.len() - watch_only, &tkeys, &taddresses, &blocks, &txs)?;
+
+        let sent_to = if version >= 7 {
+            Vector::read(&mut reader, |r| {
+                let address = utils::read_string(&mut *r)?;
+                let last_sent = r.read_u64::<LittleEndian>()?;
+                let count = r.read_u64::<LittleEndian>()?;
+                Ok((address, (last_sent, count)))
+            })?.into_iter().collect::<HashMap<String, (u64, u64)>>()
+        } else {
+            HashMap::new()
+        };
+
+        // Before accounts, every address was in the default account
+        let accounts = if version >= 9 {
+            Accounts::read(&mut reader)?
+        } else {
+            Accounts::new(extfvks.len() - watch_only, taddresses.len())
+        };
+        if accounts.zaddrs.len() != extfvks.len() - watch_only || accounts.taddrs.len() != taddresses.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "The accounts don't match the addresses"));
+        }
+
+        let fiat_rates = if version >= 11 {
+            Vector::read(&mut reader, |r| {
+                let mut txid_bytes = [0u8; 32];
+                r.read_exact(&mut txid_bytes)?;
+                Ok((TxId{0: txid_bytes}, FiatRate::read(r)?))
+            })?.into_iter().collect::<HashMap<TxId, FiatRate>>()
+        } else {
+            HashMap::new()
+        };
+
         Ok(LightWallet{
             encrypted:   encrypted,
-            unlocked:    !encrypted, // When reading from disk, if wallet is encrypted, it starts off locked. 
+            // When reading from disk, if wallet is encrypted, it starts off locked. 
+            unlocked_for_viewing:  !encrypted,
+            unlocked_for_spending: !encrypted,
             enc_seed:    enc_seed,
             nonce:       nonce,
+            kdf:         kdf,
             seed:        seed_bytes,
             extsks:      Arc::new(RwLock::new(extsks)),
             extfvks:     Arc::new(RwLock::new(extfvks)),
             zaddress:    Arc::new(RwLock::new(addresses)),
+            watch_only:  Arc::new(RwLock::new(watch_only)),
             tkeys:       Arc::new(RwLock::new(tkeys)),
             taddresses:  Arc::new(RwLock::new(taddresses)),
             blocks:      Arc::new(RwLock::new(blocks)),
@@ -337,15 +809,34 @@ impl LightWallet {
             mempool_txs: Arc::new(RwLock::new(HashMap::new())),
             config:      config.clone(),
             birthday,
+            daily_sent:  Arc::new(RwLock::new(daily_sent)),
+            sent_to:     Arc::new(RwLock::new(sent_to)),
+            accounts:    Arc::new(RwLock::new(accounts)),
+            fiat_rates:  Arc::new(RwLock::new(fiat_rates)),
+            state:       AtomicU64::new(next_wallet_state()),
         })
     }
 
-    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
-        if self.encrypted && self.unlocked {
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        if self.encrypted && self.unlocked_for_spending {
             return Err(Error::new(ErrorKind::InvalidInput, 
                         format!("Cannot write while wallet is unlocked while encrypted.")));
         }
 
+        self.write_with_keys(writer, true)
+    }
+
+    /// Write the wallet the way `write` would once it's locked, without locking it. An encrypted wallet is written
+    /// without the seed and the spending keys it holds while unlocked for spending, so it stays unlocked.
+    pub fn write_locked<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.write_with_keys(writer, !self.encrypted)
+    }
+
+    /// Write the wallet, with the seed and the spending keys only if `spending_keys` is set. Otherwise they're
+    /// written the way a locked wallet has them: a zeroed seed and no keys.
+    fn write_with_keys<W: Write>(&self, writer: W, spending_keys: bool) -> io::Result<()> {
+        let mut writer = ChecksumWriter::new(writer);
+
         // Write the version
         writer.write_u64::<LittleEndian>(LightWallet::serialized_version())?;
 
@@ -358,14 +849,18 @@ impl LightWallet {
         // Write the nonce
         Vector::write(&mut writer, &self.nonce, |w, b| w.write_u8(*b))?;
 
+        // Write how the key was derived
+        self.kdf.write(&mut writer)?;
+
         // Write the seed
-        writer.write_all(&self.seed)?;
+        writer.write_all(if spending_keys { &self.seed } else { &[0u8; 32] })?;
 
         // Flush after writing the seed, so in case of a disaster, we can still recover the seed.
         writer.flush()?;
 
         // Write all the spending keys
-        Vector::write(&mut writer, &self.extsks.read().unwrap(),
+        let extsks = self.extsks.read().unwrap();
+        Vector::write(&mut writer, if spending_keys { &extsks[..] } else { &[] },
              |w, sk| sk.write(w)
         )?;
 
@@ -375,7 +870,8 @@ impl LightWallet {
         )?;
 
         // Write the transparent private keys
-        Vector::write(&mut writer, &self.tkeys.read().unwrap(),
+        let tkeys = self.tkeys.read().unwrap();
+        Vector::write(&mut writer, if spending_keys { &tkeys[..] } else { &[] },
             |w, pk| w.write_all(&pk[..])
         )?;
 
@@ -386,124 +882,670 @@ impl LightWallet {
 
         Vector::write(&mut writer, &self.blocks.read().unwrap(), |w, b| b.write(w))?;
                 
-        // The hashmap, write as a set of tuples
-        Vector::write(&mut writer, &self.txs.read().unwrap().iter().collect::<Vec<(&TxId, &WalletTx)>>(),
-                        |w, (k, v)| {
-                            w.write_all(&k.0)?;
-                            v.write(w)
-                        })?;
+        // The hashmap, write as a set of tuples. In order of txid, so that the same wallet is always written
+        // the same way, whatever order the map happens to be in.
+        {
+            let txs = self.txs.read().unwrap();
+            let mut sorted_txs = txs.iter().collect::<Vec<(&TxId, &WalletTx)>>();
+            sorted_txs.sort_by(|a, b| (a.0).0.cmp(&(b.0).0));
+            Vector::write(&mut writer, &sorted_txs,
+                            |w, (k, v)| {
+                                w.write_all(&k.0)?;
+                                v.write(w)
+                            })?;
+        }
         utils::write_string(&mut writer, &self.config.chain_name)?;
 
         // While writing the birthday, get it from the fn so we recalculate it properly
         // in case of rescans etc...
         writer.write_u64::<LittleEndian>(self.get_birthday())?;
 
-        Ok(())
+        // The amount sent today
+        let (day, sent) = *self.daily_sent.read().unwrap();
+        writer.write_u64::<LittleEndian>(day)?;
+        writer.write_u64::<LittleEndian>(sent)?;
+
+        // How many of the viewing keys are watch-only
+        writer.write_u64::<LittleEndian>(*self.watch_only.read().unwrap() as u64)?;
+
+        // The addresses sent to, in order so that the same history is always written the same way
+        let mut sent_to = self.sent_to.read().unwrap().iter().map(|(a, v)| (a.clone(), *v)).collect::<Vec<_>>();
+        sent_to.sort();
+        Vector::write(&mut writer, &sent_to, |w, (address, (last_sent, count))| {
+            utils::write_string(&mut *w, address)?;
+            w.write_u64::<LittleEndian>(*last_sent)?;
+            w.write_u64::<LittleEndian>(*count)
+        })?;
+
+        self.accounts.read().unwrap().write(&mut writer)?;
+
+        // The exchange rates, in order of txid
+        let mut fiat_rates = self.fiat_rates.read().unwrap().iter().map(|(txid, r)| (txid.0, r.clone())).collect::<Vec<_>>();
+        fiat_rates.sort_by(|a, b| a.0.cmp(&b.0));
+        Vector::write(&mut writer, &fiat_rates, |w, (txid, rate)| {
+            w.write_all(txid)?;
+            rate.write(w)
+        })?;
+
+        // And the checksum of all of the above
+        writer.finish()
     }
 
-    pub fn note_address(hrp: &str, note: &SaplingNoteData) -> Option<String> {
-        match note.extfvk.fvk.vk.into_payment_address(note.diversifier, &JUBJUB) {
-            Some(pa) => Some(encode_payment_address(hrp, &pa)),
-            None     => None
-        }
+    /// Write the wallet like `write` does, gzipped, which makes it several times smaller: most of it is the
+    /// notes' witnesses, which repeat a lot. The file starts with COMPRESSED_WALLET_MAGIC instead of the 
+    /// version, and ends with a checksum of the compressed wallet, so that it's checked before it's 
+    /// decompressed. `read` reads both.
+    pub fn write_compressed<W: Write>(&self, writer: W) -> io::Result<()> {
+        LightWallet::compress(writer, |w| self.write(w))
     }
 
-    pub fn get_birthday(&self) -> u64 {
-        if self.birthday == 0 {
-            self.get_first_tx_block()
-        } else {
-            cmp::min(self.get_first_tx_block(), self.birthday)
-        }
+    /// `write_compressed`, writing the wallet the way `write_locked` does
+    pub fn write_compressed_locked<W: Write>(&self, writer: W) -> io::Result<()> {
+        LightWallet::compress(writer, |w| self.write_locked(w))
     }
 
-    // Get the first block that this wallet has a tx in. This is often used as the wallet's "birthday"
-    // If there are no Txns, then the actual birthday (which is recorder at wallet creation) is returned
-    // If no birthday was recorded, return the sapling activation height
-    pub fn get_first_tx_block(&self) -> u64 {
-        // Find the first transaction
-        let mut blocks = self.txs.read().unwrap().values()
-            .map(|wtx| wtx.block as u64)
-            .collect::<Vec<u64>>();
-        blocks.sort();
+    fn compress<W: Write, F>(writer: W, write: F) -> io::Result<()>
+            where F: FnOnce(&mut dyn Write) -> io::Result<()> {
+        let mut writer = ChecksumWriter::new(writer);
+        writer.write_all(&COMPRESSED_WALLET_MAGIC)?;
 
-        *blocks.first() // Returns optional, so if there's no txns, it'll get the activation height
-            .unwrap_or(&cmp::max(self.birthday, self.config.sapling_activation_height))
+        let mut encoder = GzEncoder::new(&mut writer, Compression::default());
+        write(&mut encoder)?;
+        encoder.finish()?;
+
+        writer.finish()
     }
 
-    // Get all z-address private keys. Returns a Vector of (address, privatekey)
-    pub fn get_z_private_keys(&self) -> Vec<(String, String)> {
-        self.extsks.read().unwrap().iter().map(|sk| {
-            (encode_payment_address(self.config.hrp_sapling_address(),
-                                    &ExtendedFullViewingKey::from(sk).default_address().unwrap().1),
-             encode_extended_spending_key(self.config.hrp_sapling_private_key(), &sk)
-            )
-        }).collect::<Vec<(String, String)>>()
+    /// Roughly how many bytes `write` writes, usually a little more, so that a buffer for the wallet can be
+    /// allocated once without writing the wallet twice. Nearly all of it is the blocks and the transactions, so
+    /// it's their counts times the size of the last block and the average size of a few transactions.
+    pub fn estimated_size(&self) -> usize {
+        fn size_of(write: impl FnOnce(&mut ByteCounter) -> io::Result<()>) -> usize {
+            let mut counter = ByteCounter(0);
+            write(&mut counter).map(|_| counter.0).unwrap_or(0)
+        }
+
+        let blocks = self.blocks.read().unwrap();
+        let block_size = blocks.last().map_or(0, |b| size_of(|w| b.write(w)));
+
+        let txs = self.txs.read().unwrap();
+        let step = cmp::max(txs.len() / SIZE_ESTIMATE_TX_SAMPLES, 1);
+        let (sampled, sampled_size) = txs.values().step_by(step).fold((0, 0), |(n, size), tx| {
+            (n + 1, size + 32 + size_of(|w| tx.write(w)))
+        });
+        let tx_size = if sampled == 0 { 0 } else { sampled_size / sampled };
+
+        // The keys, addresses and everything else fit in the margin, and the 10% covers the samples being off
+        (blocks.len() * block_size + txs.len() * tx_size + SIZE_ESTIMATE_MARGIN) * 11 / 10
     }
 
-    /// Get all t-address private keys. Returns a Vector of (address, secretkey)
-    pub fn get_t_secret_keys(&self) -> Vec<(String, String)> {
-        self.tkeys.read().unwrap().iter().map(|sk| {
-            (self.address_from_sk(sk), 
-             sk[..].to_base58check(&self.config.base58_secretkey_prefix(), &[0x01]))
-        }).collect::<Vec<(String, String)>>()
+    /// The version of the JSON from `to_json`, which is separate from the wallet file's
+    pub fn json_version() -> u64 {
+        1
     }
 
-    /// Adds a new z address to the wallet. This will derive a new address from the seed
-    /// at the next position and add it to the wallet.
-    /// NOTE: This does NOT rescan
-    pub fn add_zaddr(&self) -> String {
-        if !self.unlocked {
-            return "".to_string();
+    /// The wallet's contents as JSON, which unlike the wallet file stays readable when the file format changes:
+    /// the addresses in order, with their account, the transactions with their notes and utxos, and what was
+    /// sent. The seed and the private keys are only in it when the wallet is unlocked. The blocks and the notes'
+    /// witnesses are left out, a rescan gets them again.
+    pub fn to_json(&self) -> JsonValue {
+        let unlocked = self.is_unlocked_for_spending();
+        let account_names = self.account_names();
+        let accounts = self.accounts.read().unwrap();
+        let account_name = |a: u32| accounts.name(a).unwrap_or(DEFAULT_ACCOUNT).to_string();
+
+        let hd = self.hd_zaddr_count();
+        let extsks = self.extsks.read().unwrap();
+        let extfvks = self.extfvks.read().unwrap();
+        let z_addresses = self.zaddress.read().unwrap().iter().enumerate().map(|(i, address)| {
+            let mut o = object!{
+                "index"      => i,
+                "address"    => encode_payment_address(self.config.hrp_sapling_address(), address),
+                "watch_only" => i >= hd,
+            };
+            if i >= hd {
+                o["viewing_key"] = encode_extended_full_viewing_key(self.config.hrp_sapling_viewing_key(), &extfvks[i]).into();
+            } else {
+                o["account"] = account_name(accounts.zaddrs[i]).into();
+                if unlocked {
+                    o["private_key"] = encode_extended_spending_key(self.config.hrp_sapling_private_key(), &extsks[i]).into();
+                }
+            }
+            o
+        }).collect::<Vec<_>>();
+
+        let t_keys = if unlocked { self.get_t_secret_keys() } else { vec![] };
+        let t_addresses = self.taddresses.read().unwrap().iter().enumerate().map(|(i, address)| {
+            let mut o = object!{
+                "index"   => i,
+                "address" => address.clone(),
+                "account" => account_name(accounts.taddrs[i]),
+            };
+            if let Some((_, sk)) = t_keys.get(i) {
+                o["private_key"] = sk.as_str().into();
+            }
+            o
+        }).collect::<Vec<_>>();
+
+        let mut txs = self.txs.read().unwrap().values()
+            .map(|wtx| ((wtx.block, wtx.txid.0), wtx.to_json(self.config.hrp_sapling_address())))
+            .collect::<Vec<_>>();
+        txs.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let (day, sent) = *self.daily_sent.read().unwrap();
+        let mut sent_to = self.sent_to.read().unwrap().iter()
+            .map(|(address, (last_sent, count))| object!{
+                "address"   => address.clone(),
+                "last_sent" => *last_sent,
+                "count"     => *count,
+            }).collect::<Vec<_>>();
+        sent_to.sort_by(|a, b| a["address"].as_str().cmp(&b["address"].as_str()));
+
+        let mut fiat_rates = self.fiat_rates.read().unwrap().iter()
+            .map(|(txid, rate)| {
+                let mut o = rate.to_json();
+                o["txid"] = format!("{}", txid).into();
+                o
+            }).collect::<Vec<_>>();
+        fiat_rates.sort_by(|a, b| a["txid"].as_str().cmp(&b["txid"].as_str()));
+
+        let mut o = object!{
+            "version"      => LightWallet::json_version(),
+            "chain_name"   => self.config.chain_name.clone(),
+            "birthday"     => self.get_birthday(),
+            "encrypted"    => self.encrypted,
+            "accounts"     => account_names,
+            "z_addresses"  => z_addresses,
+            "t_addresses"  => t_addresses,
+            "transactions" => txs.into_iter().map(|(_, j)| j).collect::<Vec<_>>(),
+            "daily_sent"   => object!{ "day" => day, "amount" => sent },
+            "sent_to"      => sent_to,
+            "fiat_rates"   => fiat_rates,
+        };
+        if unlocked {
+            o["seed"] = self.get_seed_phrase().as_str().into();
         }
 
-        let pos = self.extsks.read().unwrap().len() as u32;
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&self.seed, Language::English).unwrap(), "");
-
-        let (extsk, extfvk, address) =
-            LightWallet::get_zaddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), pos);
+        o
+    }
 
-        let zaddr = encode_payment_address(self.config.hrp_sapling_address(), &address);
-        self.extsks.write().unwrap().push(extsk);
-        self.extfvks.write().unwrap().push(extfvk);
-        self.zaddress.write().unwrap().push(address);
+    /// Rebuild a wallet from the JSON of `to_json`, which has to have the seed. Its addresses are derived from the
+    /// seed again, and have to come out the same. It has no blocks, and the notes have no witnesses, so it has to
+    /// be rescanned from its birthday before they can be spent.
+    pub fn from_json(json: &JsonValue, config: &LightClientConfig) -> io::Result<Self> {
+        let invalid = |e: String| Error::new(ErrorKind::InvalidData, e);
 
-        zaddr
-    }
+        let version = utils::json_u64(json, "version")?;
+        if version > LightWallet::json_version() {
+            return Err(invalid(format!("Can't read wallet JSON version {}, only up to {} is supported", version, LightWallet::json_version())));
+        }
 
-    /// Add a new t address to the wallet. This will derive a new address from the seed
-    /// at the next position.
-    /// NOTE: This is not rescan the wallet
-    pub fn add_taddr(&self) -> String {
-        if !self.unlocked {
-            return "".to_string();
+        let chain_name = utils::json_str(json, "chain_name")?;
+        if chain_name != config.chain_name {
+            return Err(invalid(format!("Wallet chain name {} doesn't match expected {}", chain_name, config.chain_name)));
         }
 
-        let pos = self.tkeys.read().unwrap().len() as u32;
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&self.seed, Language::English).unwrap(), "");
-        
-        let sk = LightWallet::get_taddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), pos);
-        let address = self.address_from_sk(&sk);
+        let phrase = json["seed"].as_str()
+            .ok_or_else(|| invalid("There is no seed, the wallet has to be unlocked when it is exported".to_string()))?;
+        let mnemonic = Mnemonic::from_phrase(utils::normalize_seed_phrase(phrase).as_str(), Language::English)
+            .map(Secret::new)
+            .map_err(|e| invalid(format!("Error parsing phrase: {}", e)))?;
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&mnemonic.entropy());
 
-        self.tkeys.write().unwrap().push(sk);
-        self.taddresses.write().unwrap().push(address.clone());
+        let lw = LightWallet {
+            encrypted:   false,
+            unlocked_for_viewing:  true,
+            unlocked_for_spending: true,
+            enc_seed:    [0u8; 48],
+            nonce:       vec![],
+            kdf:         Kdf::DoubleSha256,
+            seed,
+            extsks:      Arc::new(RwLock::new(vec![])),
+            extfvks:     Arc::new(RwLock::new(vec![])),
+            zaddress:    Arc::new(RwLock::new(vec![])),
+            watch_only:  Arc::new(RwLock::new(0)),
+            tkeys:       Arc::new(RwLock::new(vec![])),
+            taddresses:  Arc::new(RwLock::new(vec![])),
+            blocks:      Arc::new(RwLock::new(vec![])),
+            txs:         Arc::new(RwLock::new(HashMap::new())),
+            mempool_txs: Arc::new(RwLock::new(HashMap::new())),
+            config:      config.clone(),
+            birthday:    utils::json_u64(json, "birthday")?,
+            daily_sent:  Arc::new(RwLock::new((0, 0))),
+            sent_to:     Arc::new(RwLock::new(HashMap::new())),
+            accounts:    Arc::new(RwLock::new(Accounts::new(0, 0))),
+            fiat_rates:  Arc::new(RwLock::new(HashMap::new())),
+            state:       AtomicU64::new(next_wallet_state()),
+        };
 
-        address
-    }
+        for name in json["accounts"].members().skip(1) {
+            let name = name.as_str().ok_or_else(|| invalid("'accounts' is missing or wrong".to_string()))?;
+            lw.add_account(name).map_err(invalid)?;
+        }
 
-    /// Clears all the downloaded blocks and resets the state back to the initial block.
-    /// After this, the wallet's initial state will need to be set
-    /// and the wallet will need to be rescanned
-    pub fn clear_blocks(&self) {
-        self.blocks.write().unwrap().clear();
-        self.txs.write().unwrap().clear();
-        self.mempool_txs.write().unwrap().clear();
-    }
+        let find_account = |a: &JsonValue| -> io::Result<u32> {
+            let name = utils::json_str(a, "account")?;
+            lw.find_account(name).ok_or_else(|| invalid(format!("No account named {}", name)))
+        };
 
-    pub fn set_initial_block(&self, height: i32, hash: &str, sapling_tree: &str) -> bool {
-        let mut blocks = self.blocks.write().unwrap();
-        if !blocks.is_empty() {
-            return false;
-        }
+        // The watch-only addresses come after the ones derived from the seed, which keeps them in order
+        for a in json["z_addresses"].members() {
+            let address = utils::json_str(a, "address")?;
+            let added = if utils::json_bool(a, "watch_only")? {
+                lw.add_watch_only_key(utils::json_str(a, "viewing_key")?).map_err(invalid)?
+            } else {
+                lw.add_zaddr_to_account(find_account(a)?)
+            };
+
+            if added != address {
+                return Err(invalid(format!("The seed derives {} instead of {}", added, address)));
+            }
+        }
+        let exported = json["z_addresses"].members().map(|a| a["address"].as_str().unwrap()).collect::<Vec<_>>();
+        let rebuilt = lw.zaddress.read().unwrap().iter()
+            .map(|a| encode_payment_address(config.hrp_sapling_address(), a))
+            .collect::<Vec<_>>();
+        if exported != rebuilt {
+            return Err(invalid("The watch-only z addresses have to come after the others".to_string()));
+        }
+
+        for a in json["t_addresses"].members() {
+            let address = utils::json_str(a, "address")?;
+            let added = lw.add_taddr_to_account(find_account(a)?);
+            if added != address {
+                return Err(invalid(format!("The seed derives {} instead of {}", added, address)));
+            }
+        }
+
+        {
+            let extfvks = lw.extfvks.read().unwrap();
+            let mut txs = lw.txs.write().unwrap();
+            for tx in json["transactions"].members() {
+                let wtx = WalletTx::from_json(tx, &extfvks)?;
+                txs.insert(wtx.txid.clone(), wtx);
+            }
+        }
+
+        *lw.daily_sent.write().unwrap() = (utils::json_u64(&json["daily_sent"], "day")?, utils::json_u64(&json["daily_sent"], "amount")?);
+        for s in json["sent_to"].members() {
+            lw.sent_to.write().unwrap().insert(utils::json_str(s, "address")?.to_string(),
+                                               (utils::json_u64(s, "last_sent")?, utils::json_u64(s, "count")?));
+        }
+        for r in json["fiat_rates"].members() {
+            let txid = utils::json_txid(r, "txid")?.ok_or_else(|| invalid("'txid' is missing or wrong".to_string()))?;
+            lw.fiat_rates.write().unwrap().insert(txid, FiatRate::from_json(r)?);
+        }
+
+        Ok(lw)
+    }
+
+    /// How much has been sent today (UTC)
+    pub fn sent_today(&self) -> u64 {
+        let (day, sent) = *self.daily_sent.read().unwrap();
+        if day == today() { sent } else { 0 }
+    }
+
+    /// Count a send towards today's total
+    pub fn add_sent_today(&self, amount: u64) {
+        let _ = self.try_add_sent_today(amount, None);
+    }
+
+    /// Count a send towards today's total, unless that takes it over `max`, in which case what was already
+    /// sent today is returned as the error. The check and the count are made under one lock, so that two
+    /// sends at once can't both fit under the limit when only one of them does.
+    pub fn try_add_sent_today(&self, amount: u64, max: Option<u64>) -> Result<(), u64> {
+        let mut daily_sent = self.daily_sent.write().unwrap();
+        if daily_sent.0 != today() {
+            *daily_sent = (today(), 0);
+        }
+
+        if max.map_or(false, |max| daily_sent.1 + amount > max) {
+            return Err(daily_sent.1);
+        }
+        daily_sent.1 += amount;
+        Ok(())
+    }
+
+    /// Take back a send that was counted towards today's total, but didn't go out
+    pub fn remove_sent_today(&self, amount: u64) {
+        let mut daily_sent = self.daily_sent.write().unwrap();
+        if daily_sent.0 == today() {
+            daily_sent.1 = daily_sent.1.saturating_sub(amount);
+        }
+    }
+
+    /// Before version 5 of the wallet file, what was sent today wasn't kept. Add up today's sends from the
+    /// transactions instead, so that upgrading doesn't reset the daily limit.
+    fn migrate_daily_sent(&self) {
+        let today = today();
+        let sent = self.txs.read().unwrap().values()
+            .filter(|wtx| wtx.datetime / (24 * 60 * 60) == today)
+            .flat_map(|wtx| wtx.outgoing_metadata.iter().map(|om| om.value))
+            .sum::<u64>();
+
+        *self.daily_sent.write().unwrap() = (today, sent);
+    }
+
+    /// Before version 7 of the wallet file, the addresses sent to weren't kept. Find them in the sent 
+    /// transactions instead.
+    fn migrate_sent_to(&self) {
+        let mut sent_to = self.sent_to.write().unwrap();
+
+        for wtx in self.txs.read().unwrap().values() {
+            let mut seen = HashSet::new();
+            for om in wtx.outgoing_metadata.iter().filter(|om| seen.insert(om.address.clone())) {
+                let entry = sent_to.entry(om.address.clone()).or_insert((0, 0));
+                *entry = (cmp::max(entry.0, wtx.datetime), entry.1 + 1);
+            }
+        }
+    }
+
+    /// Remember that a send went to each of `addresses`. An address that is in there more than once,
+    /// eg. with several memos, counts once.
+    pub fn add_sent_to(&self, addresses: &[&str]) {
+        let now = now() as u64;
+        let mut sent_to = self.sent_to.write().unwrap();
+
+        let mut seen = HashSet::new();
+        for a in addresses.iter().filter(|a| seen.insert(**a)) {
+            let entry = sent_to.entry(a.to_string()).or_insert((0, 0));
+            *entry = (now, entry.1 + 1);
+        }
+    }
+
+    /// Remember the exchange rate that the amount of the send `txid` was converted at
+    pub fn set_fiat_rate(&self, txid: &TxId, rate: FiatRate) {
+        self.fiat_rates.write().unwrap().insert(txid.clone(), rate);
+    }
+
+    /// The exchange rate of the send `txid`, if its amount was given in a fiat currency
+    pub fn fiat_rate(&self, txid: &TxId) -> Option<FiatRate> {
+        self.fiat_rates.read().unwrap().get(txid).cloned()
+    }
+
+    /// Whether the wallet has sent to `address` before
+    pub fn has_sent_to(&self, address: &str) -> bool {
+        self.sent_to.read().unwrap().contains_key(address)
+    }
+
+    /// The addresses the wallet has sent to, as (address, last sent, number of sends), most recently used first
+    pub fn sent_to_history(&self) -> Vec<(String, u64, u64)> {
+        let mut history = self.sent_to.read().unwrap().iter()
+            .map(|(a, (last_sent, count))| (a.clone(), *last_sent, *count))
+            .collect::<Vec<_>>();
+        history.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        history
+    }
+
+    pub fn note_address(hrp: &str, note: &SaplingNoteData) -> Option<String> {
+        match note.extfvk.fvk.vk.into_payment_address(note.diversifier, &JUBJUB) {
+            Some(pa) => Some(encode_payment_address(hrp, &pa)),
+            None     => None
+        }
+    }
+
+    pub fn get_birthday(&self) -> u64 {
+        if self.birthday == 0 {
+            self.get_first_tx_block()
+        } else {
+            cmp::min(self.get_first_tx_block(), self.birthday)
+        }
+    }
+
+    /// Move the birthday back to `height`, if that's earlier, so that rescans start from there
+    pub fn lower_birthday(&mut self, height: u64) {
+        if height < self.get_birthday() {
+            self.birthday = height;
+        }
+    }
+
+    // Get the first block that this wallet has a tx in. This is often used as the wallet's "birthday"
+    // If there are no Txns, then the actual birthday (which is recorder at wallet creation) is returned
+    // If no birthday was recorded, return the sapling activation height
+    pub fn get_first_tx_block(&self) -> u64 {
+        // Find the first transaction
+        let mut blocks = self.txs.read().unwrap().values()
+            .map(|wtx| wtx.block as u64)
+            .collect::<Vec<u64>>();
+        blocks.sort();
+
+        *blocks.first() // Returns optional, so if there's no txns, it'll get the activation height
+            .unwrap_or(&cmp::max(self.birthday, self.config.sapling_activation_height))
+    }
+
+    // Get all z-address private keys. Returns a Vector of (address, privatekey)
+    pub fn get_z_private_keys(&self) -> Vec<(String, Secret<String>)> {
+        self.extsks.read().unwrap().iter().map(|sk| {
+            (encode_payment_address(self.config.hrp_sapling_address(),
+                                    &ExtendedFullViewingKey::from(sk).default_address().unwrap().1),
+             Secret::new(encode_extended_spending_key(self.config.hrp_sapling_private_key(), &sk))
+            )
+        }).collect::<Vec<(String, Secret<String>)>>()
+    }
+
+    /// Get all t-address private keys. Returns a Vector of (address, secretkey)
+    pub fn get_t_secret_keys(&self) -> Vec<(String, Secret<String>)> {
+        self.tkeys.read().unwrap().iter().map(|sk| {
+            (self.address_from_sk(sk), 
+             Secret::new(sk[..].to_base58check(&self.config.base58_secretkey_prefix(), &[0x01])))
+        }).collect::<Vec<(String, Secret<String>)>>()
+    }
+
+    /// Get the BIP32 extended keys of the transparent account, i.e., the parent of all the t-address
+    /// keys in this wallet. Returns (derivation path, xprv, xpub), or None if the wallet is locked.
+    /// The t-addresses are derived from this key at <path>/0/<index>
+    pub fn get_t_account_xkeys(&self) -> Option<(String, String, String)> {
+        if !self.unlocked_for_spending {
+            return None;
+        }
+
+        let bip39_seed = LightWallet::bip39_seed(&self.seed);
+
+        let coin_type = self.config.get_coin_type();
+        let purpose_idx = KeyIndex::hardened_from_normalize_index(44).unwrap();
+        let coin_idx    = KeyIndex::hardened_from_normalize_index(coin_type).unwrap();
+        let account_idx = KeyIndex::hardened_from_normalize_index(0).unwrap();
+
+        let coin_key = ExtendedPrivKey::with_seed(bip39_seed.as_bytes()).unwrap()
+            .derive_private_key(purpose_idx).unwrap()
+            .derive_private_key(coin_idx).unwrap();
+        let account_key = coin_key.derive_private_key(account_idx).unwrap();
+
+        let child_number = match account_idx {
+            KeyIndex::Hardened(i) | KeyIndex::Normal(i) => i
+        };
+
+        let xprv = account_key.xprv_payload(3, coin_key.fingerprint(), child_number)
+                        .to_base58check(&self.config.base58_xprv_prefix(), &[]);
+        let xpub = account_key.xpub_payload(3, coin_key.fingerprint(), child_number)
+                        .to_base58check(&self.config.base58_xpub_prefix(), &[]);
+
+        Some((format!("m/44'/{}'/0'", coin_type), xprv, xpub))
+    }
+
+    /// Adds a new z address to the wallet. This will derive a new address from the seed
+    /// at the next position and add it to the wallet.
+    /// NOTE: This does NOT rescan
+    pub fn add_zaddr(&self) -> String {
+        self.add_zaddr_to_account(0)
+    }
+
+    /// Like `add_zaddr`, but the address is derived at the next position in `account`
+    pub fn add_zaddr_to_account(&self, account: u32) -> String {
+        if !self.unlocked_for_spending {
+            return "".to_string();
+        }
+
+        let pos = self.hd_zaddr_count();
+        let account_pos = self.accounts.read().unwrap().zaddrs.iter().filter(|a| **a == account).count();
+        let bip39_seed = LightWallet::bip39_seed(&self.seed);
+
+        let (extsk, extfvk, address) =
+            LightWallet::get_zaddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), account, account_pos as u32);
+
+        // It goes before the watch-only keys, so their notes move up an account
+        if *self.watch_only.read().unwrap() > 0 {
+            for wtx in self.txs.write().unwrap().values_mut().chain(self.mempool_txs.write().unwrap().values_mut()) {
+                for nd in wtx.notes.iter_mut().filter(|nd| nd.account >= pos) {
+                    nd.account += 1;
+                }
+            }
+        }
+
+        let zaddr = encode_payment_address(self.config.hrp_sapling_address(), &address);
+        self.extsks.write().unwrap().push(extsk);
+        self.extfvks.write().unwrap().insert(pos, extfvk);
+        self.zaddress.write().unwrap().insert(pos, address);
+        self.accounts.write().unwrap().zaddrs.push(account);
+        self.state_changed();
+
+        zaddr
+    }
+
+    /// How many of the z addresses are derived from the seed. They come first, the watch-only ones after them.
+    fn hd_zaddr_count(&self) -> usize {
+        self.extfvks.read().unwrap().len() - *self.watch_only.read().unwrap()
+    }
+
+    /// Whether `address` is one of the z addresses of an imported viewing key, which can't be spent from
+    pub fn is_watch_only_address(&self, address: &str) -> bool {
+        let hd = self.hd_zaddr_count();
+        self.zaddress.read().unwrap()[hd..].iter()
+            .any(|a| encode_payment_address(self.config.hrp_sapling_address(), a) == address)
+    }
+
+    /// The imported viewing keys, in the order they were added
+    pub fn get_watch_only_keys(&self) -> Vec<String> {
+        let hd = self.hd_zaddr_count();
+        self.extfvks.read().unwrap()[hd..].iter()
+            .map(|k| encode_extended_full_viewing_key(self.config.hrp_sapling_viewing_key(), k))
+            .collect()
+    }
+
+    /// Watch the z address of an extended full viewing key. Its notes are found when scanning, and count
+    /// towards the balance, but they can't be spent. Returns the address.
+    /// NOTE: This does NOT rescan
+    pub fn add_watch_only_key(&self, key: &str) -> Result<String, String> {
+        let extfvk = match decode_extended_full_viewing_key(self.config.hrp_sapling_viewing_key(), key) {
+            Ok(Some(extfvk)) => extfvk,
+            _ => return Err(format!("Not a valid viewing key for {}", self.config.chain_name))
+        };
+
+        if self.extfvks.read().unwrap().contains(&extfvk) {
+            return Err("The wallet already has this key".to_string());
+        }
+
+        let address = extfvk.default_address().unwrap().1;
+        let zaddr = encode_payment_address(self.config.hrp_sapling_address(), &address);
+
+        self.extfvks.write().unwrap().push(extfvk);
+        self.zaddress.write().unwrap().push(address);
+        *self.watch_only.write().unwrap() += 1;
+        self.state_changed();
+
+        Ok(zaddr)
+    }
+
+    /// Add a new t address to the wallet. This will derive a new address from the seed
+    /// at the next position.
+    /// NOTE: This is not rescan the wallet
+    pub fn add_taddr(&self) -> String {
+        self.add_taddr_to_account(0)
+    }
+
+    /// Like `add_taddr`, but the address is derived at the next position in `account`
+    pub fn add_taddr_to_account(&self, account: u32) -> String {
+        if !self.unlocked_for_spending {
+            return "".to_string();
+        }
+
+        let pos = self.accounts.read().unwrap().taddrs.iter().filter(|a| **a == account).count() as u32;
+        let bip39_seed = LightWallet::bip39_seed(&self.seed);
+        
+        let sk = LightWallet::get_taddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), account, pos);
+        let address = self.address_from_sk(&sk);
+
+        self.tkeys.write().unwrap().push(sk);
+        self.taddresses.write().unwrap().push(address.clone());
+        self.accounts.write().unwrap().taddrs.push(account);
+        self.state_changed();
+
+        address
+    }
+
+    /// Add an account with the given name. Its addresses are derived from the seed apart from the other
+    /// accounts'. Returns its number.
+    pub fn add_account(&self, name: &str) -> Result<u32, String> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(format!("'{}' can't be an account name, it has to be one word", name));
+        }
+
+        let mut accounts = self.accounts.write().unwrap();
+        if accounts.find(name).is_some() {
+            return Err(format!("There already is an account named {}", name));
+        }
+
+        accounts.names.push(name.to_string());
+        self.state_changed();
+
+        Ok(accounts.names.len() as u32)
+    }
+
+    /// The names of the accounts, the default one first
+    pub fn account_names(&self) -> Vec<String> {
+        let accounts = self.accounts.read().unwrap();
+        std::iter::once(DEFAULT_ACCOUNT.to_string()).chain(accounts.names.iter().cloned()).collect()
+    }
+
+    /// The number of the account named `name`
+    pub fn find_account(&self, name: &str) -> Option<u32> {
+        self.accounts.read().unwrap().find(name)
+    }
+
+    /// The z and t addresses in `account`, in the order they were added
+    pub fn account_addresses(&self, account: u32) -> (Vec<String>, Vec<String>) {
+        let accounts = self.accounts.read().unwrap();
+
+        let zaddrs = self.zaddress.read().unwrap().iter().zip(accounts.zaddrs.iter())
+            .filter(|(_, a)| **a == account)
+            .map(|(ad, _)| encode_payment_address(self.config.hrp_sapling_address(), ad))
+            .collect();
+        let taddrs = self.taddresses.read().unwrap().iter().zip(accounts.taddrs.iter())
+            .filter(|(_, a)| **a == account)
+            .map(|(ad, _)| ad.clone())
+            .collect();
+
+        (zaddrs, taddrs)
+    }
+
+    /// Clears all the downloaded blocks and resets the state back to the initial block.
+    /// After this, the wallet's initial state will need to be set
+    /// and the wallet will need to be rescanned
+    pub fn clear_blocks(&self) {
+        self.blocks.write().unwrap().clear();
+        self.txs.write().unwrap().clear();
+        self.mempool_txs.write().unwrap().clear();
+        self.state_changed();
+    }
+
+    /// Identifies the current state of the notes, utxos, blocks and addresses. It is different after
+    /// any of them changed.
+    pub fn state(&self) -> u64 {
+        self.state.load(Ordering::SeqCst)
+    }
+
+    // Called after changing anything that `state` covers
+    fn state_changed(&self) {
+        self.state.store(next_wallet_state(), Ordering::SeqCst);
+    }
+
+    pub fn set_initial_block(&self, height: i32, hash: &str, sapling_tree: &str) -> bool {
+        let mut blocks = self.blocks.write().unwrap();
+        if !blocks.is_empty() {
+            return false;
+        }
 
         let hash = match hex::decode(hash) {
             Ok(hash) => {
@@ -527,6 +1569,7 @@ impl LightWallet {
 
         if let Ok(tree) = CommitmentTree::read(&sapling_tree[..]) {
             blocks.push(BlockData { height, hash, tree });
+            self.state_changed();
             true
         } else {
             false
@@ -583,6 +1626,63 @@ impl LightWallet {
         }
     }
 
+    /// Encrypt a message to the z address, the same way a memo is encrypted, but without a 
+    /// transaction. Only the address' owner can decrypt it, with `decrypt_message`.
+    pub fn encrypt_message(&self, to: PaymentAddress<Bls12>, msg: &str) -> Result<Vec<u8>, String> {
+        let memo = Memo::from_str(msg).ok_or(format!("Message is too long, it has to fit in a memo"))?;
+
+        let mut rng = OsRng;
+        let note = Note {
+            g_d: to.diversifier.g_d::<Bls12>(&JUBJUB).ok_or("Invalid diversifier".to_string())?,
+            pk_d: to.pk_d.clone(),
+            value: 0,
+            r: Fs::random(&mut rng),
+        };
+
+        let ovk = self.extfvks.read().unwrap()[0].fvk.ovk;
+        let encryptor = SaplingNoteEncryption::new(ovk, note.clone(), to, memo, &mut rng);
+
+        let mut blob = MESSAGE_MAGIC.to_vec();
+        encryptor.epk().write(&mut blob).map_err(|e| e.to_string())?;
+        note.cm(&JUBJUB).into_repr().write_le(&mut blob).map_err(|e| e.to_string())?;
+        blob.extend_from_slice(&encryptor.encrypt_note_plaintext()[..]);
+
+        Ok(blob)
+    }
+
+    /// Decrypt a message from `encrypt_message` with any of our z addresses. Returns the address
+    /// it was sent to, and the message.
+    pub fn decrypt_message(&self, blob: &[u8]) -> Result<(String, String), String> {
+        if blob.len() != MESSAGE_MAGIC.len() + 32 + 32 + ENC_CIPHERTEXT_SIZE || !blob.starts_with(MESSAGE_MAGIC) {
+            return Err("Not an encrypted message".to_string());
+        }
+        let blob = &blob[MESSAGE_MAGIC.len()..];
+
+        let epk = edwards::Point::<Bls12, _>::read(&blob[..32], &JUBJUB)
+                    .map_err(|e| format!("Invalid epk: {}", e))?
+                    .as_prime_order(&JUBJUB)
+                    .ok_or("Invalid epk".to_string())?;
+
+        let mut repr = FrRepr::default();
+        repr.read_le(&blob[32..64]).map_err(|e| format!("Invalid cmu: {}", e))?;
+        let cmu = Fr::from_repr(repr).map_err(|e| format!("Invalid cmu: {}", e))?;
+
+        let ct = &blob[64..];
+
+        let ivks: Vec<_> = self.extfvks.read().unwrap().iter().map(
+            |extfvk| extfvk.fvk.vk.ivk().clone()
+        ).collect();
+
+        for ivk in ivks.iter() {
+            if let Some((_note, to, memo)) = try_sapling_note_decryption(ivk, &epk, &cmu, ct) {
+                let msg = LightWallet::memo_str(&Some(memo)).ok_or("Message is not valid UTF-8".to_string())?;
+                return Ok((encode_payment_address(self.config.hrp_sapling_address(), &to), msg));
+            }
+        }
+
+        Err("Couldn't decrypt the message with any of the wallet's z addresses".to_string())
+    }
+
     pub fn memo_str(memo: &Option<Memo>) -> Option<String> {
         match memo {
             Some(memo) => {
@@ -622,25 +1722,36 @@ impl LightWallet {
         }
     }
 
-    pub fn get_seed_phrase(&self) -> String {
-        if !self.unlocked {
-            return "".to_string();
+    pub fn get_seed_phrase(&self) -> Secret<String> {
+        if !self.unlocked_for_spending {
+            return Secret::new("".to_string());
         }
 
-        Mnemonic::from_entropy(&self.seed, 
-                                Language::English,
-        ).unwrap().phrase().to_string()
+        let mnemonic = Secret::new(Mnemonic::from_entropy(&self.seed, Language::English).unwrap());
+        Secret::new(mnemonic.phrase().to_string())
     }
 
     pub fn encrypt(&mut self, passwd: String) -> io::Result<()> {
-        use sodiumoxide::crypto::secretbox;
+        let passwd = Secret::new(passwd);
 
         if self.encrypted {
             return Err(io::Error::new(ErrorKind::AlreadyExists, "Wallet is already encrypted"));
         }
 
-        // Get the doublesha256 of the password, which is the right length
-        let key = secretbox::Key::from_slice(&double_sha256(passwd.as_bytes())).unwrap();
+        self.encrypt_seed(&passwd)?;
+
+        self.encrypted = true;
+        self.lock()?;
+
+        Ok(())
+    }
+
+    /// Encrypt the seed with a key derived from `passwd` the way new encryptions do
+    fn encrypt_seed(&mut self, passwd: &str) -> io::Result<()> {
+        use sodiumoxide::crypto::secretbox;
+
+        let kdf = Kdf::argon2id();
+        let key = kdf.derive_key(passwd)?;
         let nonce = secretbox::gen_nonce();
 
         let cipher = secretbox::seal(&self.seed, &nonce, &key);
@@ -648,64 +1759,116 @@ impl LightWallet {
         self.enc_seed.copy_from_slice(&cipher);
         self.nonce = vec![];
         self.nonce.extend_from_slice(nonce.as_ref());
-
-        self.encrypted = true;
-        self.lock()?;
+        self.kdf = kdf;
 
         Ok(())
     }
 
+    /// How the key of the encrypted seed is derived from the password
+    pub fn kdf(&self) -> &Kdf {
+        &self.kdf
+    }
+
+    /// Lock the wallet completely, so that it can neither spend nor be synced until it's unlocked again
     pub fn lock(&mut self) -> io::Result<()> {
         if !self.encrypted {
             return Err(io::Error::new(ErrorKind::AlreadyExists, "Wallet is not encrypted"));
         }
 
-        if !self.unlocked {
+        if !self.unlocked_for_viewing {
             return Err(io::Error::new(ErrorKind::AlreadyExists, "Wallet is already locked"));
         }
 
+        self.lock_spending()?;
+        self.unlocked_for_viewing = false;
+
+        Ok(())
+    }
+
+    /// Remove the seed and the spending keys from memory, but keep the wallet unlocked for viewing
+    pub fn lock_spending(&mut self) -> io::Result<()> {
+        if !self.encrypted {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, "Wallet is not encrypted"));
+        }
+
         // Empty the seed and the secret keys
-        self.seed.copy_from_slice(&[0u8; 32]);
+        self.seed.wipe();
+        self.tkeys.write().unwrap().wipe();
         self.tkeys = Arc::new(RwLock::new(vec![]));
+        self.extsks.write().unwrap().wipe();
         self.extsks = Arc::new(RwLock::new(vec![]));
 
-        self.unlocked = false;
+        self.unlocked_for_spending = false;
 
         Ok(())
     }
 
-    pub fn unlock(&mut self, passwd: String) -> io::Result<()> {
+    /// Decrypt the seed with the password, failing if the password is wrong
+    fn decrypt_seed(&self, passwd: &str) -> io::Result<Secret<Vec<u8>>> {
         use sodiumoxide::crypto::secretbox;
 
+        let key = self.kdf.derive_key(passwd)?;
+        let nonce = secretbox::Nonce::from_slice(&self.nonce).unwrap();
+
+        match secretbox::open(&self.enc_seed, &nonce, &key) {
+            Ok(s) => Ok(Secret::new(s)),
+            Err(_) => Err(io::Error::new(ErrorKind::InvalidData, "Decryption failed. Is your password correct?"))
+        }
+    }
+
+    /// Unlock the wallet only to sync it and show its balances. The password is checked, but the seed and the
+    /// spending keys stay encrypted, so spending or exporting keys needs `unlock`. The viewing keys are never
+    /// encrypted, in memory or in the wallet file, so being locked for viewing is only a gate in front of the
+    /// commands that use them, it doesn't keep anyone who can read the file from seeing the wallet's history.
+    pub fn unlock_viewing(&mut self, passwd: String) -> io::Result<()> {
+        let passwd = Secret::new(passwd);
+
         if !self.encrypted {
             return Err(Error::new(ErrorKind::AlreadyExists, "Wallet is not encrypted"));
         }
 
-        if self.encrypted && self.unlocked {
+        if self.unlocked_for_viewing {
             return Err(Error::new(ErrorKind::AlreadyExists, "Wallet is already unlocked"));
         }
 
-        // Get the doublesha256 of the password, which is the right length
-        let key = secretbox::Key::from_slice(&double_sha256(passwd.as_bytes())).unwrap();
-        let nonce = secretbox::Nonce::from_slice(&self.nonce).unwrap();
+        self.decrypt_seed(&passwd)?;
+        self.unlocked_for_viewing = true;
 
-        let seed = match secretbox::open(&self.enc_seed, &nonce, &key) {
-            Ok(s) => s,
-            Err(_) => {return Err(io::Error::new(ErrorKind::InvalidData, "Decryption failed. Is your password correct?"));}
-        };
+        Ok(())
+    }
 
-        // Now that we have the seed, we'll generate the extsks and tkeys, and verify the fvks and addresses
-        // respectively match
+    /// Unlock the wallet for spending, which also unlocks it for viewing
+    pub fn unlock(&mut self, passwd: String) -> io::Result<()> {
+        let passwd = Secret::new(passwd);
 
-        // The seed bytes is the raw entropy. To pass it to HD wallet generation, 
+        if !self.encrypted {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Wallet is not encrypted"));
+        }
+
+        if self.encrypted && self.unlocked_for_spending {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Wallet is already unlocked"));
+        }
+
+        let seed = self.decrypt_seed(&passwd)?;
+
+        // Now that we have the seed, we'll generate the extsks and tkeys, and verify the fvks and addresses
+        // respectively match
+
+        // The seed bytes is the raw entropy. To pass it to HD wallet generation, 
         // we need to get the 64 byte bip39 entropy
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&seed, Language::English).unwrap(), "");
+        let bip39_seed = LightWallet::bip39_seed(&seed);
+
+        let (zaddr_accounts, taddr_accounts) = {
+            let accounts = self.accounts.read().unwrap();
+            (accounts.zaddrs.clone(), accounts.taddrs.clone())
+        };
 
         // Sapling keys
-        let mut extsks = vec![];
-        for pos in 0..self.zaddress.read().unwrap().len() {
+        let mut extsks: Secret<Vec<ExtendedSpendingKey>> = Secret::new(vec![]);
+        for pos in 0..self.hd_zaddr_count() {
+            let (account, account_pos) = Accounts::derivation(&zaddr_accounts, pos);
             let (extsk, extfvk, address) =
-                LightWallet::get_zaddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), pos as u32);
+                LightWallet::get_zaddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), account, account_pos);
 
             if address != self.zaddress.read().unwrap()[pos] {
                 return Err(io::Error::new(ErrorKind::InvalidData, 
@@ -722,9 +1885,10 @@ impl LightWallet {
         }
 
         // Transparent keys
-        let mut tkeys = vec![];
+        let mut tkeys: Secret<Vec<SecretKey>> = Secret::new(vec![]);
         for pos in 0..self.taddresses.read().unwrap().len() {
-            let sk = LightWallet::get_taddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), pos as u32);
+            let (account, account_pos) = Accounts::derivation(&taddr_accounts, pos);
+            let sk = LightWallet::get_taddr_from_bip39seed(&self.config, &bip39_seed.as_bytes(), account, account_pos);
             let address = self.address_from_sk(&sk);
 
             if address != self.taddresses.read().unwrap()[pos] {
@@ -735,13 +1899,22 @@ impl LightWallet {
             tkeys.push(sk);
         }
 
-        // Everything checks out, so we'll update our wallet with the decrypted values
-        self.extsks = Arc::new(RwLock::new(extsks));
-        self.tkeys = Arc::new(RwLock::new(tkeys));
+        // Everything checks out, so we'll update our wallet with the decrypted values. The keys are moved, not copied,
+        // so that no unwiped copies are left behind.
+        self.extsks = Arc::new(RwLock::new(std::mem::replace(&mut *extsks, vec![])));
+        self.tkeys = Arc::new(RwLock::new(std::mem::replace(&mut *tkeys, vec![])));
         self.seed.copy_from_slice(&seed);
                 
         self.encrypted = true;
-        self.unlocked = true;
+        self.unlocked_for_viewing = true;
+        self.unlocked_for_spending = true;
+
+        // A seed encrypted with an older key derivation is encrypted again with the current one, which the 
+        // next save writes
+        if !self.kdf.is_current() {
+            info!("The seed was encrypted with {}, encrypting it again with Argon2id", self.kdf.to_json()["algorithm"]);
+            self.encrypt_seed(&passwd)?;
+        }
 
         Ok(())
     }
@@ -754,7 +1927,7 @@ impl LightWallet {
         }
 
         // Unlock the wallet if it's locked
-        if !self.unlocked {
+        if !self.unlocked_for_spending {
             self.unlock(passwd)?;
         }
         
@@ -770,8 +1943,79 @@ impl LightWallet {
         return self.encrypted;
     }
 
+    pub fn is_unlocked_for_viewing(&self) -> bool {
+        return self.unlocked_for_viewing;
+    }
+
     pub fn is_unlocked_for_spending(&self) -> bool {
-        return self.unlocked;
+        return self.unlocked_for_spending;
+    }
+
+    /// Look for inconsistencies in the wallet's data, like a crash or a bad sync could leave behind, 
+    /// and describe each one found
+    pub fn check_consistency(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        let anchor = self.blocks.read().unwrap().last().map(|b| (b.height, b.tree.root()));
+        let txs = self.txs.read().unwrap();
+
+        for (txid, wtx) in txs.iter() {
+            for (i, nd) in wtx.notes.iter().enumerate() {
+                let note = format!("Note {} of {}", i, txid);
+
+                match (nd.witnesses.last(), &anchor) {
+                    (Some(w), Some((height, root))) if w.root() != *root => 
+                        problems.push(format!("{}: witness doesn't match the tree at height {}", note, height)),
+                    (None, Some(_)) if nd.spent.is_none() => 
+                        problems.push(format!("{}: unspent, but has no witness", note)),
+                    _ => {}
+                }
+
+                if let Some(spent) = &nd.spent {
+                    if !txs.contains_key(spent) {
+                        problems.push(format!("{}: spent by {}, which is not in the wallet", note, spent));
+                    }
+                    if nd.unconfirmed_spent.is_some() {
+                        problems.push(format!("{}: both spent and unconfirmed spent", note));
+                    }
+                }
+            }
+
+            for u in wtx.utxos.iter() {
+                if let Some(spent) = &u.spent {
+                    if !txs.contains_key(spent) {
+                        problems.push(format!("Utxo {}:{}: spent by {}, which is not in the wallet", u.txid, u.output_index, spent));
+                    }
+                    if u.unconfirmed_spent.is_some() {
+                        problems.push(format!("Utxo {}:{}: both spent and unconfirmed spent", u.txid, u.output_index));
+                    }
+                }
+            }
+
+            // What the transaction spent has to add up to the notes and utxos it spent
+            let shielded_spent = txs.values()
+                .flat_map(|t| t.notes.iter())
+                .filter(|nd| nd.spent.as_ref() == Some(txid))
+                .map(|nd| nd.note.value)
+                .sum::<u64>();
+            if shielded_spent != wtx.total_shielded_value_spent {
+                problems.push(format!("Transaction {}: spent {} in notes, but its shielded total is {}", 
+                                        txid, shielded_spent, wtx.total_shielded_value_spent));
+            }
+
+            let transparent_spent = txs.values()
+                .flat_map(|t| t.utxos.iter())
+                .filter(|u| u.spent.as_ref() == Some(txid))
+                .map(|u| u.value)
+                .sum::<u64>();
+            if transparent_spent != wtx.total_transparent_value_spent {
+                problems.push(format!("Transaction {}: spent {} in utxos, but its transparent total is {}", 
+                                        txid, transparent_spent, wtx.total_transparent_value_spent));
+            }
+        }
+
+        problems.sort();
+        problems
     }
 
     pub fn zbalance(&self, addr: Option<String>) -> u64 {
@@ -889,6 +2133,47 @@ impl LightWallet {
                 }
             }
         }
+        drop(txs);
+
+        self.state_changed();
+    }
+
+    /// Add a UTXO that the server reported for one of our t addresses, without having scanned the tx
+    /// that created it. If the block scan finds the same output later, it is not added again.
+    pub fn add_utxo(&self, address: String, txid: TxId, output_index: u64, script: Vec<u8>, value: u64, height: i32) -> bool {
+        if !self.taddresses.read().unwrap().contains(&address) {
+            warn!("Not adding UTXO {}:{} for {}, which is not in this wallet", txid, output_index, address);
+            return false;
+        }
+
+        let mut txs = self.txs.write().unwrap();
+
+        if !txs.contains_key(&txid) {
+            txs.insert(txid.clone(), WalletTx::new(height, 0, &txid));
+        }
+        let tx_entry = txs.get_mut(&txid).unwrap();
+
+        if tx_entry.utxos.iter().any(|utxo| utxo.output_index == output_index) {
+            return false;
+        }
+
+        info!("Added UTXO from server {}:{}", txid, output_index);
+        tx_entry.utxos.push(Utxo {
+            address: address.clone(),
+            txid,
+            output_index,
+            script,
+            value,
+            height,
+            spent: None,
+            unconfirmed_spent: None,
+        });
+        drop(txs);
+        self.state_changed();
+
+        self.ensure_hd_taddresses(&address);
+
+        true
     }
 
     // If one of the last 'n' taddress was used, ensure we add the next HD taddress to the wallet. 
@@ -939,6 +2224,31 @@ impl LightWallet {
         }
     }
 
+    /// Whether the address belongs to one of the wallet's accounts, including diversified addresses
+    /// that aren't in the address list, like fresh change addresses. Watched viewing keys don't count.
+    pub fn is_own_zaddress(&self, address: &PaymentAddress<Bls12>) -> bool {
+        let hd = self.hd_zaddr_count();
+        self.extfvks.read().unwrap()[..hd].iter().any(|extfvk| {
+            extfvk.fvk.vk.into_payment_address(address.diversifier, &JUBJUB).map_or(false, |a| a == *address)
+        })
+    }
+
+    /// A new diversified address of the first account, to send change to. All the diversified addresses
+    /// of an account share its viewing key, so they are found when restoring from the seed, without
+    /// using up the HD addresses covered by the gap limit.
+    fn fresh_change_address(&self) -> PaymentAddress<Bls12> {
+        let extfvk = self.extfvks.read().unwrap()[0].clone();
+
+        let mut index = [0u8; 11];
+        let mut rng = OsRng;
+        rng.fill(&mut index);
+
+        match extfvk.address(DiversifierIndex(index)) {
+            Ok((_, address)) => address,
+            Err(_)           => extfvk.default_address().unwrap().1
+        }
+    }
+
     // Scan the full Tx and update memos for incoming shielded transactions.
     pub fn scan_full_tx(&self, tx: &Transaction, height: i32, datetime: u64) {
         let mut total_transparent_spend: u64 = 0;
@@ -1085,12 +2395,6 @@ impl LightWallet {
             // If it can, then we sent this transaction, so we should be able to get
             // the memo and value for our records
 
-            // First, collect all our z addresses, to check for change
-            // Collect z addresses
-            let z_addresses = self.zaddress.read().unwrap().iter().map( |ad| {
-                encode_payment_address(self.config.hrp_sapling_address(), &ad)
-            }).collect::<HashSet<String>>();
-
             // Search all ovks that we have
             let ovks: Vec<_> = self.extfvks.read().unwrap().iter().map(
                 |extfvk| extfvk.fvk.ovk.clone()
@@ -1108,7 +2412,7 @@ impl LightWallet {
                                             &payment_address);
 
                             // Check if this is a change address
-                            if z_addresses.contains(&address) {
+                            if self.is_own_zaddress(&payment_address) {
                                 continue;
                             }
 
@@ -1146,6 +2450,20 @@ impl LightWallet {
                 None => {},
             };
         }
+
+        self.state_changed();
+    }
+
+    // Same as invalidate_block, but refuses to invalidate more than max_blocks blocks, 
+    // in which case nothing is changed.
+    pub fn invalidate_block_within(&self, at_height: i32, max_blocks: u64) -> Result<u64, String> {
+        let depth = self.blocks.read().unwrap().iter().filter(|b| b.height >= at_height).count() as u64;
+        if depth > max_blocks {
+            return Err(format!("Reorg at height {} would roll back {} blocks, more than the {} allowed", 
+                                at_height, depth, max_blocks));
+        }
+
+        Ok(self.invalidate_block(at_height))
     }
 
     // Invalidate all blocks including and after "at_height".
@@ -1203,12 +2521,44 @@ impl LightWallet {
                 }
             }
         }
+        self.state_changed();
         
         num_invalidated as u64
     }
 
+    /// Drop the witnesses of the notes that were spent more than `MAX_REORG` blocks ago. No reorg can unspend
+    /// them any more, so they are never needed again, but every scanned block would still add to them.
+    /// Returns how many witnesses were dropped.
+    pub fn prune_witnesses(&self) -> usize {
+        let last_height = self.last_scanned_height();
+        let mut txs = self.txs.write().unwrap();
+
+        let spend_heights = txs.values()
+            .map(|wtx| (wtx.txid, wtx.block))
+            .collect::<HashMap<TxId, i32>>();
+
+        let mut pruned = 0;
+        for nd in txs.values_mut().flat_map(|wtx| wtx.notes.iter_mut()) {
+            let final_spend = nd.spent
+                .and_then(|txid| spend_heights.get(&txid))
+                .map_or(false, |h| *h + (MAX_REORG as i32) < last_height);
+
+            if final_spend && !nd.witnesses.is_empty() {
+                pruned += nd.witnesses.len();
+                nd.witnesses.clear();
+            }
+        }
+
+        pruned
+    }
+
     // Scan a block. Will return an error with the block height that failed to scan
     pub fn scan_block(&self, block_bytes: &[u8]) -> Result<Vec<TxId>, i32> {
+        self.scan_block_with(block_bytes, &CpuScanner{})
+    }
+
+    /// Same as `scan_block`, but finds the wallet's notes with `scanner`
+    pub fn scan_block_with(&self, block_bytes: &[u8], scanner: &dyn NoteScanner) -> Result<Vec<TxId>, i32> {
         let block: CompactBlock = match parse_from_bytes(block_bytes) {
             Ok(block) => block,
             Err(e) => {
@@ -1308,7 +2658,7 @@ impl LightWallet {
                     .flatten()
                     .collect();
 
-                scan_block(
+                scanner.scan_block(
                     block.clone(),
                     &self.extfvks.read().unwrap(),
                     &nf_refs[..],
@@ -1382,8 +2732,10 @@ impl LightWallet {
                     None => {}
                 }
 
-                match tx_entry.notes.iter().find(|nd| nd.nullifier == new_note.nullifier) {
+                match tx_entry.notes.iter_mut().find(|nd| nd.nullifier == new_note.nullifier) {
                     None => tx_entry.notes.push(new_note),
+                    // A note imported from JSON gets its witnesses back
+                    Some(nd) if nd.witnesses.is_empty() => nd.witnesses = new_note.witnesses,
                     Some(_) => warn!("Tried to insert duplicate note for Tx {}", tx.txid)
                 };                
             }
@@ -1416,9 +2768,288 @@ impl LightWallet {
             }
         }
 
+        self.state_changed();
+
         Ok(all_txs)
     }
 
+    /// The verified notes a send of `target_value` (fee included) spends, in the order they are picked, along
+    /// with the address each one was received at
+    fn select_notes(&self, target_value: u64, anchor_offset: usize) -> Vec<(SpendableNote, Option<String>)> {
+        let hrp = self.config.hrp_sapling_address();
+
+        self.txs.read().unwrap().iter()
+            .flat_map(|(txid, tx)| tx.notes.iter().map(move |note| (*txid, note)))
+            .filter_map(|(txid, note)|
+                // Watch-only notes have no spending key
+                self.extsks.read().unwrap().get(note.account)
+                    .filter(|_| note.account < self.hd_zaddr_count())
+                    .and_then(|extsk| SpendableNote::from(txid, note, anchor_offset, extsk))
+                    .map(|spendable| (spendable, note))
+            )
+            .scan(0, |running_total, (spendable, note)| {
+                let value = spendable.note.value;
+                let ret = if *running_total < target_value {
+                    Some((spendable, LightWallet::note_address(hrp, note)))
+                } else {
+                    None
+                };
+                *running_total += value;
+                ret
+            })
+            .collect()
+    }
+
+    /// The utxos a send spends. All of them go into every send, to shield them.
+    /// The value of the utxos that aren't being spent by an unconfirmed transaction
+    pub fn spendable_tbalance(&self) -> u64 {
+        self.spendable_utxos().iter().map(|utxo| utxo.value).sum::<u64>()
+    }
+
+    fn spendable_utxos(&self) -> Vec<Utxo> {
+        self.get_utxos().into_iter()
+            .filter(|utxo| utxo.unconfirmed_spent.is_none()) // Remove any unconfirmed spends
+            .collect()
+    }
+
+    /// What sending to `tos` would spend, without building the transaction, and the ways the send would
+    /// weaken the wallet's privacy, so that they can be shown before the send is confirmed.
+    pub fn estimate_send(&self, tos: Vec<(&str, u64, Option<String>, usize)>) -> Result<SendEstimate, String> {
+        if tos.is_empty() {
+            return Err("Need at least one destination address".to_string());
+        }
+
+        let tos = self.resolve_send_max(tos)?;
+        let anchor_offset = match self.get_target_height_and_anchor_offset() {
+            Some((_, anchor_offset)) => anchor_offset,
+            None => return Err("Cannot send funds before scanning any blocks".to_string())
+        };
+
+        let total = tos.iter().map(|to| to.1).sum::<u64>();
+        let (shielded, transparent): (Vec<_>, Vec<_>) = tos.iter()
+            .partition(|to| LightWallet::is_shielded_address(&to.0.to_string(), &self.config));
+        let sapling_outputs = shielded.iter().map(|to| to.3).sum::<usize>();
+        LightWallet::check_sapling_outputs(sapling_outputs, 0)?;
+
+        let utxos = self.spendable_utxos();
+        let (notes, mut fee) = self.select_notes_and_fee(total, &utxos, transparent.len(), sapling_outputs, anchor_offset);
+
+        let selected = notes.iter().map(|(n, _)| n.note.value).sum::<u64>() + utxos.iter().map(|u| u.value).sum::<u64>();
+        if selected < total + fee {
+            return Err(format!("Insufficient verified funds (have {}, need {})", selected, total + fee));
+        }
+        let mut change = selected - total - fee;
+        self.check_dust_change(change)?;
+
+        let mut warnings = vec![];
+
+        if LightWallet::is_dust(change) {
+            warnings.push(format!("Adds the change of {} to the fee, since it is not more than the fee and would cost more to spend than it is worth", 
+                                  change));
+            fee += change;
+            change = 0;
+        }
+        LightWallet::check_sapling_outputs(sapling_outputs, change)?;
+
+        // Spending together what was received at different addresses shows they are the same wallet's
+        let zaddrs = notes.iter().filter_map(|(_, a)| a.clone()).collect::<HashSet<_>>();
+        let taddrs = utxos.iter().map(|u| u.address.clone()).collect::<HashSet<_>>();
+        if zaddrs.len() + taddrs.len() > 1 {
+            warnings.push(format!("Spends funds received at {} different addresses ({} shielded, {} transparent) together, which links those addresses to each other", 
+                                  zaddrs.len() + taddrs.len(), zaddrs.len(), taddrs.len()));
+        }
+
+        // Without fresh change addresses, the change goes back to the first spent note's address, or the
+        // default address when only utxos are spent
+        if change > 0 && !self.config.fresh_change_address {
+            let change_address = notes.first().and_then(|(_, a)| a.clone())
+                .unwrap_or_else(|| encode_payment_address(self.config.hrp_sapling_address(), &self.zaddress.read().unwrap()[0]));
+            warnings.push(format!("Sends the change back to {}, which was used before, so this transaction can be linked to the ones that paid it", 
+                                  change_address));
+        }
+
+        if !taddrs.is_empty() && !transparent.is_empty() {
+            warnings.push("Sends from transparent to transparent addresses, so both sides and the amounts are public".to_string());
+        }
+
+        Ok(SendEstimate { total, fee, change, notes: notes.len(), utxos: utxos.len(), warnings })
+    }
+
+    /// Refuse more than MAX_SAPLING_OUTPUTS shielded outputs in a transaction. Change that isn't dust is
+    /// one more, on top of the `sapling_outputs` to the recipients.
+    fn check_sapling_outputs(sapling_outputs: usize, change: u64) -> Result<(), String> {
+        if change > 0 && !LightWallet::is_dust(change) && sapling_outputs + 1 > MAX_SAPLING_OUTPUTS {
+            return Err(format!("Too many shielded outputs ({} and the change), a transaction can have at most {}",
+                               sapling_outputs, MAX_SAPLING_OUTPUTS));
+        }
+        if sapling_outputs > MAX_SAPLING_OUTPUTS {
+            return Err(format!("Too many shielded outputs ({}), a transaction can have at most {}", sapling_outputs, MAX_SAPLING_OUTPUTS));
+        }
+
+        Ok(())
+    }
+
+    /// Change of no more than the fee costs more to spend than it is worth
+    pub fn is_dust(change: u64) -> bool {
+        change > 0 && change <= u64::from(DEFAULT_FEE)
+    }
+
+    /// Refuse a send that would leave dust change, unless `donate_dust_to_fee` is set, in which case the dust is 
+    /// added to the fee instead of being sent as change.
+    fn check_dust_change(&self, change: u64) -> Result<(), String> {
+        if !LightWallet::is_dust(change) || self.config.donate_dust_to_fee {
+            return Ok(());
+        }
+
+        let fee = u64::from(DEFAULT_FEE);
+        Err(format!("{}: the send would leave {} as change, which is not more than the fee of {} and costs more to spend than it is worth. \
+                     Send {} more or {} less, or turn on donate_dust_to_fee to allow it",
+                    DUST_CHANGE, change, fee, change, fee + 1 - change))
+    }
+
+    /// Replace a `SEND_MAX` amount of the last recipient with all the verified funds that are left after
+    /// the other recipients and the fee. Sends without one are returned as they are.
+    pub fn resolve_send_max<'a>(&self, mut tos: Vec<(&'a str, u64, Option<String>, usize)>) 
+            -> Result<Vec<(&'a str, u64, Option<String>, usize)>, String> {
+        let others = match tos.split_last() {
+            Some((last, others)) if last.1 == SEND_MAX => others,
+            _ => {
+                if tos.iter().any(|to| to.1 == SEND_MAX) {
+                    return Err("Only the last recipient can be sent the max amount".to_string());
+                }
+                return Ok(tos);
+            }
+        };
+
+        if others.iter().any(|to| to.1 == SEND_MAX) {
+            return Err("Only the last recipient can be sent the max amount".to_string());
+        }
+
+        let anchor_offset = match self.get_target_height_and_anchor_offset() {
+            Some((_, anchor_offset)) => anchor_offset,
+            None => return Err("Cannot send funds before scanning any blocks".to_string())
+        };
+
+        // The same notes and utxos that sending can select from
+        let notes = self.select_notes(u64::max_value(), anchor_offset);
+        let utxos = self.spendable_utxos();
+        let available = notes.iter().map(|(n, _)| n.note.value).sum::<u64>()
+                        + utxos.iter().map(|utxo| utxo.value).sum::<u64>();
+
+        // All of them are spent, and nothing is left for a change output
+        let sapling_outputs = tos.iter()
+            .filter(|to| LightWallet::is_shielded_address(&to.0.to_string(), &self.config))
+            .map(|to| to.3)
+            .sum::<usize>();
+        let t_outputs = tos.iter().filter(|to| !LightWallet::is_shielded_address(&to.0.to_string(), &self.config)).count();
+        let fee = LightWallet::min_fee(LightWallet::actions(utxos.len(), t_outputs, notes.len(), sapling_outputs), 
+                                       self.config.min_fee_per_action);
+
+        let needed = others.iter().map(|to| to.1).sum::<u64>() + fee;
+        if available <= needed {
+            let e = format!("Nothing left to send to {} (have {} verified, the other recipients and the fee need {})", 
+                            tos.last().unwrap().0, available, needed);
+            error!("{}", e);
+            return Err(e);
+        }
+
+        tos.last_mut().unwrap().1 = available - needed;
+        Ok(tos)
+    }
+
+    /// The logical actions of a transaction, the way ZIP-317 counts them: the larger of its number of transparent
+    /// inputs and outputs, plus the larger of its number of sapling spends and outputs
+    pub fn logical_actions(tx: &Transaction) -> u64 {
+        LightWallet::actions(tx.vin.len(), tx.vout.len(), tx.shielded_spends.len(), tx.shielded_outputs.len())
+    }
+
+    /// The logical actions of a transaction with this many transparent inputs and outputs, and sapling spends
+    /// and outputs, see `logical_actions`
+    pub fn actions(t_inputs: usize, t_outputs: usize, spends: usize, outputs: usize) -> u64 {
+        (cmp::max(t_inputs, t_outputs) + cmp::max(spends, outputs)) as u64
+    }
+
+    /// Refuse a transaction that pays less than the network wants for its logical actions, and would be rejected
+    fn check_fee(&self, fee: u64, actions: u64) -> Result<(), String> {
+        let needed = LightWallet::min_fee(actions, self.config.min_fee_per_action);
+        if fee >= needed {
+            return Ok(());
+        }
+
+        Err(format!("{}: the network wants a fee of {} for a transaction with {} logical actions, but the fee is {}",
+                    FEE_TOO_LOW, needed, actions, fee))
+    }
+
+    /// The notes to spend on sending `total` to `t_outputs` transparent and `sapling_outputs` shielded outputs,
+    /// along with `utxos`, and the fee to pay. Every note spent can be another logical action, which can raise
+    /// the fee, so the notes are selected again until they cover the fee they make the transaction need.
+    fn select_notes_and_fee(&self, total: u64, utxos: &[Utxo], t_outputs: usize, sapling_outputs: usize, anchor_offset: usize)
+            -> (Vec<(SpendableNote, Option<String>)>, u64) {
+        let utxo_value = utxos.iter().map(|utxo| utxo.value).sum::<u64>();
+
+        let mut fee = u64::from(DEFAULT_FEE);
+        loop {
+            let notes = self.select_notes(total + fee, anchor_offset);
+            let selected = notes.iter().map(|(n, _)| n.note.value).sum::<u64>() + utxo_value;
+
+            // Any change is one more output
+            let change_outputs = if selected > total + fee { 1 } else { 0 };
+            let actions = LightWallet::actions(utxos.len(), t_outputs, notes.len(), sapling_outputs + change_outputs);
+            let needed = LightWallet::min_fee(actions, self.config.min_fee_per_action);
+            if needed <= fee {
+                return (notes, fee);
+            }
+            fee = needed;
+        }
+    }
+
+    /// The lowest fee the network takes for a transaction with `actions` logical actions, when it wants 
+    /// `fee_per_action` for each of them. Never less than DEFAULT_FEE.
+    pub fn min_fee(actions: u64, fee_per_action: u64) -> u64 {
+        cmp::max(u64::from(DEFAULT_FEE), fee_per_action * cmp::max(actions, GRACE_ACTIONS))
+    }
+
+    /// The fee per logical action that `fee` works out to
+    pub fn fee_rate(fee: u64, actions: u64) -> u64 {
+        fee / cmp::max(actions, GRACE_ACTIONS)
+    }
+
+    /// The fee `tx` pays, if all of its transparent inputs are the wallet's, so that their values are known
+    pub fn tx_fee(&self, tx: &Transaction) -> Option<u64> {
+        let txs = self.txs.read().unwrap();
+
+        let mut transparent_in = 0;
+        for vin in tx.vin.iter() {
+            let txid = TxId {0: vin.prevout.hash};
+            let utxo = txs.get(&txid)?.utxos.iter().find(|u| u.output_index == (vin.prevout.n as u64))?;
+            transparent_in += utxo.value as i64;
+        }
+        let transparent_out = tx.vout.iter().map(|o| i64::from(o.value)).sum::<i64>();
+
+        let fee = transparent_in - transparent_out + i64::from(tx.value_balance);
+        if fee < 0 { None } else { Some(fee as u64) }
+    }
+
+    /// Split `value` into `count` roughly equal parts that add up to `value`. The remainder is 
+    /// spread over the first parts, one zatoshi each. Every part has to be larger than the fee,
+    /// otherwise the resulting notes would cost more to spend than they are worth. 
+    pub fn split_value(value: u64, count: usize) -> Result<Vec<u64>, String> {
+        if count == 0 || count > MAX_SPLIT_OUTPUTS {
+            return Err(format!("Can only split into 1 to {} outputs, not {}", MAX_SPLIT_OUTPUTS, count));
+        }
+
+        let base      = value / count as u64;
+        let remainder = value % count as u64;
+
+        let fee = u64::from(DEFAULT_FEE);
+        if count > 1 && base <= fee {
+            return Err(format!("Splitting {} into {} outputs would create notes of {}, which is not more than the fee of {}", 
+                                value, count, base, fee));
+        }
+
+        Ok((0..count as u64).map(|i| if i < remainder { base + 1 } else { base }).collect())
+    }
+
     pub fn send_to_address(
         &self,
         consensus_branch_id: u32,
@@ -1426,7 +3057,20 @@ impl LightWallet {
         output_params: &[u8],
         tos: Vec<(&str, u64, Option<String>)>
     ) -> Result<Box<[u8]>, String> {
-        if !self.unlocked {
+        let tos = tos.into_iter().map(|(a, v, m)| (a, v, m, 1)).collect();
+        self.send_to_address_with_splits(consensus_branch_id, spend_params, output_params, tos)
+    }
+
+    /// Same as `send_to_address`, but each recipient also has a number of outputs the amount is 
+    /// split across. The amount is sent to a shielded address as that many roughly equal notes.
+    pub fn send_to_address_with_splits(
+        &self,
+        consensus_branch_id: u32,
+        spend_params: &[u8],
+        output_params: &[u8],
+        tos: Vec<(&str, u64, Option<String>, usize)>
+    ) -> Result<Box<[u8]>, String> {
+        if !self.unlocked_for_spending {
             return Err("Cannot spend while wallet is locked".to_string());
         }
 
@@ -1435,6 +3079,8 @@ impl LightWallet {
             return Err("Need at least one destination address".to_string());
         }
 
+        let tos = self.resolve_send_max(tos)?;
+
         // Check for duplicates in the to list
         if tos.len() > 1 {
             let mut to_addresses = tos.iter().map(|t| t.0.to_string()).collect::<Vec<_>>();
@@ -1452,7 +3098,8 @@ impl LightWallet {
             total_value, tos.len()
         );
 
-        // Convert address (str) to RecepientAddress and value to Amount
+        // Convert address (str) to RecepientAddress and value to Amount, splitting the value
+        // into several outputs if asked to
         let recepients = tos.iter().map(|to| {
             let ra = match address::RecipientAddress::from_str(to.0, 
                             self.config.hrp_sapling_address(), 
@@ -1460,16 +3107,55 @@ impl LightWallet {
                             self.config.base58_script_address()) {
                 Some(to) => to,
                 None => {
-                    let e = format!("Invalid recipient address: '{}'", to.0);
+                    // Every recipient has to be on the wallet's chain, so a send can't mix chains either
+                    let chains = LightWallet::address_chains(to.0);
+                    let e = if chains.is_empty() {
+                        format!("Invalid recipient address: '{}'", to.0)
+                    } else {
+                        format!("Recipient address '{}' is for {}, but the wallet is on {}", to.0, chains.join(" or "), self.config.chain_name)
+                    };
                     error!("{}", e);
                     return Err(e);
                 }
             };
 
-            let value = Amount::from_u64(to.1).unwrap();
+            if to.3 > 1 {
+                if let address::RecipientAddress::Transparent(_) = ra {
+                    let e = format!("Can only split outputs to a shielded address, not '{}'", to.0);
+                    error!("{}", e);
+                    return Err(e);
+                }
+            }
 
-            Ok((ra, value, to.2.clone()))
-        }).collect::<Result<Vec<(address::RecipientAddress, Amount, Option<String>)>, String>>()?;
+            let values = LightWallet::split_value(to.1, to.3)?;
+
+            // Compute memo if it exists
+            let memo = match &to.2 {
+                None    => None,
+                Some(s) => match Memo::from_str(s) {
+                    None => {
+                        let e = format!("Error creating output. Memo {:?} is too long", s);
+                        error!("{}", e);
+                        return Err(e);
+                    },
+                    Some(m) => Some(m)
+                }
+            };
+
+            Ok(values.into_iter().map(|v| (ra.clone(), Amount::from_u64(v).unwrap(), memo.clone())).collect::<Vec<_>>())
+        }).collect::<Result<Vec<Vec<(address::RecipientAddress, Amount, Option<Memo>)>>, String>>()?
+          .into_iter()
+          .flatten()
+          .collect::<Vec<_>>();
+
+        let sapling_outputs = recepients.iter().filter(|(ra, _, _)| match ra {
+            address::RecipientAddress::Shielded(_) => true,
+            _ => false
+        }).count();
+        if let Err(e) = LightWallet::check_sapling_outputs(sapling_outputs, 0) {
+            error!("{}", e);
+            return Err(e);
+        }
 
         // Target the next block, assuming we are up-to-date.
         let (height, anchor_offset) = match self.get_target_height_and_anchor_offset() {
@@ -1481,39 +3167,309 @@ impl LightWallet {
             }
         };
 
-        // Select notes to cover the target value
-        println!("{}: Selecting notes", now() - start_time);
-        let target_value = Amount::from_u64(total_value).unwrap() + DEFAULT_FEE ;
-        let notes: Vec<_> = self.txs.read().unwrap().iter()
-            .map(|(txid, tx)| tx.notes.iter().map(move |note| (*txid, note)))
-            .flatten()
-            .filter_map(|(txid, note)|
-                SpendableNote::from(txid, note, anchor_offset, &self.extsks.read().unwrap()[note.account])
-            )
-            .scan(0, |running_total, spendable| {
-                let value = spendable.note.value;
-                let ret = if *running_total < u64::from(target_value) {
-                    Some(spendable)
-                } else {
-                    None
-                };
-                *running_total = *running_total + value;
-                ret
-            })
-            .collect();
-
-        let mut builder = Builder::new(height);
-
         // A note on t addresses
         // Funds received by t-addresses can't be explicitly spent in ZecWallet. 
         // ZecWallet will lazily consolidate all t address funds into your shielded addresses. 
         // Specifically, if you send an outgoing transaction that is sent to a shielded address,
         // ZecWallet will add all your t-address funds into that transaction, and send them to your shielded
         // address as change.
-        let tinputs: Vec<_> = self.get_utxos().iter()
-                                .filter(|utxo| utxo.unconfirmed_spent.is_none()) // Remove any unconfirmed spends
-                                .map(|utxo| utxo.clone())
-                                .collect();
+        let tinputs = self.spendable_utxos();
+
+        // Select notes to cover the target value, and the fee for the logical actions that makes
+        println!("{}: Selecting notes", now() - start_time);
+        let t_outputs = recepients.len() - sapling_outputs;
+        let (notes, mut fee) = self.select_notes_and_fee(total_value, &tinputs, t_outputs, sapling_outputs, anchor_offset);
+        let notes = notes.into_iter()
+                        .map(|(spendable, _)| spendable)
+                        .collect::<Vec<_>>();
+        let target_value = Amount::from_u64(total_value + fee).unwrap();
+
+        // Confirm we were able to select sufficient value
+        let selected_value = notes.iter().map(|selected| selected.note.value).sum::<u64>() 
+                             + tinputs.iter().map::<u64, _>(|utxo| utxo.value.into()).sum::<u64>();
+
+        if selected_value < u64::from(target_value) {
+            // See if the funds are there, but are being spent by a tx that hasn't been mined yet
+            let pending_value = self.txs.read().unwrap().values()
+                .map(|tx| {
+                    tx.notes.iter()
+                        .filter(|nd| nd.spent.is_none() && nd.unconfirmed_spent.is_some())
+                        .map(|nd| nd.note.value)
+                        .sum::<u64>()
+                    + tx.utxos.iter()
+                        .filter(|utxo| utxo.spent.is_none() && utxo.unconfirmed_spent.is_some())
+                        .map(|utxo| utxo.value)
+                        .sum::<u64>()
+                })
+                .sum::<u64>();
+
+            // Or the funds are there, but in addresses the wallet can only watch
+            let hd = self.hd_zaddr_count();
+            let watch_only_value = self.txs.read().unwrap().values()
+                .flat_map(|tx| tx.notes.iter())
+                .filter(|nd| nd.account >= hd && nd.spent.is_none() && nd.unconfirmed_spent.is_none())
+                .map(|nd| nd.note.value)
+                .sum::<u64>();
+
+            let e = if watch_only_value > 0 && selected_value + watch_only_value >= u64::from(target_value) {
+                format!(
+                    "Insufficient spendable funds (have {}, need {:?}): {} is in watch-only addresses, which can't be spent from",
+                    selected_value, target_value, watch_only_value
+                )
+            } else if pending_value > 0 && selected_value + pending_value >= u64::from(target_value) {
+                format!(
+                    "Insufficient verified funds (have {}, need {:?}): {} is locked by a pending transaction. Please wait for it to be confirmed.",
+                    selected_value, target_value, pending_value
+                )
+            } else {
+                format!(
+                    "Insufficient verified funds (have {}, need {:?}). NOTE: funds need {} confirmations before they can be spent.",
+                    selected_value, target_value, self.config.anchor_offset + 1
+                )
+            };
+            error!("{}", e);
+            return Err(e);
+        }
+
+        let change = selected_value - u64::from(target_value);
+        if let Err(e) = self.check_dust_change(change) {
+            error!("{}", e);
+            return Err(e);
+        }
+
+        // Dust change goes to the fee, so the builder doesn't create a note for it
+        if LightWallet::is_dust(change) {
+            info!("Adding the dust change of {} to the fee", change);
+            fee += change;
+        }
+
+        if let Err(e) = LightWallet::check_sapling_outputs(sapling_outputs, change) {
+            error!("{}", e);
+            return Err(e);
+        }
+
+        let tx = self.build_transaction(consensus_branch_id, spend_params, output_params, height, &notes, &tinputs, recepients, fee)?;
+
+        // Mark notes as spent.
+        {
+            // Mark sapling notes as unconfirmed spent
+            let mut txs = self.txs.write().unwrap();
+            for selected in notes {
+                let mut spent_note = txs.get_mut(&selected.txid).unwrap()
+                                        .notes.iter_mut()
+                                        .find(|nd| &nd.nullifier[..] == &selected.nullifier[..])
+                                        .unwrap();
+                spent_note.unconfirmed_spent = Some(tx.txid());
+            }
+
+            // Mark this utxo as unconfirmed spent
+            for utxo in tinputs {
+                let mut spent_utxo = txs.get_mut(&utxo.txid).unwrap().utxos.iter_mut()
+                                        .find(|u| utxo.txid == u.txid && utxo.output_index == u.output_index)
+                                        .unwrap();
+                spent_utxo.unconfirmed_spent = Some(tx.txid());
+            }
+        }
+
+        // Add this Tx to the mempool structure
+        {
+            let mut mempool_txs = self.mempool_txs.write().unwrap();
+
+            match mempool_txs.get_mut(&tx.txid()) {
+                None => {
+                    // Collect the outgoing metadata
+                    let outgoing_metadata = tos.iter().map(|(addr, amt, maybe_memo, _)| {
+                        OutgoingTxMetadata {
+                            address: addr.to_string(),
+                            value: *amt,
+                            memo: match maybe_memo {
+                                None    => Memo::default(),
+                                Some(s) => {
+                                    // If the address is not a z-address, then drop the memo
+                                    if LightWallet::is_shielded_address(&addr.to_string(), &self.config) {
+                                            Memo::from_str(s).unwrap()
+                                    } else {
+                                        Memo::default()
+                                    }                                        
+                                }
+                            },
+                        }
+                    }).collect::<Vec<_>>();
+
+                    // Create a new WalletTx
+                    let mut wtx = WalletTx::new(height as i32, now() as u64, &tx.txid());
+                    wtx.outgoing_metadata = outgoing_metadata;
+                    wtx.fee = Some(fee);
+
+                    // Add it into the mempool 
+                    mempool_txs.insert(tx.txid(), wtx);
+                },
+                Some(_) => {
+                    warn!("A newly created Tx was already in the mempool! How's that possible? Txid: {}", tx.txid());
+                }
+            }
+        }
+
+        self.state_changed();
+
+        // Return the encoded transaction, so the caller can send it.
+        let mut raw_tx = vec![];
+        tx.write(&mut raw_tx).unwrap();
+        Ok(raw_tx.into_boxed_slice())
+    }
+
+    /// Build a transaction that replaces the pending transaction `txid`, which this wallet sent: it pays the same
+    /// recipients out of the same notes and utxos, with a fee of `new_fee`, which comes out of the change. Since
+    /// both spend the same notes, at most one of them can be mined. A payment that was split over several notes
+    /// is paid with one. Returns the transaction and its fee, which includes change too small to keep. Nothing
+    /// in the wallet changes, see `replace_pending_tx`.
+    pub fn build_fee_bump(
+        &self,
+        consensus_branch_id: u32,
+        spend_params: &[u8],
+        output_params: &[u8],
+        txid: &TxId,
+        new_fee: u64
+    ) -> Result<(Transaction, u64), String> {
+        if !self.unlocked_for_spending {
+            return Err("Cannot spend while wallet is locked".to_string());
+        }
+
+        if self.txs.read().unwrap().contains_key(txid) {
+            return Err(format!("Transaction {} is already confirmed, so its fee can't be bumped", txid));
+        }
+
+        let (outputs, old_fee) = match self.mempool_txs.read().unwrap().get(txid) {
+            Some(wtx) => (wtx.outgoing_metadata.iter().map(|om| (om.address.clone(), om.value, om.memo.clone())).collect::<Vec<_>>(), wtx.fee),
+            None      => return Err(format!("{} isn't a pending transaction sent from this wallet", txid))
+        };
+        let old_fee = old_fee.ok_or(format!("The fee of {} isn't known, so it can't be bumped", txid))?;
+        if new_fee <= old_fee {
+            return Err(format!("The new fee has to be more than the {} that {} pays", old_fee, txid));
+        }
+
+        let (height, anchor_offset) = self.get_target_height_and_anchor_offset()
+            .ok_or("Cannot send funds before scanning any blocks".to_string())?;
+
+        // The notes and utxos the transaction spends
+        let notes = {
+            let extsks = self.extsks.read().unwrap();
+            self.txs.read().unwrap().iter()
+                .flat_map(|(note_txid, tx)| tx.notes.iter().map(move |nd| (*note_txid, nd)))
+                .filter(|(_, nd)| nd.unconfirmed_spent == Some(*txid))
+                .map(|(note_txid, nd)| extsks.get(nd.account)
+                    .and_then(|extsk| SpendableNote::respend(note_txid, nd, anchor_offset, extsk))
+                    .ok_or(format!("A note that {} spends can't be spent again", txid)))
+                .collect::<Result<Vec<_>, String>>()?
+        };
+        let tinputs = self.get_utxos().into_iter()
+            .filter(|utxo| utxo.unconfirmed_spent == Some(*txid))
+            .collect::<Vec<_>>();
+        if notes.is_empty() && tinputs.is_empty() {
+            return Err(format!("Nothing that {} spends is pending anymore", txid));
+        }
+
+        let total_value = outputs.iter().map(|(_, value, _)| *value).sum::<u64>();
+        let recepients = outputs.into_iter().map(|(address, value, memo)| {
+            let ra = address::RecipientAddress::from_str(&address,
+                            self.config.hrp_sapling_address(),
+                            self.config.base58_pubkey_address(),
+                            self.config.base58_script_address())
+                .ok_or(format!("Invalid recipient address: '{}'", address))?;
+
+            Ok((ra, Amount::from_u64(value).unwrap(), Some(memo)))
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        let sapling_outputs = recepients.iter().filter(|(ra, _, _)| match ra {
+            address::RecipientAddress::Shielded(_) => true,
+            _ => false
+        }).count();
+
+        let selected_value = notes.iter().map(|selected| selected.note.value).sum::<u64>()
+                             + tinputs.iter().map(|utxo| utxo.value).sum::<u64>();
+        if selected_value < total_value + new_fee {
+            let e = format!("{} only has {} left over for the fee, which isn't enough for a fee of {}",
+                            txid, selected_value - total_value, new_fee);
+            error!("{}", e);
+            return Err(e);
+        }
+
+        let change = selected_value - total_value - new_fee;
+        if let Err(e) = self.check_dust_change(change) {
+            error!("{}", e);
+            return Err(e);
+        }
+
+        // Dust change goes to the fee, so the builder doesn't create a note for it
+        let mut fee = new_fee;
+        if LightWallet::is_dust(change) {
+            info!("Adding the dust change of {} to the fee", change);
+            fee += change;
+        }
+
+        if let Err(e) = LightWallet::check_sapling_outputs(sapling_outputs, change) {
+            error!("{}", e);
+            return Err(e);
+        }
+
+        let tx = self.build_transaction(consensus_branch_id, spend_params, output_params, height, &notes, &tinputs, recepients, fee)?;
+        Ok((tx, fee))
+    }
+
+    /// Replace the pending transaction `txid` with `tx` from `build_fee_bump`, once `tx` is broadcast. The notes 
+    /// and utxos that `txid` spent are pending in `tx` instead, and `txid` isn't pending anymore, so that the two
+    /// aren't both listed.
+    pub fn replace_pending_tx(&self, txid: &TxId, tx: &Transaction, fee: u64) {
+        let new_txid = tx.txid();
+
+        for wtx in self.txs.write().unwrap().values_mut() {
+            for nd in wtx.notes.iter_mut().filter(|nd| nd.unconfirmed_spent == Some(*txid)) {
+                nd.unconfirmed_spent = Some(new_txid);
+            }
+            for utxo in wtx.utxos.iter_mut().filter(|utxo| utxo.unconfirmed_spent == Some(*txid)) {
+                utxo.unconfirmed_spent = Some(new_txid);
+            }
+        }
+
+        {
+            let mut mempool_txs = self.mempool_txs.write().unwrap();
+            if let Some(mut wtx) = mempool_txs.remove(txid) {
+                // Expires counting from the block it was built for, like the original did
+                if let Some((height, _)) = self.get_target_height_and_anchor_offset() {
+                    wtx.block = height as i32;
+                }
+                wtx.txid = new_txid;
+                wtx.datetime = now() as u64;
+                wtx.fee = Some(fee);
+                mempool_txs.insert(new_txid, wtx);
+            }
+        }
+
+        // A send in a fiat currency keeps the rate it was converted at
+        let rate = self.fiat_rates.write().unwrap().remove(txid);
+        if let Some(rate) = rate {
+            self.set_fiat_rate(&new_txid, rate);
+        }
+
+        self.state_changed();
+    }
+
+    /// Build the transaction for the next block, `height`, that spends `notes` and `tinputs`, pays `recepients`
+    /// and `fee`, and sends what is left to the wallet as change. Fails if the fee doesn't cover the logical
+    /// actions the transaction ends up with. Nothing in the wallet changes.
+    fn build_transaction(
+        &self,
+        consensus_branch_id: u32,
+        spend_params: &[u8],
+        output_params: &[u8],
+        height: u32,
+        notes: &[SpendableNote],
+        tinputs: &[Utxo],
+        recepients: Vec<(address::RecipientAddress, Amount, Option<Memo>)>,
+        fee: u64
+    ) -> Result<Transaction, String> {
+        let start_time = now();
+        let mut builder = Builder::new(height);
         
         // Create a map from address -> sk for all taddrs, so we can spend from the 
         // right address
@@ -1545,20 +3501,8 @@ impl LightWallet {
             })
             .collect::<Result<Vec<_>, _>>()
             .map_err(|e| format!("{}", e))?;
-        
 
-        // Confirm we were able to select sufficient value
-        let selected_value = notes.iter().map(|selected| selected.note.value).sum::<u64>() 
-                             + tinputs.iter().map::<u64, _>(|utxo| utxo.value.into()).sum::<u64>();
-
-        if selected_value < u64::from(target_value) {
-            let e = format!(
-                "Insufficient verified funds (have {}, need {:?}). NOTE: funds need {} confirmations before they can be spent.",
-                selected_value, target_value, self.config.anchor_offset + 1
-            );
-            error!("{}", e);
-            return Err(e);
-        }
+        builder.set_fee(Amount::from_u64(fee).unwrap());
 
         // Create the transaction
         println!("{}: Adding {} notes and {} utxos", now() - start_time, notes.len(), tinputs.len());
@@ -1576,10 +3520,15 @@ impl LightWallet {
             }
         }
 
-        // If no Sapling notes were added, add the change address manually. That is,
+        // Send the change to a fresh address, so that the wallet's sends can't be linked by their change.
+        // Otherwise, if no Sapling notes were added, add the change address manually. That is,
         // send the change to our sapling address manually. Note that if a sapling note was spent,
         // the builder will automatically send change to that address
-        if notes.len() == 0 {
+        if self.config.fresh_change_address {
+            builder.send_change_to(
+                ExtendedFullViewingKey::from(&self.extsks.read().unwrap()[0]).fvk.ovk,
+                self.fresh_change_address());
+        } else if notes.len() == 0 {
             builder.send_change_to(
                 ExtendedFullViewingKey::from(&self.extsks.read().unwrap()[0]).fvk.ovk,
                 self.extsks.read().unwrap()[0].default_address().unwrap().1);
@@ -1589,24 +3538,11 @@ impl LightWallet {
         let ovk = self.extfvks.read().unwrap()[0].fvk.ovk;
 
         for (to, value, memo) in recepients {
-            // Compute memo if it exists
-            let encoded_memo = match memo {
-                None => None,
-                Some(s) => match Memo::from_str(&s) {
-                    None => {
-                        let e = format!("Error creating output. Memo {:?} is too long", s);
-                        error!("{}", e);
-                        return Err(e);
-                    },
-                    Some(m) => Some(m)
-                }
-            };
-            
             println!("{}: Adding output", now() - start_time);
 
             if let Err(e) = match to {
                 address::RecipientAddress::Shielded(to) => {
-                    builder.add_sapling_output(ovk, to.clone(), value, encoded_memo)
+                    builder.add_sapling_output(ovk, to.clone(), value, memo)
                 }
                 address::RecipientAddress::Transparent(to) => {
                     builder.add_transparent_output(&to, value)
@@ -1634,69 +3570,13 @@ impl LightWallet {
         println!("{}: Transaction created", now() - start_time);
         println!("Transaction ID: {}", tx.txid());
 
-        // Mark notes as spent.
-        {
-            // Mark sapling notes as unconfirmed spent
-            let mut txs = self.txs.write().unwrap();
-            for selected in notes {
-                let mut spent_note = txs.get_mut(&selected.txid).unwrap()
-                                        .notes.iter_mut()
-                                        .find(|nd| &nd.nullifier[..] == &selected.nullifier[..])
-                                        .unwrap();
-                spent_note.unconfirmed_spent = Some(tx.txid());
-            }
-
-            // Mark this utxo as unconfirmed spent
-            for utxo in tinputs {
-                let mut spent_utxo = txs.get_mut(&utxo.txid).unwrap().utxos.iter_mut()
-                                        .find(|u| utxo.txid == u.txid && utxo.output_index == u.output_index)
-                                        .unwrap();
-                spent_utxo.unconfirmed_spent = Some(tx.txid());
-            }
-        }
-
-        // Add this Tx to the mempool structure
-        {
-            let mut mempool_txs = self.mempool_txs.write().unwrap();
-
-            match mempool_txs.get_mut(&tx.txid()) {
-                None => {
-                    // Collect the outgoing metadata
-                    let outgoing_metadata = tos.iter().map(|(addr, amt, maybe_memo)| {
-                        OutgoingTxMetadata {
-                            address: addr.to_string(),
-                            value: *amt,
-                            memo: match maybe_memo {
-                                None    => Memo::default(),
-                                Some(s) => {
-                                    // If the address is not a z-address, then drop the memo
-                                    if LightWallet::is_shielded_address(&addr.to_string(), &self.config) {
-                                            Memo::from_str(s).unwrap()
-                                    } else {
-                                        Memo::default()
-                                    }                                        
-                                }
-                            },
-                        }
-                    }).collect::<Vec<_>>();
-
-                    // Create a new WalletTx
-                    let mut wtx = WalletTx::new(height as i32, now() as u64, &tx.txid());
-                    wtx.outgoing_metadata = outgoing_metadata;
-
-                    // Add it into the mempool 
-                    mempool_txs.insert(tx.txid(), wtx);
-                },
-                Some(_) => {
-                    warn!("A newly created Tx was already in the mempool! How's that possible? Txid: {}", tx.txid());
-                }
-            }
+        // Make sure the fee covers the actions the transaction really has, so the network doesn't reject it
+        if let Err(e) = self.check_fee(fee, LightWallet::logical_actions(&tx)) {
+            error!("{}", e);
+            return Err(e);
         }
 
-        // Return the encoded transaction, so the caller can send it.
-        let mut raw_tx = vec![];
-        tx.write(&mut raw_tx).unwrap();
-        Ok(raw_tx.into_boxed_slice())
+        Ok(tx)
     }
 
     // After some blocks have been mined, we need to remove the Txns from the mempool_tx structure
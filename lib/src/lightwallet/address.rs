@@ -6,6 +6,7 @@ use zcash_client_backend::encoding::{decode_payment_address, decode_transparent_
 use zcash_primitives::legacy::TransparentAddress;
 
 /// An address that funds can be sent to.
+#[derive(Clone)]
 pub enum RecipientAddress {
     Shielded(PaymentAddress<Bls12>),
     Transparent(TransparentAddress),
@@ -0,0 +1,199 @@
+///
+/// A portable, password protected backup of the things that can't be recovered from the blockchain: the seed, the
+/// imported viewing keys, the accounts and the birthday. The scan state (blocks, witnesses and transactions) is not
+/// in it, it is rebuilt by rescanning from the birthday after restoring.
+///
+/// The file is a header followed by the encrypted contents. All integers are little endian.
+///
+/// | offset | size | field                                                                  |
+/// |--------|------|------------------------------------------------------------------------|
+/// | 0      | 8    | magic, the ASCII bytes `YECBACKP`                                      |
+/// | 8      | 1    | format version, currently 1                                            |
+/// | 9      | 8    | Argon2id opslimit (u64)                                                |
+/// | 17     | 8    | Argon2id memlimit in bytes (u64)                                       |
+/// | 25     | 16   | Argon2id salt                                                          |
+/// | 41     | 24   | XChaCha20-Poly1305 nonce                                               |
+/// | 65     | rest | XChaCha20-Poly1305 ciphertext, with the 16 byte tag at the end         |
+///
+/// The 32 byte key is derived from the UTF-8 password with Argon2id v1.3, using the opslimit, memlimit and salt from
+/// the header (libsodium's `crypto_pwhash` with `crypto_pwhash_ALG_ARGON2ID13`). The contents are encrypted with
+/// XChaCha20-Poly1305 (IETF, libsodium's `crypto_aead_xchacha20poly1305_ietf`), and the 65 header bytes are its
+/// associated data, so changing any of them makes decryption fail just like a wrong password does.
+///
+/// The decrypted contents are a UTF-8 JSON object:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "chain_name": "main",
+///   "seed": "<24 word bip39 seed phrase>",
+///   "birthday": 600000,
+///   "accounts": [ { "name": "default", "zaddrs": 1, "taddrs": 1 }, { "name": "savings", "zaddrs": 1, "taddrs": 1 } ],
+///   "viewing_keys": [ "<bech32 extended full viewing key>" ]
+/// }
+/// ```
+///
+/// `accounts` lists the default account first, and then the others in the order they were added, which is their
+/// account number. `zaddrs` and `taddrs` are how many addresses were derived in the account; they are derived again
+/// at the same positions when restoring. `viewing_keys` are the imported watch-only keys.
+
+use json::{object, JsonValue};
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::pwhash::argon2id13;
+
+use super::secret::Secret;
+
+const MAGIC: &[u8; 8] = b"YECBACKP";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8 + 1 + 8 + 8 + argon2id13::SALTBYTES + aead::NONCEBYTES;
+
+/// An account in a backup, with the number of addresses derived in it
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupAccount {
+    pub name:   String,
+    pub zaddrs: u32,
+    pub taddrs: u32,
+}
+
+/// The decrypted contents of a backup
+#[derive(Debug, PartialEq)]
+pub struct WalletBackup {
+    pub chain_name:   String,
+    pub seed_phrase:  Secret<String>,
+    pub birthday:     u64,
+    pub accounts:     Vec<BackupAccount>,
+    pub viewing_keys: Vec<String>,
+}
+
+impl WalletBackup {
+    pub fn serialized_version() -> u64 {
+        1
+    }
+
+    /// Encrypt the backup with a key derived from `password`
+    pub fn encrypt(&self, password: &str) -> Result<Vec<u8>, String> {
+        let salt = argon2id13::gen_salt();
+        let nonce = aead::gen_nonce();
+        let (opslimit, memlimit) = (argon2id13::OPSLIMIT_INTERACTIVE, argon2id13::MEMLIMIT_INTERACTIVE);
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.push(FORMAT_VERSION);
+        header.extend_from_slice(&(opslimit.0 as u64).to_le_bytes());
+        header.extend_from_slice(&(memlimit.0 as u64).to_le_bytes());
+        header.extend_from_slice(&salt.0);
+        header.extend_from_slice(&nonce.0);
+
+        // The seed goes into the JSON by hand, so that it isn't copied into a JsonValue that isn't wiped. It is only
+        // lowercase words and spaces, so it needs no escaping.
+        if !self.seed_phrase.chars().all(|c| c.is_ascii_lowercase() || c == ' ') {
+            return Err("The seed phrase isn't valid".to_string());
+        }
+        let rest = self.to_json().dump();
+        let mut contents = Secret::new(String::with_capacity(rest.len() + self.seed_phrase.len() + 16));
+        contents.push_str("{\"seed\":\"");
+        contents.push_str(&self.seed_phrase);
+        contents.push_str("\",");
+        contents.push_str(&rest[1..]);
+
+        let key = derive_key(password, &salt, opslimit, memlimit)?;
+        let ciphertext = aead::seal(contents.as_bytes(), Some(&header[..]), &nonce, &key);
+
+        let mut blob = header;
+        blob.extend_from_slice(&ciphertext);
+        Ok(blob)
+    }
+
+    /// Decrypt a backup made by `encrypt`. A wrong password, or a backup that was changed in any way, is an error.
+    pub fn decrypt(blob: &[u8], password: &str) -> Result<Self, String> {
+        if blob.len() < HEADER_SIZE + aead::TAGBYTES || &blob[..8] != MAGIC {
+            return Err("This isn't a yecshell backup".to_string());
+        }
+
+        let (header, ciphertext) = blob.split_at(HEADER_SIZE);
+        if header[8] != FORMAT_VERSION {
+            return Err(format!("The backup is in format {}, which this version of yecshell can't read", header[8]));
+        }
+
+        let mut limit = [0u8; 8];
+        limit.copy_from_slice(&header[9..17]);
+        let opslimit = u64::from_le_bytes(limit);
+        limit.copy_from_slice(&header[17..25]);
+        let memlimit = u64::from_le_bytes(limit);
+
+        // Don't let a crafted header make us spend forever, or all the memory, deriving the key
+        if opslimit > argon2id13::OPSLIMIT_SENSITIVE.0 as u64 || memlimit > argon2id13::MEMLIMIT_SENSITIVE.0 as u64 {
+            return Err("The backup asks for more work to derive its key than yecshell allows".to_string());
+        }
+
+        let salt = argon2id13::Salt::from_slice(&header[25..25 + argon2id13::SALTBYTES]).unwrap();
+        let nonce = aead::Nonce::from_slice(&header[25 + argon2id13::SALTBYTES..]).unwrap();
+
+        let key = derive_key(password, &salt, argon2id13::OpsLimit(opslimit as usize), argon2id13::MemLimit(memlimit as usize))?;
+        let contents = aead::open(ciphertext, Some(header), &nonce, &key).map(Secret::new)
+            .map_err(|_| "Couldn't decrypt the backup. The password is wrong, or the file was changed".to_string())?;
+
+        let contents = std::str::from_utf8(&contents).map_err(|_| "The backup's contents aren't valid".to_string())?;
+        let json = json::parse(contents).map_err(|e| format!("The backup's contents aren't valid: {}", e))?;
+
+        WalletBackup::from_json(json)
+    }
+
+    /// Everything but the seed, which `encrypt` adds
+    fn to_json(&self) -> JsonValue {
+        let accounts = self.accounts.iter().map(|a| object!{
+            "name"   => a.name.clone(),
+            "zaddrs" => a.zaddrs,
+            "taddrs" => a.taddrs,
+        }).collect::<Vec<JsonValue>>();
+
+        object!{
+            "version"      => WalletBackup::serialized_version(),
+            "chain_name"   => self.chain_name.clone(),
+            "birthday"     => self.birthday,
+            "accounts"     => accounts,
+            "viewing_keys" => self.viewing_keys.clone(),
+        }
+    }
+
+    fn from_json(mut json: JsonValue) -> Result<Self, String> {
+        let invalid = |field: &str| format!("The backup's contents aren't valid, '{}' is missing or wrong", field);
+
+        match json["version"].as_u64() {
+            Some(v) if v <= WalletBackup::serialized_version() => {},
+            Some(v) => return Err(format!("The backup's contents are version {}, which this version of yecshell can't read", v)),
+            None    => return Err(invalid("version")),
+        }
+
+        let chain_name = json["chain_name"].as_str().ok_or_else(|| invalid("chain_name"))?.to_string();
+        // Moved out of the JsonValue, so that the only copy is wiped
+        let seed_phrase = Secret::new(json["seed"].take_string().ok_or_else(|| invalid("seed"))?);
+        let birthday = json["birthday"].as_u64().ok_or_else(|| invalid("birthday"))?;
+
+        let accounts = json["accounts"].members().map(|a| {
+            match (a["name"].as_str(), a["zaddrs"].as_u32(), a["taddrs"].as_u32()) {
+                (Some(name), Some(zaddrs), Some(taddrs)) => Ok(BackupAccount { name: name.to_string(), zaddrs, taddrs }),
+                _ => Err(invalid("accounts")),
+            }
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        if accounts.is_empty() {
+            return Err(invalid("accounts"));
+        }
+
+        let viewing_keys = json["viewing_keys"].members().map(|k| {
+            k.as_str().map(|k| k.to_string()).ok_or_else(|| invalid("viewing_keys"))
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        Ok(WalletBackup { chain_name, seed_phrase, birthday, accounts, viewing_keys })
+    }
+}
+
+fn derive_key(password: &str, salt: &argon2id13::Salt, opslimit: argon2id13::OpsLimit, memlimit: argon2id13::MemLimit)
+        -> Result<aead::Key, String> {
+    let mut key = aead::Key([0u8; aead::KEYBYTES]);
+    argon2id13::derive_key(&mut key.0, password.as_bytes(), salt, opslimit, memlimit)
+        .map_err(|_| "Couldn't derive the backup's key from the password".to_string())?;
+
+    Ok(key)
+}
@@ -10,11 +10,10 @@
 /// 2. Move any funds in these addresses to the first address
 /// 3. Re-derive the addresses
 
-use super::LightWallet;
+use super::{LightWallet, Accounts};
 use crate::lightclient::LightClient;
 
 use json::object;
-use bip39::{Mnemonic, Language};
 
 pub struct BugBip39Derivation {}
 
@@ -34,12 +33,14 @@ impl BugBip39Derivation {
 
         // The seed bytes is the raw entropy. To pass it to HD wallet generation, 
         // we need to get the 64 byte bip39 entropy
-        let bip39_seed = bip39::Seed::new(&Mnemonic::from_entropy(&wallet.seed, Language::English).unwrap(), "");
+        let bip39_seed = LightWallet::bip39_seed(&wallet.seed);
 
         // Check z addresses
-        for pos in 0..wallet.zaddress.read().unwrap().len() {
+        let accounts = wallet.accounts.read().unwrap();
+        for pos in 0..wallet.hd_zaddr_count() {
+            let (account, account_pos) = Accounts::derivation(&accounts.zaddrs, pos);
             let (_, _, address) =
-                LightWallet::get_zaddr_from_bip39seed(&wallet.config, &bip39_seed.as_bytes(), pos as u32);
+                LightWallet::get_zaddr_from_bip39seed(&wallet.config, &bip39_seed.as_bytes(), account, account_pos);
 
             if address != wallet.zaddress.read().unwrap()[pos] {
                 return true;
@@ -48,7 +49,8 @@ impl BugBip39Derivation {
 
         // Check t addresses
         for pos in 0..wallet.taddresses.read().unwrap().len() {
-            let sk = LightWallet::get_taddr_from_bip39seed(&wallet.config, &bip39_seed.as_bytes(), pos as u32);
+            let (account, account_pos) = Accounts::derivation(&accounts.taddrs, pos);
+            let sk = LightWallet::get_taddr_from_bip39seed(&wallet.config, &bip39_seed.as_bytes(), account, account_pos);
             let address = wallet.address_from_sk(&sk);
 
             if address != wallet.taddresses.read().unwrap()[pos] {
@@ -101,8 +103,12 @@ impl BugBip39Derivation {
 
         // regen addresses
         let wallet = client.wallet.read().unwrap();
-        let num_zaddrs = wallet.zaddress.read().unwrap().len();
-        let num_taddrs = wallet.taddresses.read().unwrap().len();
+        let num_zaddrs = wallet.hd_zaddr_count();
+
+        // The watch-only keys go back after the regenerated ones, so their notes keep their accounts
+        let watched = wallet.extfvks.read().unwrap()[num_zaddrs..].to_vec();
+        let watched_addresses = wallet.zaddress.read().unwrap()[num_zaddrs..].to_vec();
+        *wallet.watch_only.write().unwrap() = 0;
 
         wallet.extsks.write().unwrap().truncate(1);
         wallet.extfvks.write().unwrap().truncate(1);
@@ -111,14 +117,27 @@ impl BugBip39Derivation {
         wallet.tkeys.write().unwrap().truncate(1);
         wallet.taddresses.write().unwrap().truncate(1);
 
-        for _ in 1..num_zaddrs {
-            wallet.add_zaddr();
+        // Each address is derived again in the account it was in
+        let (zaddr_accounts, taddr_accounts) = {
+            let mut accounts = wallet.accounts.write().unwrap();
+            let all = (accounts.zaddrs.clone(), accounts.taddrs.clone());
+            accounts.zaddrs.truncate(1);
+            accounts.taddrs.truncate(1);
+            all
+        };
+
+        for account in zaddr_accounts.iter().skip(1) {
+            wallet.add_zaddr_to_account(*account);
         }
 
-        for _ in 1..num_taddrs {
-            wallet.add_taddr();
+        for account in taddr_accounts.iter().skip(1) {
+            wallet.add_taddr_to_account(*account);
         }
 
+        *wallet.watch_only.write().unwrap() = watched.len();
+        wallet.extfvks.write().unwrap().extend(watched);
+        wallet.zaddress.write().unwrap().extend(watched_addresses);
+
         let r = object!{
             "has_bug" => true,
             "fixed"   => true,
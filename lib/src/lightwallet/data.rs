@@ -22,6 +22,11 @@ use zcash_primitives::{
         fs::{Fs, FsRepr},
     }
 };
+
+use json::{object, JsonValue};
+use zcash_client_backend::encoding::encode_payment_address;
+
+use super::{utils, DEFAULT_ACCOUNT};
 use zcash_primitives::zip32::ExtendedSpendingKey;
 
 
@@ -42,7 +47,7 @@ impl BlockData {
 
         let endtag = reader.read_u64::<LittleEndian>()?;
         if endtag != 11 {
-            println!("End tag for blockdata {}", endtag);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Wrong end tag {} for block {}", endtag, height)));
         }
 
 
@@ -142,7 +147,9 @@ impl SaplingNoteData {
     // Reading a note also needs the corresponding address to read from.
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
         let version = reader.read_u64::<LittleEndian>()?;
-        assert_eq!(version, SaplingNoteData::serialized_version());
+        if version != SaplingNoteData::serialized_version() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown note version {}", version)));
+        }
 
         let account = reader.read_u64::<LittleEndian>()? as usize;
 
@@ -156,7 +163,8 @@ impl SaplingNoteData {
         // to recreate the note
         let (value, r) = read_note(&mut reader)?; // TODO: This method is in a different package, because of some fields that are private
 
-        let maybe_note = extfvk.fvk.vk.into_payment_address(diversifier, &JUBJUB).unwrap().create_note(value, r, &JUBJUB);
+        let maybe_note = extfvk.fvk.vk.into_payment_address(diversifier, &JUBJUB)
+            .and_then(|address| address.create_note(value, r, &JUBJUB));
 
         let note = match maybe_note {
             Some(n)  => Ok(n),
@@ -235,6 +243,76 @@ impl SaplingNoteData {
         Ok(())
     }
 
+    /// The nullifier that shows up on chain when this note is spent
+    pub fn nullifier(&self) -> [u8; 32] {
+        self.nullifier
+    }
+
+    /// The note as JSON, for `LightWallet::to_json`. Everything but the witnesses is in it.
+    pub fn to_json(&self, hrp_sapling_address: &str) -> JsonValue {
+        let address = self.extfvk.fvk.vk.into_payment_address(self.diversifier, &JUBJUB)
+            .map(|pa| encode_payment_address(hrp_sapling_address, &pa));
+
+        let mut rcm = [0; 32];
+        self.note.r.into_repr().write_le(&mut rcm[..]).unwrap();
+
+        object!{
+            "address_index"     => self.account,
+            "address"           => address,
+            "diversifier"       => hex::encode(self.diversifier.0),
+            "value"             => self.note.value,
+            "rcm"               => hex::encode(rcm),
+            "nullifier"         => hex::encode(self.nullifier),
+            "spent"             => self.spent.map(|t| format!("{}", t)),
+            "unconfirmed_spent" => self.unconfirmed_spent.map(|t| format!("{}", t)),
+            "memo"              => self.memo.as_ref().map(|m| hex::encode(&m.as_bytes()[..])),
+            "memo_text"         => self.memo.as_ref().and_then(|m| m.to_utf8()).and_then(|m| m.ok()),
+            "is_change"         => self.is_change,
+        }
+    }
+
+    /// Read a note written by `to_json`. `extfvks` are the wallet's viewing keys, which `address_index` is into.
+    /// It has no witnesses, until the wallet is rescanned.
+    pub fn from_json(json: &JsonValue, extfvks: &[ExtendedFullViewingKey]) -> io::Result<Self> {
+        let account = utils::json_u64(json, "address_index")? as usize;
+        let extfvk = extfvks.get(account)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("No address {} for a note", account)))?;
+
+        let mut diversifier = Diversifier{0: [0u8; 11]};
+        diversifier.0.copy_from_slice(&utils::json_hex(json, "diversifier", 11)?);
+
+        let value = utils::json_u64(json, "value")?;
+        let r = Fs::from_repr(read_fs(&utils::json_hex(json, "rcm", 32)?))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Couldn't parse randomness"))?;
+
+        let note = extfvk.fvk.vk.into_payment_address(diversifier, &JUBJUB)
+            .and_then(|address| address.create_note(value, r, &JUBJUB))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Couldn't create the note for the address"))?;
+
+        let mut nullifier = [0u8; 32];
+        nullifier.copy_from_slice(&utils::json_hex(json, "nullifier", 32)?);
+
+        let memo = if json["memo"].is_null() {
+            None
+        } else {
+            let memo = Memo::from_bytes(&utils::json_hex(json, "memo", 512)?)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Couldn't create the memo"))?;
+            Some(memo)
+        };
+
+        Ok(SaplingNoteData {
+            account,
+            extfvk: extfvk.clone(),
+            diversifier,
+            note,
+            witnesses: vec![],
+            nullifier,
+            spent: utils::json_txid(json, "spent")?,
+            unconfirmed_spent: None,
+            memo,
+            is_change: utils::json_bool(json, "is_change")?,
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -261,13 +339,17 @@ impl Utxo {
 
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
         let version = reader.read_u64::<LittleEndian>()?;
-        assert_eq!(version, Utxo::serialized_version());
+        if version != Utxo::serialized_version() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown utxo version {}", version)));
+        }
 
-        let address_len = reader.read_i32::<LittleEndian>()?;
-        let mut address_bytes = vec![0; address_len as usize];
-        reader.read_exact(&mut address_bytes)?;
-        let address = String::from_utf8(address_bytes).unwrap();
-        assert_eq!(address.chars().take(1).collect::<Vec<char>>()[0], 's');
+        let address_len = reader.read_u32::<LittleEndian>()?;
+        let address_bytes = utils::read_bytes(&mut reader, address_len as u64)?;
+        let address = String::from_utf8(address_bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if !address.starts_with('s') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Utxo address {} isn't a t address", address)));
+        }
 
         let mut txid_bytes = [0; 32];
         reader.read_exact(&mut txid_bytes)?;
@@ -323,6 +405,35 @@ impl Utxo {
 
         Ok(())
     }
+
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            "address"           => self.address.clone(),
+            "output_index"      => self.output_index,
+            "script"            => hex::encode(&self.script),
+            "value"             => self.value,
+            "height"            => self.height,
+            "spent"             => self.spent.map(|t| format!("{}", t)),
+            "unconfirmed_spent" => self.unconfirmed_spent.map(|t| format!("{}", t)),
+        }
+    }
+
+    /// Read a utxo of the transaction `txid` written by `to_json`
+    pub fn from_json(json: &JsonValue, txid: &TxId) -> io::Result<Self> {
+        let script = hex::decode(utils::json_str(json, "script")?)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "'script' is missing or wrong"))?;
+
+        Ok(Utxo {
+            address: utils::json_str(json, "address")?.to_string(),
+            txid: txid.clone(),
+            output_index: utils::json_u64(json, "output_index")?,
+            script,
+            value: utils::json_u64(json, "value")?,
+            height: utils::json_u64(json, "height")? as i32,
+            spent: utils::json_txid(json, "spent")?,
+            unconfirmed_spent: None,
+        })
+    }
 }
 
 pub struct OutgoingTxMetadata {
@@ -333,16 +444,14 @@ pub struct OutgoingTxMetadata {
 
 impl OutgoingTxMetadata {
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
-        let address_len = reader.read_u64::<LittleEndian>()?;
-        let mut address_bytes = vec![0; address_len as usize];
-        reader.read_exact(&mut address_bytes)?;
-        let address = String::from_utf8(address_bytes).unwrap();
+        let address = utils::read_string(&mut reader)?;
 
         let value = reader.read_u64::<LittleEndian>()?;
 
         let mut memo_bytes = [0u8; 512];
         reader.read_exact(&mut memo_bytes)?;
-        let memo = Memo::from_bytes(&memo_bytes).unwrap();
+        let memo = Memo::from_bytes(&memo_bytes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Couldn't create the memo"))?;
 
         Ok(OutgoingTxMetadata{
             address,
@@ -359,6 +468,67 @@ impl OutgoingTxMetadata {
         writer.write_u64::<LittleEndian>(self.value)?;
         writer.write_all(self.memo.as_bytes())
     }
+
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            "address"   => self.address.clone(),
+            "value"     => self.value,
+            "memo"      => hex::encode(&self.memo.as_bytes()[..]),
+            "memo_text" => self.memo.to_utf8().and_then(|m| m.ok()),
+        }
+    }
+
+    pub fn from_json(json: &JsonValue) -> io::Result<Self> {
+        let memo = Memo::from_bytes(&utils::json_hex(json, "memo", 512)?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Couldn't create the memo"))?;
+
+        Ok(OutgoingTxMetadata {
+            address: utils::json_str(json, "address")?.to_string(),
+            value: utils::json_u64(json, "value")?,
+            memo,
+        })
+    }
+}
+
+/// The exchange rate that a send with its amount given in a fiat currency was converted at, see
+/// `LightClient::do_send_fiat`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiatRate {
+    pub currency: String, // eg. USD
+    pub amount  : f64,    // What was sent, in the currency
+    pub price   : f64,    // What 1 YEC was worth in the currency
+}
+
+impl FiatRate {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let currency = utils::read_string(&mut reader)?;
+        let amount = reader.read_f64::<LittleEndian>()?;
+        let price = reader.read_f64::<LittleEndian>()?;
+
+        Ok(FiatRate { currency, amount, price })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        utils::write_string(&mut writer, &self.currency)?;
+        writer.write_f64::<LittleEndian>(self.amount)?;
+        writer.write_f64::<LittleEndian>(self.price)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        object!{
+            "currency" => self.currency.clone(),
+            "amount"   => self.amount,
+            "price"    => self.price,
+        }
+    }
+
+    pub fn from_json(json: &JsonValue) -> io::Result<Self> {
+        Ok(FiatRate {
+            currency: utils::json_str(json, "currency")?.to_string(),
+            amount: utils::json_f64(json, "amount")?,
+            price: utils::json_f64(json, "price")?,
+        })
+    }
 }
 
 pub struct WalletTx {
@@ -392,6 +562,9 @@ pub struct WalletTx {
 
     // Whether this TxID was downloaded from the server and scanned for Memos
     pub full_tx_scanned: bool,
+
+    // The fee, for the pending transactions this wallet sent, which are the only ones it's known for. Not saved.
+    pub fee: Option<u64>,
 }
 
 impl WalletTx {
@@ -410,12 +583,15 @@ impl WalletTx {
             total_transparent_value_spent: 0,
             outgoing_metadata: vec![],
             full_tx_scanned: false,
+            fee: None,
         }
     }
 
     pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
         let version = reader.read_u64::<LittleEndian>()?;
-        assert!(version <= WalletTx::serialized_version());
+        if version > WalletTx::serialized_version() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown transaction version {}", version)));
+        }
 
         let block = reader.read_i32::<LittleEndian>()?;
 
@@ -450,7 +626,8 @@ impl WalletTx {
             total_shielded_value_spent,
             total_transparent_value_spent,
             outgoing_metadata,
-            full_tx_scanned
+            full_tx_scanned,
+            fee: None,
         })
     }
 
@@ -476,6 +653,105 @@ impl WalletTx {
 
         Ok(())
     }
+
+    pub fn to_json(&self, hrp_sapling_address: &str) -> JsonValue {
+        object!{
+            "txid"                          => format!("{}", self.txid),
+            "block"                         => self.block,
+            "datetime"                      => self.datetime,
+            "notes"                         => self.notes.iter().map(|nd| nd.to_json(hrp_sapling_address)).collect::<Vec<_>>(),
+            "utxos"                         => self.utxos.iter().map(|u| u.to_json()).collect::<Vec<_>>(),
+            "total_shielded_value_spent"    => self.total_shielded_value_spent,
+            "total_transparent_value_spent" => self.total_transparent_value_spent,
+            "outgoing_metadata"             => self.outgoing_metadata.iter().map(|om| om.to_json()).collect::<Vec<_>>(),
+            "full_tx_scanned"               => self.full_tx_scanned,
+        }
+    }
+
+    /// Read a transaction written by `to_json`. See `SaplingNoteData::from_json` for `extfvks`.
+    pub fn from_json(json: &JsonValue, extfvks: &[ExtendedFullViewingKey]) -> io::Result<Self> {
+        let txid = utils::json_txid(json, "txid")?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "'txid' is missing or wrong"))?;
+
+        Ok(WalletTx {
+            block: utils::json_u64(json, "block")? as i32,
+            datetime: utils::json_u64(json, "datetime")?,
+            txid: txid.clone(),
+            notes: json["notes"].members().map(|nd| SaplingNoteData::from_json(nd, extfvks)).collect::<io::Result<_>>()?,
+            utxos: json["utxos"].members().map(|u| Utxo::from_json(u, &txid)).collect::<io::Result<_>>()?,
+            total_shielded_value_spent: utils::json_u64(json, "total_shielded_value_spent")?,
+            total_transparent_value_spent: utils::json_u64(json, "total_transparent_value_spent")?,
+            outgoing_metadata: json["outgoing_metadata"].members().map(|om| OutgoingTxMetadata::from_json(om)).collect::<io::Result<_>>()?,
+            full_tx_scanned: utils::json_bool(json, "full_tx_scanned")?,
+            fee: None,
+        })
+    }
+}
+
+/// The wallet's accounts, which split the addresses derived from its seed into groups, like savings 
+/// and spending. Account 0 is the default one, which every address was in before there were accounts.
+/// The others have names, and derive their addresses from the seed separately.
+pub struct Accounts {
+    pub names:  Vec<String>,    // Of accounts 1 and up
+    pub zaddrs: Vec<u32>,       // The account of each z address derived from the seed, in order
+    pub taddrs: Vec<u32>,       // The account of each t address, in order
+}
+
+impl Accounts {
+    pub fn serialized_version() -> u64 {
+        return 1;
+    }
+
+    /// Just the default account, with the given number of addresses
+    pub fn new(zaddrs: usize, taddrs: usize) -> Self {
+        Accounts { names: vec![], zaddrs: vec![0; zaddrs], taddrs: vec![0; taddrs] }
+    }
+
+    pub fn name(&self, account: u32) -> Option<&str> {
+        match account {
+            0 => Some(DEFAULT_ACCOUNT),
+            n => self.names.get(n as usize - 1).map(|s| s.as_str())
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<u32> {
+        if name == DEFAULT_ACCOUNT {
+            return Some(0);
+        }
+        self.names.iter().position(|n| n == name).map(|i| i as u32 + 1)
+    }
+
+    /// How the `index`th address of `accounts` (either `zaddrs` or `taddrs`) is derived: its account, 
+    /// and its position among that account's addresses
+    pub fn derivation(accounts: &[u32], index: usize) -> (u32, u32) {
+        let account = accounts[index];
+        (account, accounts[..index].iter().filter(|a| **a == account).count() as u32)
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let version = reader.read_u64::<LittleEndian>()?;
+        if version > Accounts::serialized_version() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown accounts version {}", version)));
+        }
+
+        let names = Vector::read(&mut reader, |r| utils::read_string(r))?;
+        let zaddrs = Vector::read(&mut reader, |r| r.read_u32::<LittleEndian>())?;
+        let taddrs = Vector::read(&mut reader, |r| r.read_u32::<LittleEndian>())?;
+
+        if let Some(a) = zaddrs.iter().chain(taddrs.iter()).find(|a| **a as usize > names.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Address in account {}, but there are only {}", a, names.len() + 1)));
+        }
+
+        Ok(Accounts { names, zaddrs, taddrs })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(Accounts::serialized_version())?;
+
+        Vector::write(&mut writer, &self.names, |w, n| utils::write_string(w, n))?;
+        Vector::write(&mut writer, &self.zaddrs, |w, a| w.write_u32::<LittleEndian>(*a))?;
+        Vector::write(&mut writer, &self.taddrs, |w, a| w.write_u32::<LittleEndian>(*a))
+    }
 }
 
 pub struct SpendableNote {
@@ -490,8 +766,17 @@ pub struct SpendableNote {
 impl SpendableNote {
     pub fn from(txid: TxId, nd: &SaplingNoteData, anchor_offset: usize, extsk: &ExtendedSpendingKey) -> Option<Self> {
         // Include only notes that haven't been spent, or haven't been included in an unconfirmed spend yet.
-        if nd.spent.is_none() && nd.unconfirmed_spent.is_none() &&
-                nd.witnesses.len() >= (anchor_offset + 1) {
+        if nd.spent.is_none() && nd.unconfirmed_spent.is_none() {
+            SpendableNote::respend(txid, nd, anchor_offset, extsk)
+        } else {
+            None
+        }
+    }
+
+    /// Like `from`, but for a note that an unconfirmed transaction is already spending, to spend it again in
+    /// a transaction that replaces that one
+    pub fn respend(txid: TxId, nd: &SaplingNoteData, anchor_offset: usize, extsk: &ExtendedSpendingKey) -> Option<Self> {
+        if nd.spent.is_none() && nd.witnesses.len() >= (anchor_offset + 1) {
             let witness = nd.witnesses.get(nd.witnesses.len() - anchor_offset - 1);
 
             witness.map(|w| SpendableNote {
@@ -4,6 +4,8 @@ use ring::{
 };
 use lazy_static::lazy_static;
 use secp256k1::{PublicKey, Secp256k1, SecretKey, SignOnly, VerifyOnly, Error};
+use sha2::{Sha256, Digest};
+use ripemd160::Ripemd160;
 
 lazy_static! {
     static ref SECP256K1_SIGN_ONLY: Secp256k1<SignOnly> = Secp256k1::signing_only();
@@ -124,4 +126,44 @@ impl ExtendedPrivKey {
             chain_code: chain_code.to_vec(),
         })
     }
+
+    /// The public key corresponding to this private key
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_secret_key(&SECP256K1_SIGN_ONLY, &self.private_key)
+    }
+
+    /// The BIP32 fingerprint of this key, which is the first 4 bytes of the hash160 of the public key.
+    pub fn fingerprint(&self) -> [u8; 4] {
+        let mut hash160 = Ripemd160::new();
+        hash160.input(Sha256::digest(&self.public_key().serialize()[..]));
+
+        let mut fp = [0u8; 4];
+        fp.copy_from_slice(&hash160.result()[..4]);
+        fp
+    }
+
+    /// Serialize as a BIP32 extended private key payload (everything except the 4 version bytes
+    /// and the checksum): depth || parent fingerprint || child number || chain code || 0x00 || key
+    pub fn xprv_payload(&self, depth: u8, parent_fingerprint: [u8; 4], child_number: u32) -> Vec<u8> {
+        let mut key = vec![0x00];
+        key.extend_from_slice(&self.private_key[..]);
+
+        self.payload(depth, parent_fingerprint, child_number, &key)
+    }
+
+    /// Serialize as a BIP32 extended public key payload (everything except the 4 version bytes
+    /// and the checksum): depth || parent fingerprint || child number || chain code || pubkey
+    pub fn xpub_payload(&self, depth: u8, parent_fingerprint: [u8; 4], child_number: u32) -> Vec<u8> {
+        self.payload(depth, parent_fingerprint, child_number, &self.public_key().serialize())
+    }
+
+    fn payload(&self, depth: u8, parent_fingerprint: [u8; 4], child_number: u32, key: &[u8]) -> Vec<u8> {
+        let mut v = vec![depth];
+        v.extend_from_slice(&parent_fingerprint);
+        v.extend_from_slice(&child_number.to_be_bytes());
+        v.extend_from_slice(&self.chain_code);
+        v.extend_from_slice(key);
+
+        v
+    }
 }
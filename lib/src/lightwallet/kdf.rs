@@ -0,0 +1,115 @@
+///
+/// How the key that encrypts the wallet's seed is derived from the password. It is stored with the encrypted
+/// seed, so that the derivation can be made stronger without losing the wallets encrypted the old way.
+///
+/// In the wallet file it is a u8 algorithm, followed by that algorithm's parameters:
+///
+/// | algorithm | parameters                                                                      |
+/// |-----------|---------------------------------------------------------------------------------|
+/// | 0         | none. The key is the double SHA-256 of the password, as before wallet version 10 |
+/// | 1         | Argon2id v1.3: opslimit (u64), memlimit in bytes (u64) and a 16 byte salt        |
+
+use std::io::{self, Read, Write, Error, ErrorKind};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use json::{object, JsonValue};
+use sodiumoxide::crypto::pwhash::argon2id13;
+use sodiumoxide::crypto::secretbox;
+
+use super::double_sha256;
+use super::secret::Secret;
+
+const DOUBLE_SHA256: u8 = 0;
+const ARGON2ID: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kdf {
+    /// What wallets were encrypted with before there was a choice. It isn't memory-hard, so it is only used
+    /// to unlock them, and they are encrypted again with Argon2id once unlocked.
+    DoubleSha256,
+    Argon2id { opslimit: u64, memlimit: u64, salt: [u8; argon2id13::SALTBYTES] },
+}
+
+impl Kdf {
+    /// Argon2id with a new salt, and the parameters new encryptions use
+    pub fn argon2id() -> Self {
+        Kdf::Argon2id {
+            opslimit: argon2id13::OPSLIMIT_MODERATE.0 as u64,
+            memlimit: argon2id13::MEMLIMIT_MODERATE.0 as u64,
+            salt:     argon2id13::gen_salt().0,
+        }
+    }
+
+    /// Whether this is what new encryptions use. Wallets that aren't are encrypted again when unlocked.
+    pub fn is_current(&self) -> bool {
+        match self {
+            Kdf::Argon2id { opslimit, memlimit, .. } =>
+                *opslimit >= argon2id13::OPSLIMIT_MODERATE.0 as u64 && *memlimit >= argon2id13::MEMLIMIT_MODERATE.0 as u64,
+            Kdf::DoubleSha256 => false,
+        }
+    }
+
+    pub fn derive_key(&self, passwd: &str) -> io::Result<secretbox::Key> {
+        match self {
+            Kdf::DoubleSha256 => {
+                let hash = Secret::new(double_sha256(passwd.as_bytes()));
+                Ok(secretbox::Key::from_slice(&hash).unwrap())
+            },
+            Kdf::Argon2id { opslimit, memlimit, salt } => {
+                // Derived right into the key, which wipes itself when dropped, so no copy of it is left behind
+                let mut key = secretbox::Key([0u8; secretbox::KEYBYTES]);
+                argon2id13::derive_key(&mut key.0, passwd.as_bytes(), &argon2id13::Salt(*salt),
+                                       argon2id13::OpsLimit(*opslimit as usize), argon2id13::MemLimit(*memlimit as usize))
+                    .map_err(|_| Error::new(ErrorKind::Other, "Couldn't derive the key from the password"))?;
+
+                Ok(key)
+            }
+        }
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        match reader.read_u8()? {
+            DOUBLE_SHA256 => Ok(Kdf::DoubleSha256),
+            ARGON2ID => {
+                let opslimit = reader.read_u64::<LittleEndian>()?;
+                let memlimit = reader.read_u64::<LittleEndian>()?;
+                let mut salt = [0u8; argon2id13::SALTBYTES];
+                reader.read_exact(&mut salt)?;
+
+                // Don't let a damaged file make unlocking take forever, or all the memory
+                if opslimit > argon2id13::OPSLIMIT_SENSITIVE.0 as u64 || memlimit > argon2id13::MEMLIMIT_SENSITIVE.0 as u64 {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                                          format!("Argon2id parameters out of range ({} iterations, {} bytes)", opslimit, memlimit)));
+                }
+
+                Ok(Kdf::Argon2id { opslimit, memlimit, salt })
+            },
+            algorithm => Err(Error::new(ErrorKind::InvalidData, format!("Unknown key derivation {}", algorithm))),
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        match self {
+            Kdf::DoubleSha256 => writer.write_u8(DOUBLE_SHA256),
+            Kdf::Argon2id { opslimit, memlimit, salt } => {
+                writer.write_u8(ARGON2ID)?;
+                writer.write_u64::<LittleEndian>(*opslimit)?;
+                writer.write_u64::<LittleEndian>(*memlimit)?;
+                writer.write_all(salt)
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        match self {
+            Kdf::DoubleSha256 => object!{
+                "algorithm" => "double_sha256",
+            },
+            Kdf::Argon2id { opslimit, memlimit, .. } => object!{
+                "algorithm"  => "argon2id",
+                "memory"     => *memlimit,
+                "iterations" => *opslimit,
+            },
+        }
+    }
+}
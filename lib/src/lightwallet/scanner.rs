@@ -0,0 +1,45 @@
+///
+/// Finding the wallet's notes in the compact blocks sent by the server. This is where almost all of the
+/// time syncing goes, so it is behind the `NoteScanner` trait, so that other crates can plug in a faster 
+/// scanner (eg. trial-decrypting on a GPU) without changing the sync. `CpuScanner` is the default.
+
+use zcash_client_backend::{
+    proto::compact_formats::CompactBlock, wallet::WalletTx, welding_rig,
+};
+
+use zcash_primitives::{
+    merkle_tree::{CommitmentTree, IncrementalWitness},
+    sapling::Node,
+    zip32::ExtendedFullViewingKey,
+};
+
+pub trait NoteScanner: Send + Sync {
+    /// Trial-decrypt the block's outputs with `extfvks`, and look for spends of `nullifiers`, given with the
+    /// account each of them belongs to. Returns the transactions that have any of the wallet's outputs or 
+    /// spends, with a witness for each new note. The commitments of all the outputs in the block have to be
+    /// appended to `tree` and to `existing_witnesses`, in order, so that the wallet can spend its notes.
+    fn scan_block(
+        &self,
+        block: CompactBlock,
+        extfvks: &[ExtendedFullViewingKey],
+        nullifiers: &[(&[u8], usize)],
+        tree: &mut CommitmentTree<Node>,
+        existing_witnesses: &mut [&mut IncrementalWitness<Node>],
+    ) -> Vec<WalletTx>;
+}
+
+/// Trial-decrypts every output on the CPU, one at a time
+pub struct CpuScanner {}
+
+impl NoteScanner for CpuScanner {
+    fn scan_block(
+        &self,
+        block: CompactBlock,
+        extfvks: &[ExtendedFullViewingKey],
+        nullifiers: &[(&[u8], usize)],
+        tree: &mut CommitmentTree<Node>,
+        existing_witnesses: &mut [&mut IncrementalWitness<Node>],
+    ) -> Vec<WalletTx> {
+        welding_rig::scan_block(block, extfvks, nullifiers, tree, existing_witnesses)
+    }
+}
@@ -0,0 +1,128 @@
+///
+/// Secret material, like the seed, the keys and passwords, that is wiped from memory as soon as it isn't needed,
+/// instead of lingering in freed memory (or swap) until something else happens to overwrite it.
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use bip39::{Mnemonic, Seed};
+use pairing::bls12_381::Bls12;
+use sodiumoxide::utils::memzero;
+use zcash_primitives::{keys::ExpandedSpendingKey, zip32::ExtendedSpendingKey};
+
+/// Something that can overwrite itself with zeros
+pub trait Wipe {
+    fn wipe(&mut self);
+}
+
+impl Wipe for Vec<u8> {
+    fn wipe(&mut self) {
+        memzero(&mut self[..]);
+    }
+}
+
+impl Wipe for String {
+    fn wipe(&mut self) {
+        // Zeros are valid UTF-8
+        unsafe { memzero(self.as_bytes_mut()) };
+    }
+}
+
+impl Wipe for [u8; 32] {
+    fn wipe(&mut self) {
+        memzero(&mut self[..]);
+    }
+}
+
+impl Wipe for secp256k1::SecretKey {
+    fn wipe(&mut self) {
+        unsafe { memzero(std::slice::from_raw_parts_mut(self.as_mut_ptr(), secp256k1::constants::SECRET_KEY_SIZE)) };
+    }
+}
+
+impl Wipe for Vec<secp256k1::SecretKey> {
+    fn wipe(&mut self) {
+        self.iter_mut().for_each(|sk| sk.wipe());
+    }
+}
+
+impl Wipe for ExtendedSpendingKey {
+    fn wipe(&mut self) {
+        // The spending authority and the nullifier key. Without them, what is left can't spend or derive spending keys.
+        let expsk = &mut self.expsk as *mut ExpandedSpendingKey<Bls12> as *mut u8;
+        unsafe { memzero(std::slice::from_raw_parts_mut(expsk, std::mem::size_of::<ExpandedSpendingKey<Bls12>>())) };
+    }
+}
+
+impl Wipe for Vec<ExtendedSpendingKey> {
+    fn wipe(&mut self) {
+        self.iter_mut().for_each(|extsk| extsk.wipe());
+    }
+}
+
+// The bip39 crate only hands out its bytes through shared references, so they are wiped through those
+
+impl Wipe for Mnemonic {
+    fn wipe(&mut self) {
+        let (phrase, entropy) = (self.phrase(), self.entropy());
+        unsafe {
+            memzero(std::slice::from_raw_parts_mut(phrase.as_ptr() as *mut u8, phrase.len()));
+            memzero(std::slice::from_raw_parts_mut(entropy.as_ptr() as *mut u8, entropy.len()));
+        }
+    }
+}
+
+impl Wipe for Seed {
+    fn wipe(&mut self) {
+        let bytes = self.as_bytes();
+        unsafe { memzero(std::slice::from_raw_parts_mut(bytes.as_ptr() as *mut u8, bytes.len())) };
+    }
+}
+
+/// A value that is wiped when it's dropped. It can't be cloned, so copies of it are only made on purpose,
+/// and it doesn't show itself in debug output.
+pub struct Secret<T: Wipe>(T);
+
+impl<T: Wipe> Secret<T> {
+    pub fn new(t: T) -> Self {
+        Secret(t)
+    }
+}
+
+impl<T: Wipe> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.wipe();
+    }
+}
+
+impl<T: Wipe> Deref for Secret<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Wipe> DerefMut for Secret<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Wipe> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+impl<T: Wipe + PartialEq> PartialEq for Secret<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl PartialEq<str> for Secret<String> {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
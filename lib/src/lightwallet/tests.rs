@@ -1,11 +1,11 @@
 use std::convert::TryInto;
 use std::io::{Error};
-use rand::{RngCore, rngs::OsRng};
+use rand::{RngCore, SeedableRng, rngs::{OsRng, StdRng}};
 
 use ff::{Field, PrimeField, PrimeFieldRepr};
 use pairing::bls12_381::Bls12;
 use protobuf::{Message, UnknownFields, CachedSize, RepeatedField};
-use zcash_client_backend::{encoding::encode_payment_address,
+use zcash_client_backend::{encoding::{encode_payment_address, encode_extended_full_viewing_key},
     proto::compact_formats::{
         CompactBlock, CompactOutput, CompactSpend, CompactTx,
     }
@@ -27,7 +27,7 @@ use zcash_primitives::{
 
 use sha2::{Sha256, Digest};
 
-use super::LightWallet;
+use super::{LightWallet, FiatRate};
 use super::LightClientConfig;
 use secp256k1::{Secp256k1, key::PublicKey, key::SecretKey};
 use crate::SaplingParams;
@@ -327,6 +327,44 @@ fn test_z_balances() {
     assert_eq!(wallet.zbalance(address.clone()), AMOUNT1 + AMOUNT2);
 }
 
+#[test]
+fn test_nullifiers() {
+    const AMOUNT1: u64 = 50000;
+    const AMOUNT2: u64 = 20000;
+    let config = get_test_config();
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+
+    let mut cb1 = FakeCompactBlock::new(0, BlockHash([0; 32]));
+    let (nf1, txid1) = cb1.add_tx_paying(wallet.extfvks.read().unwrap()[0].clone(), AMOUNT1);
+    wallet.scan_block(&cb1.as_bytes()).unwrap();
+
+    let nullifiers = |w: &LightWallet| {
+        let mut serialized_data = vec![];
+        w.write(&mut serialized_data).expect("Serialize wallet");
+        let lc = crate::lightclient::LightClient::read_from_buffer(&config, &serialized_data[..]).unwrap();
+        lc.do_nullifiers().unwrap()
+    };
+
+    let list = nullifiers(&wallet);
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0]["nullifier"], hex::encode(&nf1));
+    assert_eq!(list[0]["value"], AMOUNT1);
+    assert_eq!(list[0]["created_in_txid"], format!("{}", txid1));
+    assert_eq!(list[0]["address"], encode_payment_address(config.hrp_sapling_address(), &wallet.zaddress.read().unwrap()[0]));
+
+    // Once the note is spent, only the change is left
+    let addr2 = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[0u8; 32])).default_address().unwrap().1;
+    let mut cb2 = FakeCompactBlock::new(1, cb1.hash());
+    let txid2 = cb2.add_tx_spending((nf1, AMOUNT1), wallet.extfvks.read().unwrap()[0].clone(), addr2, AMOUNT2);
+    wallet.scan_block(&cb2.as_bytes()).unwrap();
+
+    let list = nullifiers(&wallet);
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0]["value"], AMOUNT1 - AMOUNT2);
+    assert_eq!(list[0]["created_in_txid"], format!("{}", txid2));
+    assert_eq!(list[0]["nullifier"], hex::encode(wallet.txs.read().unwrap()[&txid2].notes[0].nullifier));
+}
+
 #[test]
 fn test_z_change_balances() {
     let wallet = LightWallet::new(None, &get_test_config(), 0).unwrap();
@@ -437,6 +475,115 @@ fn test_t_receive_spend() {
 }
 
 
+#[test]
+fn test_t_prefetched_utxo() {
+    let mut rng = OsRng;
+    let secp = Secp256k1::new();
+
+    let wallet = LightWallet::new(None, &get_test_config(), 0).unwrap();
+
+    let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+    let taddr = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+
+    const AMOUNT1: u64 = 20;
+
+    let mut tx = FakeTransaction::new(&mut rng);
+    tx.add_t_output(&pk, AMOUNT1);
+    let txid1 = tx.get_tx().txid();
+    let script = tx.get_tx().vout[0].script_pubkey.0.clone();
+
+    // The server tells us about the UTXO before we scan the tx
+    assert!(wallet.add_utxo(taddr.clone(), txid1, 0, script.clone(), AMOUNT1, 100));
+    assert_eq!(wallet.tbalance(None), AMOUNT1);
+
+    // Adding it again, or for an address that's not ours, does nothing
+    assert!(!wallet.add_utxo(taddr.clone(), txid1, 0, script.clone(), AMOUNT1, 100));
+    let other_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+    assert!(!wallet.add_utxo(other_taddr, txid1, 1, script.clone(), AMOUNT1, 100));
+    assert_eq!(wallet.tbalance(None), AMOUNT1);
+
+    // Scanning the tx later doesn't duplicate it
+    wallet.scan_full_tx(&tx.get_tx(), 100, 0);
+    {
+        let txs = wallet.txs.read().unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[&txid1].utxos.len(), 1);
+        assert_eq!(txs[&txid1].utxos[0].address, taddr);
+        assert_eq!(txs[&txid1].utxos[0].script, script);
+    }
+    assert_eq!(wallet.tbalance(None), AMOUNT1);
+
+    // And spending it is detected normally
+    let mut tx = FakeTransaction::new(&mut rng);
+    tx.add_t_input(txid1, 0);
+    let txid2 = tx.get_tx().txid();
+    wallet.scan_full_tx(&tx.get_tx(), 101, 0);
+
+    {
+        let txs = wallet.txs.read().unwrap();
+        assert_eq!(txs[&txid1].utxos[0].spent, Some(txid2));
+    }
+    assert_eq!(wallet.tbalance(None), 0);
+}
+
+#[test]
+fn test_list_mixed_t_tx() {
+    let mut rng = OsRng;
+    let secp = Secp256k1::new();
+    let config = get_test_config();
+
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+    let taddr1 = wallet.add_taddr();
+    let pk0 = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+    let pk1 = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[1]);
+    let taddr0 = wallet.address_from_sk(&wallet.tkeys.read().unwrap()[0]);
+    let non_wallet_pk = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[1u8; 32]).unwrap());
+
+    // Received at both t addresses
+    let mut tx = FakeTransaction::new(&mut rng);
+    tx.add_t_output(&pk0, 100);
+    tx.add_t_output(&pk1, 50);
+    let txid1 = tx.get_tx().txid();
+    wallet.scan_full_tx(&tx.get_tx(), 100, 0);
+
+    // Spends the first one, sending 60 away and 30 back to the second address
+    let mut tx = FakeTransaction::new(&mut rng);
+    tx.add_t_input(txid1, 0);
+    tx.add_t_output(&non_wallet_pk, 60);
+    tx.add_t_output(&pk1, 30);
+    let txid2 = tx.get_tx().txid();
+    wallet.scan_full_tx(&tx.get_tx(), 101, 0);
+
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let lc = crate::lightclient::LightClient::read_from_buffer(&config, &serialized_data[..]).unwrap();
+    let list = lc.do_list_transactions();
+
+    // One entry for each address that received, without any spend
+    let received = list.members().filter(|t| t["txid"] == format!("{}", txid1)).collect::<Vec<_>>();
+    assert_eq!(received.len(), 2);
+    assert!(received.iter().all(|t| !t["address"].as_str().unwrap().contains(",")));
+    let amount_at = |address: &str| received.iter().find(|t| t["address"] == address).unwrap()["amount"].as_i64();
+    assert_eq!(amount_at(&taddr0), Some(100));
+    assert_eq!(amount_at(&taddr1), Some(50));
+
+    // The spend is one entry, with what came back counted as change, and how each address came out
+    let spend = list.members().filter(|t| t["txid"] == format!("{}", txid2)).collect::<Vec<_>>();
+    assert_eq!(spend.len(), 1);
+    assert_eq!(spend[0]["amount"].as_i64(), Some(30 - 100));
+    assert_eq!(spend[0]["change"].as_u64(), Some(30));
+    let transparent = spend[0]["transparent"].members()
+        .map(|t| (t["address"].as_str().unwrap().to_string(), t["received"].as_u64().unwrap(), t["spent"].as_u64().unwrap()))
+        .collect::<Vec<_>>();
+    let mut expected = vec![(taddr0, 0, 100), (taddr1, 30, 0)];
+    expected.sort();
+    assert_eq!(transparent, expected);
+
+    // So the amounts add up to the balance
+    let total = list.members().map(|t| t["amount"].as_i64().unwrap()).sum::<i64>();
+    assert_eq!(total, wallet.tbalance(None) as i64);
+}
+
 #[test]
 /// This test spends and receives t addresses among non-wallet t addresses to make sure that
 /// we're detecting and spending only our t addrs.
@@ -617,6 +764,396 @@ fn test_serialization() {
     }
 }
 
+#[test]
+fn test_check_consistency() {
+    let config = get_test_config();
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+    let extfvk = wallet.extfvks.read().unwrap()[0].clone();
+
+    let mut cb1 = FakeCompactBlock::new(0, BlockHash([0; 32]));
+    let (nf1, txid1) = cb1.add_tx_paying(extfvk.clone(), 100);
+    wallet.scan_block(&cb1.as_bytes()).unwrap();
+
+    let mut cb2 = FakeCompactBlock::new(1, cb1.hash());
+    cb2.add_tx_paying(extfvk.clone(), 200);
+    let addr = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[0u8; 32])).default_address().unwrap().1;
+    let txid2 = cb2.add_tx_spending((nf1, 100), extfvk.clone(), addr, 50);
+    wallet.scan_block(&cb2.as_bytes()).unwrap();
+
+    assert_eq!(wallet.check_consistency(), Vec::<String>::new());
+
+    // A spend marked twice
+    wallet.txs.write().unwrap().get_mut(&txid1).unwrap().notes[0].unconfirmed_spent = Some(txid2);
+    let problems = wallet.check_consistency();
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains("both spent and unconfirmed spent"));
+
+    // Spent by something that isn't there, which also throws off the spending tx's total
+    {
+        let mut txs = wallet.txs.write().unwrap();
+        let nd = &mut txs.get_mut(&txid1).unwrap().notes[0];
+        nd.unconfirmed_spent = None;
+        nd.spent = Some(TxId{0: [9; 32]});
+    }
+    let problems = wallet.check_consistency();
+    assert_eq!(problems.len(), 2);
+    assert!(problems.iter().any(|p| p.contains("which is not in the wallet")));
+    assert!(problems.iter().any(|p| p.contains("spent 0 in notes, but its shielded total is 100")));
+
+    // A witness that fell behind the tree
+    {
+        let mut txs = wallet.txs.write().unwrap();
+        txs.get_mut(&txid1).unwrap().notes[0].spent = Some(txid2);
+        for tx in txs.values_mut() {
+            for nd in tx.notes.iter_mut() {
+                nd.witnesses.pop();
+            }
+        }
+    }
+    let problems = wallet.check_consistency();
+    assert!(!problems.is_empty());
+    assert!(problems.iter().all(|p| p.contains("witness")));
+}
+
+#[test]
+fn test_out_of_order_blocks() {
+    use super::BlockReorderBuffer;
+
+    let config = get_test_config();
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+
+    // A second copy of the wallet, to scan the blocks in order
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let in_order = LightWallet::read(&serialized_data[..], &config).unwrap();
+
+    let extfvk = wallet.extfvks.read().unwrap()[0].clone();
+    let mut blocks = vec![];
+    let mut prev_hash = BlockHash([0; 32]);
+    for height in 0..10 {
+        let mut cb = FakeCompactBlock::new(height, prev_hash);
+        if height % 3 == 0 {
+            cb.add_tx_paying(extfvk.clone(), 100 + height as u64);
+        }
+        prev_hash = cb.hash();
+        blocks.push((height as u64, cb.as_bytes()));
+    }
+
+    for (_, block) in &blocks {
+        in_order.scan_block(block).unwrap();
+    }
+
+    // Swap some neighbours, and send one block a lot later than it should have been
+    let mut shuffled = blocks.clone();
+    shuffled.swap(0, 1);
+    shuffled.swap(4, 5);
+    let late = shuffled.remove(2);
+    shuffled.insert(8, late);
+
+    let mut buffer = BlockReorderBuffer::new(0, 10);
+    for (height, block) in shuffled {
+        for (_, block) in buffer.push(height, block).unwrap() {
+            wallet.scan_block(&block).unwrap();
+        }
+    }
+    buffer.finish(9).unwrap();
+
+    assert_eq!(wallet.last_scanned_height(), 9);
+    assert_eq!(wallet.zbalance(None), in_order.zbalance(None));
+    assert_eq!(wallet.zbalance(None), 100 + 103 + 106 + 109);
+
+    let tree = |w: &LightWallet| {
+        let mut buf = vec![];
+        w.blocks.read().unwrap().last().unwrap().tree.write(&mut buf).unwrap();
+        buf
+    };
+    assert_eq!(tree(&wallet), tree(&in_order));
+
+    // Not waiting forever for a missing block
+    let mut buffer = BlockReorderBuffer::new(0, 2);
+    assert!(buffer.push(1, blocks[1].1.clone()).unwrap().is_empty());
+    assert!(buffer.push(2, blocks[2].1.clone()).unwrap().is_empty());
+    assert!(buffer.push(3, blocks[3].1.clone()).is_err());
+
+    // Or if it never came
+    let mut buffer = BlockReorderBuffer::new(0, 10);
+    assert_eq!(buffer.push(0, blocks[0].1.clone()).unwrap().len(), 1);
+    assert!(buffer.push(2, blocks[2].1.clone()).unwrap().is_empty());
+    assert!(buffer.finish(2).is_err());
+
+    // Or the stream ended before the last one
+    let mut buffer = BlockReorderBuffer::new(0, 10);
+    assert_eq!(buffer.push(0, blocks[0].1.clone()).unwrap().len(), 1);
+    assert_eq!(buffer.push(1, blocks[1].1.clone()).unwrap().len(), 1);
+    assert!(buffer.finish(1).is_ok());
+    assert_eq!(buffer.finish(3).unwrap_err(), "Server didn't send blocks 2 to 3");
+
+    // And blocks can't come twice
+    assert!(buffer.push(0, blocks[0].1.clone()).is_err());
+}
+
+#[test]
+fn test_sent_today_serialization() {
+    let config = get_test_config();
+
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+    assert_eq!(wallet.sent_today(), 0);
+
+    wallet.add_sent_today(100);
+    wallet.add_sent_today(250);
+    assert_eq!(wallet.sent_today(), 350);
+
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let wallet2 = LightWallet::read(&serialized_data[..], &config).unwrap();
+
+    assert_eq!(wallet2.sent_today(), 350);
+}
+
+#[test]
+fn test_sent_to_history() {
+    let config = get_test_config();
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+    assert!(wallet.sent_to_history().is_empty());
+
+    let (a, b) = ("ytestsapling1recipienta", "tmRecipientB");
+    wallet.add_sent_to(&[a, a, b]);
+    assert!(wallet.has_sent_to(a) && wallet.has_sent_to(b));
+    assert!(!wallet.has_sent_to("tmSomeoneElse"));
+
+    // Several outputs to the same address are one send
+    wallet.add_sent_to(&[a]);
+    let history = wallet.sent_to_history();
+    assert_eq!(history.len(), 2);
+    let count = |address: &str| history.iter().find(|(h, _, _)| h == address).unwrap().2;
+    assert_eq!((count(a), count(b)), (2, 1));
+    assert!(history.iter().all(|(_, last_used, _)| *last_used > 0));
+
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let wallet2 = LightWallet::read(&serialized_data[..], &config).unwrap();
+    assert_eq!(wallet2.sent_to_history(), history);
+}
+
+#[test]
+fn test_wallet_checksum() {
+    let config = get_test_config();
+    let (wallet, _, _) = get_test_wallet(100000);
+
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let len = serialized_data.len();
+    assert!(LightWallet::read(&serialized_data[..], &config).is_ok());
+
+    // Cut short anywhere after the version, the file is reported as corrupt
+    for cut in &[8, 9, 40, len / 2, len - 33, len - 32, len - 1] {
+        let e = LightWallet::read(&serialized_data[..*cut], &config).err().unwrap();
+        assert!(super::is_wallet_corrupt(&e), "Cut at {}: {}", cut, e);
+    }
+
+    // And so it is with a flipped bit, in the contents or the checksum
+    for pos in &[8, len / 2, len - 1] {
+        let mut damaged = serialized_data.clone();
+        damaged[*pos] ^= 0x10;
+        let e = LightWallet::read(&damaged[..], &config).err().unwrap();
+        assert!(super::is_wallet_corrupt(&e), "Flipped bit at {}: {}", pos, e);
+    }
+
+    // Other errors aren't corruption
+    let other_chain = LightClientConfig { chain_name: "main".to_string(), ..config.clone() };
+    assert!(!super::is_wallet_corrupt(&LightWallet::read(&serialized_data[..], &other_chain).err().unwrap()));
+
+    // Version 7 files had no checksum, and still read
+    let mut v7 = serialized_data[..len - 32].to_vec();
+    v7[..8].copy_from_slice(&7u64.to_le_bytes());
+    let wallet2 = LightWallet::read(&v7[..], &config).unwrap();
+    assert_eq!(wallet2.zbalance(None), wallet.zbalance(None));
+}
+
+#[test]
+fn test_read_malformed_wallet() {
+    let config = get_test_config();
+    let (wallet, _, _) = get_test_wallet(100000);
+    wallet.add_sent_to(&["ytestsapling1recipient"]);
+
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let len = serialized_data.len();
+
+    // Damage the contents, but give them a matching checksum, so that it's the parsing that has to cope
+    let read_damaged = |damage: &dyn Fn(&mut Vec<u8>)| {
+        let mut contents = serialized_data[..len - 32].to_vec();
+        damage(&mut contents);
+        let mut hasher = Sha256::new();
+        hasher.input(&contents);
+        contents.extend_from_slice(&hasher.result());
+
+        // Either an error, or a wallet that can be written and read again
+        if let Ok(wallet2) = LightWallet::read(&contents[..], &config) {
+            let mut rewritten = vec![];
+            wallet2.write(&mut rewritten).unwrap();
+            LightWallet::read(&rewritten[..], &config).unwrap();
+        }
+    };
+
+    // Every byte after the version flipped, and 8 bytes from there maxed out, which also catches length 
+    // prefixes claiming billions of entries
+    for pos in 8..len - 32 {
+        read_damaged(&|c: &mut Vec<u8>| c[pos] ^= 0x01);
+        read_damaged(&|c: &mut Vec<u8>| for b in c.iter_mut().skip(pos).take(8) { *b = 0xff });
+    }
+
+    // And random damage, a few bytes at a time. The seed is printed, so that a failure shows up with it, and
+    // it can be rerun with the same damage by setting WALLET_FUZZ_SEED.
+    let seed = std::env::var("WALLET_FUZZ_SEED").ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| OsRng.next_u64());
+    println!("Damaging the wallet with WALLET_FUZZ_SEED={}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..500 {
+        let changes = (0..1 + rng.next_u32() % 4)
+            .map(|_| (8 + rng.next_u32() as usize % (len - 40), rng.next_u32() as u8))
+            .collect::<Vec<_>>();
+        read_damaged(&|c: &mut Vec<u8>| for (pos, b) in &changes { c[*pos] = *b });
+    }
+
+    // Cut short with a matching checksum
+    for cut in &[9, 100, len / 2, len - 33] {
+        read_damaged(&|c: &mut Vec<u8>| c.truncate(*cut));
+    }
+}
+
+#[test]
+fn test_accounts() {
+    let config = get_test_config();
+    let mut wallet = LightWallet::new(None, &config, 0).unwrap();
+    let zaddr0 = wallet.account_addresses(0).0[0].clone();
+
+    assert_eq!(wallet.add_account("savings"), Ok(1));
+    assert!(wallet.add_account("savings").is_err());
+    assert!(wallet.add_account(super::DEFAULT_ACCOUNT).is_err());
+    assert!(wallet.add_account("rainy day").is_err());
+    assert_eq!(wallet.account_names(), vec![super::DEFAULT_ACCOUNT.to_string(), "savings".to_string()]);
+    assert_eq!(wallet.find_account("savings"), Some(1));
+
+    // Addresses go in their account, interleaved or not
+    let zsavings = wallet.add_zaddr_to_account(1);
+    let zaddr1 = wallet.add_zaddr();
+    let tsavings = wallet.add_taddr_to_account(1);
+    assert_eq!(wallet.account_addresses(1), (vec![zsavings.clone()], vec![tsavings.clone()]));
+    assert_eq!(wallet.account_addresses(0).0, vec![zaddr0, zaddr1.clone()]);
+    assert_eq!(wallet.account_addresses(0).1.len(), 1);
+
+    // Each account derives its addresses from the start of its own path, so the first one of the savings
+    // account isn't any of the default account's
+    let bip39_seed = bip39::Seed::new(&bip39::Mnemonic::from_entropy(&wallet.seed, bip39::Language::English).unwrap(), "");
+    let derived = |account, pos| encode_payment_address(config.hrp_sapling_address(),
+        &LightWallet::get_zaddr_from_bip39seed(&config, bip39_seed.as_bytes(), account, pos).2);
+    assert_eq!(derived(1, 0), zsavings);
+    assert_eq!(derived(0, 1), zaddr1);
+    assert_ne!(derived(0, 0), zsavings);
+    let tderived = wallet.address_from_sk(&LightWallet::get_taddr_from_bip39seed(&config, bip39_seed.as_bytes(), 1, 0));
+    assert_eq!(tderived, tsavings);
+
+    // The accounts are saved, and unlocking derives every address in its own account again
+    wallet.encrypt("somepassword".to_string()).unwrap();
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+
+    let mut wallet2 = LightWallet::read(&serialized_data[..], &config).unwrap();
+    assert_eq!(wallet2.account_names(), wallet.account_names());
+    assert_eq!(wallet2.account_addresses(1), (vec![zsavings], vec![tsavings]));
+    wallet2.unlock("somepassword".to_string()).unwrap();
+    assert_eq!(wallet2.extsks.read().unwrap().len(), 3);
+}
+
+#[test]
+fn test_read_old_versions() {
+    let config = get_test_config();
+    let fixture = |version: u64| std::fs::read(format!("{}/testdata/wallets/v{}.dat", env!("CARGO_MANIFEST_DIR"), version)).unwrap();
+    let (a, b) = ("ytestsapling1fixturerecipient", "smFixtureRecipient");
+    const SENT_ON: u64 = 1577836800;
+
+    for version in 3..=LightWallet::serialized_version() {
+        let wallet = LightWallet::read(&fixture(version)[..], &config).unwrap();
+
+        assert_eq!(wallet.seed, (0..32).collect::<Vec<u8>>()[..], "Version {}", version);
+        assert_eq!(wallet.birthday, 100);
+        assert_eq!(wallet.last_scanned_height(), 101);
+        assert_eq!(wallet.txs.read().unwrap().len(), 1);
+        assert_eq!(wallet.txs.read().unwrap()[&TxId([0x11; 32])].outgoing_metadata.len(), 3);
+        assert_eq!(wallet.account_names(), vec![super::DEFAULT_ACCOUNT.to_string()]);
+
+        // The history of addresses sent to is found in the transactions, which had no time before version 3
+        let sent_on = if version > 3 { SENT_ON } else { 0 };
+        assert_eq!(wallet.sent_to_history(), vec![(b.to_string(), sent_on, 1), (a.to_string(), sent_on, 1)], "Version {}", version);
+
+        // What was sent today can be worked out too, and that was nothing
+        let daily_sent = *wallet.daily_sent.read().unwrap();
+        if version >= 5 {
+            assert_eq!(daily_sent, (SENT_ON / (24 * 60 * 60), 70000));
+        } else {
+            assert_eq!(daily_sent, (super::today(), 0));
+        }
+
+        // Written again, it's the current version, which reads the same
+        let mut serialized_data = vec![];
+        wallet.write(&mut serialized_data).expect("Serialize wallet");
+        assert_eq!(serialized_data[..8], LightWallet::serialized_version().to_le_bytes());
+        let wallet2 = LightWallet::read(&serialized_data[..], &config).unwrap();
+        assert_eq!(wallet2.sent_to_history(), wallet.sent_to_history());
+        assert_eq!(*wallet2.daily_sent.read().unwrap(), daily_sent);
+
+        let mut serialized_data2 = vec![];
+        wallet2.write(&mut serialized_data2).expect("Serialize wallet");
+        assert_eq!(serialized_data2, serialized_data, "Version {}", version);
+    }
+
+    // A newer version is refused, rather than read as whatever it happens to parse as
+    let mut newer = fixture(LightWallet::serialized_version());
+    newer[..8].copy_from_slice(&(LightWallet::serialized_version() + 1).to_le_bytes());
+    let e = LightWallet::read(&newer[..], &config).err().unwrap();
+    assert!(e.to_string().contains("created by a newer version"));
+}
+
+#[test]
+fn test_deterministic_serialization() {
+    const AMOUNT: u64 = 50000;
+    let (wallet, _, block_hash) = get_test_wallet(AMOUNT);
+    let config = get_test_config();
+
+    // Enough transactions that a map would likely iterate them in a different order than they were added
+    let extfvk = wallet.extfvks.read().unwrap()[0].clone();
+    let mut prev_hash = block_hash;
+    for height in 2..12 {
+        let mut cb = FakeCompactBlock::new(height, prev_hash);
+        cb.add_tx_paying(extfvk.clone(), AMOUNT + height as u64);
+        cb.add_tx_paying(extfvk.clone(), AMOUNT);
+        wallet.scan_block(&cb.as_bytes()).unwrap();
+        prev_hash = cb.hash();
+    }
+    wallet.add_zaddr();
+    wallet.add_taddr();
+    assert_eq!(wallet.txs.read().unwrap().len(), 21);
+
+    let write = |w: &LightWallet| {
+        let mut serialized_data = vec![];
+        w.write(&mut serialized_data).expect("Serialize wallet");
+        serialized_data
+    };
+
+    // Saved, read back and saved again, it's the same bytes, and so is saving it twice
+    let serialized_data = write(&wallet);
+    assert_eq!(write(&wallet), serialized_data);
+
+    let wallet2 = LightWallet::read(&serialized_data[..], &config).unwrap();
+    assert_eq!(write(&wallet2), serialized_data);
+
+    let wallet3 = LightWallet::read(&write(&wallet2)[..], &config).unwrap();
+    assert_eq!(write(&wallet3), serialized_data);
+    assert_eq!(wallet3.zbalance(None), wallet.zbalance(None));
+}
+
 #[test]
 fn test_multi_serialization() {
     let config = get_test_config();
@@ -651,13 +1188,14 @@ fn test_multi_serialization() {
 
 fn get_test_config() -> LightClientConfig {
     LightClientConfig {
-        server: "0.0.0.0:0".parse().unwrap(),
+        servers: vec!["0.0.0.0:0".parse().unwrap()],
         chain_name: "test".to_string(),
         sapling_activation_height: 0,
         consensus_branch_id: "000000".to_string(),
         anchor_offset: 0,
         no_cert_verification: false,
         data_dir: None,
+        ..LightClientConfig::create_unconnected("test".to_string(), None)
     }
 }
 
@@ -689,6 +1227,94 @@ fn get_test_wallet(amount: u64) -> (LightWallet, TxId, BlockHash) {
     (wallet, txid1, cb2.hash())
 }
 
+/// `count` compact blocks from `start` on, as a server sends them, a minute and a quarter apart, with
+/// transactions paying each of `payments` (height, viewing key, value). For syncing wallets from
+/// `grpcconnector::tests::lightwalletd_server`, or scanning them directly.
+pub(crate) fn fake_blocks_paying(start: i32, count: i32, payments: &[(i32, ExtendedFullViewingKey, u64)]) -> Vec<Vec<u8>> {
+    let mut prev_hash = BlockHash([0; 32]);
+    (start..start + count).map(|height| {
+        let mut cb = FakeCompactBlock::new(height, prev_hash);
+        cb.block.set_time(1_600_000_000 + 75 * height as u32);
+        for (_, extfvk, value) in payments.iter().filter(|(h, _, _)| *h == height) {
+            cb.add_tx_paying(extfvk.clone(), *value);
+        }
+
+        prev_hash = cb.hash();
+        cb.as_bytes()
+    }).collect()
+}
+
+/// The viewing key of the wallet's first z address
+pub(crate) fn first_extfvk(wallet: &LightWallet) -> ExtendedFullViewingKey {
+    wallet.extfvks.read().unwrap()[0].clone()
+}
+
+/// A raw transaction paying `value` to the wallet's first t address
+pub(crate) fn fake_tx_paying_taddr(wallet: &LightWallet, value: u64) -> Vec<u8> {
+    let secp = Secp256k1::new();
+    let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+
+    let mut tx = FakeTransaction::new(&mut OsRng);
+    tx.add_t_output(&pk, value);
+
+    let mut tx_bytes = vec![];
+    tx.get_tx().write(&mut tx_bytes).unwrap();
+    tx_bytes
+}
+
+/// Make the wallet's transaction `txid` look like one it sent: `spent` of the wallet's funds went into it,
+/// the notes it received are the change, and `outputs` (address, value, memo) went to others
+pub(crate) fn fake_sent(wallet: &LightWallet, txid: &TxId, spent: u64, outputs: &[(&str, u64, &str)]) {
+    let mut txs = wallet.txs.write().unwrap();
+    let wtx = txs.get_mut(txid).unwrap();
+
+    wtx.total_shielded_value_spent = spent;
+    wtx.notes.iter_mut().for_each(|nd| nd.is_change = true);
+    wtx.outgoing_metadata.extend(outputs.iter().map(|(address, value, memo)| super::OutgoingTxMetadata {
+        address: address.to_string(),
+        value: *value,
+        memo: Memo::from_str(memo).unwrap(),
+    }));
+}
+
+#[test]
+fn test_note_scanner() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use zcash_primitives::{merkle_tree::{CommitmentTree, IncrementalWitness}, sapling::Node};
+    use super::scanner::{NoteScanner, CpuScanner};
+
+    // Counts the blocks it scans, and leaves the scanning to the default scanner
+    struct CountingScanner { blocks: AtomicUsize }
+    impl NoteScanner for CountingScanner {
+        fn scan_block(&self, block: CompactBlock, extfvks: &[ExtendedFullViewingKey], nullifiers: &[(&[u8], usize)],
+                      tree: &mut CommitmentTree<Node>, existing_witnesses: &mut [&mut IncrementalWitness<Node>])
+                      -> Vec<zcash_client_backend::wallet::WalletTx> {
+            self.blocks.fetch_add(1, Ordering::SeqCst);
+            CpuScanner{}.scan_block(block, extfvks, nullifiers, tree, existing_witnesses)
+        }
+    }
+
+    const AMOUNT1: u64 = 5;
+    let config = get_test_config();
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+    let scanner = CountingScanner { blocks: AtomicUsize::new(0) };
+
+    let mut cb1 = FakeCompactBlock::new(0, BlockHash([0; 32]));
+    let (_, txid1) = cb1.add_tx_paying(wallet.extfvks.read().unwrap()[0].clone(), AMOUNT1);
+    assert_eq!(wallet.scan_block_with(&cb1.as_bytes(), &scanner).unwrap(), vec![txid1]);
+
+    // A rescan of the same block isn't scanned again
+    assert_eq!(wallet.scan_block_with(&cb1.as_bytes(), &scanner).unwrap(), vec![]);
+    assert_eq!(scanner.blocks.load(Ordering::SeqCst), 1);
+
+    // The note it found is in the wallet, with a witness
+    assert_eq!(wallet.zbalance(None), AMOUNT1);
+    let txs = wallet.txs.read().unwrap();
+    assert_eq!(txs[&txid1].notes.len(), 1);
+    assert_eq!(txs[&txid1].notes[0].note.value, AMOUNT1);
+    assert_eq!(txs[&txid1].notes[0].witnesses.len(), 1);
+}
+
 #[test]
 fn test_z_spend_to_z() {
     const AMOUNT1: u64 = 50000;
@@ -791,6 +1417,59 @@ fn test_z_spend_to_z() {
     }
 }
 
+#[test]
+fn test_fresh_change_address() {
+    const AMOUNT1: u64 = 50000;
+    let (wallet, _txid1, block_hash) = get_test_wallet(AMOUNT1);
+
+    let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+    let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                        &fvk.default_address().unwrap().1);
+
+    const AMOUNT_SENT: u64 = 20;
+
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+
+    // Send twice, mining each send before the next one, so that the second one spends the first one's change
+    let mut block_hash = block_hash;
+    let mut change_addresses = vec![];
+    for &height in &[2, 4] {
+        let raw_tx = wallet.send_to_address(branch_id, &ss, &so,
+                                vec![(&ext_address, AMOUNT_SENT, None)]).unwrap();
+        let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+
+        let mut cb = FakeCompactBlock::new(height, block_hash);
+        cb.add_tx(&sent_tx);
+        wallet.scan_block(&cb.as_bytes()).unwrap();
+        wallet.scan_full_tx(&sent_tx, height, 0);
+
+        let cb_next = FakeCompactBlock::new(height + 1, cb.hash());
+        wallet.scan_block(&cb_next.as_bytes()).unwrap();
+        block_hash = cb_next.hash();
+
+        let txs = wallet.txs.read().unwrap();
+        let nd = &txs[&sent_tx.txid()].notes[0];
+        assert_eq!(txs[&sent_tx.txid()].notes.len(), 1);
+        assert!(nd.is_change);
+
+        // The change isn't mistaken for an outgoing payment
+        assert_eq!(txs[&sent_tx.txid()].outgoing_metadata.len(), 1);
+        assert_eq!(txs[&sent_tx.txid()].outgoing_metadata[0].address, ext_address);
+
+        change_addresses.push(nd.extfvk.fvk.vk.into_payment_address(nd.diversifier, &JUBJUB).unwrap());
+    }
+
+    // Both are the wallet's own, but neither of them is its first address, nor the same as the other one
+    let default_address = wallet.extfvks.read().unwrap()[0].default_address().unwrap().1;
+    assert!(change_addresses.iter().all(|a| wallet.is_own_zaddress(a) && *a != default_address));
+    assert!(change_addresses[0] != change_addresses[1]);
+    assert!(!wallet.is_own_zaddress(&fvk.default_address().unwrap().1));
+
+    let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+    assert_eq!(wallet.zbalance(None), AMOUNT1 - 2 * (AMOUNT_SENT + fee));
+}
+
 #[test]
 fn test_multi_z() {
     const AMOUNT1: u64 = 50000;
@@ -1531,11 +2210,853 @@ fn test_bad_send() {
 }
 
 #[test]
-#[should_panic]
-fn test_bad_params() {
-    let (wallet, _, _) = get_test_wallet(100000);
-    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());  
-
+fn test_back_to_back_sends() {
+    const AMOUNT1: u64 = 50000;
+    const AMOUNT2: u64 = 40000;
+    let config = get_test_config();
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+
+    // Two notes
+    let mut cb1 = FakeCompactBlock::new(0, BlockHash([0; 32]));
+    let (_, txid1) = cb1.add_tx_paying(wallet.extfvks.read().unwrap()[0].clone(), AMOUNT1);
+    let (_, txid2) = cb1.add_tx_paying(wallet.extfvks.read().unwrap()[0].clone(), AMOUNT2);
+    wallet.scan_block(&cb1.as_bytes()).unwrap();
+
+    let cb2 = FakeCompactBlock::new(1, cb1.hash());
+    wallet.scan_block(&cb2.as_bytes()).unwrap();
+    assert_eq!(wallet.verified_zbalance(None), AMOUNT1 + AMOUNT2);
+
+    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+
+    // Each send needs exactly one note
+    let raw_tx1 = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_taddr, 100, None)]).unwrap();
+    let raw_tx2 = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_taddr, 100, None)]).unwrap();
+
+    let sent_tx1 = Transaction::read(&raw_tx1[..]).unwrap();
+    let sent_tx2 = Transaction::read(&raw_tx2[..]).unwrap();
+
+    // The second send didn't reuse the first one's note
+    assert_eq!(sent_tx1.shielded_spends.len(), 1);
+    assert_eq!(sent_tx2.shielded_spends.len(), 1);
+    assert_ne!(sent_tx1.shielded_spends[0].nullifier, sent_tx2.shielded_spends[0].nullifier);
+
+    {
+        let txs = wallet.txs.read().unwrap();
+        let spent_by = vec![txs[&txid1].notes[0].unconfirmed_spent, txs[&txid2].notes[0].unconfirmed_spent];
+        assert!(spent_by.contains(&Some(sent_tx1.txid())));
+        assert!(spent_by.contains(&Some(sent_tx2.txid())));
+    }
+
+    // And now all the funds are locked by the pending sends
+    let raw_tx3 = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_taddr, 100, None)]);
+    assert!(raw_tx3.err().unwrap().contains("locked by a pending transaction"));
+}
+
+#[test]
+fn test_split_value() {
+    assert_eq!(LightWallet::split_value(100_000, 1).unwrap(), vec![100_000]);
+    assert_eq!(LightWallet::split_value(100_000, 4).unwrap(), vec![25_000; 4]);
+
+    // The remainder goes to the first outputs
+    assert_eq!(LightWallet::split_value(100_002, 3).unwrap(), vec![33_335, 33_334, 33_333]);
+
+    // Bad counts
+    assert!(LightWallet::split_value(100_000, 0).is_err());
+    assert!(LightWallet::split_value(10_000_000, super::MAX_SPLIT_OUTPUTS + 1).is_err());
+
+    // Dust
+    assert!(LightWallet::split_value(20_000, 2).is_err());
+    assert!(LightWallet::split_value(20, 1).is_ok());
+}
+
+#[test]
+fn test_send_max() {
+    const AMOUNT1: u64 = 100000;
+    let (wallet, _, _) = get_test_wallet(AMOUNT1);
+
+    let zaddr2 = wallet.add_zaddr();
+    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+    let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+    // Sends without "max" are left alone
+    let tos = vec![(ext_taddr.as_str(), 100, None, 1)];
+    assert_eq!(wallet.resolve_send_max(tos.clone()).unwrap(), tos);
+
+    // "max" gets what the other recipients and the fee leave
+    let tos = wallet.resolve_send_max(vec![(ext_taddr.as_str(), super::SEND_MAX, None, 1)]).unwrap();
+    assert_eq!(tos[0].1, AMOUNT1 - fee);
+    let tos = wallet.resolve_send_max(vec![(ext_taddr.as_str(), 100, None, 1), (zaddr2.as_str(), super::SEND_MAX, None, 1)]).unwrap();
+    assert_eq!(tos[1].1, AMOUNT1 - 100 - fee);
+
+    // Only the last recipient can have it, and there has to be something left for it
+    assert!(wallet.resolve_send_max(vec![(zaddr2.as_str(), super::SEND_MAX, None, 1), (ext_taddr.as_str(), 100, None, 1)]).is_err());
+    assert!(wallet.resolve_send_max(vec![(ext_taddr.as_str(), AMOUNT1 - fee, None, 1), (zaddr2.as_str(), super::SEND_MAX, None, 1)]).is_err());
+
+    // Sending max spends everything, without change
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+    let raw_tx = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_taddr, super::SEND_MAX, None)]).unwrap();
+    let sent_txid = Transaction::read(&raw_tx[..]).unwrap().txid();
+
+    let mem = wallet.mempool_txs.read().unwrap();
+    assert_eq!(mem[&sent_txid].outgoing_metadata.len(), 1);
+    assert_eq!(mem[&sent_txid].outgoing_metadata[0].value, AMOUNT1 - fee);
+    assert_eq!(wallet.verified_zbalance(None), 0);
+}
+
+#[test]
+fn test_estimate_send() {
+    let mut rng = OsRng;
+    let secp = Secp256k1::new();
+
+    const AMOUNT_Z: u64 = 50000;
+    const AMOUNT_T: u64 = 40000;
+    let (mut wallet, _, _) = get_test_wallet(AMOUNT_Z);
+    let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+    let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+    let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                        &fvk.default_address().unwrap().1);
+    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+
+    // Only the z note, so nothing to warn about
+    let estimate = wallet.estimate_send(vec![(&ext_address, 20, None, 1)]).unwrap();
+    assert_eq!((estimate.total, estimate.fee, estimate.change), (20, fee, AMOUNT_Z - 20 - fee));
+    assert_eq!((estimate.notes, estimate.utxos), (1, 0));
+    assert!(estimate.warnings.is_empty());
+
+    // Receive to the t address too, which is spent along with the note
+    let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[0]);
+    let mut tx = FakeTransaction::new(&mut rng);
+    tx.add_t_output(&pk, AMOUNT_T);
+    wallet.scan_full_tx(&tx.get_tx(), 1, 0);
+
+    let estimate = wallet.estimate_send(vec![(&ext_address, 20, None, 1)]).unwrap();
+    assert_eq!((estimate.notes, estimate.utxos), (1, 1));
+    assert_eq!(estimate.change, AMOUNT_Z + AMOUNT_T - 20 - fee);
+    assert_eq!(estimate.warnings.len(), 1);
+    assert!(estimate.warnings[0].contains("2 different addresses"));
+
+    // Transparent to transparent
+    let estimate = wallet.estimate_send(vec![(&ext_taddr, 20, None, 1)]).unwrap();
+    assert_eq!(estimate.warnings.len(), 2);
+    assert!(estimate.warnings[1].contains("transparent to transparent"));
+
+    // Change back to a used address
+    wallet.config.fresh_change_address = false;
+    let estimate = wallet.estimate_send(vec![(&ext_address, 20, None, 1)]).unwrap();
+    assert_eq!(estimate.warnings.len(), 2);
+    assert!(estimate.warnings[1].contains("change back"));
+
+    // Sending everything leaves no change to warn about
+    let estimate = wallet.estimate_send(vec![(&ext_address, super::SEND_MAX, None, 1)]).unwrap();
+    assert_eq!(estimate.change, 0);
+    assert_eq!(estimate.warnings.len(), 1);
+
+    assert!(wallet.estimate_send(vec![(&ext_address, AMOUNT_Z + AMOUNT_T, None, 1)]).is_err());
+}
+
+#[test]
+fn test_compressed_wallet() {
+    use super::data::{SaplingNoteData, WalletTx};
+    use super::{is_wallet_corrupt, COMPRESSED_WALLET_MAGIC};
+
+    let config = get_test_config();
+    let (wallet, txid1, _) = get_test_wallet(50000);
+    let mut note_bytes = vec![];
+    wallet.txs.read().unwrap()[&txid1].notes[0].write(&mut note_bytes).unwrap();
+    {
+        let mut txs = wallet.txs.write().unwrap();
+        for i in 0..1_000u64 {
+            let mut txid = TxId([0u8; 32]);
+            txid.0[..8].copy_from_slice(&(i + 1).to_le_bytes());
+
+            let mut wtx = WalletTx::new(1, 0, &txid);
+            wtx.notes.push(SaplingNoteData::read(&note_bytes[..]).unwrap());
+            txs.insert(txid, wtx);
+        }
+    }
+
+    let mut plain = vec![];
+    wallet.write(&mut plain).unwrap();
+    let mut compressed = vec![];
+    wallet.write_compressed(&mut compressed).unwrap();
+    assert_eq!(compressed[..8], COMPRESSED_WALLET_MAGIC);
+    assert!(compressed.len() * 3 < plain.len(), "{} compressed, {} not", compressed.len(), plain.len());
+
+    // Either one reads as the same wallet
+    for bytes in [&plain, &compressed].iter() {
+        let read = LightWallet::read(&bytes[..], &config).unwrap();
+        assert_eq!(read.txs.read().unwrap().len(), 1_001);
+        assert_eq!(read.zbalance(None), wallet.zbalance(None));
+        assert_eq!(read.last_scanned_height(), wallet.last_scanned_height());
+    }
+
+    // A file of an older version can be saved compressed, and it's the same when read again
+    let old = LightWallet::read(&std::fs::read(format!("{}/testdata/wallets/v10.dat", env!("CARGO_MANIFEST_DIR"))).unwrap()[..], &config).unwrap();
+    let mut old_compressed = vec![];
+    old.write_compressed(&mut old_compressed).unwrap();
+    let read = LightWallet::read(&old_compressed[..], &config).unwrap();
+    assert_eq!(read.sent_to_history(), old.sent_to_history());
+
+    // Damaged or cut short, it's corrupt
+    let mut damaged = compressed.clone();
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 1;
+    assert!(is_wallet_corrupt(&LightWallet::read(&damaged[..], &config).err().unwrap()));
+    assert!(is_wallet_corrupt(&LightWallet::read(&compressed[..middle], &config).err().unwrap()));
+}
+
+#[test]
+fn test_estimated_size() {
+    use super::data::{SaplingNoteData, WalletTx};
+
+    /// Keeps only how much was written, and the largest single write
+    struct Measure { total: usize, largest: usize }
+    impl std::io::Write for Measure {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.total += buf.len();
+            self.largest = std::cmp::max(self.largest, buf.len());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    /// A buffer that keeps track of the most memory it ever held at once. While a Vec grows, it holds both
+    /// its old and its new allocation.
+    struct PeakBuffer { buffer: Vec<u8>, peak: usize }
+    impl std::io::Write for PeakBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let before = self.buffer.capacity();
+            self.buffer.extend_from_slice(buf);
+            let after = self.buffer.capacity();
+            let held = if after > before { before + after } else { after };
+            self.peak = std::cmp::max(self.peak, held);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    // A large wallet, with thousands of copies of a note
+    let (wallet, txid1, _) = get_test_wallet(50000);
+    let mut note_bytes = vec![];
+    wallet.txs.read().unwrap()[&txid1].notes[0].write(&mut note_bytes).unwrap();
+    {
+        let mut txs = wallet.txs.write().unwrap();
+        for i in 0..5_000u64 {
+            let mut txid = TxId([0u8; 32]);
+            txid.0[..8].copy_from_slice(&(i + 1).to_le_bytes());
+
+            let mut wtx = WalletTx::new(1, 0, &txid);
+            wtx.notes.push(SaplingNoteData::read(&note_bytes[..]).unwrap());
+            txs.insert(txid, wtx);
+        }
+    }
+
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+
+    // The estimate is a little over, so the buffer never has to grow, but not by much
+    let estimate = wallet.estimated_size();
+    assert!(estimate >= serialized_data.len(), "Estimate {} for {} bytes", estimate, serialized_data.len());
+    assert!(estimate <= serialized_data.len() * 5 / 4, "Estimate {} for {} bytes", estimate, serialized_data.len());
+
+    // The most memory writing the wallet takes, growing the buffer as it goes, and allocating it up front
+    let mut growing = PeakBuffer { buffer: vec![], peak: 0 };
+    wallet.write(&mut growing).unwrap();
+    let mut reserved = PeakBuffer { buffer: Vec::with_capacity(estimate), peak: estimate };
+    wallet.write(&mut reserved).unwrap();
+    println!("Wallet of {} bytes: peak {} bytes growing the buffer, {} bytes reserving it", 
+        serialized_data.len(), growing.peak, reserved.peak);
+    assert_eq!(reserved.buffer.capacity(), estimate);
+    assert!(reserved.peak < growing.peak);
+
+    // Writing it out goes piece by piece, nothing is gathered up into one big write
+    let mut measure = Measure { total: 0, largest: 0 };
+    wallet.write(&mut measure).unwrap();
+    assert_eq!(measure.total, serialized_data.len());
+    assert!(measure.largest <= 512, "Largest write {}", measure.largest);
+    assert!(measure.total > 5_000 * note_bytes.len());
+}
+
+/// Not a test, but how long listing the notes of a wallet with 50,000 of them takes, and how long it would
+/// take on top of that to put them in their lists by their JSON, as do_list_notes did before it used the
+/// notes' spent fields. Run it with
+///     cargo test --release -- --ignored bench_list_notes --nocapture
+#[test]
+#[ignore]
+fn bench_list_notes() {
+    use std::time::Instant;
+    use super::data::{SaplingNoteData, WalletTx};
+
+    const NOTES: usize = 50_000;
+    let (wallet, txid1, _) = get_test_wallet(50000);
+
+    // Copies of the one note, a third each unspent, spent and pending
+    let mut note_bytes = vec![];
+    wallet.txs.read().unwrap()[&txid1].notes[0].write(&mut note_bytes).unwrap();
+    {
+        let mut txs = wallet.txs.write().unwrap();
+        for i in 0..NOTES {
+            let mut txid = TxId([0u8; 32]);
+            txid.0[..8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+
+            let mut nd = SaplingNoteData::read(&note_bytes[..]).unwrap();
+            match i % 3 {
+                1 => nd.spent = Some(txid1),
+                2 => nd.unconfirmed_spent = Some(txid1),
+                _ => {},
+            }
+
+            let mut wtx = WalletTx::new(1, 0, &txid);
+            wtx.notes.push(nd);
+            txs.insert(txid, wtx);
+        }
+    }
+
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let lc = crate::lightclient::LightClient::read_from_buffer(&get_test_config(), &serialized_data[..]).unwrap();
+
+    let start = Instant::now();
+    let notes = lc.do_list_notes(true);
+    let listed = start.elapsed();
+
+    assert_eq!(notes["count"].as_usize(), Some(NOTES + 1));
+    assert_eq!(notes["spent_notes"].len(), NOTES / 3);
+    assert_eq!(notes["pending_notes"].len(), NOTES / 3);
+
+    // What it did before: all the notes in one list, then each one into its list by which fields are null
+    let all = notes["unspent_notes"].members()
+        .chain(notes["spent_notes"].members())
+        .chain(notes["pending_notes"].members())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let (mut unspent, mut spent, mut pending) = (vec![], vec![], vec![]);
+    for note in all {
+        if note["spent"].is_null() && note["unconfirmed_spent"].is_null() {
+            unspent.push(note);
+        } else if !note["spent"].is_null() {
+            spent.push(note);
+        } else {
+            pending.push(note);
+        }
+    }
+    let partitioned = start.elapsed();
+    assert_eq!((unspent.len(), spent.len(), pending.len()), (notes["unspent_notes"].len(), NOTES / 3, NOTES / 3));
+
+    println!("Listing {} notes took {:?}. Partitioning them by their JSON would add {:?}", NOTES + 1, listed, partitioned);
+}
+
+#[test]
+fn test_dust_change() {
+    const AMOUNT1: u64 = 50000;
+    let (mut wallet, _, block_hash) = get_test_wallet(AMOUNT1);
+    let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+    let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+    let ext_address = encode_payment_address(wallet.config.hrp_sapling_address(),
+                        &fvk.default_address().unwrap().1);
+
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+
+    // Leaves 100 as change, which is less than the fee
+    const AMOUNT_SENT: u64 = AMOUNT1 - 10100;
+    let e = wallet.estimate_send(vec![(&ext_address, AMOUNT_SENT, None, 1)]).unwrap_err();
+    assert!(e.starts_with(super::DUST_CHANGE));
+    let e = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_address, AMOUNT_SENT, None)]).unwrap_err();
+    assert!(e.starts_with(super::DUST_CHANGE));
+
+    // Nothing was marked as spent
+    assert_eq!(wallet.verified_zbalance(None), AMOUNT1);
+
+    // Change of exactly the fee is dust too, but one more isn't
+    assert!(wallet.estimate_send(vec![(&ext_address, AMOUNT1 - 2 * fee, None, 1)]).is_err());
+    assert!(wallet.estimate_send(vec![(&ext_address, AMOUNT1 - 2 * fee - 1, None, 1)]).is_ok());
+
+    // When allowed, the dust goes to the fee instead of a change note, and the estimate warns about it
+    wallet.config.donate_dust_to_fee = true;
+    let estimate = wallet.estimate_send(vec![(&ext_address, AMOUNT_SENT, None, 1)]).unwrap();
+    assert_eq!(estimate.change, 0);
+    assert_eq!(estimate.fee, AMOUNT1 - AMOUNT_SENT);
+    assert!(estimate.warnings.iter().any(|w| w.contains("to the fee")));
+
+    let raw_tx = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_address, AMOUNT_SENT, None)]).unwrap();
+    let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+    assert_eq!(sent_tx.shielded_outputs.len(), 1);
+    assert_eq!(wallet.tx_fee(&sent_tx), Some(AMOUNT1 - AMOUNT_SENT));
+
+    let mut cb3 = FakeCompactBlock::new(2, block_hash);
+    cb3.add_tx(&sent_tx);
+    wallet.scan_block(&cb3.as_bytes()).unwrap();
+    wallet.scan_full_tx(&sent_tx, 2, 0);
+
+    assert_eq!(wallet.zbalance(None), 0);
+    assert!(wallet.txs.read().unwrap()[&sent_tx.txid()].notes.is_empty());
+}
+
+#[test]
+fn test_max_sapling_outputs() {
+    use super::MAX_SAPLING_OUTPUTS;
+
+    let (wallet, _, _) = get_test_wallet(10_000_000);
+    let addresses = (0..MAX_SAPLING_OUTPUTS as u8).map(|i| {
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[i; 32]));
+        encode_payment_address(wallet.config.hrp_sapling_address(), &fvk.default_address().unwrap().1)
+    }).collect::<Vec<_>>();
+    let taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+    let tos = |n: usize| addresses[..n].iter().map(|a| (a.as_str(), 1000, None, 1)).collect::<Vec<_>>();
+
+    // The change is a shielded output too, so it has to fit as well
+    let e = wallet.estimate_send(tos(MAX_SAPLING_OUTPUTS)).unwrap_err();
+    assert!(e.starts_with(&format!("Too many shielded outputs ({} and the change)", MAX_SAPLING_OUTPUTS)), "{}", e);
+    assert!(wallet.estimate_send(tos(MAX_SAPLING_OUTPUTS - 1)).is_ok());
+
+    // Transparent outputs don't count
+    let mut with_taddr = tos(MAX_SAPLING_OUTPUTS - 1);
+    with_taddr.push((taddr.as_str(), 1000, None, 1));
+    assert!(wallet.estimate_send(with_taddr).is_ok());
+}
+
+#[test]
+fn test_memo_preview() {
+    use super::utils::memo_preview;
+
+    assert_eq!(memo_preview("Hello", 10), "Hello");
+    assert_eq!(memo_preview("Hello", 5), "Hello");
+    assert_eq!(memo_preview("Hello world", 6), "Hello…");
+    assert_eq!(memo_preview("Hello", 0), "");
+
+    // Multi-byte characters are never split, however many bytes they are
+    assert_eq!(memo_preview("héllo wörld", 5), "héll…");
+    assert_eq!(memo_preview("日本語のメモ", 4), "日本語…");
+    assert_eq!(memo_preview("🎉🎉🎉", 2), "🎉…");
+    assert_eq!(memo_preview("🎉🎉", 2), "🎉🎉");
+
+    // One line, without trailing spaces before the ellipsis
+    assert_eq!(memo_preview("Line one\nline two\n", 20), "Line one line two");
+    assert_eq!(memo_preview("Line one\nline two", 10), "Line one…");
+}
+
+#[test]
+fn test_normalize_seed_phrase() {
+    use super::utils::normalize_seed_phrase;
+
+    let config = get_test_config();
+    let phrase = String::clone(&LightWallet::new(None, &config, 0).unwrap().get_seed_phrase());
+
+    // Extra spaces, non-breaking spaces, a tab, a newline and capitals
+    let messy = format!("  {}\n", phrase.replacen(" ", "   ", 3).replacen(" ", "\u{a0}", 1).replacen(" ", "\t", 1).to_uppercase());
+    assert_ne!(messy, phrase);
+    assert_eq!(*normalize_seed_phrase(&messy), phrase);
+
+    // It derives the same wallet as the clean phrase
+    let clean = LightWallet::new(Some(phrase.clone()), &config, 0).unwrap();
+    let restored = LightWallet::new(Some(messy), &config, 0).unwrap();
+    assert_eq!(*restored.get_seed_phrase(), phrase);
+    assert_eq!(*restored.zaddress.read().unwrap(), *clean.zaddress.read().unwrap());
+    assert_eq!(*restored.taddresses.read().unwrap(), *clean.taddresses.read().unwrap());
+
+    // NFKD: a composed character and its decomposed form are the same
+    assert_eq!(normalize_seed_phrase("caf\u{e9}"), normalize_seed_phrase("cafe\u{301}"));
+
+    // Only whitespace between the words is collapsed, and it's all trimmed at the ends
+    assert_eq!(*normalize_seed_phrase(" \t "), "");
+    assert_eq!(*normalize_seed_phrase("Abandon\u{a0}\u{a0}ABILITY "), "abandon ability");
+}
+
+#[test]
+fn test_memo_reply_to() {
+    use super::utils::memo_reply_to;
+
+    let addr = "ytestsapling1fixturerecipient";
+    let reply_to = Some(addr.to_string());
+
+    // At the end, the way wallets add it to what was typed
+    assert_eq!(memo_reply_to(&format!("Thanks for lunch\nReply-To:\n{}", addr)), reply_to);
+    assert_eq!(memo_reply_to(&format!("Thanks for lunch\n\nreply-to: {}\n", addr)), reply_to);
+
+    // Or as a header at the start
+    assert_eq!(memo_reply_to(&format!("Reply-To: {}\nThanks for lunch", addr)), reply_to);
+    assert_eq!(memo_reply_to(&format!("REPLY-TO:\n{}\nThanks for lunch", addr)), reply_to);
+
+    // Anything else is just text
+    assert_eq!(memo_reply_to("Thanks for lunch"), None);
+    assert_eq!(memo_reply_to(""), None);
+    assert_eq!(memo_reply_to("Reply-To:"), None);
+    assert_eq!(memo_reply_to(&format!("Thanks\nReply-To: {}\nfor lunch", addr)), None);
+    assert_eq!(memo_reply_to("Thanks for lunch\nReply-To: the office"), None);
+    assert_eq!(memo_reply_to("Réply-To: x"), None);
+}
+
+#[test]
+fn test_fiat_rates() {
+    let config = get_test_config();
+    let (wallet, txid, _) = get_test_wallet(100000);
+    assert_eq!(wallet.fiat_rate(&txid), None);
+
+    let rate = FiatRate { currency: "EUR".to_string(), amount: 12.5, price: 0.3125 };
+    wallet.set_fiat_rate(&txid, rate.clone());
+    assert_eq!(wallet.fiat_rate(&txid), Some(rate.clone()));
+
+    // The rates are saved with the wallet
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let wallet2 = LightWallet::read(&serialized_data[..], &config).unwrap();
+    assert_eq!(wallet2.fiat_rate(&txid), Some(rate));
+    assert_eq!(wallet2.fiat_rate(&TxId([7; 32])), None);
+}
+
+#[test]
+fn test_json_round_trip() {
+    const AMOUNT1: u64 = 50000;
+    const AMOUNT_T: u64 = 20000;
+    let mut rng = OsRng;
+    let secp = Secp256k1::new();
+    let config = get_test_config();
+
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+    let savings = wallet.add_account("savings").unwrap();
+    wallet.add_zaddr_to_account(savings);
+    wallet.add_taddr_to_account(savings);
+    let watched = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[2u8; 32]));
+    wallet.add_watch_only_key(&encode_extended_full_viewing_key(config.hrp_sapling_viewing_key(), &watched)).unwrap();
+
+    // A note to the savings z address, and a utxo to the savings t address
+    let mut cb1 = FakeCompactBlock::new(0, BlockHash([0; 32]));
+    cb1.add_tx_paying(wallet.extfvks.read().unwrap()[1].clone(), AMOUNT1);
+    wallet.scan_block(&cb1.as_bytes()).unwrap();
+    let cb2 = FakeCompactBlock::new(1, cb1.hash());
+    wallet.scan_block(&cb2.as_bytes()).unwrap();
+
+    let pk = PublicKey::from_secret_key(&secp, &wallet.tkeys.read().unwrap()[1]);
+    let mut tx = FakeTransaction::new(&mut rng);
+    tx.add_t_output(&pk, AMOUNT_T);
+    wallet.scan_full_tx(&tx.get_tx(), 1, 0);
+    wallet.set_fiat_rate(&TxId([7; 32]), FiatRate { currency: "USD".to_string(), amount: 5.0, price: 0.25 });
+
+    let json = wallet.to_json();
+    assert_eq!(json["z_addresses"].len(), 3);
+    assert_eq!(json["fiat_rates"][0]["currency"], "USD");
+    assert_eq!(json["z_addresses"][1]["account"], "savings");
+    assert_eq!(json["z_addresses"][2]["watch_only"], true);
+    assert_eq!(json["transactions"].len(), 2);
+
+    let restored = LightWallet::from_json(&json, &config).unwrap();
+    assert_eq!(restored.to_json(), json);
+    assert_eq!(restored.zbalance(None), AMOUNT1);
+    assert_eq!(restored.tbalance(None), AMOUNT_T);
+    assert_eq!(restored.account_addresses(savings), wallet.account_addresses(savings));
+    assert_eq!(restored.get_watch_only_keys(), wallet.get_watch_only_keys());
+    assert_eq!(restored.fiat_rate(&TxId([7; 32])), wallet.fiat_rate(&TxId([7; 32])));
+
+    // The note has no witnesses until the blocks are scanned again
+    let witnesses = |w: &LightWallet| w.txs.read().unwrap().values().flat_map(|wtx| wtx.notes.iter().map(|nd| nd.witnesses.len())).collect::<Vec<_>>();
+    assert_eq!(witnesses(&restored), vec![0]);
+    restored.scan_block(&cb1.as_bytes()).unwrap();
+    restored.scan_block(&cb2.as_bytes()).unwrap();
+    assert_eq!(witnesses(&restored), witnesses(&wallet));
+    assert_eq!(restored.verified_zbalance(None), AMOUNT1);
+
+    // Without the seed, or on another chain, it can't be rebuilt
+    let mut no_seed = json.clone();
+    no_seed.remove("seed");
+    assert!(LightWallet::from_json(&no_seed, &config).is_err());
+
+    let mut other_chain = json.clone();
+    other_chain["chain_name"] = "main".into();
+    assert!(LightWallet::from_json(&other_chain, &config).is_err());
+}
+
+#[test]
+fn test_watch_only() {
+    const AMOUNT1: u64 = 100000;
+    const AMOUNT_WATCHED: u64 = 50000;
+    let (wallet, _, block_hash) = get_test_wallet(AMOUNT1);
+    let config = get_test_config();
+
+    let watched = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[2u8; 32]));
+    let key = encode_extended_full_viewing_key(config.hrp_sapling_viewing_key(), &watched);
+    let watched_address = encode_payment_address(config.hrp_sapling_address(), &watched.default_address().unwrap().1);
+
+    assert!(wallet.add_watch_only_key("not a key").is_err());
+    assert_eq!(wallet.add_watch_only_key(&key).unwrap(), watched_address);
+    assert!(wallet.add_watch_only_key(&key).unwrap_err().contains("already"));
+    assert!(wallet.is_watch_only_address(&watched_address));
+    assert!(!wallet.is_own_zaddress(&watched.default_address().unwrap().1));
+    let zaddr1 = encode_payment_address(config.hrp_sapling_address(), &wallet.zaddress.read().unwrap()[0]);
+    assert!(!wallet.is_watch_only_address(&zaddr1));
+
+    // Notes to the watched address count towards the balance
+    let mut cb3 = FakeCompactBlock::new(2, block_hash);
+    let (_, txid2) = cb3.add_tx_paying(watched.clone(), AMOUNT_WATCHED);
+    wallet.scan_block(&cb3.as_bytes()).unwrap();
+    let cb4 = FakeCompactBlock::new(3, cb3.hash());
+    wallet.scan_block(&cb4.as_bytes()).unwrap();
+
+    assert_eq!(wallet.zbalance(None), AMOUNT1 + AMOUNT_WATCHED);
+    assert_eq!(wallet.zbalance(Some(watched_address.clone())), AMOUNT_WATCHED);
+
+    // ...but can't be spent
+    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+    let e = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_taddr, AMOUNT1 + 1000, None)]).unwrap_err();
+    assert!(e.contains("watch-only"));
+
+    // New addresses go before the watched one, which keeps its notes
+    let zaddr2 = wallet.add_zaddr();
+    assert!(!wallet.is_watch_only_address(&zaddr2));
+    assert!(wallet.is_watch_only_address(&watched_address));
+    assert_eq!(wallet.txs.read().unwrap()[&txid2].notes[0].account, 2);
+    assert_eq!(wallet.zbalance(Some(watched_address.clone())), AMOUNT_WATCHED);
+    assert_eq!(wallet.zbalance(Some(zaddr2.clone())), 0);
+
+    // Still watched after a round trip
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let wallet2 = LightWallet::read(&serialized_data[..], &config).unwrap();
+    assert!(wallet2.is_watch_only_address(&watched_address));
+    assert!(!wallet2.is_watch_only_address(&zaddr2));
+    assert_eq!(wallet2.extsks.read().unwrap().len(), 2);
+    assert_eq!(wallet2.zbalance(Some(watched_address)), AMOUNT_WATCHED);
+}
+
+#[test]
+fn test_fee_rate() {
+    const AMOUNT1: u64 = 100000;
+    let (wallet, _, _) = get_test_wallet(AMOUNT1);
+    let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+    // DEFAULT_FEE is the floor, and covers the grace actions
+    assert_eq!(LightWallet::min_fee(1, 0), fee);
+    assert_eq!(LightWallet::min_fee(5, 0), fee);
+    assert_eq!(LightWallet::min_fee(1, 5000), fee);
+    assert_eq!(LightWallet::min_fee(3, 5000), 15000);
+    assert_eq!(LightWallet::fee_rate(fee, 1), fee / super::GRACE_ACTIONS);
+    assert_eq!(LightWallet::fee_rate(fee, 4), fee / 4);
+
+    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+
+    // 1 spend, and the change output, plus the t output
+    let raw_tx = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_taddr, 20000, None)]).unwrap();
+    let tx = Transaction::read(&raw_tx[..]).unwrap();
+    assert_eq!(LightWallet::logical_actions(&tx), 2);
+    assert_eq!(wallet.tx_fee(&tx), Some(fee));
+
+    // A network that wants more than the default fee for that many actions gets what it wants
+    let (mut wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+    wallet.config.min_fee_per_action = fee;
+    let raw_tx = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_taddr, 20000, None)]).unwrap();
+    let tx = Transaction::read(&raw_tx[..]).unwrap();
+    assert_eq!(LightWallet::logical_actions(&tx), 2);
+    assert_eq!(wallet.tx_fee(&tx), Some(2 * fee));
+
+    // ...and the change is smaller by that much
+    let mut cb3 = FakeCompactBlock::new(2, block_hash);
+    cb3.add_tx(&tx);
+    wallet.scan_block(&cb3.as_bytes()).unwrap();
+    wallet.scan_full_tx(&tx, 2, 0);
+    let txs = wallet.txs.read().unwrap();
+    assert_eq!(txs[&txid1].notes[0].spent, Some(tx.txid()));
+    assert_eq!(txs[&tx.txid()].notes[0].note.value, AMOUNT1 - 20000 - 2 * fee);
+}
+
+#[test]
+fn test_fee_bump() {
+    const AMOUNT1: u64 = 100000;
+    const AMOUNT_SENT: u64 = 20000;
+    let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+    let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+
+    let raw_tx = wallet.send_to_address(branch_id, &ss, &so, vec![(&ext_taddr, AMOUNT_SENT, None)]).unwrap();
+    let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+    let sent_txid = sent_tx.txid();
+    assert_eq!(wallet.mempool_txs.read().unwrap()[&sent_txid].fee, Some(fee));
+
+    // The fee has to go up, and can't take more than the change
+    assert!(wallet.build_fee_bump(branch_id, &ss, &so, &sent_txid, fee).unwrap_err().contains("has to be more than"));
+    assert!(wallet.build_fee_bump(branch_id, &ss, &so, &sent_txid, AMOUNT1).unwrap_err().contains("isn't enough for a fee"));
+
+    // The replacement spends the same note, and pays the same recipient, with the higher fee
+    let (bumped_tx, bumped_fee) = wallet.build_fee_bump(branch_id, &ss, &so, &sent_txid, 2 * fee).unwrap();
+    assert_eq!(bumped_fee, 2 * fee);
+    assert_eq!(wallet.tx_fee(&bumped_tx), Some(2 * fee));
+    assert_eq!(bumped_tx.shielded_spends.len(), 1);
+    assert_eq!(bumped_tx.shielded_spends[0].nullifier, sent_tx.shielded_spends[0].nullifier);
+    assert_eq!(bumped_tx.vout.len(), 1);
+    assert_eq!(bumped_tx.vout[0].value, Amount::from_u64(AMOUNT_SENT).unwrap());
+
+    // Building it doesn't change the wallet
+    assert!(wallet.mempool_txs.read().unwrap().contains_key(&sent_txid));
+    assert_eq!(wallet.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, Some(sent_txid));
+
+    // Replacing the pending transaction does, and leaves only the replacement pending
+    wallet.replace_pending_tx(&sent_txid, &bumped_tx, bumped_fee);
+    {
+        let mempool_txs = wallet.mempool_txs.read().unwrap();
+        assert_eq!(mempool_txs.len(), 1);
+        assert_eq!(mempool_txs[&bumped_tx.txid()].fee, Some(2 * fee));
+        assert_eq!(mempool_txs[&bumped_tx.txid()].outgoing_metadata[0].value, AMOUNT_SENT);
+    }
+    assert_eq!(wallet.txs.read().unwrap()[&txid1].notes[0].unconfirmed_spent, Some(bumped_tx.txid()));
+    assert!(wallet.build_fee_bump(branch_id, &ss, &so, &sent_txid, 3 * fee).unwrap_err().contains("isn't a pending transaction"));
+
+    // Once it's mined, its fee can't be bumped anymore
+    let mut cb3 = FakeCompactBlock::new(2, block_hash);
+    cb3.add_tx(&bumped_tx);
+    wallet.scan_block(&cb3.as_bytes()).unwrap();
+    wallet.scan_full_tx(&bumped_tx, 2, 0);
+    assert!(wallet.build_fee_bump(branch_id, &ss, &so, &bumped_tx.txid(), 3 * fee).unwrap_err().contains("already confirmed"));
+}
+
+#[test]
+fn test_send_to_multiple_taddrs() {
+    const AMOUNT1: u64 = 1_000_000;
+    let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+    let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+
+    let taddrs = (1..=3u8).map(|i| wallet.address_from_sk(&SecretKey::from_slice(&[i; 32]).unwrap())).collect::<Vec<_>>();
+    let amounts = [10_000u64, 20_000, 30_000];
+    let tos = taddrs.iter().zip(amounts.iter()).map(|(a, v)| (a.as_str(), *v, None)).collect::<Vec<_>>();
+
+    // A mainnet address among them is refused, and nothing is spent
+    let main_taddr = LightWallet::address_from_prefix_sk(&zcash_primitives::constants::mainnet::B58_PUBKEY_ADDRESS_PREFIX,
+                                                         &SecretKey::from_slice(&[4u8; 32]).unwrap());
+    let mut mixed = tos.clone();
+    mixed.push((main_taddr.as_str(), 1000, None));
+    let e = wallet.send_to_address(branch_id, &ss, &so, mixed).unwrap_err();
+    assert_eq!(e, format!("Recipient address '{}' is for main, but the wallet is on test", main_taddr));
+    assert!(wallet.send_to_address(branch_id, &ss, &so, vec![("tmNotAnAddress", 1000, None)]).unwrap_err().starts_with("Invalid recipient address"));
+    assert_eq!(wallet.verified_zbalance(None), AMOUNT1);
+
+    // One transaction, with a t output of the right amount for each address
+    let raw_tx = wallet.send_to_address(branch_id, &ss, &so, tos).unwrap();
+    let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+    let sent_txid = sent_tx.txid();
+    assert_eq!(sent_tx.vout.len(), 3);
+    for (taddr, amount) in taddrs.iter().zip(amounts.iter()) {
+        let out = sent_tx.vout.iter()
+            .find(|o| wallet.address_from_pubkeyhash(o.script_pubkey.address()).as_ref() == Some(taddr))
+            .unwrap();
+        assert_eq!(i64::from(out.value), *amount as i64);
+    }
+
+    // 3 t outputs, and a spend and the change: the fee has to cover 4 logical actions
+    let actions = LightWallet::logical_actions(&sent_tx);
+    assert_eq!(actions, 4);
+    assert_eq!(wallet.tx_fee(&sent_tx), Some(fee));
+    assert!(fee >= LightWallet::min_fee(actions, wallet.config.min_fee_per_action));
+
+    let mut cb3 = FakeCompactBlock::new(2, block_hash);
+    cb3.add_tx(&sent_tx);
+    wallet.scan_block(&cb3.as_bytes()).unwrap();
+    wallet.scan_full_tx(&sent_tx, 2, 0);
+    {
+        let txs = wallet.txs.read().unwrap();
+        assert_eq!(txs[&txid1].notes[0].spent, Some(sent_txid));
+        assert_eq!(txs[&sent_txid].notes[0].note.value, AMOUNT1 - amounts.iter().sum::<u64>() - fee);
+
+        let mut sent = txs[&sent_txid].outgoing_metadata.iter().map(|om| (om.address.clone(), om.value)).collect::<Vec<_>>();
+        sent.sort();
+        let mut expected = taddrs.iter().cloned().zip(amounts.iter().cloned()).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(sent, expected);
+    }
+
+    // When the network charges for each action, the default fee only covers one t output, so sending to three
+    // pays for all four actions
+    let (mut wallet, _, _) = get_test_wallet(AMOUNT1);
+    wallet.config.min_fee_per_action = fee / super::GRACE_ACTIONS;
+    let tos = taddrs.iter().zip(amounts.iter()).map(|(a, v)| (a.as_str(), *v, None)).collect::<Vec<_>>();
+    let estimate_tos = tos.iter().map(|(a, v, m)| (*a, *v, m.clone(), 1)).collect::<Vec<_>>();
+    assert_eq!(wallet.estimate_send(estimate_tos).unwrap().fee, 4 * fee / super::GRACE_ACTIONS);
+    let raw_tx = wallet.send_to_address(branch_id, &ss, &so, tos).unwrap();
+    let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+    assert_eq!(LightWallet::logical_actions(&sent_tx), 4);
+    assert_eq!(wallet.tx_fee(&sent_tx), Some(4 * fee / super::GRACE_ACTIONS));
+
+    // Sending the max to the last of them leaves what the fee for all the actions needs
+    let (mut wallet, _, _) = get_test_wallet(AMOUNT1);
+    wallet.config.min_fee_per_action = fee / super::GRACE_ACTIONS;
+    let max_tos = vec![(taddrs[0].as_str(), amounts[0], None), (taddrs[1].as_str(), amounts[1], None), 
+                       (taddrs[2].as_str(), super::SEND_MAX, None)];
+    let raw_tx = wallet.send_to_address(branch_id, &ss, &so, max_tos).unwrap();
+    let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+    assert_eq!(LightWallet::logical_actions(&sent_tx), 4);
+    assert_eq!(wallet.tx_fee(&sent_tx), Some(4 * fee / super::GRACE_ACTIONS));
+    assert_eq!(sent_tx.shielded_outputs.len(), 0);
+}
+
+#[test]
+fn test_z_spend_split() {
+    const AMOUNT1: u64 = 100000;
+    let (wallet, txid1, block_hash) = get_test_wallet(AMOUNT1);
+
+    let zaddr2 = wallet.add_zaddr();
+    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());
+
+    const AMOUNT_SENT: u64 = 60001;
+    let fee: u64 = DEFAULT_FEE.try_into().unwrap();
+
+    let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
+    let (ss, so) = get_sapling_params().unwrap();
+
+    // Can't split to a t address
+    let raw_tx = wallet.send_to_address_with_splits(branch_id, &ss, &so,
+                            vec![(&ext_taddr, AMOUNT_SENT, None, 2)]);
+    assert!(raw_tx.err().unwrap().contains("shielded"));
+
+    // Send to our second address as 3 notes
+    let raw_tx = wallet.send_to_address_with_splits(branch_id, &ss, &so,
+                            vec![(&zaddr2, AMOUNT_SENT, None, 3)]).unwrap();
+    let sent_tx = Transaction::read(&raw_tx[..]).unwrap();
+    let sent_txid = sent_tx.txid();
+
+    // 3 outputs + change
+    assert_eq!(sent_tx.shielded_outputs.len(), 4);
+
+    let mut cb3 = FakeCompactBlock::new(2, block_hash);
+    cb3.add_tx(&sent_tx);
+    wallet.scan_block(&cb3.as_bytes()).unwrap();
+    wallet.scan_full_tx(&sent_tx, 2, 0);
+
+    {
+        let txs = wallet.txs.read().unwrap();
+        assert_eq!(txs[&txid1].notes[0].spent, Some(sent_txid));
+
+        let mut values = txs[&sent_txid].notes.iter()
+                            .filter(|n| !n.is_change)
+                            .map(|n| n.note.value)
+                            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(values, vec![20000, 20000, 20001]);
+
+        let change_note = txs[&sent_txid].notes.iter().find(|n| n.is_change).unwrap();
+        assert_eq!(change_note.note.value, AMOUNT1 - AMOUNT_SENT - fee);
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_bad_params() {
+    let (wallet, _, _) = get_test_wallet(100000);
+    let ext_taddr = wallet.address_from_sk(&SecretKey::from_slice(&[1u8; 32]).unwrap());  
+
     let branch_id = u32::from_str_radix("2bb40e60", 16).unwrap();
     // Bad params
     let _ = wallet.send_to_address(branch_id, &[], &[],
@@ -1743,13 +3264,14 @@ fn test_rollback() {
 #[test]
 fn test_t_derivation() {
     let lc = LightClientConfig {
-        server: "0.0.0.0:0".parse().unwrap(),
+        servers: vec!["0.0.0.0:0".parse().unwrap()],
         chain_name: "main".to_string(),
         sapling_activation_height: 0,
         consensus_branch_id: "000000".to_string(),
         anchor_offset: 1,
         no_cert_verification: false,
         data_dir: None,
+        ..LightClientConfig::create_unconnected("main".to_string(), None)
     };
 
     let seed_phrase = Some("chimney better bulb horror rebuild whisper improve intact letter giraffe brave rib appear bulk aim burst snap salt hill sad merge tennis phrase raise".to_string());
@@ -1771,7 +3293,7 @@ fn test_t_derivation() {
     assert_eq!(zaddr, "ys1qxxggphflwgtj9u0jp7d7vl4rlulrqus3mcmt04jewe7jyu2gvjw0ulu566s89muv5ydgct9mkf");
     assert_eq!(sk, "secret-extended-key-main1q0s95lnpqqqqpqprun5juwk7l7yx20d59vzvqs0rr2lzw2damfk7z535ly05wau55cecp27rpnx6n9zzz4vw3jg39xl6vkx3l4fad7fq8ngas8r63u9sxk7aummma0day07sy6n5sy445674f3vc9c8uf60vs9fzs3dmqfgd3gvex4f9yht8pngl78292geelw0r95hea436xj7wpx9mzl4jfcl3924urex2fjdjjpy6737s6tgftytj05l06mjc4phlzje7znxy2vsp3r398");
 
-    assert_eq!(seed_phrase, Some(wallet.get_seed_phrase()));
+    assert_eq!(seed_phrase.unwrap(), *wallet.get_seed_phrase());
 }
 
 #[test]
@@ -1892,6 +3414,131 @@ fn test_lock_unlock() {
     assert!(wallet2.unlock("newpassword".to_string()).is_err());
 }
 
+#[test]
+fn test_unlock_viewing() {
+    const AMOUNT: u64 = 500000;
+
+    let (mut wallet, _, _) = get_test_wallet(AMOUNT);
+    let seed = wallet.seed;
+
+    // A wallet that's not encrypted is always unlocked
+    assert!(wallet.is_unlocked_for_viewing() && wallet.is_unlocked_for_spending());
+    assert!(wallet.unlock_viewing("somepassword".to_string()).is_err());
+
+    // Encrypting locks it completely
+    wallet.encrypt("somepassword".to_string()).unwrap();
+    assert!(!wallet.is_unlocked_for_viewing() && !wallet.is_unlocked_for_spending());
+
+    // Unlocking for viewing checks the password, but doesn't bring back the seed or the spending keys
+    assert!(wallet.unlock_viewing("differentpassword".to_string()).is_err());
+    assert!(!wallet.is_unlocked_for_viewing());
+    wallet.unlock_viewing("somepassword".to_string()).unwrap();
+    assert!(wallet.is_unlocked_for_viewing() && !wallet.is_unlocked_for_spending());
+    assert!(wallet.unlock_viewing("somepassword".to_string()).is_err());
+    assert_eq!([0u8; 32], wallet.seed);
+    assert_eq!(wallet.extsks.read().unwrap().len(), 0);
+    assert_eq!(wallet.get_seed_phrase().len(), 0);
+    assert_eq!(wallet.zbalance(None), AMOUNT);
+
+    // It can be written while unlocked for viewing
+    wallet.write(&mut vec![]).expect("Serialize wallet");
+
+    // Unlocking for spending from there works, and locking spending keeps it unlocked for viewing
+    wallet.unlock("somepassword".to_string()).unwrap();
+    assert!(wallet.is_unlocked_for_viewing() && wallet.is_unlocked_for_spending());
+    assert_eq!(seed, wallet.seed);
+
+    wallet.lock_spending().unwrap();
+    assert!(wallet.is_unlocked_for_viewing() && !wallet.is_unlocked_for_spending());
+    assert_eq!([0u8; 32], wallet.seed);
+
+    // Locking locks it completely
+    wallet.lock().unwrap();
+    assert!(!wallet.is_unlocked_for_viewing() && !wallet.is_unlocked_for_spending());
+    assert!(wallet.lock().is_err());
+}
+
+#[test]
+fn test_secrets() {
+    use super::secret::{Secret, Wipe};
+
+    let config = get_test_config();
+    let mut wallet = LightWallet::new(None, &config, 0).unwrap();
+
+    // The key accessors hand out secrets, which are wiped when they are dropped
+    let _: Secret<String> = wallet.get_seed_phrase();
+    let _: Vec<(String, Secret<String>)> = wallet.get_z_private_keys();
+    let _: Vec<(String, Secret<String>)> = wallet.get_t_secret_keys();
+
+    let mut phrase = wallet.get_seed_phrase();
+    assert_eq!(phrase.split(' ').count(), 24);
+    assert_eq!(format!("{:?}", phrase), "Secret(..)");
+    phrase.wipe();
+    assert!(phrase.bytes().all(|b| b == 0));
+
+    let mut sk = wallet.tkeys.read().unwrap()[0].clone();
+    sk.wipe();
+    assert!(sk[..].iter().all(|b| *b == 0));
+
+    // Locking wipes the seed and the keys
+    wallet.encrypt("somepassword".to_string()).unwrap();
+    assert_eq!(wallet.seed, [0u8; 32]);
+    assert!(wallet.tkeys.read().unwrap().is_empty());
+    assert!(wallet.get_seed_phrase().is_empty());
+}
+
+#[test]
+fn test_kdf_upgrade() {
+    use sodiumoxide::crypto::secretbox;
+    use super::kdf::Kdf;
+
+    let config = get_test_config();
+    let mut wallet = LightWallet::new(None, &config, 0).unwrap();
+    let seed = wallet.seed;
+
+    // New encryptions use Argon2id
+    wallet.encrypt("somepassword".to_string()).unwrap();
+    assert!(wallet.kdf().is_current());
+    assert_eq!(wallet.kdf().to_json()["algorithm"], "argon2id");
+    wallet.unlock("somepassword".to_string()).unwrap();
+    wallet.remove_encryption("somepassword".to_string()).unwrap();
+
+    // Encrypt it the way wallets were before there was a choice of key derivation
+    let key = secretbox::Key::from_slice(&super::double_sha256("somepassword".as_bytes())).unwrap();
+    let nonce = secretbox::gen_nonce();
+    wallet.enc_seed.copy_from_slice(&secretbox::seal(&wallet.seed, &nonce, &key));
+    wallet.nonce = nonce.as_ref().to_vec();
+    wallet.kdf = Kdf::DoubleSha256;
+    wallet.encrypted = true;
+    wallet.lock().unwrap();
+
+    let mut serialized_data = vec![];
+    wallet.write(&mut serialized_data).expect("Serialize wallet");
+    let mut wallet2 = LightWallet::read(&serialized_data[..], &config).unwrap();
+    assert_eq!(*wallet2.kdf(), Kdf::DoubleSha256);
+
+    // It still unlocks with the old key derivation, and is encrypted again with the new one
+    assert!(wallet2.unlock("differentpassword".to_string()).is_err());
+    wallet2.unlock("somepassword".to_string()).unwrap();
+    assert_eq!(seed, wallet2.seed);
+    assert!(wallet2.kdf().is_current());
+
+    // Which is what the next save writes
+    wallet2.lock().unwrap();
+    let mut serialized_data = vec![];
+    wallet2.write(&mut serialized_data).expect("Serialize wallet");
+    let mut wallet3 = LightWallet::read(&serialized_data[..], &config).unwrap();
+    assert_eq!(wallet3.kdf(), wallet2.kdf());
+    assert!(wallet3.unlock("differentpassword".to_string()).is_err());
+    wallet3.unlock("somepassword".to_string()).unwrap();
+    assert_eq!(seed, wallet3.seed);
+
+    // A damaged file can't make unlocking take all the memory
+    let mut kdf_bytes = vec![];
+    Kdf::Argon2id { opslimit: 3, memlimit: u64::max_value(), salt: [0; 16] }.write(&mut kdf_bytes).unwrap();
+    assert!(Kdf::read(&kdf_bytes[..]).is_err());
+}
+
 #[test]
 #[should_panic]
 fn test_invalid_bip39_t() {
@@ -1936,6 +3583,68 @@ fn test_invalid_scan_blocks() {
     assert_eq!(wallet.blocks.read().unwrap().len(), 5);
 }
 
+#[test]
+fn test_reorg_depth_limit() {
+    const AMOUNT: u64 = 500000;
+    const MAX_DEPTH: u64 = 3;
+    let (wallet, _txid1, block_hash) = get_test_wallet(AMOUNT);
+
+    add_blocks(&wallet, 2, 9, block_hash).unwrap();
+    assert_eq!(wallet.last_scanned_height(), 10);
+
+    // A server on a fork that goes deeper than MAX_DEPTH. Every block it sends doesn't connect to 
+    // ours, so each round rolls back one more block, until the limit is hit.
+    let mut total_reorg = 0;
+    let err = loop {
+        let fork_blk = FakeCompactBlock::new(wallet.last_scanned_height() + 1, BlockHash([0; 32]));
+        let invalid_height = wallet.scan_block(&fork_blk.as_bytes()).unwrap_err();
+
+        match wallet.invalidate_block_within(invalid_height, MAX_DEPTH - total_reorg) {
+            Ok(n)  => total_reorg += n,
+            Err(e) => break e
+        }
+    };
+
+    assert!(err.contains("more than the 0 allowed"));
+    assert_eq!(total_reorg, MAX_DEPTH);
+
+    // It stopped at the limit, instead of rolling back any further
+    assert_eq!(wallet.last_scanned_height(), 10 - MAX_DEPTH as i32);
+
+    // A deep rollback in one go is refused without changing anything
+    assert!(wallet.invalidate_block_within(2, 3).is_err());
+    assert_eq!(wallet.last_scanned_height(), 7);
+    assert_eq!(wallet.invalidate_block_within(5, 3), Ok(3));
+    assert_eq!(wallet.last_scanned_height(), 4);
+}
+
+#[test]
+fn test_encrypt_decrypt_message() {
+    let config = get_test_config();
+    let sender = LightWallet::new(None, &config, 0).unwrap();
+    let recipient = LightWallet::new(None, &config, 0).unwrap();
+
+    let to = recipient.zaddress.read().unwrap()[0].clone();
+    let to_str = encode_payment_address(config.hrp_sapling_address(), &to);
+
+    let blob = sender.encrypt_message(to, "Hello, off-chain").unwrap();
+
+    // Only the recipient can read it
+    assert_eq!(recipient.decrypt_message(&blob), Ok((to_str, "Hello, off-chain".to_string())));
+    assert!(sender.decrypt_message(&blob).is_err());
+
+    // Tampering with it, or truncating it, makes it unreadable
+    let mut bad = blob.clone();
+    let last = bad.len() - 1;
+    bad[last] ^= 1;
+    assert!(recipient.decrypt_message(&bad).is_err());
+    assert!(recipient.decrypt_message(&blob[..blob.len() - 1]).is_err());
+
+    // Messages have to fit in a memo
+    let to = recipient.zaddress.read().unwrap()[0].clone();
+    assert!(sender.encrypt_message(to, &"a".repeat(513)).is_err());
+}
+
 #[test]
 fn test_encrypted_zreceive() {
     const AMOUNT1: u64 = 50000;
@@ -2151,3 +3860,113 @@ fn test_encrypted_treceive() {
         assert_eq!(utxo2.unconfirmed_spent, None);
     }
 }
+
+#[test]
+fn test_resolve_recipient() {
+    use zcash_client_backend::{constants::mainnet, encoding::encode_extended_spending_key};
+
+    let config = get_test_config();
+
+    let extsk = ExtendedSpendingKey::master(&[3u8; 32]);
+    let extfvk = ExtendedFullViewingKey::from(&extsk);
+    let zaddr = encode_payment_address(config.hrp_sapling_address(), &extfvk.default_address().unwrap().1);
+
+    // Addresses are left as they are
+    assert_eq!(LightWallet::resolve_recipient(&zaddr, &config).unwrap(), zaddr);
+
+    // A viewing key pays its default address, every time
+    let key = encode_extended_full_viewing_key(config.hrp_sapling_viewing_key(), &extfvk);
+    assert_eq!(LightWallet::resolve_recipient(&key, &config).unwrap(), zaddr);
+    assert_eq!(LightWallet::resolve_recipient(&key, &config).unwrap(), zaddr);
+
+    // A viewing key for another chain is refused
+    let main_key = encode_extended_full_viewing_key(mainnet::HRP_SAPLING_EXTENDED_FULL_VIEWING_KEY, &extfvk);
+    assert!(LightWallet::resolve_recipient(&main_key, &config).unwrap_err().contains("not for test"));
+
+    // A spending key is refused without being repeated in the error
+    let sk = encode_extended_spending_key(config.hrp_sapling_private_key(), &extsk);
+    let e = LightWallet::resolve_recipient(&sk, &config).unwrap_err();
+    assert_eq!(e, "Invalid recipient address");
+    assert!(!e.contains(&sk));
+
+    assert!(LightWallet::resolve_recipient("not an address", &config).is_err());
+}
+
+#[test]
+fn test_prune_witnesses() {
+    const AMOUNT: u64 = 1_000_000;
+    const PAYMENT: u64 = 1_000;
+    let config = get_test_config();
+    let wallet = LightWallet::new(None, &config, 0).unwrap();
+    let extfvk = wallet.extfvks.read().unwrap()[0].clone();
+    let addr2 = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[0u8; 32])).default_address().unwrap().1;
+    let witnesses = |w: &LightWallet| w.txs.read().unwrap().values()
+        .flat_map(|wtx| wtx.notes.iter().map(|nd| nd.witnesses.len()))
+        .sum::<usize>();
+
+    let mut cb = FakeCompactBlock::new(0, BlockHash([0; 32]));
+    let (mut nf, _) = cb.add_tx_paying(extfvk.clone(), AMOUNT);
+    wallet.scan_block(&cb.as_bytes()).unwrap();
+
+    // A long scan in which the wallet keeps spending its change, checkpointing every 50 blocks like a sync
+    let mut value = AMOUNT;
+    let mut counts = vec![];
+    for height in 1..500 {
+        let mut next = FakeCompactBlock::new(height, cb.hash());
+        let spend = if height % 10 == 0 {
+            Some(next.add_tx_spending((nf.clone(), value), extfvk.clone(), addr2.clone(), PAYMENT))
+        } else {
+            None
+        };
+        wallet.scan_block(&next.as_bytes()).unwrap();
+
+        if let Some(txid) = spend {
+            value -= PAYMENT;
+            nf = wallet.txs.read().unwrap()[&txid].notes[0].nullifier.to_vec();
+        }
+        if height % 50 == 0 {
+            wallet.prune_witnesses();
+            counts.push(witnesses(&wallet));
+        }
+        cb = next;
+    }
+
+    // Once the first spends are older than a reorg can go back, the witnesses stop growing
+    assert!(counts[4] > 0);
+    assert!(counts[4..].iter().all(|c| *c <= counts[4] + counts[4] / 4), "{:?}", counts);
+
+    // Only the notes spent long enough ago lose theirs. The change can still be spent.
+    wallet.prune_witnesses();
+    {
+        let txs = wallet.txs.read().unwrap();
+        for nd in txs.values().flat_map(|wtx| wtx.notes.iter()) {
+            let spent_at = nd.spent.map(|txid| txs[&txid].block);
+            match spent_at {
+                Some(h) if h + (super::MAX_REORG as i32) < wallet.last_scanned_height() => assert!(nd.witnesses.is_empty()),
+                _ => assert!(!nd.witnesses.is_empty()),
+            }
+        }
+    }
+    assert_eq!(wallet.zbalance(None), value);
+}
+
+#[test]
+fn test_wipe_spending_keys() {
+    use super::secret::Wipe;
+
+    let mut extsk = ExtendedSpendingKey::master(&[1u8; 32]);
+    extsk.wipe();
+    assert!(extsk.expsk.ask.is_zero());
+    assert!(extsk.expsk.nsk.is_zero());
+    assert_eq!(extsk.expsk.ovk.0, [0u8; 32]);
+
+    // Locking wipes the keys the wallet had, not just its references to them
+    let (mut wallet, _, _) = get_test_wallet(0);
+    wallet.encrypt("password".to_string()).unwrap();
+    assert!(wallet.extsks.read().unwrap().is_empty());
+    wallet.unlock("password".to_string()).unwrap();
+    let extsks = wallet.extsks.clone();
+    wallet.lock_spending().unwrap();
+    assert!(!extsks.read().unwrap().is_empty());
+    assert!(extsks.read().unwrap().iter().all(|extsk| extsk.expsk.ask.is_zero()));
+}
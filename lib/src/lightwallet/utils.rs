@@ -1,11 +1,16 @@
 use std::io::{self, Read, Write};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use json::JsonValue;
+use unicode_normalization::UnicodeNormalization;
+use zcash_primitives::transaction::TxId;
+
+use crate::grpcconnector::txid_from_hex;
+use super::secret::Secret;
 
 pub fn read_string<R: Read>(mut reader: R) -> io::Result<String> {
     // Strings are written as <littleendian> len + bytes
     let str_len = reader.read_u64::<LittleEndian>()?;
-    let mut str_bytes = vec![0; str_len as usize];
-    reader.read_exact(&mut str_bytes)?;
+    let str_bytes = read_bytes(&mut reader, str_len)?;
 
     let str = String::from_utf8(str_bytes).map_err(|e| {
         io::Error::new(io::ErrorKind::InvalidData, e.to_string())
@@ -14,8 +19,145 @@ pub fn read_string<R: Read>(mut reader: R) -> io::Result<String> {
     Ok(str)
 }
 
+/// Read `len` bytes. The buffer only grows as the bytes come in, so a corrupt length can't make it 
+/// allocate more than there is to read.
+pub fn read_bytes<R: Read>(reader: R, len: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    reader.take(len).read_to_end(&mut bytes)?;
+
+    if (bytes.len() as u64) < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Expected {} bytes, found {}", len, bytes.len())));
+    }
+
+    Ok(bytes)
+}
+
 pub fn write_string<W: Write>(mut writer: W, s: &String) -> io::Result<()> {
     // Strings are written as len + utf8
     writer.write_u64::<LittleEndian>(s.as_bytes().len() as u64)?;
     writer.write_all(s.as_bytes())
-}
\ No newline at end of file
+}
+
+fn json_field_error(field: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("'{}' is missing or wrong", field))
+}
+
+/// Read `field` of a JSON object, which has to be a number
+pub fn json_u64(json: &JsonValue, field: &str) -> io::Result<u64> {
+    json[field].as_u64().ok_or_else(|| json_field_error(field))
+}
+
+/// Read `field` of a JSON object, which has to be a number, with or without a fraction
+pub fn json_f64(json: &JsonValue, field: &str) -> io::Result<f64> {
+    json[field].as_f64().ok_or_else(|| json_field_error(field))
+}
+
+/// Read `field` of a JSON object, which has to be a string
+pub fn json_str<'a>(json: &'a JsonValue, field: &str) -> io::Result<&'a str> {
+    json[field].as_str().ok_or_else(|| json_field_error(field))
+}
+
+/// Read `field` of a JSON object, which has to be true or false
+pub fn json_bool(json: &JsonValue, field: &str) -> io::Result<bool> {
+    json[field].as_bool().ok_or_else(|| json_field_error(field))
+}
+
+/// Read `field` of a JSON object, which has to be `len` bytes in hex
+pub fn json_hex(json: &JsonValue, field: &str, len: usize) -> io::Result<Vec<u8>> {
+    match hex::decode(json_str(json, field)?) {
+        Ok(bytes) if bytes.len() == len => Ok(bytes),
+        _ => Err(json_field_error(field)),
+    }
+}
+
+/// Read `field` of a JSON object, which has to be a txid as block explorers show it, or null
+pub fn json_txid(json: &JsonValue, field: &str) -> io::Result<Option<TxId>> {
+    if json[field].is_null() {
+        return Ok(None);
+    }
+
+    txid_from_hex(json_str(json, field)?).map(Some).map_err(|_| json_field_error(field))
+}
+
+/// A seed phrase the way BIP39 reads it: NFKD normalized, lowercase, and the words separated by single spaces.
+/// Phrases copied from other apps often have extra or non-breaking spaces, or capitals, which would otherwise
+/// fail to parse. The phrase is normalized one char at a time, straight into a buffer that is big enough for 
+/// all of it, so that there are no intermediate copies of it that aren't wiped.
+pub fn normalize_seed_phrase(phrase: &str) -> Secret<String> {
+    let chars = || phrase.nfkd().flat_map(|c| c.to_lowercase());
+
+    let mut normalized = Secret::new(String::with_capacity(chars().map(|c| c.len_utf8()).sum()));
+    let mut space = false;
+    for c in chars() {
+        if c.is_whitespace() {
+            space = !normalized.is_empty();
+        } else {
+            if space {
+                normalized.push(' ');
+                space = false;
+            }
+            normalized.push(c);
+        }
+    }
+
+    normalized
+}
+
+const REPLY_TO: &str = "reply-to:";
+
+/// What follows "Reply-To:" on a line that starts with it
+fn reply_to_header(line: &str) -> Option<&str> {
+    if line.len() >= REPLY_TO.len() && line.is_char_boundary(REPLY_TO.len()) 
+            && line[..REPLY_TO.len()].eq_ignore_ascii_case(REPLY_TO) {
+        Some(line[REPLY_TO.len()..].trim())
+    } else {
+        None
+    }
+}
+
+/// The address a memo asks replies to go to, for memos that follow the reply-to convention of Ycash and
+/// Zcash wallets: a "Reply-To:" line followed by the address, either on the same line or the next one,
+/// at the start of the memo or at its end. Whether it's a valid address is up to the caller. Memos that 
+/// don't follow it are just text, and have none.
+pub fn memo_reply_to(memo: &str) -> Option<String> {
+    let lines = memo.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).collect::<Vec<_>>();
+
+    let address = match (lines.first(), lines.last()) {
+        (None, _) | (_, None) => None,
+
+        // At the start
+        (Some(first), _) if reply_to_header(first).is_some() => match reply_to_header(first) {
+            Some("") => lines.get(1).cloned(),
+            address  => address,
+        },
+
+        // At the end
+        (_, Some(last)) => match reply_to_header(last) {
+            Some("") => None,
+            Some(address) => Some(address),
+            None if lines.len() >= 2 && reply_to_header(lines[lines.len() - 2]) == Some("") => Some(*last),
+            None => None,
+        },
+    };
+
+    address.filter(|a| !a.contains(char::is_whitespace)).map(|a| a.to_string())
+}
+
+/// A memo shortened to at most `max_chars` characters for display, ending in an ellipsis if anything was
+/// cut off. It is cut between characters, never inside a multi-byte one, and line breaks and other
+/// control characters become spaces so that the preview fits on one line.
+pub fn memo_preview(memo: &str, max_chars: usize) -> String {
+    let chars = memo.trim().chars().map(|c| if c.is_control() { ' ' } else { c }).collect::<Vec<char>>();
+
+    if chars.len() <= max_chars {
+        return chars.into_iter().collect();
+    }
+
+    if max_chars == 0 {
+        return "".to_string();
+    }
+
+    let mut preview = chars[..max_chars - 1].iter().collect::<String>().trim_end().to_string();
+    preview.push('…');
+    preview
+}
@@ -0,0 +1,221 @@
+use std::io::{self, Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use crate::lightclient::{self, LightClient, LightClientConfig};
+use crate::commands;
+
+/// Keeps several named wallets, eg. a personal and a business one, in the same data dir, each in its own
+/// file (see `LightClientConfig::wallet_name`). At most one of them is open at a time, and the `do_*` calls
+/// go to the open one through `active`. Opening or creating a wallet closes the open one first, which saves it.
+pub struct WalletManager {
+    config: LightClientConfig,
+    active: Mutex<Option<(String, Arc<LightClient>)>>,
+}
+
+impl WalletManager {
+    /// The wallets are in `config`'s data dir. Its `wallet_name` is ignored, each wallet gets its own.
+    pub fn new(config: &LightClientConfig) -> Self {
+        WalletManager {
+            config: LightClientConfig { wallet_name: None, ..config.clone() },
+            active: Mutex::new(None),
+        }
+    }
+
+    /// The config of the wallet called `name`
+    pub fn config_for(&self, name: &str) -> LightClientConfig {
+        LightClientConfig { wallet_name: Some(name.to_string()), ..self.config.clone() }
+    }
+
+    /// The names of the wallets in the data dir, sorted
+    pub fn list_wallets(&self) -> io::Result<Vec<String>> {
+        let prefix = lightclient::wallet_file_name("");
+        let (start, end) = prefix.split_at(prefix.len() - ".dat".len());
+
+        let mut names = std::fs::read_dir(self.config.get_data_dir())?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|file| file.len() > prefix.len() && file.starts_with(start) && file.ends_with(end))
+            .map(|file| file[start.len()..file.len() - end.len()].to_string())
+            .filter(|name| WalletManager::is_valid_name(name))
+            .collect::<Vec<_>>();
+        names.sort();
+
+        Ok(names)
+    }
+
+    /// Names are used in the wallet's file name, so they can only have letters, digits, '-' and '_'
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    /// Create a new wallet, with a new seed, and make it the open one. Fails if there already is a wallet
+    /// with this name.
+    pub fn create(&self, name: &str, latest_block: u64) -> io::Result<Arc<LightClient>> {
+        let config = self.checked_config_for(name)?;
+
+        // Held until the new wallet is the open one, so no other wallet can be opened in between
+        let mut active = self.active.lock().unwrap();
+        if config.wallet_exists() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("There already is a wallet called {}", name)));
+        }
+
+        WalletManager::close_active(&mut active)?;
+        let lc = Arc::new(LightClient::new(&config, latest_block)?);
+        info!("Created wallet {}", name);
+
+        *active = Some((name.to_string(), lc.clone()));
+        Ok(lc)
+    }
+
+    /// Open the wallet called `name`, closing the one that's open
+    pub fn open(&self, name: &str) -> io::Result<Arc<LightClient>> {
+        let config = self.checked_config_for(name)?;
+
+        let mut active = self.active.lock().unwrap();
+        if !config.wallet_exists() {
+            return Err(Error::new(ErrorKind::NotFound, format!("There is no wallet called {}", name)));
+        }
+
+        if let Some((active_name, lc)) = active.as_ref() {
+            if active_name == name {
+                return Ok(lc.clone());
+            }
+        }
+
+        WalletManager::close_active(&mut active)?;
+        let lc = Arc::new(LightClient::read_from_disk(&config)?);
+        info!("Opened wallet {}", name);
+
+        *active = Some((name.to_string(), lc.clone()));
+        Ok(lc)
+    }
+
+    /// Close the open wallet, if there is one: stop its sync, save it, disconnect from the server and let other
+    /// processes open it, even if the caller still has the `LightClient`
+    pub fn close(&self) -> io::Result<()> {
+        WalletManager::close_active(&mut self.active.lock().unwrap())
+    }
+
+    fn close_active(active: &mut Option<(String, Arc<LightClient>)>) -> io::Result<()> {
+        let (name, lc) = match active.take() {
+            Some(active) => active,
+            None         => return Ok(()),
+        };
+
+        // Shutting down only saves an auto-saved wallet
+        let saved = lc.do_shutdown().and_then(|_| if lc.config.auto_save { Ok(()) } else { lc.do_save() });
+        lc.release_lock();
+        info!("Closed wallet {}", name);
+
+        saved.map_err(|e| Error::new(ErrorKind::Other, format!("Couldn't save wallet {}: {}", name, e)))
+    }
+
+    /// The name of the open wallet
+    pub fn active_name(&self) -> Option<String> {
+        self.active.lock().unwrap().as_ref().map(|(name, _)| name.clone())
+    }
+
+    /// The open wallet, to make the `do_*` calls on
+    pub fn active(&self) -> Result<Arc<LightClient>, String> {
+        self.active.lock().unwrap().as_ref()
+            .map(|(_, lc)| lc.clone())
+            .ok_or_else(|| "No wallet is open".to_string())
+    }
+
+    /// Run a command on the open wallet, see `commands::do_user_command`
+    pub fn do_user_command(&self, cmd: &str, args: &Vec<&str>) -> String {
+        match self.active() {
+            Ok(lc) => commands::do_user_command(cmd, args, &lc),
+            Err(e) => e,
+        }
+    }
+
+    fn checked_config_for(&self, name: &str) -> io::Result<LightClientConfig> {
+        if !WalletManager::is_valid_name(name) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("Invalid wallet name '{}', it can only have letters, digits, '-' and '_'", name)));
+        }
+
+        Ok(self.config_for(name))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+    use tempdir::TempDir;
+
+    use super::WalletManager;
+    use crate::grpcconnector::tests::lightwalletd_server;
+    use crate::lightclient::LightClientConfig;
+    use crate::lightwallet::tests::{fake_blocks_paying, first_extfvk};
+
+    #[test]
+    pub fn test_wallet_manager() {
+        let tmp = TempDir::new("lctest").unwrap();
+        let dir_name = tmp.path().to_str().map(|s| s.to_string());
+
+        // The wallets sync from a mock server, which gets its blocks once the wallets exist
+        let blocks = Arc::new(Mutex::new(vec![]));
+        let server = lightwalletd_server(blocks.clone(), vec![]);
+        let config = LightClientConfig {
+            servers: vec![format!("http://{}", server).parse().unwrap()],
+            sapling_activation_height: 1,
+            max_attempts: 1,
+            ..LightClientConfig::create_unconnected("test".to_string(), dir_name)
+        };
+
+        let manager = WalletManager::new(&config);
+        assert!(manager.list_wallets().unwrap().is_empty());
+        assert!(manager.active().is_err());
+        assert_eq!(manager.do_user_command("balance", &vec![]), "No wallet is open");
+
+        assert!(manager.create("../personal", 0).is_err());
+        assert!(manager.open("personal").is_err());
+
+        // Each wallet has its own addresses
+        let personal = manager.create("personal", 0).unwrap();
+        let personal_zaddr = personal.do_new_address("z").unwrap()[0].clone();
+        assert_eq!(manager.active_name(), Some("personal".to_string()));
+
+        let business = manager.create("business", 0).unwrap();
+        assert_eq!(manager.active_name(), Some("business".to_string()));
+        let business_addresses = business.do_address();
+        assert!(!business_addresses["z_addresses"].members().any(|a| *a == personal_zaddr));
+        assert!(manager.create("business", 0).is_err());
+
+        assert_eq!(manager.list_wallets().unwrap(), vec!["business".to_string(), "personal".to_string()]);
+        assert!(!config.wallet_exists());
+
+        // Both are paid in the same block, and each only finds its own note
+        *blocks.lock().unwrap() = fake_blocks_paying(1, 3, &[(1, first_extfvk(&personal.wallet.read().unwrap()), 100_000), 
+                                                             (1, first_extfvk(&business.wallet.read().unwrap()), 250_000)]);
+        business.do_sync(false).unwrap();
+        assert_eq!(business.do_balance()["zbalance"].as_u64(), Some(250_000));
+
+        // Switching back opens the personal wallet as it was saved, before any sync
+        let personal = manager.open("personal").unwrap();
+        assert!(personal.do_address()["z_addresses"].members().any(|a| *a == personal_zaddr));
+        assert_eq!(manager.active().unwrap().do_address(), personal.do_address());
+        assert_eq!(personal.do_balance()["zbalance"].as_u64(), Some(0));
+
+        personal.do_sync(false).unwrap();
+        assert_eq!(personal.do_balance()["zbalance"].as_u64(), Some(100_000));
+
+        // ...and the business one is as it was synced, without syncing again
+        let business = manager.open("business").unwrap();
+        assert_eq!(business.do_address(), business_addresses);
+        assert_eq!(business.do_balance()["zbalance"].as_u64(), Some(250_000));
+        assert_eq!(business.wallet.read().unwrap().last_scanned_height(), 3);
+
+        let personal = manager.open("personal").unwrap();
+        assert_eq!(personal.do_balance()["zbalance"].as_u64(), Some(100_000));
+        assert_eq!(personal.do_list_notes(true)["unspent_notes"].len(), 1);
+
+        manager.close().unwrap();
+        assert!(manager.active().is_err());
+        assert!(!crate::lightclient::WalletLock::lock_path(&manager.config_for("personal").get_wallet_path()).exists());
+        assert_eq!(manager.list_wallets().unwrap().len(), 2);
+    }
+}